@@ -0,0 +1,22 @@
+#include <stdio.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+int child_work(int n) {
+    int total = 0;
+    for (int i = 1; i <= n; i++) {
+        total += i;
+    }
+    return total;
+}
+
+int main() {
+    pid_t pid = fork();
+    if (pid == 0) {
+        printf("child: %d\n", child_work(10));
+        return 0;
+    }
+    waitpid(pid, NULL, 0);
+    printf("parent: child %d finished\n", pid);
+    return 0;
+}
@@ -0,0 +1,25 @@
+#include <pthread.h>
+#include <stdio.h>
+#include <unistd.h>
+
+void *worker(void *arg) {
+    long id = (long)arg;
+    for (int i = 0; i < 3; i++) {
+        printf("worker %ld: %d\n", id, i);
+        sleep(1);
+    }
+    return NULL;
+}
+
+int main() {
+    pthread_t threads[2];
+    for (long i = 0; i < 2; i++) {
+        pthread_create(&threads[i], NULL, worker, (void *)i);
+    }
+    sleep(1);
+    for (int i = 0; i < 2; i++) {
+        pthread_join(threads[i], NULL);
+    }
+    printf("all done\n");
+    return 0;
+}
@@ -0,0 +1,104 @@
+//! A tiny expression language for breakpoint conditions. A condition is either a single operand,
+//! which is true when nonzero, or two operands compared with one of `== != < <= > >=`. Operands
+//! are registers (`rax` or `$rax`), integer literals (`42`, `-1`, `0x10`), or `*ADDRESS`, which
+//! reads the int stored at that address.
+
+use crate::inferior::{is_register, register_value};
+
+enum Operand {
+    Register(String),
+    Literal(i64),
+    Memory(usize),
+}
+
+pub struct Condition {
+    left: Operand,
+    /// Comparison operator and right-hand operand, if the condition isn't a single operand
+    comparison: Option<(&'static str, Operand)>,
+}
+
+const OPERATORS: [&str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+
+fn parse_integer(s: &str) -> Option<i64> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+    let value = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()? as i64,
+        None => digits.parse().ok()?,
+    };
+    if negative {
+        value.checked_neg()
+    } else {
+        Some(value)
+    }
+}
+
+fn parse_operand(s: &str) -> Result<Operand, String> {
+    let s = s.trim();
+    if let Some(addr) = s.strip_prefix('*') {
+        return match parse_integer(addr.trim()) {
+            Some(addr) if addr >= 0 => Ok(Operand::Memory(addr as usize)),
+            _ => Err(format!("invalid address \"{}\"", addr)),
+        };
+    }
+    if let Some(value) = parse_integer(s) {
+        return Ok(Operand::Literal(value));
+    }
+    let name = s.strip_prefix('$').unwrap_or(s);
+    if is_register(name) {
+        Ok(Operand::Register(name.to_string()))
+    } else {
+        Err(format!("unknown operand \"{}\"", s))
+    }
+}
+
+impl Condition {
+    pub fn parse(expr: &str) -> Result<Condition, String> {
+        for op in OPERATORS {
+            if let Some((left, right)) = expr.split_once(op) {
+                return Ok(Condition {
+                    left: parse_operand(left)?,
+                    comparison: Some((op, parse_operand(right)?)),
+                });
+            }
+        }
+        Ok(Condition {
+            left: parse_operand(expr)?,
+            comparison: None,
+        })
+    }
+
+    /// Evaluates the condition against the inferior's registers. `read_int` reads the int at an
+    /// address in the inferior's memory.
+    pub fn evaluate(
+        &self,
+        regs: &libc::user_regs_struct,
+        read_int: impl Fn(usize) -> Result<i64, nix::Error>,
+    ) -> Result<bool, String> {
+        let value = |operand: &Operand| -> Result<i64, String> {
+            match operand {
+                Operand::Register(name) => Ok(register_value(regs, name).unwrap() as i64),
+                Operand::Literal(value) => Ok(*value),
+                Operand::Memory(addr) => read_int(*addr)
+                    .map_err(|err| format!("cannot access memory at address {:#x}: {}", addr, err)),
+            }
+        };
+        let left = value(&self.left)?;
+        Ok(match &self.comparison {
+            None => left != 0,
+            Some((op, right)) => {
+                let right = value(right)?;
+                match *op {
+                    "==" => left == right,
+                    "!=" => left != right,
+                    "<=" => left <= right,
+                    ">=" => left >= right,
+                    "<" => left < right,
+                    _ => left > right,
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,370 @@
+//! Debug Adapter Protocol server. `deet --dap PORT TARGET` waits for an editor that speaks DAP (VS
+//! Code, Neovim with nvim-dap, ...) to connect to PORT on localhost, then lets it drive the
+//! debugger with DAP requests instead of typed commands. See
+//! https://microsoft.github.io/debug-adapter-protocol/specification for the protocol.
+//!
+//! Every message is a JSON object preceded by a `Content-Length` header, like an HTTP body. The
+//! program being debugged is always TARGET, and its output goes to our terminal rather than to the
+//! editor. It has a single thread as far as the editor is concerned.
+
+use crate::debugger::Breakpoint;
+use crate::dwarf_data::DwarfData;
+use crate::inferior::{Frame, Inferior, Status};
+use nix::sys::signal::Signal;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// Id of the program's only thread
+const THREAD_ID: u64 = 1;
+
+struct Session {
+    conn: TcpStream,
+    /// Sequence number of the last message we sent
+    seq: u64,
+    target: String,
+    debug_data: DwarfData,
+    inferior: Option<Inferior>,
+    /// Whether to stop as soon as the program starts, rather than letting it run once the editor
+    /// has set its breakpoints
+    stop_on_entry: bool,
+    breakpoints: HashMap<usize, Option<Breakpoint>>,
+    /// Addresses of the breakpoints in each source file. The editor sends all of a file's
+    /// breakpoints at once, replacing the ones it sent before.
+    source_breakpoints: HashMap<String, Vec<usize>>,
+    /// Stack frames as of the last stackTrace request. DAP frame ids are indices into this, and
+    /// variable references are frame ids plus one (since 0 means "no variables").
+    frames: Vec<Frame>,
+}
+
+/// Waits for a DAP client to connect to `port`, then debugs `target` for it until it disconnects.
+pub fn serve(port: u16, target: &str, debug_data: DwarfData) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Listening for a DAP client on port {}", port);
+    let (conn, peer_addr) = listener.accept()?;
+    println!("DAP client connected from {}", peer_addr);
+    let mut reader = BufReader::new(conn.try_clone()?);
+    let mut session = Session {
+        conn,
+        seq: 0,
+        target: target.to_string(),
+        debug_data,
+        inferior: None,
+        stop_on_entry: false,
+        breakpoints: HashMap::new(),
+        source_breakpoints: HashMap::new(),
+        frames: Vec::new(),
+    };
+    while let Some(request) = read_message(&mut reader)? {
+        if !session.handle(&request)? {
+            break;
+        }
+    }
+    if let Some(inferior) = &mut session.inferior {
+        inferior.kill();
+    }
+    println!("DAP client disconnected.");
+    Ok(())
+}
+
+/// Reads the next message from the client, or returns None if it has closed the connection.
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().ok();
+            }
+        }
+    }
+    let content_length = content_length
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "DAP message without a Content-Length"))?;
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+impl Session {
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(self.conn, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    fn send_event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({"type": "event", "event": event, "body": body}))
+    }
+
+    /// Handles a request from the client. Returns false once the client has asked us to stop.
+    fn handle(&mut self, request: &Value) -> io::Result<bool> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsEvaluateForHovers": true,
+            })),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            // We don't stop on exceptions, but clients send this whether or not we support it
+            "setExceptionBreakpoints" | "configurationDone" | "disconnect" | "terminate" => Ok(json!({})),
+            "threads" => Ok(json!({"threads": [{"id": THREAD_ID, "name": self.target}]})),
+            "continue" => self.running().map(|_| json!({"allThreadsContinued": true})),
+            "next" => self.running().map(|_| json!({})),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => self.scopes(args),
+            "variables" => self.variables(args),
+            "evaluate" => self.evaluate(args),
+            _ => Err(format!("Unsupported request \"{}\"", command)),
+        };
+        let succeeded = result.is_ok();
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+            "success": succeeded,
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)?;
+        if !succeeded {
+            return Ok(true);
+        }
+
+        // Events caused by the request have to come after the response
+        match command {
+            "initialize" => self.send_event("initialized", json!({}))?,
+            "configurationDone" if self.inferior.is_some() => {
+                if self.stop_on_entry {
+                    self.send_stopped("entry", None)?;
+                } else {
+                    self.resume(false)?;
+                }
+            }
+            "continue" => self.resume(false)?,
+            "next" => self.resume(true)?,
+            "disconnect" | "terminate" => return Ok(false),
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn running(&self) -> Result<&Inferior, String> {
+        self.inferior.as_ref().ok_or_else(|| "The program is not being run.".to_string())
+    }
+
+    /// Starts the program, stopped before its first instruction. It runs once the client says it
+    /// has finished setting breakpoints.
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program_args: Vec<String> = args["args"]
+            .as_array()
+            .map(|args| args.iter().filter_map(|arg| arg.as_str().map(String::from)).collect())
+            .unwrap_or_default();
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        match Inferior::new(&self.target, &program_args, &mut self.breakpoints) {
+            Some(inferior) => {
+                self.inferior = Some(inferior);
+                Ok(json!({}))
+            }
+            None => Err(format!("Could not start {}", self.target)),
+        }
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["source"]["path"]
+            .as_str()
+            .ok_or_else(|| "setBreakpoints needs a source path".to_string())?;
+        for addr in self.source_breakpoints.remove(path).unwrap_or_default() {
+            self.remove_breakpoint(addr);
+        }
+        let mut addrs = Vec::new();
+        let mut results = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let line = breakpoint["line"].as_u64().unwrap_or_default() as usize;
+            // Compilers may record a path other than the one the editor has open, such as a
+            // relative one, so fall back to matching the file name
+            let file_name = path.rsplit('/').next().unwrap_or(path);
+            let addr = self
+                .debug_data
+                .get_addr_for_line(Some(path), line)
+                .or_else(|| self.debug_data.get_addr_for_line(Some(file_name), line));
+            let result = match addr {
+                Some(addr) => self.add_breakpoint(addr).map(|_| addr),
+                None => Err("No code at this line".to_string()),
+            };
+            results.push(match result {
+                Ok(addr) => {
+                    addrs.push(addr);
+                    // The breakpoint goes on the first line with code at or after the requested one
+                    let actual_line = self.debug_data.get_line_from_addr(addr).map_or(line, |line| line.number);
+                    json!({"verified": true, "line": actual_line})
+                }
+                Err(message) => json!({"verified": false, "line": line, "message": message}),
+            });
+        }
+        self.source_breakpoints.insert(path.to_string(), addrs);
+        Ok(json!({"breakpoints": results}))
+    }
+
+    fn add_breakpoint(&mut self, addr: usize) -> Result<(), String> {
+        if self.breakpoints.contains_key(&addr) {
+            return Ok(());
+        }
+        match &mut self.inferior {
+            Some(inferior) => {
+                let orig_byte = inferior.write_byte(addr, 0xcc).map_err(|err| err.to_string())?;
+                self.breakpoints.insert(addr, Some(Breakpoint::new(addr, orig_byte)));
+            }
+            // orig_byte is filled in when the program starts
+            None => {
+                self.breakpoints.insert(addr, None);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, addr: usize) {
+        if let (Some(Some(breakpoint)), Some(inferior)) = (self.breakpoints.remove(&addr), &mut self.inferior) {
+            let _ = inferior.write_byte(addr, breakpoint.orig_byte);
+        }
+    }
+
+    /// Lets the program run until it stops again (or, if `step` is set, until it reaches another
+    /// line), and tells the client why it stopped.
+    fn resume(&mut self, step: bool) -> io::Result<()> {
+        self.frames.clear();
+        let inferior = self.inferior.as_mut().unwrap();
+        let status = if step {
+            inferior.next(&self.debug_data, &self.breakpoints)
+        } else {
+            inferior.continue_exec(&self.breakpoints)
+        };
+        match status {
+            Ok(Status::Stopped(signal, rip)) => {
+                let reason = if step {
+                    "step"
+                } else if signal == Signal::SIGTRAP && self.breakpoints.contains_key(&rip) {
+                    "breakpoint"
+                } else {
+                    "exception"
+                };
+                self.send_stopped(reason, Some(signal))
+            }
+            Ok(Status::SyscallStop(_)) => unreachable!("system calls are only caught on request"),
+            Ok(Status::Forked(..)) => unreachable!("forks are only caught on request"),
+            Ok(status @ (Status::Exited(_) | Status::Signaled(_))) => {
+                self.inferior = None;
+                let (exit_code, description) = match status {
+                    Status::Exited(code) => (code, format!("Child exited (status {})", code)),
+                    Status::Signaled(signal) => (128 + signal as i32, format!("Child exited (signal {})", signal)),
+                    _ => unreachable!(),
+                };
+                self.send_event("output", json!({"category": "console", "output": description + "\n"}))?;
+                self.send_event("exited", json!({"exitCode": exit_code}))?;
+                self.send_event("terminated", json!({}))
+            }
+            Err(err) => {
+                let output = format!("Error resuming the program: {}\n", err);
+                self.send_event("output", json!({"category": "stderr", "output": output}))
+            }
+        }
+    }
+
+    fn send_stopped(&mut self, reason: &str, signal: Option<Signal>) -> io::Result<()> {
+        let mut body = json!({"reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true});
+        if let Some(signal) = signal {
+            body["description"] = json!(format!("Child stopped (signal {})", signal));
+        }
+        self.send_event("stopped", body)
+    }
+
+    fn stack_trace(&mut self) -> Result<Value, String> {
+        let inferior = self.running()?;
+        let frames = inferior.frames(&self.debug_data).map_err(|err| err.to_string())?;
+        let stack_frames: Vec<Value> = frames
+            .iter()
+            .enumerate()
+            .map(|(id, frame)| {
+                // In outer frames, rip is the return address, which may be on the line after the call
+                let addr = if id > 0 { frame.rip - 1 } else { frame.rip };
+                let name = self.debug_data.get_function_from_addr(addr);
+                let mut stack_frame = json!({
+                    "id": id,
+                    "name": name.as_deref().unwrap_or("??"),
+                    "line": 0,
+                    "column": 0,
+                });
+                if let Some(line) = self.debug_data.get_line_from_addr(addr) {
+                    let file_name = line.file.rsplit('/').next().unwrap_or(&line.file);
+                    stack_frame["source"] = json!({"name": file_name, "path": line.file});
+                    stack_frame["line"] = json!(line.number);
+                }
+                stack_frame
+            })
+            .collect();
+        self.frames = frames;
+        Ok(json!({"stackFrames": stack_frames, "totalFrames": stack_frames.len()}))
+    }
+
+    /// Returns the frame with the given id, or the innermost frame if there is no id.
+    fn frame(&self, id: Option<u64>) -> Result<Frame, String> {
+        let inferior = self.running()?;
+        match id {
+            Some(id) => self
+                .frames
+                .get(id as usize)
+                .copied()
+                .ok_or_else(|| format!("No frame with id {}", id)),
+            None => {
+                let regs = inferior.get_registers().map_err(|err| err.to_string())?;
+                Ok(Frame { rip: regs.rip as usize, rsp: regs.rsp as usize, rbp: regs.rbp as usize })
+            }
+        }
+    }
+
+    fn scopes(&self, args: &Value) -> Result<Value, String> {
+        let id = args["frameId"].as_u64().unwrap_or_default();
+        self.frame(Some(id))?;
+        Ok(json!({"scopes": [{"name": "Locals", "variablesReference": id + 1, "expensive": false}]}))
+    }
+
+    fn variables(&self, args: &Value) -> Result<Value, String> {
+        let reference = args["variablesReference"].as_u64().unwrap_or_default();
+        let Frame { rip, rsp, rbp } = self.frame(Some(reference.saturating_sub(1)))?;
+        let variables: Vec<Value> = self
+            .running()?
+            .locals(&self.debug_data, rip, rsp, rbp)
+            .into_iter()
+            .map(|(name, value)| json!({"name": name, "value": value, "variablesReference": 0}))
+            .collect();
+        Ok(json!({"variables": variables}))
+    }
+
+    /// Evaluates a variable name, the only kind of expression `print` understands too.
+    fn evaluate(&self, args: &Value) -> Result<Value, String> {
+        let name = args["expression"].as_str().unwrap_or_default().trim();
+        let Frame { rip, rbp, .. } = self.frame(args["frameId"].as_u64())?;
+        let var = self
+            .debug_data
+            .get_variable(name, Some(rip))
+            .ok_or_else(|| format!("No symbol \"{}\" in current context.", name))?;
+        let addr = var.location.get_address(rbp);
+        let value = self
+            .running()?
+            .read_variable(&var.entity_type, addr)
+            .map_err(|err| format!("Cannot access memory at address {:#x}: {}", addr, err))?;
+        Ok(json!({"result": value, "variablesReference": 0}))
+    }
+}
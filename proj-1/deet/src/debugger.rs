@@ -1,16 +1,89 @@
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
 
+use capstone::prelude::*;
+use crate::condition::Condition;
 use crate::debugger_command::DebuggerCommand;
-use crate::dwarf_data::{DwarfData, Error as DwarfError};
-use crate::inferior::{Inferior, Status};
+use crate::dwarf_data::{DwarfData, Error as DwarfError, Line, Location};
+use crate::highlight::Highlighter;
+use crate::inferior::{
+    installed_breakpoint, register_value, register_values, FollowForkMode, Frame, Inferior, Status,
+};
+use crate::maps;
+use crate::remote;
+use crate::syscalls::syscall_numbers;
+use crate::tui::{SourceView, Tui};
+use nix::sys::signal;
 use rustyline::error::ReadlineError;
 use rustyline::history::FileHistory;
 use rustyline::Editor;
 
+/// Number of source lines printed on either side of the centre line by `list`
+const LIST_CONTEXT_LINES: usize = 5;
+
+/// Number of bytes `find` reads from the inferior at a time
+const FIND_CHUNK_SIZE: usize = 512;
+
 #[derive(Clone)]
 pub struct Breakpoint {
     pub addr: usize,
+    /// Byte replaced by 0xcc. Only meaningful while the breakpoint is enabled in a running inferior
     pub orig_byte: u8,
+    /// Disabled breakpoints stay in the list but aren't installed in the inferior
+    pub enabled: bool,
+    /// Expression that must be true for the breakpoint to stop the program
+    pub condition: Option<String>,
+    /// If set, the breakpoint only stops the program on this hit
+    pub hit_count: Option<usize>,
+    /// Number of times the breakpoint has been hit (with its condition true) in this run
+    pub current_hits: usize,
+    /// Commands to run when the breakpoint stops the program
+    pub commands: Vec<String>,
+}
+
+impl Breakpoint {
+    pub fn new(addr: usize, orig_byte: u8) -> Breakpoint {
+        Breakpoint {
+            addr,
+            orig_byte,
+            enabled: true,
+            condition: None,
+            hit_count: None,
+            current_hits: 0,
+            commands: Vec::new(),
+        }
+    }
+}
+
+/// A software watchpoint: we single-step the inferior and stop when the watched value changes
+pub struct Watchpoint {
+    /// Variable name or `*address` that the user asked to watch
+    pub expr: String,
+    pub addr: usize,
+    /// Number of bytes compared, at most 8
+    pub size: usize,
+    /// Value the last time we looked
+    pub value: u64,
+    /// For local variables, the canonical frame address of the frame they live in. The variable
+    /// goes out of scope once the stack pointer rises to it (i.e. the function returns).
+    pub frame: Option<usize>,
+}
+
+/// Loads the debugging symbols of `target`, exiting if it has none we can read.
+pub fn load_debug_data(target: &str) -> DwarfData {
+    match DwarfData::from_file(target) {
+        Ok(val) => val,
+        Err(DwarfError::ErrorOpeningFile) => {
+            println!("Could not open file {}", target);
+            std::process::exit(1);
+        }
+        Err(DwarfError::DwarfFormatError(err)) => {
+            println!("Could not debugging symbols from {}: {:?}", target, err);
+            std::process::exit(1);
+        }
+    }
 }
 
 pub struct Debugger {
@@ -20,23 +93,46 @@ pub struct Debugger {
     inferior: Option<Inferior>,
     debug_data: DwarfData,
     breakpoints: HashMap<usize, Option<Breakpoint>>,
+    /// Breakpoint addresses in the order they were set; a breakpoint's number is its index here
+    breakpoint_order: Vec<usize>,
+    /// Breakpoint locations that couldn't be found yet, such as functions in shared libraries the
+    /// program hasn't loaded. They are looked up again every time the program stops.
+    pending_breakpoints: Vec<String>,
+    /// Addresses of breakpoints set in shared libraries, with the location they were set at.
+    /// Libraries are loaded somewhere else each run, so these go back to pending on restart.
+    library_breakpoints: Vec<(usize, String)>,
+    watchpoints: Vec<Watchpoint>,
+    /// File and last line printed by the previous `list`, so that another `list` continues from there
+    last_listed: Option<(String, usize)>,
+    /// Stack frame that `print`, `info locals` and `list` look at, counting outwards from the
+    /// innermost frame (0). Every time the program stops, the innermost frame is selected again.
+    selected_frame: usize,
+    /// Variables to print every time the program stops, with the numbers `undisplay` removes them by
+    display_exprs: Vec<(usize, String)>,
+    /// Number the next `display` gets
+    next_display_number: usize,
+    /// Set by `catch syscall`: Some(None) stops on every system call, and Some(Some(number)) only
+    /// on that one
+    catch_syscall: Option<Option<u64>>,
+    /// Set by `catch fork`: stop whenever the program forks
+    catch_fork: bool,
+    /// Which side of a fork to keep debugging, from --follow-fork-mode
+    follow_fork_mode: FollowForkMode,
+    syscall_numbers: HashMap<&'static str, u64>,
+    /// When running as a remote stub, the connection that commands are read from instead of the
+    /// terminal
+    remote_conn: Option<BufReader<TcpStream>>,
+    /// Whether to colour output: syntax highlighting in `list`, and region kinds in the memory map
+    color: bool,
+    /// Full-screen interface that commands are read through and output is shown in, with --tui
+    tui: Option<Tui>,
 }
 
 impl Debugger {
     /// Initializes the debugger.
     pub fn new(target: &str) -> Debugger {
         // TODO (milestone 3): initialize the DwarfData
-        let debug_data = match DwarfData::from_file(target) {
-            Ok(val) => val,
-            Err(DwarfError::ErrorOpeningFile) => {
-                println!("Could not open file {}", target);
-                std::process::exit(1);
-            }
-            Err(DwarfError::DwarfFormatError(err)) => {
-                println!("Could not debugging symbols from {}: {:?}", target, err);
-                std::process::exit(1);
-            }
-        };
+        let debug_data = load_debug_data(target);
         debug_data.print();
 
         let history_path = format!("{}/.deet_history", std::env::var("HOME").unwrap());
@@ -51,141 +147,1614 @@ impl Debugger {
             inferior: None,
             debug_data,
             breakpoints: HashMap::new(),
+            breakpoint_order: Vec::new(),
+            pending_breakpoints: Vec::new(),
+            library_breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            last_listed: None,
+            selected_frame: 0,
+            display_exprs: Vec::new(),
+            next_display_number: 1,
+            catch_syscall: None,
+            catch_fork: false,
+            follow_fork_mode: FollowForkMode::Parent,
+            syscall_numbers: syscall_numbers(),
+            remote_conn: None,
+            color: false,
+            tui: None,
         }
     }
 
+    pub fn set_color(&mut self, color: bool) {
+        self.color = color;
+    }
+
+    pub fn set_follow_fork_mode(&mut self, follow_fork_mode: FollowForkMode) {
+        self.follow_fork_mode = follow_fork_mode;
+    }
+
+    /// Makes the debugger take its commands from a remote debugger's connection. Our output should
+    /// already be going to the connection.
+    pub fn serve_remote(&mut self, conn: BufReader<TcpStream>) {
+        self.remote_conn = Some(conn);
+    }
+
+    /// Makes the debugger read commands through the full-screen interface, which should already be
+    /// collecting our output.
+    pub fn use_tui(&mut self, tui: Tui) {
+        self.tui = Some(tui);
+    }
+
     pub fn run(&mut self) {
         loop {
-            match self.get_next_command() {
-                DebuggerCommand::Backtrace => {
-                    if let Some(inferior) = &self.inferior {
-                        inferior.print_backtrace(&self.debug_data).unwrap();
-                    }
-                }
-                DebuggerCommand::Break(breakpoint) => {
-                    let addr;
-                    if breakpoint.starts_with("*") {
-                        addr = Self::parse_address(&breakpoint[1..]);
-                    } else if let Ok(line_number) = breakpoint.parse() {
-                        addr = self.debug_data.get_addr_for_line(None, line_number);
+            let command = self.get_next_command();
+            if !self.execute(command) {
+                return;
+            }
+        }
+    }
+
+    /// Carries out a command. Returns false if it was quit.
+    fn execute(&mut self, command: DebuggerCommand) -> bool {
+        match command {
+            DebuggerCommand::Attach(pid) => {
+                self.release_inferior();
+                self.unresolve_library_breakpoints();
+                self.watchpoints.retain(|watchpoint| watchpoint.frame.is_none());
+                for breakpoint in self.breakpoints.values_mut().flatten() {
+                    breakpoint.current_hits = 0;
+                }
+                if let Some(mut inferior) = Inferior::from_pid(pid, &mut self.breakpoints) {
+                    inferior.set_fork_handling(self.follow_fork_mode, self.catch_fork);
+                    println!("Attached to process {}", pid);
+                    // Show where the process was when we stopped it
+                    inferior.print_backtrace(&self.debug_data, false).unwrap();
+                    self.inferior = Some(inferior);
+                    self.last_listed = None;
+                    self.selected_frame = 0;
+                    // The process has already loaded its libraries
+                    self.resolve_pending_breakpoints();
+                }
+            }
+            DebuggerCommand::Backtrace(full) => {
+                if let Some(inferior) = &self.inferior {
+                    inferior.print_backtrace(&self.debug_data, full).unwrap();
+                }
+            }
+            DebuggerCommand::Break(location, hit_count) => {
+                self.set_breakpoint(&location, None, hit_count);
+            }
+            DebuggerCommand::BreakCondition(location, condition) => {
+                self.set_breakpoint(&location, Some(condition), None);
+            }
+            DebuggerCommand::CatchFork => {
+                println!("Catchpoint (fork)");
+                self.catch_fork = true;
+                if let Some(inferior) = &mut self.inferior {
+                    inferior.set_fork_handling(self.follow_fork_mode, true);
+                }
+            }
+            DebuggerCommand::CatchSyscall(name) => {
+                self.set_syscall_catchpoint(name);
+            }
+            DebuggerCommand::Commands(number) => {
+                self.set_breakpoint_commands(number);
+            }
+            DebuggerCommand::Continue => {
+                self.continue_exec();
+            }
+            DebuggerCommand::Detach => {
+                if self.inferior.is_some() {
+                    self.detach();
+                } else {
+                    println!("The program is not being run.");
+                }
+            }
+            DebuggerCommand::Disassemble(location) => {
+                self.disassemble(location);
+            }
+            DebuggerCommand::Delete(number) => {
+                self.delete_breakpoint(number);
+            }
+            DebuggerCommand::DisableBreakpoint(number) => {
+                self.set_breakpoint_enabled(number, false);
+            }
+            DebuggerCommand::Display(name) => {
+                self.add_display(name);
+            }
+            DebuggerCommand::Dump { start, len, path } => {
+                self.dump_memory(start, len, &path);
+            }
+            DebuggerCommand::EnableBreakpoint(number) => {
+                self.set_breakpoint_enabled(number, true);
+            }
+            DebuggerCommand::Find { start, end, pattern } => {
+                self.find_in_memory(start, end, &pattern);
+            }
+            DebuggerCommand::Frame(number) => {
+                if let Some(frames) = self.stack_frames() {
+                    if number < frames.len() {
+                        self.select_frame(number, &frames);
                     } else {
-                        addr = self.debug_data.get_addr_for_function(None, &breakpoint);
-                    }
-                    if let Some(addr) = addr {
-                        if let Some(inferior) = &mut self.inferior {
-                            match inferior.write_byte(addr, 0xcc) {
-                                Ok(orig_byte) => {
-                                    self.breakpoints.insert(addr, Some(Breakpoint{addr, orig_byte}));
-                                }
-                                Err(err) => {
-                                    println!("{}", err);
-                                }
+                        println!("No frame at level {}.", number);
+                    }
+                }
+            }
+            DebuggerCommand::TargetRemote(addr) => {
+                if let Err(err) = remote::connect(&addr, || self.read_line("(deet) ")) {
+                    println!("{}: {}", addr, err);
+                }
+            }
+            DebuggerCommand::Thread(number) => {
+                self.select_thread(number);
+            }
+            DebuggerCommand::Undisplay(number) => {
+                let count = self.display_exprs.len();
+                self.display_exprs.retain(|(display_number, _)| *display_number != number);
+                if self.display_exprs.len() == count {
+                    println!("No display number {}.", number);
+                }
+            }
+            DebuggerCommand::Up => {
+                if let Some(frames) = self.stack_frames() {
+                    if self.selected_frame + 1 < frames.len() {
+                        self.select_frame(self.selected_frame + 1, &frames);
+                    } else {
+                        println!("Initial frame selected; you cannot go up.");
+                    }
+                }
+            }
+            DebuggerCommand::Down => {
+                if let Some(frames) = self.stack_frames() {
+                    if self.selected_frame > 0 {
+                        self.select_frame(self.selected_frame - 1, &frames);
+                    } else {
+                        println!("Bottom (innermost) frame selected; you cannot go down.");
+                    }
+                }
+            }
+            DebuggerCommand::Examine { count, format, size, addr } => {
+                self.examine(count, format, size, addr);
+            }
+            DebuggerCommand::InfoBreakpoints => {
+                self.print_breakpoints();
+            }
+            DebuggerCommand::InfoLocals => {
+                self.print_locals();
+            }
+            DebuggerCommand::InfoProcMappings => {
+                if let Some(inferior) = &self.inferior {
+                    match maps::read_mappings(inferior.pid()) {
+                        Ok(regions) => maps::print_mappings(&regions, self.color),
+                        Err(err) => println!("Could not read the memory map: {}", err),
+                    }
+                } else {
+                    println!("The program is not being run.");
+                }
+            }
+            DebuggerCommand::InfoRegisters => {
+                if let Some(inferior) = &self.inferior {
+                    match inferior.get_registers() {
+                        Ok(regs) => {
+                            for (name, value) in register_values(&regs) {
+                                println!("{:<10}{:#018x}", name, value);
                             }
-                        } else {
-                            self.breakpoints.insert(addr, None);
                         }
-                        println!("Set breakpoint {} at {:#x}", self.breakpoints.len() - 1, addr);
+                        Err(err) => println!("Could not read registers: {}", err),
                     }
+                } else {
+                    println!("The program is not being run.");
                 }
-                DebuggerCommand::Continue => {
+            }
+            DebuggerCommand::InfoThreads => {
+                self.print_threads();
+            }
+            DebuggerCommand::Jump(location) => {
+                self.jump(&location);
+            }
+            DebuggerCommand::List(line_number) => {
+                self.list_source(line_number);
+            }
+            DebuggerCommand::Next => {
+                if let Some(inferior) = &mut self.inferior {
+                    let status = inferior.next(&self.debug_data, &self.breakpoints).unwrap();
+                    self.report_status(status);
+                } else {
+                    println!("There is no inferior running.");
+                }
+            }
+            DebuggerCommand::Print(name) => {
+                self.print_variable(&name);
+            }
+            DebuggerCommand::Run(args) => {
+                self.release_inferior();
+                self.unresolve_library_breakpoints();
+                // Local variables from the previous run no longer exist
+                self.watchpoints.retain(|watchpoint| watchpoint.frame.is_none());
+                for breakpoint in self.breakpoints.values_mut().flatten() {
+                    breakpoint.current_hits = 0;
+                }
+                if let Some(mut inferior) = Inferior::new(&self.target, &args, &mut self.breakpoints) {
+                    inferior.set_fork_handling(self.follow_fork_mode, self.catch_fork);
+                    // Create the inferior
+                    self.inferior = Some(inferior);
+                    // TODO (milestone 1): make the inferior run
+                    // You may use self.inferior.as_mut().unwrap() to get a mutable reference
+                    // to the Inferior object
                     self.continue_exec();
+                } else {
+                    println!("Error starting subprocess");
                 }
-                DebuggerCommand::Run(args) => {
-                    if let Some(inferior) = &mut self.inferior {
-                        inferior.kill();
-                        self.inferior = None;
-                    }
-                    if let Some(inferior) = Inferior::new(&self.target, &args, &mut self.breakpoints) {
-                        // Create the inferior
-                        self.inferior = Some(inferior);
-                        // TODO (milestone 1): make the inferior run
-                        // You may use self.inferior.as_mut().unwrap() to get a mutable reference
-                        // to the Inferior object
+            }
+            DebuggerCommand::Set(target, value) => {
+                self.set_value(&target, value);
+            }
+            DebuggerCommand::Signal(name) => {
+                let name = name.to_uppercase();
+                let parsed = match name.parse::<i32>() {
+                    Ok(number) => signal::Signal::try_from(number),
+                    Err(_) if name.starts_with("SIG") => name.parse(),
+                    Err(_) => format!("SIG{}", name).parse(),
+                };
+                match (parsed, &mut self.inferior) {
+                    (Err(_), _) => println!("Unknown signal {}.", name),
+                    (Ok(_), None) => println!("The program is not being run."),
+                    (Ok(sig), Some(inferior)) => {
+                        println!("Continuing with signal {}.", sig);
+                        inferior.set_pending_signal(sig);
                         self.continue_exec();
-                    } else {
-                        println!("Error starting subprocess");
                     }
                 }
-                DebuggerCommand::Quit => {
-                    if let Some(inferior) = &mut self.inferior {
-                        inferior.kill();
-                        self.inferior = None;
-                    }
+            }
+            DebuggerCommand::Source(path) => return self.source(&path),
+            DebuggerCommand::Watch(expr) => {
+                self.add_watchpoint(expr);
+            }
+            DebuggerCommand::Whatis(name) => {
+                self.print_type(&name);
+            }
+            DebuggerCommand::Quit => {
+                self.release_inferior();
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Gets rid of the inferior before running or attaching to another one, or quitting. A process
+    /// we attached to is left running, since we didn't start it; one we started is killed.
+    fn release_inferior(&mut self) {
+        match &mut self.inferior {
+            Some(inferior) if inferior.attached => self.detach(),
+            Some(inferior) => {
+                inferior.kill();
+                self.inferior = None;
+            }
+            None => {}
+        }
+    }
+
+    /// Removes our breakpoints from the inferior and lets it continue running on its own.
+    fn detach(&mut self) {
+        let mut inferior = self.inferior.take().unwrap();
+        let pid = inferior.pid();
+        match inferior.detach(&self.breakpoints) {
+            Ok(()) => println!("Detaching from process {}", pid),
+            Err(err) => println!("Could not detach from process {}: {}", pid, err),
+        }
+    }
+
+    pub fn continue_exec(&mut self) {
+        if self.inferior.is_none() {
+            println!("There is no inferior running.");
+            return;
+        }
+        loop {
+            let mut status = self.resume().unwrap();
+            // Keep going past breakpoints whose condition doesn't hold, and system calls we aren't
+            // catching
+            while !self.should_stop(&status) {
+                status = self.resume().unwrap();
+            }
+            self.report_status(status);
+            // A `continue` in the breakpoint's commands goes around again here, rather than
+            // recursing, so that a breakpoint that is hit many times can't overflow the stack
+            if !self.run_breakpoint_commands() {
+                return;
+            }
+        }
+    }
+
+    /// Runs the commands of the breakpoint the program just stopped at, if any. Like gdb, a
+    /// `continue` ends the list, and returns true to have the caller resume the program.
+    fn run_breakpoint_commands(&mut self) -> bool {
+        let rip = match &self.inferior {
+            Some(inferior) => inferior.get_rip().unwrap(),
+            None => return false,
+        };
+        let commands = match installed_breakpoint(&self.breakpoints, rip) {
+            Some(breakpoint) => breakpoint.commands.clone(),
+            None => return false,
+        };
+        for line in commands {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            match DebuggerCommand::from_tokens(&tokens) {
+                Some(DebuggerCommand::Continue) => return self.inferior.is_some(),
+                // Ending the session or reading more commands from the user doesn't make sense in
+                // the middle of a list
+                Some(DebuggerCommand::Quit) | Some(DebuggerCommand::Commands(_)) => {
+                    println!("\"{}\" can't be used in breakpoint commands.", line);
+                }
+                Some(command) => {
+                    self.execute(command);
+                }
+                None => println!("Unrecognized command in breakpoint commands: {}", line),
+            }
+        }
+        false
+    }
+
+    /// Reads commands for a breakpoint from the user, one per line until `end`, to be run every
+    /// time it stops the program. An empty list removes the breakpoint's commands.
+    fn set_breakpoint_commands(&mut self, number: usize) {
+        if number >= self.breakpoint_order.len() {
+            println!("No breakpoint number {}.", number);
+            return;
+        }
+        println!("Type commands for breakpoint {}, one per line.", number);
+        println!("End with a line saying just \"end\".");
+        let mut commands = Vec::new();
+        loop {
+            let line = if self.remote_conn.is_some() {
+                self.read_remote_line()
+            } else {
+                self.read_line(">")
+            };
+            match line.as_deref().map(str::trim) {
+                None | Some("end") => break,
+                Some("") => {}
+                Some(line) => commands.push(line.to_string()),
+            }
+        }
+        self.store_breakpoint_commands(number, commands);
+    }
+
+    /// Replaces the command list of a breakpoint.
+    fn store_breakpoint_commands(&mut self, number: usize, commands: Vec<String>) {
+        let addr = match self.breakpoint_order.get(number) {
+            Some(addr) => *addr,
+            None => {
+                println!("No breakpoint number {}.", number);
+                return;
+            }
+        };
+        // Plain breakpoints set before the program started don't have an entry yet. orig_byte is
+        // filled in when the program starts.
+        self.breakpoints
+            .get_mut(&addr)
+            .unwrap()
+            .get_or_insert_with(|| Breakpoint::new(addr, 0))
+            .commands = commands;
+    }
+
+    /// Lets the inferior run until it next needs our attention. Watchpoints single-step the
+    /// inferior, which doesn't stop at system calls, so `catch syscall` has no effect while there
+    /// are any.
+    fn resume(&mut self) -> Result<Status, nix::Error> {
+        if !self.watchpoints.is_empty() {
+            return self.continue_watching();
+        }
+        let inferior = self.inferior.as_mut().unwrap();
+        let status = if self.catch_syscall.is_some() {
+            inferior.continue_to_syscall(&self.breakpoints)?
+        } else {
+            inferior.continue_exec(&self.breakpoints)?
+        };
+        if let Status::Stopped(..) = status {
+            self.resolve_pending_breakpoints();
+        }
+        Ok(status)
+    }
+
+    /// Stops the program on entry to and exit from a system call, given by name or number, or
+    /// every system call if none is given.
+    fn set_syscall_catchpoint(&mut self, name: Option<String>) {
+        let number = match name {
+            Some(name) => match name.parse().ok().or_else(|| self.syscall_numbers.get(name.as_str()).copied()) {
+                Some(number) => Some(number),
+                None => {
+                    println!("Unknown syscall name '{}'.", name);
                     return;
                 }
+            },
+            None => None,
+        };
+        match number {
+            Some(number) => println!("Catchpoint (syscall '{}' [{}])", self.syscall_name(number), number),
+            None => println!("Catchpoint (any syscall)"),
+        }
+        self.catch_syscall = Some(number);
+    }
+
+    fn syscall_name(&self, number: u64) -> String {
+        match self.syscall_numbers.iter().find(|(_, &n)| n == number) {
+            Some((name, _)) => name.to_string(),
+            None => number.to_string(),
+        }
+    }
+
+    /// Returns false if the inferior stopped at a breakpoint that shouldn't stop it this time,
+    /// because its condition is false or this isn't the hit it is waiting for. If the condition
+    /// can't be evaluated, we stop so the user can see why.
+    fn should_stop(&mut self, status: &Status) -> bool {
+        if let (Some(inferior), Status::SyscallStop(_), Some(Some(number))) = (&self.inferior, status, self.catch_syscall) {
+            return !matches!(inferior.get_registers(), Ok(regs) if regs.orig_rax != number);
+        }
+        let (inferior, rip) = match (&self.inferior, status) {
+            (Some(inferior), Status::Stopped(signal::Signal::SIGTRAP, rip)) => (inferior, *rip),
+            _ => return true,
+        };
+        let breakpoint = match installed_breakpoint(&self.breakpoints, rip) {
+            Some(breakpoint) => breakpoint,
+            None => return true,
+        };
+        if let Some(condition) = &breakpoint.condition {
+            let result = Condition::parse(condition).and_then(|parsed| {
+                let regs = inferior.get_registers().map_err(|err| err.to_string())?;
+                parsed.evaluate(&regs, |addr| {
+                    let value = inferior.read_value(addr, 4)?;
+                    Ok(value as u32 as i32 as i64)
+                })
+            });
+            match result {
+                Ok(true) => {}
+                Ok(false) => return false,
+                Err(err) => {
+                    println!("Error in breakpoint condition \"{}\": {}", condition, err);
+                    return true;
+                }
             }
         }
+        let breakpoint = self.breakpoints.get_mut(&rip).unwrap().as_mut().unwrap();
+        breakpoint.current_hits += 1;
+        match breakpoint.hit_count {
+            Some(hit_count) => breakpoint.current_hits == hit_count,
+            None => true,
+        }
     }
 
-    pub fn continue_exec(&mut self) {
+    /// Sets a breakpoint at a function, a line number, or `*ADDRESS`. If a condition is given, the
+    /// breakpoint only stops the program when it is true. If a hit count is given, it only stops
+    /// the program on that hit.
+    ///
+    /// Functions that aren't in the program are looked up in the shared libraries it has loaded.
+    /// If they can't be found there either, the breakpoint is left pending until they can.
+    fn set_breakpoint(&mut self, location: &str, condition: Option<String>, hit_count: Option<usize>) {
+        if let Some(condition) = &condition {
+            if let Err(err) = Condition::parse(condition) {
+                println!("Invalid condition \"{}\": {}", condition, err);
+                return;
+            }
+        }
+        let mut in_library = false;
+        let addr = if let Some(address) = location.strip_prefix('*') {
+            Self::parse_address(address)
+        } else if let Ok(line_number) = location.parse() {
+            self.debug_data.get_addr_for_line(None, line_number)
+        } else {
+            self.debug_data.get_addr_for_function(None, location).or_else(|| {
+                in_library = true;
+                self.find_library_function(location)
+            })
+        };
+        let addr = match addr {
+            Some(_) if in_library && (condition.is_some() || hit_count.is_some()) => {
+                // These would be lost when the breakpoint goes back to pending on restart
+                println!("Conditions and hit counts can only be used on breakpoints in the program itself.");
+                return;
+            }
+            Some(addr) => addr,
+            None if location.starts_with('*') || condition.is_some() || hit_count.is_some() => {
+                println!("Could not find location \"{}\".", location);
+                return;
+            }
+            None => {
+                if !self.pending_breakpoints.iter().any(|pending| pending == location) {
+                    self.pending_breakpoints.push(location.to_string());
+                }
+                println!("Could not find location \"{}\" yet; breakpoint pending.", location);
+                return;
+            }
+        };
+        if let Some(number) = self.breakpoint_order.iter().position(|&a| a == addr) {
+            println!("Breakpoint {} is already set at {:#x}", number, addr);
+            return;
+        }
         if let Some(inferior) = &mut self.inferior {
-            match inferior.continue_exec(&self.breakpoints).unwrap() {
-                Status::Stopped(signal, rip) => {
-                    println!("Child stopped (signal {})", signal);
-                    if let Some(line) = self.debug_data.get_line_from_addr(rip) {
-                        println!("Stopped at {}", line);
-                    }
+            match inferior.write_byte(addr, 0xcc) {
+                Ok(orig_byte) => {
+                    self.breakpoints.insert(
+                        addr,
+                        Some(Breakpoint{condition, hit_count, ..Breakpoint::new(addr, orig_byte)}),
+                    );
                 }
-                Status::Exited(status) => {
-                    self.inferior = None;
-                    println!("Child exited (status {})", status);
+                Err(err) => {
+                    println!("{}", err);
+                    return;
                 }
-                Status::Signaled(signal) => {
-                    self.inferior = None;
-                    println!("Child exited (signal {})", signal);
+            }
+        } else if condition.is_some() || hit_count.is_some() {
+            // orig_byte is filled in when the program starts
+            self.breakpoints.insert(addr, Some(Breakpoint{condition, hit_count, ..Breakpoint::new(addr, 0)}));
+        } else {
+            self.breakpoints.insert(addr, None);
+        }
+        if in_library {
+            self.library_breakpoints.push((addr, location.to_string()));
+        }
+        self.breakpoint_order.push(addr);
+        println!("Set breakpoint {} at {:#x}", self.breakpoint_order.len() - 1, addr);
+    }
+
+    /// Returns the address of a function in one of the shared libraries the inferior has loaded.
+    fn find_library_function(&self, name: &str) -> Option<usize> {
+        let inferior = self.inferior.as_ref()?;
+        let regions = maps::read_mappings(inferior.pid()).ok()?;
+        maps::find_library_function(&regions, name)
+    }
+
+    /// Moves the instruction pointer to a hex address, `*ADDRESS` or `*FUNCTION` and continues from
+    /// there.
+    fn jump(&mut self, location: &str) {
+        let inferior = match &mut self.inferior {
+            Some(inferior) => inferior,
+            None => {
+                println!("The program is not being run.");
+                return;
+            }
+        };
+        // Function names can look like hex (add, face), so they win over addresses after a *
+        let addr = match location.strip_prefix('*') {
+            Some(address) if address.starts_with("0x") => Self::parse_address(address),
+            Some(name) => self.debug_data.get_addr_for_function(None, name).or_else(|| Self::parse_address(name)),
+            None => Self::parse_address(location),
+        };
+        let addr = match addr {
+            Some(addr) => addr,
+            None => {
+                println!("Could not find location \"{}\".", location);
+                return;
+            }
+        };
+        let rip = match inferior.get_rip() {
+            Ok(rip) => rip,
+            Err(err) => {
+                println!("Could not read registers: {}", err);
+                return;
+            }
+        };
+        // The stack frame still belongs to the current function, so code in any other function
+        // will find its locals and return address in the wrong places
+        let current_function = self.debug_data.get_function_range(rip);
+        if current_function.is_none() || current_function != self.debug_data.get_function_range(addr) {
+            println!(
+                "Warning: {:#x} is not in the current function; the stack frame won't match and may be corrupted.",
+                addr
+            );
+        }
+        if let Err(err) = inferior.set_register("rip", addr as u64) {
+            println!("Could not set rip: {}", err);
+            return;
+        }
+        println!("Continuing at {:#x}.", addr);
+        self.continue_exec();
+    }
+
+    /// Looks up pending breakpoint locations again, now that the inferior may have loaded more
+    /// libraries, and installs the ones that are found.
+    fn resolve_pending_breakpoints(&mut self) {
+        if self.pending_breakpoints.is_empty() || self.inferior.is_none() {
+            return;
+        }
+        let regions = match maps::read_mappings(self.inferior.as_ref().unwrap().pid()) {
+            Ok(regions) => regions,
+            Err(_) => return,
+        };
+        for location in std::mem::take(&mut self.pending_breakpoints) {
+            let found = self.debug_data.get_addr_for_function(None, &location).is_some()
+                || location.parse().ok().and_then(|line| self.debug_data.get_addr_for_line(None, line)).is_some()
+                || maps::find_library_function(&regions, &location).is_some();
+            if found {
+                print!("Resolved pending breakpoint \"{}\": ", location);
+                self.set_breakpoint(&location, None, None);
+            } else {
+                self.pending_breakpoints.push(location);
+            }
+        }
+    }
+
+    /// Turns breakpoints in shared libraries back into pending ones before the program restarts,
+    /// since their addresses are only good for the process they were found in.
+    fn unresolve_library_breakpoints(&mut self) {
+        for (addr, location) in std::mem::take(&mut self.library_breakpoints) {
+            self.breakpoints.remove(&addr);
+            self.breakpoint_order.retain(|&a| a != addr);
+            if !self.pending_breakpoints.contains(&location) {
+                self.pending_breakpoints.push(location);
+            }
+        }
+    }
+
+    /// Single-steps the inferior until a watched value changes, a watched local goes out of scope,
+    /// or a breakpoint is reached.
+    fn continue_watching(&mut self) -> Result<Status, nix::Error> {
+        let inferior = self.inferior.as_mut().unwrap();
+        // Memory may have been changed since we last stopped (e.g. by a new run)
+        for watchpoint in &mut self.watchpoints {
+            watchpoint.value = inferior.read_value(watchpoint.addr, watchpoint.size)?;
+        }
+        loop {
+            let status = inferior.step_instruction(&self.breakpoints)?;
+            let rip = match status {
+                Status::Stopped(signal::Signal::SIGTRAP, rip) => rip,
+                status => return Ok(status),
+            };
+            let rsp = inferior.get_registers()?.rsp as usize;
+            let mut stop = installed_breakpoint(&self.breakpoints, rip).is_some();
+            let mut number = 0;
+            let mut watchpoints = std::mem::take(&mut self.watchpoints);
+            watchpoints.retain_mut(|watchpoint| {
+                number += 1;
+                if matches!(watchpoint.frame, Some(frame) if rsp >= frame) {
+                    println!(
+                        "\nWatchpoint {} deleted because the program has left the block in which its expression is valid.",
+                        number - 1
+                    );
+                    stop = true;
+                    return false;
+                }
+                match inferior.read_value(watchpoint.addr, watchpoint.size) {
+                    Ok(value) if value != watchpoint.value => {
+                        println!("\nWatchpoint {}: {}\n", number - 1, watchpoint.expr);
+                        println!("Old value = {}", Self::format_value(watchpoint.value, watchpoint.size));
+                        println!("New value = {}", Self::format_value(value, watchpoint.size));
+                        watchpoint.value = value;
+                        stop = true;
+                    }
+                    _ => {}
+                }
+                true
+            });
+            self.watchpoints = watchpoints;
+            if stop {
+                return Ok(status);
+            }
+        }
+    }
+
+    /// Sets a watchpoint on a variable, or on the int at `*ADDRESS` (like gdb, which treats a bare
+    /// address as pointing to an int).
+    fn add_watchpoint(&mut self, expr: String) {
+        let rip = self.inferior.as_ref().and_then(|inferior| inferior.get_rip().ok());
+        let (addr, size, frame) = if let Some(address) = expr.strip_prefix('*') {
+            match Self::parse_address(address) {
+                Some(addr) => (addr, 4, None),
+                None => {
+                    println!("Invalid address {}", address);
+                    return;
                 }
             }
         } else {
-            println!("There is no inferior running.");
+            let var = match self.debug_data.get_variable(&expr, rip) {
+                Some(var) => var,
+                None => {
+                    println!("No symbol \"{}\" in current context.", expr);
+                    return;
+                }
+            };
+            let size = var.entity_type.value_size();
+            match var.location {
+                Location::Address(addr) => (addr, size, None),
+                Location::FramePointerOffset(_) => {
+                    let rbp = match &self.inferior {
+                        Some(inferior) => inferior.get_registers().unwrap().rbp as usize,
+                        None => {
+                            println!("Cannot watch local variable {} without a running program.", expr);
+                            return;
+                        }
+                    };
+                    (var.location.get_address(rbp), size, Some(rbp + 16))
+                }
+            }
+        };
+        let value = match &self.inferior {
+            Some(inferior) => inferior.read_value(addr, size).unwrap_or(0),
+            None => 0,
+        };
+        println!("Watchpoint {}: {}", self.watchpoints.len(), expr);
+        self.watchpoints.push(Watchpoint{expr, addr, size, value, frame});
+    }
+
+    /// Prints the value of a variable: pointers in hex, and everything else as an integer.
+    fn print_variable(&self, name: &str) {
+        match self.format_variable(name) {
+            Ok(text) | Err(text) => println!("{}", text),
         }
     }
 
+    /// Formats a variable as `name = value` for `print` and `display`, or returns why it can't be.
+    fn format_variable(&self, name: &str) -> Result<String, String> {
+        let inferior = match &self.inferior {
+            Some(inferior) => inferior,
+            None => return Err("The program is not being run.".to_string()),
+        };
+        let Frame { rip, rbp, .. } = self.frame_registers(inferior);
+        let var = match self.debug_data.get_variable(name, Some(rip)) {
+            Some(var) => var,
+            None => return Err(format!("No symbol \"{}\" in current context.", name)),
+        };
+        let addr = self.debug_data.get_variable_addr(name, rip, rbp).unwrap();
+        match inferior.read_variable(&var.entity_type, addr) {
+            Ok(value) => Ok(format!("{} = {}", name, value)),
+            Err(err) => Err(format!("Cannot access memory at address {:#x}: {}", addr, err)),
+        }
+    }
+
+    /// Adds a variable to print every time the program stops, and prints it now if the program is
+    /// running.
+    fn add_display(&mut self, name: String) {
+        let number = self.next_display_number;
+        self.next_display_number += 1;
+        if self.inferior.is_some() {
+            self.show_display(number, &name);
+        }
+        self.display_exprs.push((number, name));
+    }
+
+    /// Prints each display, like gdb: `1: x = 5`. Variables that aren't in scope where the program
+    /// stopped are skipped.
+    fn show_displays(&self) {
+        for (number, name) in &self.display_exprs {
+            self.show_display(*number, name);
+        }
+    }
+
+    fn show_display(&self, number: usize, name: &str) {
+        if let Ok(text) = self.format_variable(name) {
+            println!("{}: {}", number, text);
+        }
+    }
+
+    /// Handles `set`: stores a value in a register (`$rax`) or in the 8 bytes at an address
+    /// (`*0x1000`), printing the old value first.
+    fn set_value(&mut self, target: &str, value: u64) {
+        let inferior = match &mut self.inferior {
+            Some(inferior) => inferior,
+            None => {
+                println!("The program is not being run.");
+                return;
+            }
+        };
+        if let Some(name) = target.strip_prefix('$') {
+            let old_value = match inferior.get_registers() {
+                Ok(regs) => match register_value(&regs, name) {
+                    Some(old_value) => old_value,
+                    None => {
+                        println!("Unknown register ${}.", name);
+                        return;
+                    }
+                },
+                Err(err) => {
+                    println!("Could not read registers: {}", err);
+                    return;
+                }
+            };
+            println!("Old value: ${} = {:#x}", name, old_value);
+            if let Err(err) = inferior.set_register(name, value) {
+                println!("Could not write register ${}: {}", name, err);
+            }
+            return;
+        }
+        let addr = match target.strip_prefix('*').and_then(DebuggerCommand::parse_number) {
+            Some(addr) => addr as usize,
+            None => {
+                println!("Can only set a register ($rax) or memory (*0x1000), not `{}`.", target);
+                return;
+            }
+        };
+        let old_value = match inferior.read_memory(addr, 8, &self.breakpoints) {
+            Ok(bytes) => u64::from_le_bytes(<[u8; 8]>::try_from(bytes).unwrap()),
+            Err(err) => {
+                println!("Cannot access memory at address {:#x}: {}", addr, err);
+                return;
+            }
+        };
+        println!("Old value: *{:#x} = {:#x}", addr, old_value);
+        for (offset, byte) in value.to_le_bytes().iter().enumerate() {
+            let byte_addr = addr + offset;
+            // Leave installed breakpoints in place, but have them put back the new byte when they
+            // are removed
+            let result = match self.breakpoints.get_mut(&byte_addr) {
+                Some(Some(breakpoint)) if breakpoint.enabled => {
+                    breakpoint.orig_byte = *byte;
+                    Ok(0)
+                }
+                _ => inferior.write_byte(byte_addr, *byte),
+            };
+            if let Err(err) = result {
+                println!("Cannot access memory at address {:#x}: {}", byte_addr, err);
+                return;
+            }
+        }
+    }
+
+    /// Prints the type of a variable, and for a pointer, the type it points to. Without a running
+    /// program, only global variables can be looked up.
+    fn print_type(&self, name: &str) {
+        let rip = self.inferior.as_ref().map(|inferior| self.frame_registers(inferior).rip);
+        match self.debug_data.get_type_name_for_variable(name, rip) {
+            Some(type_name) => println!("type = {}", type_name),
+            None => {
+                println!("No symbol \"{}\" in current context.", name);
+                return;
+            }
+        }
+        let pointee = self
+            .debug_data
+            .get_variable(name, rip)
+            .and_then(|var| var.entity_type.pointee.as_ref());
+        if let Some(pointee) = pointee {
+            println!("*{}: type = {}", name, pointee.name);
+        }
+    }
+
+    /// Prints the local variables and parameters of the current function.
+    fn print_locals(&self) {
+        let inferior = match &self.inferior {
+            Some(inferior) => inferior,
+            None => {
+                println!("No frame selected.");
+                return;
+            }
+        };
+        let Frame { rip, rsp, rbp } = self.frame_registers(inferior);
+        inferior.print_locals(&self.debug_data, rip, rsp, rbp, "");
+    }
+
+    /// Returns the stack frames of the inferior, innermost first, or prints why there aren't any.
+    fn stack_frames(&self) -> Option<Vec<Frame>> {
+        let inferior = match &self.inferior {
+            Some(inferior) => inferior,
+            None => {
+                println!("No stack.");
+                return None;
+            }
+        };
+        match inferior.frames(&self.debug_data) {
+            Ok(frames) => Some(frames),
+            Err(err) => {
+                println!("Could not read the stack: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Makes `number` the selected frame, and prints where it is.
+    fn select_frame(&mut self, number: usize, frames: &[Frame]) {
+        self.selected_frame = number;
+        self.last_listed = None;
+        let rip = frames[number].rip;
+        match (self.debug_data.get_function_from_addr(rip), self.current_line()) {
+            (Some(function), Some(line)) => println!("#{}  {} ({})", number, function, line),
+            _ => println!("#{}  {:#x} in ??", number, rip),
+        }
+    }
+
+    /// Lists the threads of the inferior and where each is stopped, marking the selected one. Threads
+    /// other than the main one are stopped for this, until the program is resumed.
+    fn print_threads(&mut self) {
+        let inferior = match &mut self.inferior {
+            Some(inferior) => inferior,
+            None => {
+                println!("No threads.");
+                return;
+            }
+        };
+        if let Err(err) = inferior.refresh_threads() {
+            println!("Could not find the threads: {}", err);
+            return;
+        }
+        for (index, tid) in inferior.threads().iter().enumerate() {
+            let marker = if *tid == inferior.tid() { '*' } else { ' ' };
+            let rip = match inferior.thread_rip(*tid) {
+                Ok(rip) => rip,
+                Err(err) => {
+                    println!("{} {}    Thread {} (could not read registers: {})", marker, index + 1, tid, err);
+                    continue;
+                }
+            };
+            match (self.debug_data.get_function_from_addr(rip), self.debug_data.get_line_from_addr(rip)) {
+                (Some(function), Some(line)) => {
+                    println!("{} {}    Thread {} {} ({})", marker, index + 1, tid, function, line)
+                }
+                _ => println!("{} {}    Thread {} {:#x} in ??", marker, index + 1, tid, rip),
+            }
+        }
+    }
+
+    /// Makes thread `number` (as numbered by `info threads`) the one that registers, backtraces and
+    /// single-steps apply to, and prints where it is.
+    fn select_thread(&mut self, number: usize) {
+        let inferior = match &mut self.inferior {
+            Some(inferior) => inferior,
+            None => {
+                println!("The program is not being run.");
+                return;
+            }
+        };
+        if let Err(err) = inferior.refresh_threads() {
+            println!("Could not find the threads: {}", err);
+            return;
+        }
+        let tid = match number.checked_sub(1).and_then(|index| inferior.threads().get(index)) {
+            Some(tid) => *tid,
+            None => {
+                println!("Invalid thread ID: {}", number);
+                return;
+            }
+        };
+        inferior.select_thread(tid);
+        println!("[Switching to thread {} (Thread {})]", number, tid);
+        if let Some(frames) = self.stack_frames() {
+            if !frames.is_empty() {
+                self.select_frame(0, &frames);
+            }
+        }
+    }
+
+    /// Returns the registers of the selected frame. The innermost frame uses the live registers,
+    /// and outer frames are found by walking the stack.
+    fn frame_registers(&self, inferior: &Inferior) -> Frame {
+        if self.selected_frame > 0 {
+            let frames = inferior.frames(&self.debug_data).unwrap_or_default();
+            if let Some(frame) = frames.get(self.selected_frame) {
+                return *frame;
+            }
+        }
+        let regs = inferior.get_registers().unwrap();
+        Frame { rip: regs.rip as usize, rsp: regs.rsp as usize, rbp: regs.rbp as usize }
+    }
+
+    /// Formats a value read from memory as a signed integer of the given size.
+    fn format_value(value: u64, size: usize) -> String {
+        let shift = 64 - 8 * size as u32;
+        (((value << shift) as i64) >> shift).to_string()
+    }
+
+    /// Prints where the inferior stopped, or clears it if it has exited.
+    fn report_status(&mut self, status: Status) {
+        // The next `list` should show code around the new location
+        self.last_listed = None;
+        self.selected_frame = 0;
+        match status {
+            Status::Stopped(signal, rip) => {
+                self.resolve_pending_breakpoints();
+                println!("Child stopped (signal {})", signal);
+                if let Some(line) = self.debug_data.get_line_from_addr(rip) {
+                    println!("Stopped at {}", line);
+                }
+            }
+            Status::Exited(status) => {
+                self.inferior = None;
+                println!("Child exited (status {})", status);
+            }
+            Status::Signaled(signal) => {
+                self.inferior = None;
+                println!("Child exited (signal {})", signal);
+            }
+            Status::SyscallStop(rip) => {
+                let regs = self.inferior.as_ref().unwrap().get_registers().unwrap();
+                let name = self.syscall_name(regs.orig_rax);
+                // The kernel sets rax to -ENOSYS before it runs the system call, and to the return
+                // value afterwards
+                if regs.rax as i64 == -(libc::ENOSYS as i64) {
+                    let args = [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9];
+                    let args: Vec<String> = args.iter().map(|arg| format!("{:#x}", arg)).collect();
+                    println!("\nCatchpoint (call to syscall {}): {}({})", name, name, args.join(", "));
+                } else {
+                    println!("\nCatchpoint (returned from syscall {}): {}", name, regs.rax as i64);
+                }
+                if let Some(line) = self.debug_data.get_line_from_addr(rip) {
+                    println!("Stopped at {}", line);
+                }
+            }
+            Status::Forked(child, rip) => {
+                println!("\nCatchpoint (forked process {})", child);
+                if let Some(line) = self.debug_data.get_line_from_addr(rip) {
+                    println!("Stopped at {}", line);
+                }
+            }
+        }
+        // There is nothing to display once the program has exited
+        if self.inferior.is_some() {
+            self.show_displays();
+        }
+    }
+
+    /// Removes a breakpoint, restoring the original instruction byte if the program is running.
+    /// Later breakpoints are renumbered to fill the gap.
+    fn delete_breakpoint(&mut self, number: usize) {
+        if number >= self.breakpoint_order.len() {
+            println!("No breakpoint number {}.", number);
+            return;
+        }
+        let addr = self.breakpoint_order[number];
+        if let (Some(inferior), Some(Some(breakpoint @ Breakpoint { enabled: true, .. }))) =
+            (&mut self.inferior, self.breakpoints.get(&addr))
+        {
+            // If we're stopped at this breakpoint, rip already points at the original instruction,
+            // so restoring the byte is all it takes for the next continue to run it normally
+            if let Err(err) = inferior.write_byte(addr, breakpoint.orig_byte) {
+                println!("Could not remove breakpoint {}: {}", number, err);
+                return;
+            }
+        }
+        self.breakpoints.remove(&addr);
+        self.breakpoint_order.remove(number);
+        self.library_breakpoints.retain(|(a, _)| *a != addr);
+        println!("Deleted breakpoint {} at {:#x}", number, addr);
+    }
+
+    /// Prints `count` units of memory starting at `addr`, like gdb's x command.
+    fn examine(&self, count: usize, format: char, size: char, addr: usize) {
+        let inferior = match &self.inferior {
+            Some(inferior) => inferior,
+            None => {
+                println!("The program is not being run.");
+                return;
+            }
+        };
+        match format {
+            's' => {
+                let mut addr = addr;
+                for _ in 0..count {
+                    match self.read_string(inferior, addr) {
+                        Ok((string, len)) => {
+                            println!("{:#x}:\t{:?}", addr, string);
+                            addr += len + 1;
+                        }
+                        Err(err) => {
+                            println!("Cannot access memory at address {:#x}: {}", addr, err);
+                            return;
+                        }
+                    }
+                }
+            }
+            'i' => {
+                // x86-64 instructions are at most 15 bytes long
+                let rip = inferior.get_rip().ok();
+                self.print_instructions(inferior, addr, count * 15, Some(count), rip);
+            }
+            _ => {
+                let size = match (format, size) {
+                    ('c', _) | (_, 'b') => 1,
+                    (_, 'h') => 2,
+                    (_, 'g') => 8,
+                    _ => 4,
+                };
+                let bytes = match inferior.read_memory(addr, count * size, &self.breakpoints) {
+                    Ok(bytes) => bytes,
+                    Err(err) => {
+                        println!("Cannot access memory at address {:#x}: {}", addr, err);
+                        return;
+                    }
+                };
+                let per_line = match size {
+                    8 => 2,
+                    4 => 4,
+                    _ => 8,
+                };
+                for (i, unit) in bytes.chunks(size).enumerate() {
+                    if i % per_line == 0 {
+                        if i > 0 {
+                            println!();
+                        }
+                        print!("{:#x}:", addr + i * size);
+                    }
+                    let mut value_bytes = [0u8; 8];
+                    value_bytes[..size].copy_from_slice(unit);
+                    let value = u64::from_le_bytes(value_bytes);
+                    match format {
+                        'd' => print!("\t{}", Self::format_value(value, size)),
+                        'c' => print!("\t{} {:?}", value as u8 as i8, value as u8 as char),
+                        _ => print!("\t{:#0width$x}", value, width = 2 + 2 * size),
+                    }
+                }
+                println!();
+            }
+        }
+    }
+
+    /// Disassembles the function containing a location (a function name or address), or the code
+    /// at the instruction pointer if no location is given.
+    fn disassemble(&self, location: Option<String>) {
+        let inferior = match &self.inferior {
+            Some(inferior) => inferior,
+            None => {
+                println!("The program is not being run.");
+                return;
+            }
+        };
+        let rip = match inferior.get_rip() {
+            Ok(rip) => rip,
+            Err(err) => {
+                println!("Could not read registers: {}", err);
+                return;
+            }
+        };
+        let location = match location {
+            Some(location) => location,
+            None => {
+                self.print_instructions(inferior, rip, 64, None, Some(rip));
+                return;
+            }
+        };
+        let addr = match location.strip_prefix('*') {
+            Some(address) => Self::parse_address(address),
+            None if location.starts_with("0x") => Self::parse_address(&location),
+            None => match self.debug_data.get_addr_for_function(None, &location) {
+                Some(addr) => Some(addr),
+                None => {
+                    println!("No symbol \"{}\" in current context.", location);
+                    return;
+                }
+            },
+        };
+        match addr.and_then(|addr| self.debug_data.get_function_range(addr)) {
+            Some((start, end)) => {
+                let name = self.debug_data.get_function_from_addr(start).unwrap_or_default();
+                println!("Dump of assembler code for function {}:", name);
+                self.print_instructions(inferior, start, end - start, None, Some(rip));
+                println!("End of assembler dump.");
+            }
+            None => println!("No function contains specified address."),
+        }
+    }
+
+    /// Writes `len` bytes of the inferior's memory starting at `start` to a file.
+    fn dump_memory(&self, start: usize, len: usize, path: &str) {
+        let inferior = match &self.inferior {
+            Some(inferior) => inferior,
+            None => {
+                println!("The program is not being run.");
+                return;
+            }
+        };
+        let bytes = match inferior.read_memory(start, len, &self.breakpoints) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                println!("Cannot access memory in {:#x}-{:#x}: {}", start, start + len, err);
+                return;
+            }
+        };
+        match std::fs::write(path, &bytes) {
+            Ok(()) => println!("Wrote {} bytes from {:#x} to {}", len, start, path),
+            Err(err) => println!("Could not write {}: {}", path, err),
+        }
+    }
+
+    /// Prints the address of every occurrence of `pattern` within `start..end`, like gdb's find.
+    /// Stops early at memory that can't be read.
+    fn find_in_memory(&self, start: usize, end: usize, pattern: &[u8]) {
+        let inferior = match &self.inferior {
+            Some(inferior) => inferior,
+            None => {
+                println!("The program is not being run.");
+                return;
+            }
+        };
+        let mut found = 0;
+        let mut chunk_start = start;
+        while chunk_start < end {
+            // Read past the end of the chunk by the pattern's length, so that matches that cross
+            // into the next chunk are found
+            let match_starts = FIND_CHUNK_SIZE.min(end - chunk_start);
+            let len = (match_starts + pattern.len() - 1).min(end - chunk_start);
+            let chunk = match inferior.read_memory(chunk_start, len, &self.breakpoints) {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    println!("Cannot access memory at {:#x}: {}", chunk_start, err);
+                    break;
+                }
+            };
+            for offset in 0..match_starts {
+                if chunk[offset..].starts_with(pattern) {
+                    println!("{:#x}", chunk_start + offset);
+                    found += 1;
+                }
+            }
+            chunk_start += match_starts;
+        }
+        match found {
+            0 => println!("Pattern not found."),
+            1 => println!("1 pattern found."),
+            _ => println!("{} patterns found.", found),
+        }
+    }
+
+    /// Disassembles up to `max_count` instructions (or all of them) in `len` bytes of the
+    /// inferior's memory starting at `addr`, marking the one at `rip`.
+    fn print_instructions(
+        &self,
+        inferior: &Inferior,
+        addr: usize,
+        len: usize,
+        max_count: Option<usize>,
+        rip: Option<usize>,
+    ) {
+        let code = match inferior.read_memory(addr, len, &self.breakpoints) {
+            Ok(code) => code,
+            Err(err) => {
+                println!("Cannot access memory at address {:#x}: {}", addr, err);
+                return;
+            }
+        };
+        let cs = Capstone::new()
+            .x86()
+            .mode(arch::x86::ArchMode::Mode64)
+            .build()
+            .expect("Failed to create disassembler");
+        let instructions = match max_count {
+            Some(count) => cs.disasm_count(&code, addr as u64, count),
+            None => cs.disasm_all(&code, addr as u64),
+        };
+        let instructions = match instructions {
+            Ok(instructions) => instructions,
+            Err(err) => {
+                println!("Could not disassemble code at {:#x}: {}", addr, err);
+                return;
+            }
+        };
+        for insn in instructions.iter() {
+            let marker = if Some(insn.address() as usize) == rip { "=>" } else { "  " };
+            let bytes: Vec<String> = insn.bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+            let text = format!("{} {}", insn.mnemonic().unwrap_or(""), insn.op_str().unwrap_or(""));
+            println!("{} {:#x}: {:<24} {}", marker, insn.address(), bytes.join(" "), text.trim_end());
+        }
+    }
+
+    /// Reads a NUL-terminated string from the inferior, returning it along with its length. Long
+    /// strings are cut off at 200 bytes, as in gdb.
+    fn read_string(&self, inferior: &Inferior, addr: usize) -> Result<(String, usize), nix::Error> {
+        const MAX_STRING_LEN: usize = 200;
+        let mut bytes = Vec::new();
+        while bytes.len() < MAX_STRING_LEN {
+            let chunk = inferior.read_memory(addr + bytes.len(), 8, &self.breakpoints)?;
+            match chunk.iter().position(|&byte| byte == 0) {
+                Some(end) => {
+                    bytes.extend_from_slice(&chunk[..end]);
+                    break;
+                }
+                None => bytes.extend_from_slice(&chunk),
+            }
+        }
+        bytes.truncate(MAX_STRING_LEN);
+        let len = bytes.len();
+        Ok((String::from_utf8_lossy(&bytes).into_owned(), len))
+    }
+
+    /// Enables or disables a breakpoint, installing or removing its 0xcc if the program is running.
+    fn set_breakpoint_enabled(&mut self, number: usize, enabled: bool) {
+        let addr = match self.breakpoint_order.get(number) {
+            Some(addr) => *addr,
+            None => {
+                println!("No breakpoint number {}.", number);
+                return;
+            }
+        };
+        let breakpoint = self.breakpoints.get_mut(&addr).unwrap();
+        match (breakpoint, &mut self.inferior) {
+            (Some(breakpoint), _) if breakpoint.enabled == enabled => {}
+            (Some(breakpoint), Some(inferior)) => {
+                let result = if enabled {
+                    inferior.write_byte(addr, 0xcc).map(|orig_byte| breakpoint.orig_byte = orig_byte)
+                } else {
+                    inferior.write_byte(addr, breakpoint.orig_byte).map(|_| ())
+                };
+                if let Err(err) = result {
+                    println!("Could not update breakpoint {}: {}", number, err);
+                    return;
+                }
+                breakpoint.enabled = enabled;
+            }
+            // Not installed in a running inferior; Inferior::new checks the flag when it starts
+            (Some(breakpoint), None) => breakpoint.enabled = enabled,
+            // Pending breakpoints are enabled by default, so only disabling needs recording.
+            // orig_byte is filled in once the breakpoint is enabled in a running inferior.
+            (breakpoint @ None, _) if !enabled => {
+                *breakpoint = Some(Breakpoint{enabled: false, ..Breakpoint::new(addr, 0)});
+            }
+            (None, _) => {}
+        }
+        println!("{} breakpoint {}", if enabled { "Enabled" } else { "Disabled" }, number);
+    }
+
+    /// Prints a table of all breakpoints. Breakpoints set before the program started aren't
+    /// installed yet, so they show as pending, as do locations that haven't been found yet (which
+    /// have no number until they are).
+    fn print_breakpoints(&self) {
+        if self.breakpoint_order.is_empty() && self.pending_breakpoints.is_empty() {
+            println!("No breakpoints.");
+            return;
+        }
+        println!("{:<4} {:<18}  {:<8} Source", "Num", "Address", "Status");
+        for (number, addr) in self.breakpoint_order.iter().enumerate() {
+            let breakpoint = self.breakpoints.get(addr).and_then(Option::as_ref);
+            let status = match (breakpoint, &self.inferior) {
+                (Some(Breakpoint { enabled: false, .. }), _) => "disabled",
+                (_, None) => "pending",
+                _ => "enabled",
+            };
+            // Libraries have no debugging information, but we know which function we looked up
+            let function = self.debug_data.get_function_from_addr(*addr).or_else(|| {
+                let library_breakpoint = self.library_breakpoints.iter().find(|(a, _)| a == addr);
+                library_breakpoint.map(|(_, location)| location.clone())
+            });
+            let source = match (function, self.debug_data.get_line_from_addr(*addr)) {
+                (Some(function), Some(line)) => format!("in {} at {}", function, line),
+                (Some(function), None) => format!("in {}", function),
+                (None, Some(line)) => format!("at {}", line),
+                (None, None) => String::new(),
+            };
+            println!("{:<4} 0x{:016x}  {:<8} {}", number, addr, status, source);
+            if let Some(breakpoint) = breakpoint {
+                if let Some(condition) = &breakpoint.condition {
+                    println!("        stop only if {}", condition);
+                }
+                if let Some(hit_count) = breakpoint.hit_count {
+                    println!("        stop only on hit {}", hit_count);
+                }
+                if breakpoint.current_hits > 0 {
+                    let plural = if breakpoint.current_hits == 1 { "" } else { "s" };
+                    println!("        breakpoint already hit {} time{}", breakpoint.current_hits, plural);
+                }
+                for command in &breakpoint.commands {
+                    println!("        {}", command);
+                }
+            }
+        }
+        for location in &self.pending_breakpoints {
+            println!("{:<4} {:<18}  {:<8} {}", "-", "<PENDING>", "pending", location);
+        }
+    }
+
+    /// Returns the source line the inferior is stopped at, or the start of main if it isn't running.
+    fn current_line(&self) -> Option<Line> {
+        let addr = match &self.inferior {
+            // In outer frames, rip is the return address, which may be on the line after the call
+            Some(inferior) if self.selected_frame > 0 => self.frame_registers(inferior).rip - 1,
+            Some(inferior) => inferior.get_rip().ok()?,
+            None => self.debug_data.get_addr_for_function(None, "main")?,
+        };
+        self.debug_data.get_line_from_addr(addr)
+    }
+
+    /// Prints source lines centred on `line_number` (or the current line), or the lines following
+    /// the previous listing if no line number is given.
+    fn list_source(&mut self, line_number: Option<usize>) {
+        let current_line = self.current_line();
+        let (file, first_line) = match (line_number, &self.last_listed) {
+            (None, Some((file, last_line))) => (file.clone(), last_line + 1),
+            _ => {
+                let file = match (&self.last_listed, &current_line) {
+                    (Some((file, _)), _) if line_number.is_some() => file.clone(),
+                    (_, Some(line)) => line.file.clone(),
+                    _ => {
+                        println!("No source file to list.");
+                        return;
+                    }
+                };
+                let center = match (line_number, &current_line) {
+                    (Some(line_number), _) => line_number,
+                    (None, Some(line)) => line.number,
+                    (None, None) => 1,
+                };
+                (file, center.saturating_sub(LIST_CONTEXT_LINES).max(1))
+            }
+        };
+        let source = match std::fs::read_to_string(&file) {
+            Ok(source) => source,
+            Err(err) => {
+                println!("Could not read {}: {}", file, err);
+                return;
+            }
+        };
+        let lines: Vec<&str> = source.lines().collect();
+        if first_line > lines.len() {
+            println!("Line number {} out of range; \"{}\" has {} lines.", first_line, file, lines.len());
+            return;
+        }
+        let last_line = (first_line + 2 * LIST_CONTEXT_LINES).min(lines.len());
+        let mut highlighter = Highlighter::default();
+        for number in first_line..=last_line {
+            let is_current = matches!(&current_line, Some(line) if line.file == file && line.number == number);
+            let marker = if is_current { "\x1b[1;33m=>\x1b[0m" } else { "  " };
+            let source = if self.color {
+                highlighter.highlight(lines[number - 1])
+            } else {
+                lines[number - 1].to_string()
+            };
+            println!("{} {:<4} {}", marker, number, source);
+        }
+        self.last_listed = Some((file, last_line));
+    }
+
+    /// Runs the commands in a script file as if they had been typed at the prompt, echoing each one
+    /// first. Blank lines and lines starting with `#` are skipped, and a line that isn't a valid
+    /// command is reported without stopping the rest of the script. Returns false if the script
+    /// quit the debugger.
+    fn source(&mut self, path: &str) -> bool {
+        let script = match std::fs::read_to_string(path) {
+            Ok(script) => script,
+            Err(err) => {
+                println!("Could not read {}: {}", path, err);
+                return true;
+            }
+        };
+        let mut lines = script.lines().map(str::trim).enumerate();
+        while let Some((index, line)) = lines.next() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            println!("(deet) {}", line);
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            match DebuggerCommand::from_tokens(&tokens) {
+                // The command list comes from the script rather than the user
+                Some(DebuggerCommand::Commands(number)) => {
+                    let commands = lines
+                        .by_ref()
+                        .map(|(_, line)| line)
+                        .take_while(|line| *line != "end")
+                        .filter(|line| !line.is_empty())
+                        .map(str::to_string)
+                        .collect();
+                    self.store_breakpoint_commands(number, commands);
+                }
+                Some(command) => {
+                    if !self.execute(command) {
+                        return false;
+                    }
+                }
+                None => println!("Warning: {}:{}: unrecognized command: {}", path, index + 1, line),
+            }
+        }
+        true
+    }
+
     fn parse_address(addr: &str) -> Option<usize> {
         let addr_without_0x = if addr.to_lowercase().starts_with("0x") {
             &addr[2..]
         } else {
-            &addr
+            addr
         };
         usize::from_str_radix(addr_without_0x, 16).ok()
     }
 
-    /// This function prompts the user to enter a command, and continues re-prompting until the user
-    /// enters a valid command. It uses DebuggerCommand::from_tokens to do the command parsing.
-    ///
-    /// You don't need to read, understand, or modify this function.
-    fn get_next_command(&mut self) -> DebuggerCommand {
+    /// Reads the next command from the remote debugger, after telling it we're ready for one.
+    fn get_next_remote_command(&mut self) -> DebuggerCommand {
         loop {
-            // Print prompt and get next line of user input
-            match self.readline.readline("(deet) ") {
-                Err(ReadlineError::Interrupted) => {
-                    // User pressed ctrl+c. We're going to ignore it
-                    println!("Type \"quit\" to exit");
-                }
-                Err(ReadlineError::Eof) => {
-                    // User pressed ctrl+d, which is the equivalent of "quit" for our purposes
-                    return DebuggerCommand::Quit;
-                }
-                Err(err) => {
-                    panic!("Unexpected I/O error: {:?}", err);
+            // The remote debugger went away, so there's nobody left to debug for
+            let line = match self.read_remote_line() {
+                Some(line) => line,
+                None => return DebuggerCommand::Quit,
+            };
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.is_empty() {
+                continue;
+            }
+            if let Some(cmd) = DebuggerCommand::from_tokens(&tokens) {
+                return cmd;
+            } else {
+                println!("Unrecognized command.");
+            }
+        }
+    }
+
+    /// Reads a line from the remote debugger, after telling it we're ready for one. Returns None
+    /// if the connection is gone.
+    fn read_remote_line(&mut self) -> Option<String> {
+        let conn = self.remote_conn.as_mut().unwrap();
+        print!("{}", remote::READY as char);
+        let _ = std::io::stdout().flush();
+        let mut line = String::new();
+        match conn.read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line),
+        }
+    }
+
+    /// Updates the source and registers panels of the full-screen interface.
+    fn update_tui(&mut self) {
+        let source = self.current_line().and_then(|line| {
+            let text = std::fs::read_to_string(&line.file).ok()?;
+            Some(SourceView {
+                file: line.file,
+                lines: text.lines().map(String::from).collect(),
+                current: line.number,
+            })
+        });
+        let registers = match &self.inferior {
+            Some(inferior) => inferior
+                .get_registers()
+                .map(|regs| register_values(&regs))
+                .unwrap_or_default(),
+            None => Vec::new(),
+        };
+        if let Some(tui) = &mut self.tui {
+            tui.set_panels(source, registers);
+        }
+    }
+
+    /// Prompts the user until they enter a non-empty line, and adds it to the history. Returns None
+    /// if they pressed ctrl+d.
+    fn read_line(&mut self, prompt: &str) -> Option<String> {
+        loop {
+            let line = if self.tui.is_some() {
+                self.update_tui();
+                let history: Vec<String> = self.readline.history().iter().cloned().collect();
+                match self.tui.as_mut().unwrap().read_line(prompt, &history) {
+                    Ok(line) => line?,
+                    Err(err) => panic!("Unexpected I/O error: {:?}", err),
                 }
-                Ok(line) => {
-                    if line.trim().len() == 0 {
+            } else {
+                match self.readline.readline(prompt) {
+                    Err(ReadlineError::Interrupted) => {
+                        // User pressed ctrl+c. We're going to ignore it
+                        println!("Type \"quit\" to exit");
                         continue;
                     }
-                    let _ = self.readline.add_history_entry(line.as_str());
-                    if let Err(err) = self.readline.save_history(&self.history_path) {
-                        println!(
-                            "Warning: failed to save history file at {}: {}",
-                            self.history_path, err
-                        );
+                    Err(ReadlineError::Eof) => {
+                        // User pressed ctrl+d, which is the equivalent of "quit" for our purposes
+                        return None;
                     }
-                    let tokens: Vec<&str> = line.split_whitespace().collect();
-                    if let Some(cmd) = DebuggerCommand::from_tokens(&tokens) {
-                        return cmd;
-                    } else {
-                        println!("Unrecognized command.");
+                    Err(err) => {
+                        panic!("Unexpected I/O error: {:?}", err);
                     }
+                    Ok(line) => line,
                 }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let _ = self.readline.add_history_entry(line.as_str());
+            if let Err(err) = self.readline.save_history(&self.history_path) {
+                println!(
+                    "Warning: failed to save history file at {}: {}",
+                    self.history_path, err
+                );
+            }
+            return Some(line);
+        }
+    }
+
+    /// This function prompts the user to enter a command, and continues re-prompting until the user
+    /// enters a valid command. It uses DebuggerCommand::from_tokens to do the command parsing.
+    ///
+    /// You don't need to read, understand, or modify this function.
+    fn get_next_command(&mut self) -> DebuggerCommand {
+        if self.remote_conn.is_some() {
+            return self.get_next_remote_command();
+        }
+        loop {
+            let line = match self.read_line("(deet) ") {
+                Some(line) => line,
+                None => return DebuggerCommand::Quit,
+            };
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if let Some(cmd) = DebuggerCommand::from_tokens(&tokens) {
+                return cmd;
+            } else {
+                println!("Unrecognized command.");
             }
         }
     }
@@ -1,17 +1,131 @@
+use std::convert::TryFrom;
+
 pub enum DebuggerCommand {
-    Backtrace,
-    Break(String),
+    Attach(u32),
+    /// Whether to print each frame's local variables too
+    Backtrace(bool),
+    /// Location, and the hit on which to stop if only one hit should stop the program
+    Break(String, Option<usize>),
+    BreakCondition(String, String),
+    CatchFork,
+    /// System call to stop on, or None for all of them
+    CatchSyscall(Option<String>),
+    /// Breakpoint whose command list to set
+    Commands(usize),
     Continue,
+    Delete(usize),
+    Detach,
+    Disassemble(Option<String>),
+    /// Variable to print every time the program stops
+    Display(String),
+    DisableBreakpoint(usize),
+    Down,
+    /// Copy `len` bytes of memory from `start` into the file at `path`
+    Dump { start: usize, len: usize, path: String },
+    EnableBreakpoint(usize),
+    Examine { count: usize, format: char, size: char, addr: usize },
+    /// Search memory from `start` up to `end` for a sequence of bytes
+    Find { start: usize, end: usize, pattern: Vec<u8> },
+    Frame(usize),
+    InfoBreakpoints,
+    InfoLocals,
+    InfoProcMappings,
+    InfoRegisters,
+    InfoThreads,
+    /// Address (hex, or `*ADDRESS`) or `*FUNCTION` to continue from
+    Jump(String),
+    List(Option<usize>),
+    Next,
+    Print(String),
     Quit,
     Run(Vec<String>),
+    /// Target (`$register` or `*address`) and the value to store there
+    Set(String, u64),
+    Signal(String),
+    /// Path of a file of commands to run
+    Source(String),
+    /// Address of a remote stub to pass commands to
+    TargetRemote(String),
+    /// Number of the thread to select, as listed by `info threads`
+    Thread(usize),
+    /// Number of the display to remove
+    Undisplay(usize),
+    Up,
+    Watch(String),
+    Whatis(String),
 }
 
 impl DebuggerCommand {
-    pub fn from_tokens(tokens: &Vec<&str>) -> Option<DebuggerCommand> {
-        match tokens[0] {
-            "bt" | "back" | "backtrace" => Some(DebuggerCommand::Backtrace),
-            "b" | "break" => Some(DebuggerCommand::Break(tokens[1].to_string())),
+    pub fn from_tokens(tokens: &[&str]) -> Option<DebuggerCommand> {
+        let command = *tokens.first()?;
+        // gdb's x takes its format glued on: x/4xw ADDR
+        if let Some(spec) = command.strip_prefix("x/") {
+            return Self::parse_examine(spec, tokens.get(1)?);
+        }
+        match command {
+            "attach" => Some(DebuggerCommand::Attach(tokens.get(1)?.parse().ok()?)),
+            "bt" | "back" | "backtrace" => match tokens.get(1) {
+                Some(&"full") => Some(DebuggerCommand::Backtrace(true)),
+                Some(_) => None,
+                None => Some(DebuggerCommand::Backtrace(false)),
+            },
+            "b" | "break" => match tokens.get(2) {
+                Some(&"if") if tokens.len() > 3 => Some(DebuggerCommand::BreakCondition(
+                    tokens[1].to_string(),
+                    tokens[3..].join(" "),
+                )),
+                Some(&"hit") if tokens.len() == 4 => match tokens[3].parse() {
+                    Ok(0) | Err(_) => None,
+                    Ok(hit_count) => Some(DebuggerCommand::Break(tokens[1].to_string(), Some(hit_count))),
+                },
+                Some(_) => None,
+                None => Some(DebuggerCommand::Break(tokens.get(1)?.to_string(), None)),
+            },
+            "commands" => Some(DebuggerCommand::Commands(tokens.get(1)?.parse().ok()?)),
             "c" | "cont" | "continue" => Some(DebuggerCommand::Continue),
+            "catch" => match *tokens.get(1)? {
+                "fork" => Some(DebuggerCommand::CatchFork),
+                "syscall" => Some(DebuggerCommand::CatchSyscall(tokens.get(2).map(|s| s.to_string()))),
+                _ => None,
+            },
+            "detach" => Some(DebuggerCommand::Detach),
+            "disas" | "disassemble" => Some(DebuggerCommand::Disassemble(tokens.get(1).map(|s| s.to_string()))),
+            "d" | "delete" => Some(DebuggerCommand::Delete(tokens.get(1)?.parse().ok()?)),
+            "dis" | "disable" => Some(DebuggerCommand::DisableBreakpoint(tokens.get(1)?.parse().ok()?)),
+            "display" => Some(DebuggerCommand::Display(tokens.get(1)?.to_string())),
+            "do" | "down" => Some(DebuggerCommand::Down),
+            "dump" => match tokens[1..] {
+                // gdb's syntax, which takes an end address rather than a length
+                ["binary", "memory", path, start, end] => {
+                    let start = Self::parse_number(start)? as usize;
+                    let len = (Self::parse_number(end)? as usize).checked_sub(start)?;
+                    Some(DebuggerCommand::Dump { start, len, path: path.to_string() })
+                }
+                _ => None,
+            },
+            "en" | "enable" => Some(DebuggerCommand::EnableBreakpoint(tokens.get(1)?.parse().ok()?)),
+            // Commas separate the arguments, and strings may contain spaces, so this one is parsed
+            // from the rest of the line rather than from tokens
+            "find" => Self::parse_find(&tokens[1..].join(" ")),
+            "f" | "frame" => Some(DebuggerCommand::Frame(tokens.get(1)?.parse().ok()?)),
+            "i" | "info" => match *tokens.get(1)? {
+                "b" | "breakpoints" => Some(DebuggerCommand::InfoBreakpoints),
+                "locals" => Some(DebuggerCommand::InfoLocals),
+                "proc" => match *tokens.get(2)? {
+                    "mappings" => Some(DebuggerCommand::InfoProcMappings),
+                    _ => None,
+                },
+                "r" | "registers" => Some(DebuggerCommand::InfoRegisters),
+                "threads" => Some(DebuggerCommand::InfoThreads),
+                _ => None,
+            },
+            "j" | "jump" => Some(DebuggerCommand::Jump(tokens.get(1)?.to_string())),
+            "l" | "list" => match tokens.get(1) {
+                Some(line_number) => Some(DebuggerCommand::List(Some(line_number.parse().ok()?))),
+                None => Some(DebuggerCommand::List(None)),
+            },
+            "n" | "next" => Some(DebuggerCommand::Next),
+            "p" | "print" => Some(DebuggerCommand::Print(tokens.get(1)?.to_string())),
             "q" | "quit" => Some(DebuggerCommand::Quit),
             "r" | "run" => {
                 let args = tokens[1..].to_vec();
@@ -19,8 +133,96 @@ impl DebuggerCommand {
                     args.iter().map(|s| s.to_string()).collect(),
                 ))
             }
+            "set" => {
+                // Accept both `set $rax = 1` and `set $rax=1`
+                let assignment = tokens[1..].join(" ");
+                let (target, value) = assignment.split_once('=')?;
+                let target = target.trim();
+                if target.is_empty() {
+                    return None;
+                }
+                Some(DebuggerCommand::Set(target.to_string(), Self::parse_number(value.trim())?))
+            }
+            "x" => match tokens.get(1)?.strip_prefix('/') {
+                Some(spec) => Self::parse_examine(spec, tokens.get(2)?),
+                None => Self::parse_examine("", tokens[1]),
+            },
+            "signal" => Some(DebuggerCommand::Signal(tokens.get(1)?.to_string())),
+            "source" => Some(DebuggerCommand::Source(tokens.get(1)?.to_string())),
+            "target" => match *tokens.get(1)? {
+                "remote" => Some(DebuggerCommand::TargetRemote(tokens.get(2)?.to_string())),
+                _ => None,
+            },
+            "thread" => Some(DebuggerCommand::Thread(tokens.get(1)?.parse().ok()?)),
+            "undisplay" => Some(DebuggerCommand::Undisplay(tokens.get(1)?.parse().ok()?)),
+            "up" => Some(DebuggerCommand::Up),
+            "watch" => Some(DebuggerCommand::Watch(tokens.get(1)?.to_string())),
+            "whatis" => Some(DebuggerCommand::Whatis(tokens.get(1)?.to_string())),
             // Default case:
             _ => None,
         }
     }
+
+    /// Parses the /NFS part of `x/NFS ADDR`: an optional count, then format and size letters in
+    /// any order. Like gdb, the defaults are one word in hex.
+    fn parse_examine(spec: &str, addr: &str) -> Option<DebuggerCommand> {
+        let letters = spec.trim_start_matches(|c: char| c.is_ascii_digit());
+        let count = match &spec[..spec.len() - letters.len()] {
+            "" => 1,
+            digits => digits.parse().ok()?,
+        };
+        let mut format = 'x';
+        let mut size = 'w';
+        for letter in letters.chars() {
+            match letter {
+                'x' | 'd' | 'i' | 's' | 'c' => format = letter,
+                'b' | 'h' | 'w' | 'g' => size = letter,
+                _ => return None,
+            }
+        }
+        let addr = Self::parse_number(addr)? as usize;
+        Some(DebuggerCommand::Examine { count, format, size, addr })
+    }
+
+    /// Parses the arguments of `find START, END, PATTERN...`, where the pattern is a list of bytes
+    /// (like 0x7f) and quoted strings, e.g. `find 0x4000, 0x5000, 0x7f, "ELF"`. Runs of spaces
+    /// inside strings are collapsed into one, since the line was split into tokens.
+    fn parse_find(args: &str) -> Option<DebuggerCommand> {
+        let (start, rest) = args.split_once(',')?;
+        let (end, mut rest) = rest.split_once(',')?;
+        let start = Self::parse_number(start.trim())? as usize;
+        let end = Self::parse_number(end.trim())? as usize;
+        let mut pattern = Vec::new();
+        loop {
+            rest = rest.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+            if rest.is_empty() {
+                break;
+            }
+            if let Some(string) = rest.strip_prefix('"') {
+                let (contents, after) = string.split_once('"')?;
+                pattern.extend_from_slice(contents.as_bytes());
+                rest = after;
+            } else {
+                let len = rest.find(|c: char| c == ',' || c.is_whitespace()).unwrap_or(rest.len());
+                pattern.push(u8::try_from(Self::parse_number(&rest[..len])?).ok()?);
+                rest = &rest[len..];
+            }
+        }
+        if pattern.is_empty() || end <= start {
+            return None;
+        }
+        Some(DebuggerCommand::Find { start, end, pattern })
+    }
+
+    /// Parses a number in hex (with a 0x prefix) or decimal. Negative numbers wrap around, so -1 is
+    /// all ones.
+    pub fn parse_number(number: &str) -> Option<u64> {
+        if let Some(negated) = number.strip_prefix('-') {
+            return Self::parse_number(negated).map(u64::wrapping_neg);
+        }
+        match number.strip_prefix("0x").or_else(|| number.strip_prefix("0X")) {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => number.parse().ok(),
+        }
+    }
 }
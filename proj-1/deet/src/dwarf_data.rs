@@ -32,7 +32,7 @@ impl DwarfData {
         let file = fs::File::open(path).or(Err(Error::ErrorOpeningFile))?;
         let mmap = unsafe { memmap2::Mmap::map(&file).or(Err(Error::ErrorOpeningFile))? };
         let object = object::File::parse(&*mmap)
-            .or_else(|e| Err(gimli_wrapper::Error::ObjectError(e.to_string())))?;
+            .map_err(|e| gimli_wrapper::Error::ObjectError(e.to_string()))?;
         let endian = if object.is_little_endian() {
             gimli::RunTimeEndian::Little
         } else {
@@ -40,7 +40,7 @@ impl DwarfData {
         };
         Ok(DwarfData {
             files: gimli_wrapper::load_file(&object, endian)?,
-            addr2line: Context::new(&object).or_else(|e| Err(gimli_wrapper::Error::from(e)))?,
+            addr2line: Context::new(&object).map_err(gimli_wrapper::Error::from)?,
         })
     }
 
@@ -55,7 +55,7 @@ impl DwarfData {
     pub fn get_addr_for_line(&self, file: Option<&str>, line_number: usize) -> Option<usize> {
         let target_file = match file {
             Some(filename) => self.get_target_file(filename)?,
-            None => self.files.get(0)?,
+            None => self.files.first()?,
         };
         Some(
             target_file
@@ -111,6 +111,58 @@ impl DwarfData {
         Some(frame.function?.raw_name().ok()?.to_string())
     }
 
+    /// Returns the function whose code contains `curr_addr`, if any.
+    fn get_function_containing(&self, curr_addr: usize) -> Option<&Function> {
+        self.files
+            .iter()
+            .flat_map(|file| file.functions.iter())
+            .find(|func| func.address <= curr_addr && curr_addr < func.address + func.text_length)
+    }
+
+    /// Returns the start and end addresses of the code of the function containing `curr_addr`.
+    pub fn get_function_range(&self, curr_addr: usize) -> Option<(usize, usize)> {
+        let func = self.get_function_containing(curr_addr)?;
+        Some((func.address, func.address + func.text_length))
+    }
+
+    /// Looks up a variable by name: a local variable or parameter of the function containing
+    /// `curr_addr` if there is one with that name, otherwise a global variable.
+    pub fn get_variable(&self, name: &str, curr_addr: Option<usize>) -> Option<&Variable> {
+        if let Some(func) = curr_addr.and_then(|addr| self.get_function_containing(addr)) {
+            if let Some(var) = func.variables.iter().find(|var| var.name == name) {
+                return Some(var);
+            }
+        }
+        self.files
+            .iter()
+            .flat_map(|file| file.global_variables.iter())
+            .find(|var| var.name == name)
+    }
+
+    /// Returns the name of the type of a variable, looked up as by get_variable.
+    pub fn get_type_name_for_variable(&self, name: &str, rip: Option<usize>) -> Option<String> {
+        Some(self.get_variable(name, rip)?.entity_type.name.clone())
+    }
+
+    /// Returns the address of a variable in the inferior's address space, given the current
+    /// instruction pointer (to find locals of the current function) and frame pointer.
+    pub fn get_variable_addr(&self, name: &str, rip: usize, rbp: usize) -> Option<usize> {
+        Some(self.get_variable(name, Some(rip))?.location.get_address(rbp))
+    }
+
+    /// Returns the name, address and type of each local variable and parameter of the function
+    /// containing `rip`, given the frame pointer of its frame.
+    pub fn get_locals_for_frame(&self, rip: usize, rbp: usize) -> Vec<(String, usize, Type)> {
+        let func = match self.get_function_containing(rip) {
+            Some(func) => func,
+            None => return Vec::new(),
+        };
+        func.variables
+            .iter()
+            .map(|var| (var.name.clone(), var.location.get_address(rbp), var.entity_type.clone()))
+            .collect()
+    }
+
     #[allow(dead_code)]
     pub fn print(&self) {
         for file in &self.files {
@@ -152,14 +204,40 @@ impl DwarfData {
 pub struct Type {
     pub name: String,
     pub size: usize,
+    pub is_pointer: bool,
+    /// For pointers, the type pointed to, if we know it
+    pub pointee: Option<Box<Type>>,
 }
 
 impl Type {
     pub fn new(name: String, size: usize) -> Self {
-        Type {
-            name: name,
-            size: size,
+        Type { name, size, is_pointer: false, pointee: None }
+    }
+
+    pub fn new_pointer(name: String, size: usize, pointee: Option<Type>) -> Self {
+        Type { name, size, is_pointer: true, pointee: pointee.map(Box::new) }
+    }
+
+    /// Number of bytes to read for a value of this type. Values bigger than a word are truncated
+    /// to one, and types of unknown size are read as a word.
+    pub fn value_size(&self) -> usize {
+        match self.size {
+            0 => 8,
+            size => size.min(8),
+        }
+    }
+
+    /// Formats a value of this type read from memory: pointers in hex, and everything else as an
+    /// integer.
+    pub fn format_value(&self, value: u64) -> String {
+        if self.is_pointer {
+            return format!("({}) 0x{:016x}", self.name, value);
+        }
+        if self.name.contains("unsigned") {
+            return value.to_string();
         }
+        let shift = 64 - 8 * self.value_size() as u32;
+        (((value << shift) as i64) >> shift).to_string()
     }
 }
 
@@ -169,6 +247,19 @@ pub enum Location {
     FramePointerOffset(isize),
 }
 
+impl Location {
+    /// Returns the address of a variable at this location, given the value of rbp in the frame of
+    /// the function it belongs to.
+    pub fn get_address(&self, rbp: usize) -> usize {
+        match *self {
+            Location::Address(addr) => addr,
+            // Offsets are from the frame base, which is the canonical frame address: rbp plus the
+            // saved rbp and return address
+            Location::FramePointerOffset(offset) => (rbp as isize + 16 + offset) as usize,
+        }
+    }
+}
+
 impl fmt::Display for Location {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
@@ -5,7 +5,6 @@
 //!
 //! This code is a huge mess. Please don't read it unless you're trying to do an extension :)
 
-use gimli;
 use gimli::{UnitOffset, UnitSectionOffset};
 use object::{Object, ObjectSection};
 use std::borrow;
@@ -37,7 +36,7 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
     let borrow_section: &dyn for<'a> Fn(
         &'a borrow::Cow<[u8]>,
     ) -> gimli::EndianSlice<'a, gimli::RunTimeEndian> =
-        &|section| gimli::EndianSlice::new(&*section, endian);
+        &|section| gimli::EndianSlice::new(section, endian);
 
     // Create `EndianSlice`s for all of the sections.
     let dwarf = dwarf_cow.borrow(&borrow_section);
@@ -52,6 +51,45 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
     while let Some(header) = iter.next()? {
         let unit = dwarf.unit(header)?;
 
+        // Pointers, typedefs and qualified types can be declared after the variables that use
+        // them, so collect them up front
+        let mut derived_types: HashMap<usize, DerivedType> = HashMap::new();
+        let mut entries = unit.entries();
+        while let Some((_, entry)) = entries.next_dfs()? {
+            let tag = entry.tag();
+            if !matches!(
+                tag,
+                gimli::DW_TAG_pointer_type
+                    | gimli::DW_TAG_typedef
+                    | gimli::DW_TAG_const_type
+                    | gimli::DW_TAG_volatile_type
+            ) {
+                continue;
+            }
+            let target = match entry.attr(gimli::DW_AT_type) {
+                Ok(Some(attr)) => match get_attr_value(&attr, &unit, &dwarf) {
+                    Ok(DebugValue::Size(offset)) => Some(offset),
+                    _ => None,
+                },
+                _ => None,
+            };
+            let byte_size = match entry.attr(gimli::DW_AT_byte_size) {
+                Ok(Some(attr)) => match get_attr_value(&attr, &unit, &dwarf) {
+                    Ok(DebugValue::Uint(byte_size)) => Some(byte_size.try_into().unwrap()),
+                    _ => None,
+                },
+                _ => None,
+            };
+            let name = match entry.attr(gimli::DW_AT_name) {
+                Ok(Some(attr)) => match get_attr_value(&attr, &unit, &dwarf) {
+                    Ok(DebugValue::Str(name)) => Some(name),
+                    _ => None,
+                },
+                _ => None,
+            };
+            derived_types.insert(entry.offset().0, DerivedType { tag, name, target, byte_size });
+        }
+
         // Iterate over the Debugging Information Entries (DIEs) in the unit.
         let mut depth = 0;
         let mut entries = unit.entries();
@@ -154,9 +192,7 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                             }
                             gimli::DW_AT_type => {
                                 if let Ok(DebugValue::Size(offset)) = val {
-                                    if let Some(dtype) = offset_to_type.get(&offset).clone() {
-                                        entity_type = Some(dtype.clone());
-                                    }
+                                    entity_type = resolve_type(offset, &derived_types, &offset_to_type);
                                 }
                             }
                             gimli::DW_AT_location => {
@@ -172,11 +208,11 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                             _ => {}
                         }
                     }
-                    if entity_type.is_some() && location.is_some() {
+                    if let (Some(entity_type), Some(location)) = (entity_type, location) {
                         let var = Variable {
                             name,
-                            entity_type: entity_type.unwrap(),
-                            location: location.unwrap(),
+                            entity_type,
+                            location,
                             line_number: line_number.try_into().unwrap(),
                         };
                         if depth == 1 {
@@ -251,6 +287,7 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
 }
 
 #[derive(Debug, Clone)]
+#[allow(dead_code)]
 pub enum DebugValue {
     Str(String),
     Uint(u64),
@@ -260,6 +297,7 @@ pub enum DebugValue {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     GimliError(gimli::Error),
     // Addr2lineError(addr2line::gimli::Error),
@@ -298,6 +336,68 @@ impl<'input, Endian> Reader for gimli::EndianSlice<'input, Endian> where
 
 trait Reader: gimli::Reader<Offset = usize> + Send + Sync {}
 
+/// A type defined in terms of another one: a pointer, typedef, or const or volatile type
+struct DerivedType {
+    tag: gimli::DwTag,
+    /// Typedefs are named, and so are pointer types in Rust programs
+    name: Option<String>,
+    /// Offset of the type this one is derived from. None means void
+    target: Option<usize>,
+    byte_size: Option<usize>,
+}
+
+/// Resolves the type at `offset` by following its chain of DW_AT_type references down to a base
+/// type, naming it along the way (e.g. `const char *`). Returns None if the chain ends somewhere we
+/// don't support, like a struct, except for pointers, which are still usable as addresses.
+fn resolve_type(
+    offset: usize,
+    derived_types: &HashMap<usize, DerivedType>,
+    offset_to_type: &HashMap<usize, Type>,
+) -> Option<Type> {
+    if let Some(dtype) = offset_to_type.get(&offset) {
+        return Some(dtype.clone());
+    }
+    let derived = derived_types.get(&offset)?;
+    let target = match derived.target {
+        Some(target) => resolve_type(target, derived_types, offset_to_type),
+        None => Some(Type::new("void".to_string(), 0)),
+    };
+    match derived.tag {
+        gimli::DW_TAG_pointer_type => {
+            let name = match &derived.name {
+                Some(name) => name.clone(),
+                None => {
+                    let pointee_name = target.as_ref().map_or("<unknown>", |target| &target.name);
+                    if pointee_name.ends_with('*') {
+                        format!("{}*", pointee_name)
+                    } else {
+                        format!("{} *", pointee_name)
+                    }
+                }
+            };
+            Some(Type::new_pointer(name, derived.byte_size.unwrap_or(8), target))
+        }
+        gimli::DW_TAG_typedef => {
+            let mut dtype = target?;
+            if let Some(name) = &derived.name {
+                dtype.name = name.clone();
+            }
+            Some(dtype)
+        }
+        _ => {
+            let mut dtype = target?;
+            let qualifier = if derived.tag == gimli::DW_TAG_const_type { "const" } else { "volatile" };
+            // Qualifiers on a pointer apply to the pointer itself, so they go after the *
+            dtype.name = if dtype.is_pointer {
+                format!("{} {}", dtype.name, qualifier)
+            } else {
+                format!("{} {}", qualifier, dtype.name)
+            };
+            Some(dtype)
+        }
+    }
+}
+
 fn get_location<R: Reader>(attr: &gimli::Attribute<R>, unit: &gimli::Unit<R>) -> Option<Location> {
     if let gimli::AttributeValue::Exprloc(ref data) = attr.value() {
         let encoding = unit.encoding();
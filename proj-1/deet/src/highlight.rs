@@ -0,0 +1,112 @@
+//! Syntax highlighting for `list`. This is a rough, line-at-a-time tokenizer rather than a real
+//! parser: it colours keywords, string literals and comments, which is enough to make source easier
+//! to scan.
+
+use std::str::FromStr;
+
+const KEYWORD_COLOR: &str = "\x1b[1;34m";
+const STRING_COLOR: &str = "\x1b[32m";
+const COMMENT_COLOR: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
+
+const KEYWORDS: &[&str] = &[
+    "fn", "let", "mut", "if", "else", "match", "loop", "while", "for", "return", "use", "struct", "enum",
+    "impl", "pub",
+];
+
+/// When to colour output, like cargo's --color
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorMode {
+    /// Colour output if it is going to a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    /// Returns whether output should be coloured.
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorMode::Auto => unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 },
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        }
+    }
+}
+
+impl FromStr for ColorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorMode::Auto),
+            "always" => Ok(ColorMode::Always),
+            "never" => Ok(ColorMode::Never),
+            _ => Err(format!("argument for --color must be auto, always, or never, but found `{}`", s)),
+        }
+    }
+}
+
+/// Highlights consecutive source lines. Block comments can span lines, so the highlighter remembers
+/// whether the previous line ended inside one.
+#[derive(Default)]
+pub struct Highlighter {
+    in_block_comment: bool,
+}
+
+impl Highlighter {
+    /// Returns the line with ANSI colour codes around keywords, strings and comments.
+    pub fn highlight(&mut self, line: &str) -> String {
+        let chars: Vec<char> = line.chars().collect();
+        let mut output = String::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let rest = &chars[i..];
+            let token_len = if self.in_block_comment || rest.starts_with(&['/', '*']) {
+                // Skip the opening /* so that /*/ doesn't count as a whole comment
+                let start = if self.in_block_comment { 0 } else { 2 };
+                let end = (start..rest.len()).find(|&j| rest[j..].starts_with(&['*', '/']));
+                self.in_block_comment = end.is_none();
+                let len = end.map_or(rest.len(), |end| end + 2);
+                Self::push_colored(&mut output, COMMENT_COLOR, &rest[..len]);
+                len
+            } else if rest.starts_with(&['/', '/']) {
+                Self::push_colored(&mut output, COMMENT_COLOR, rest);
+                rest.len()
+            } else if rest[0] == '"' {
+                let mut len = 1;
+                while len < rest.len() {
+                    len += 1;
+                    match rest[len - 1] {
+                        '\\' => len += 1,
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+                let len = len.min(rest.len());
+                Self::push_colored(&mut output, STRING_COLOR, &rest[..len]);
+                len
+            } else if rest[0].is_alphabetic() || rest[0] == '_' {
+                let len = rest.iter().position(|c| !c.is_alphanumeric() && *c != '_').unwrap_or(rest.len());
+                let word: String = rest[..len].iter().collect();
+                if KEYWORDS.contains(&word.as_str()) {
+                    Self::push_colored(&mut output, KEYWORD_COLOR, &rest[..len]);
+                } else {
+                    output.push_str(&word);
+                }
+                len
+            } else {
+                output.push(rest[0]);
+                1
+            };
+            i += token_len;
+        }
+        output
+    }
+
+    fn push_colored(output: &mut String, color: &str, text: &[char]) {
+        output.push_str(color);
+        output.extend(text);
+        output.push_str(RESET);
+    }
+}
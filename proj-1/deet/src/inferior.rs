@@ -5,10 +5,10 @@ use nix::unistd::Pid;
 use std::collections::HashMap;
 use std::mem::size_of;
 use std::os::unix::process::CommandExt;
-use std::process::Child;
 use std::process::Command;
+use std::str::FromStr;
 use crate::debugger::Breakpoint;
-use crate::dwarf_data::DwarfData;
+use crate::dwarf_data::{DwarfData, Type};
 
 pub enum Status {
     /// Indicates inferior stopped. Contains the signal that stopped the process, as well as the
@@ -21,23 +21,178 @@ pub enum Status {
     /// Indicates the inferior exited due to a signal. Contains the signal that killed the
     /// process.
     Signaled(signal::Signal),
+
+    /// Indicates inferior stopped on entry to or exit from a system call. Contains the current
+    /// instruction pointer.
+    SyscallStop(usize),
+
+    /// Indicates the inferior forked, when forks are being caught. Contains the new process's pid
+    /// and the current instruction pointer.
+    Forked(Pid, usize),
+}
+
+/// Which side of a fork stays under the debugger's control, like gdb's follow-fork-mode. The other
+/// one is detached and runs freely.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FollowForkMode {
+    Parent,
+    Child,
+}
+
+impl FromStr for FollowForkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "parent" => Ok(FollowForkMode::Parent),
+            "child" => Ok(FollowForkMode::Child),
+            _ => Err(format!("argument for --follow-fork-mode must be parent or child, but found `{}`", s)),
+        }
+    }
 }
 
 /// This function calls ptrace with PTRACE_TRACEME to enable debugging on a process. You should use
 /// pre_exec with Command to call this in the child process.
 fn child_traceme() -> Result<(), std::io::Error> {
-    ptrace::traceme().or(Err(std::io::Error::new(
-        std::io::ErrorKind::Other,
-        "ptrace TRACEME failed",
-    )))
+    ptrace::traceme().or(Err(std::io::Error::other("ptrace TRACEME failed")))
+}
+
+/// Returns the name and value of every register in `regs`, in the order `info registers` shows them.
+pub fn register_values(regs: &libc::user_regs_struct) -> Vec<(&'static str, u64)> {
+    vec![
+        ("rax", regs.rax),
+        ("rbx", regs.rbx),
+        ("rcx", regs.rcx),
+        ("rdx", regs.rdx),
+        ("rsi", regs.rsi),
+        ("rdi", regs.rdi),
+        ("rbp", regs.rbp),
+        ("rsp", regs.rsp),
+        ("r8", regs.r8),
+        ("r9", regs.r9),
+        ("r10", regs.r10),
+        ("r11", regs.r11),
+        ("r12", regs.r12),
+        ("r13", regs.r13),
+        ("r14", regs.r14),
+        ("r15", regs.r15),
+        ("rip", regs.rip),
+        ("eflags", regs.eflags),
+        ("cs", regs.cs),
+        ("ss", regs.ss),
+        ("ds", regs.ds),
+        ("es", regs.es),
+        ("fs", regs.fs),
+        ("gs", regs.gs),
+        ("fs_base", regs.fs_base),
+        ("gs_base", regs.gs_base),
+    ]
+}
+
+/// Returns the value of the register called `name`, using the names from register_values.
+pub fn register_value(regs: &libc::user_regs_struct, name: &str) -> Option<u64> {
+    register_values(regs)
+        .into_iter()
+        .find(|(register, _)| *register == name)
+        .map(|(_, value)| value)
+}
+
+/// Returns the field of `regs` for the register called `name`, using the names from register_values.
+fn register_mut<'a>(regs: &'a mut libc::user_regs_struct, name: &str) -> Option<&'a mut u64> {
+    Some(match name {
+        "rax" => &mut regs.rax,
+        "rbx" => &mut regs.rbx,
+        "rcx" => &mut regs.rcx,
+        "rdx" => &mut regs.rdx,
+        "rsi" => &mut regs.rsi,
+        "rdi" => &mut regs.rdi,
+        "rbp" => &mut regs.rbp,
+        "rsp" => &mut regs.rsp,
+        "r8" => &mut regs.r8,
+        "r9" => &mut regs.r9,
+        "r10" => &mut regs.r10,
+        "r11" => &mut regs.r11,
+        "r12" => &mut regs.r12,
+        "r13" => &mut regs.r13,
+        "r14" => &mut regs.r14,
+        "r15" => &mut regs.r15,
+        "rip" => &mut regs.rip,
+        "eflags" => &mut regs.eflags,
+        "cs" => &mut regs.cs,
+        "ss" => &mut regs.ss,
+        "ds" => &mut regs.ds,
+        "es" => &mut regs.es,
+        "fs" => &mut regs.fs,
+        "gs" => &mut regs.gs,
+        "fs_base" => &mut regs.fs_base,
+        "gs_base" => &mut regs.gs_base,
+        _ => return None,
+    })
+}
+
+/// Returns whether `name` is one of the registers listed by register_values.
+pub fn is_register(name: &str) -> bool {
+    // Every field of user_regs_struct is an integer, so all zeroes is a valid value
+    let regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+    register_value(&regs, name).is_some()
+}
+
+/// Returns the breakpoint at `addr` if it is installed, i.e. the inferior has a 0xcc there.
+pub fn installed_breakpoint(breakpoints: &HashMap<usize, Option<Breakpoint>>, addr: usize) -> Option<&Breakpoint> {
+    match breakpoints.get(&addr) {
+        Some(Some(breakpoint)) if breakpoint.enabled => Some(breakpoint),
+        _ => None,
+    }
+}
+
+/// Writes a byte into the memory of a traced process, returning the byte it replaced.
+fn write_process_byte(pid: Pid, addr: usize, val: u8) -> Result<u8, nix::Error> {
+    let aligned_addr = align_addr_to_word(addr);
+    let byte_offset = addr - aligned_addr;
+    let word = ptrace::read(pid, aligned_addr as ptrace::AddressType)? as u64;
+    let orig_byte = (word >> (8 * byte_offset)) & 0xff;
+    let masked_word = word & !(0xff << (8 * byte_offset));
+    let updated_word = masked_word | ((val as u64) << (8 * byte_offset));
+    unsafe {
+        ptrace::write(
+            pid,
+            aligned_addr as ptrace::AddressType,
+            updated_word as *mut std::ffi::c_void,
+        )?;
+    }
+    Ok(orig_byte as u8)
 }
 
 fn align_addr_to_word(addr: usize) -> usize {
     addr & (-(size_of::<usize>() as isize) as usize)
 }
 
+/// The registers that locate a stack frame: where its function is executing, and the extent of the
+/// frame on the stack
+#[derive(Clone, Copy)]
+pub struct Frame {
+    pub rip: usize,
+    pub rsp: usize,
+    pub rbp: usize,
+}
+
 pub struct Inferior {
-    child: Child,
+    pid: Pid,
+    /// Whether we attached to an existing process rather than starting it
+    pub attached: bool,
+    /// Signal to deliver the next time the process is resumed
+    pending_signal: Option<signal::Signal>,
+    /// TIDs of the process's threads as of the last refresh_threads, main thread (whose TID is the
+    /// pid) first
+    threads: Vec<Pid>,
+    /// Threads other than the main one that we have attached to. They stay stopped until the
+    /// process is resumed.
+    attached_threads: Vec<Pid>,
+    /// Thread that register reads and writes, backtraces and single-steps apply to
+    selected_tid: Pid,
+    follow_fork_mode: FollowForkMode,
+    /// Whether to report forks as a stop (`catch fork`) rather than carrying on after following one
+    stop_at_forks: bool,
 }
 
 impl Inferior {
@@ -52,99 +207,552 @@ impl Inferior {
         }
         match command.spawn() {
             Ok(child) => {
-                let mut inferior = Inferior{child};
-                for (addr, breakpoint) in breakpoints {
-                    match inferior.write_byte(*addr, 0xcc) {
-                        Ok(orig_byte) => {
-                            *breakpoint = Some(Breakpoint{addr: *addr, orig_byte});
-                        }
-                        Err(err) => {
-                            println!("{}", err);
-                        }
-                    }
+                let mut inferior = Inferior::with_pid(Pid::from_raw(child.id() as i32), false);
+                // The child stops with SIGTRAP once it execs the target; wait for that before
+                // touching its memory
+                match inferior.wait(None) {
+                    Ok(Status::Stopped(signal::Signal::SIGTRAP, _)) => {}
+                    _ => return None,
                 }
+                inferior.set_trace_options().ok()?;
+                inferior.install_breakpoints(breakpoints);
                 Some(inferior)
             }
             Err(_) => None,
         }
     }
 
+    /// Attaches to a running process. Returns Some(Inferior) once the process has stopped, or None
+    /// if it can't be traced.
+    pub fn from_pid(pid: u32, breakpoints: &mut HashMap<usize, Option<Breakpoint>>) -> Option<Inferior> {
+        let pid = Pid::from_raw(pid as i32);
+        if let Err(err) = ptrace::attach(pid) {
+            println!("Could not attach to process {}: {}", pid, err);
+            return None;
+        }
+        let mut inferior = Inferior::with_pid(pid, true);
+        // Attaching sends the process a SIGSTOP; wait until it has stopped
+        match inferior.wait(None) {
+            Ok(Status::Stopped(signal::Signal::SIGSTOP, _)) => {}
+            _ => return None,
+        }
+        inferior.set_trace_options().ok()?;
+        inferior.install_breakpoints(breakpoints);
+        Some(inferior)
+    }
+
+    fn with_pid(pid: Pid, attached: bool) -> Inferior {
+        Inferior {
+            pid,
+            attached,
+            pending_signal: None,
+            threads: vec![pid],
+            attached_threads: Vec::new(),
+            selected_tid: pid,
+            follow_fork_mode: FollowForkMode::Parent,
+            stop_at_forks: false,
+        }
+    }
+
+    /// Sets which side of a fork to follow, and whether forks stop the inferior.
+    pub fn set_fork_handling(&mut self, follow_fork_mode: FollowForkMode, stop_at_forks: bool) {
+        self.follow_fork_mode = follow_fork_mode;
+        self.stop_at_forks = stop_at_forks;
+    }
+
+    /// Writes 0xcc at each enabled breakpoint, recording the bytes it replaces.
+    fn install_breakpoints(&mut self, breakpoints: &mut HashMap<usize, Option<Breakpoint>>) {
+        for (addr, breakpoint) in breakpoints {
+            if let Some(Breakpoint { enabled: false, .. }) = breakpoint {
+                continue;
+            }
+            match self.write_byte(*addr, 0xcc) {
+                Ok(orig_byte) => match breakpoint {
+                    Some(breakpoint) => breakpoint.orig_byte = orig_byte,
+                    None => {
+                        *breakpoint = Some(Breakpoint::new(*addr, orig_byte));
+                    }
+                },
+                Err(err) => {
+                    println!("{}", err);
+                }
+            }
+        }
+    }
+
+    /// Makes system call stops distinguishable from SIGTRAPs, so that `catch syscall` can tell
+    /// them apart from breakpoints, and has the kernel trace new processes from the moment they are
+    /// forked, so that we can follow them.
+    fn set_trace_options(&self) -> Result<(), nix::Error> {
+        ptrace::setoptions(
+            self.pid(),
+            ptrace::Options::PTRACE_O_TRACESYSGOOD | ptrace::Options::PTRACE_O_TRACEFORK,
+        )
+    }
+
     pub fn continue_exec(&mut self, breakpoints: &HashMap<usize, Option<Breakpoint>>) -> Result<Status, nix::Error> {
-        let mut regs = ptrace::getregs(self.pid())?;
-        let instruction_ptr: usize = regs.rip as usize;
-        if let Some(breakpoint) = breakpoints.get(&(instruction_ptr - 1)) {
-            let breakpoint = breakpoint.as_ref().unwrap();
+        self.resume(breakpoints, false)
+    }
+
+    /// Like continue_exec, but also stops on the next entry to or exit from a system call.
+    pub fn continue_to_syscall(&mut self, breakpoints: &HashMap<usize, Option<Breakpoint>>) -> Result<Status, nix::Error> {
+        self.resume(breakpoints, true)
+    }
+
+    fn resume(&mut self, breakpoints: &HashMap<usize, Option<Breakpoint>>, stop_at_syscalls: bool) -> Result<Status, nix::Error> {
+        // Only the main thread is traced while the process runs
+        self.release_threads();
+        // If we are stopped on a breakpoint, execute the original instruction before resuming so
+        // that we don't immediately trap on the same 0xcc again
+        let regs = ptrace::getregs(self.tid())?;
+        if installed_breakpoint(breakpoints, regs.rip as usize).is_some() {
+            if let status @ (Status::Exited(_) | Status::Signaled(_)) = self.step_instruction(breakpoints)? {
+                return Ok(status);
+            }
+        }
+        let restart = |pid, signal| {
+            if stop_at_syscalls {
+                ptrace::syscall(pid, signal)
+            } else {
+                ptrace::cont(pid, signal)
+            }
+        };
+        restart(self.pid(), self.pending_signal.take())?;
+        let status = self.wait_following_forks(breakpoints, None, |pid| restart(pid, None))?;
+        self.rewind_breakpoint(status, breakpoints)
+    }
+
+    /// Delivers a signal to the process when it is next resumed, as if it had been sent with kill.
+    pub fn set_pending_signal(&mut self, signal: signal::Signal) {
+        self.pending_signal = Some(signal);
+    }
+
+    /// Steps over the current source line, running any called functions to completion. Stops
+    /// once the line changes or the current function returns.
+    pub fn next(&mut self, debug_data: &DwarfData, breakpoints: &HashMap<usize, Option<Breakpoint>>) -> Result<Status, nix::Error> {
+        let rip = ptrace::getregs(self.tid())?.rip as usize;
+        let (start_line, (func_start, func_end)) = match (
+            debug_data.get_line_from_addr(rip),
+            debug_data.get_function_range(rip),
+        ) {
+            (Some(line), Some(range)) => (line, range),
+            // Without debugging information, the best we can do is a single instruction
+            _ => return self.step_instruction(breakpoints),
+        };
+        loop {
+            let prev_regs = ptrace::getregs(self.tid())?;
+            let mut rip = match self.step_instruction(breakpoints)? {
+                Status::Stopped(signal::Signal::SIGTRAP, rip) => rip,
+                status => return Ok(status),
+            };
+            if rip < func_start || rip >= func_end {
+                // A call pushes a return address pointing just past the call instruction
+                let regs = ptrace::getregs(self.tid())?;
+                let return_addr = ptrace::read(self.pid(), regs.rsp as ptrace::AddressType)? as usize;
+                let prev_rip = prev_regs.rip as usize;
+                if regs.rsp == prev_regs.rsp - 8 && return_addr > prev_rip && return_addr <= prev_rip + 15 {
+                    match self.finish_call(return_addr, regs.rsp as usize + 8, breakpoints)? {
+                        Status::Stopped(signal::Signal::SIGTRAP, addr) if addr == return_addr => rip = addr,
+                        status => return Ok(status),
+                    }
+                } else {
+                    // The current function returned
+                    return Ok(Status::Stopped(signal::Signal::SIGTRAP, rip));
+                }
+            }
+            if let Some(line) = debug_data.get_line_from_addr(rip) {
+                if line.number != start_line.number || line.file != start_line.file {
+                    return Ok(Status::Stopped(signal::Signal::SIGTRAP, rip));
+                }
+            }
+        }
+    }
+
+    /// Executes a single instruction. If a breakpoint is installed at the current instruction,
+    /// the original byte is restored while stepping and reinstalled afterwards.
+    pub fn step_instruction(&mut self, breakpoints: &HashMap<usize, Option<Breakpoint>>) -> Result<Status, nix::Error> {
+        let rip = ptrace::getregs(self.tid())?.rip as usize;
+        if let Some(breakpoint) = installed_breakpoint(breakpoints, rip) {
+            // A pending signal is left for the next resume: its handler would otherwise return
+            // onto the breakpoint and trap on it a second time
             self.write_byte(breakpoint.addr, breakpoint.orig_byte)?;
-            regs.rip = breakpoint.addr as u64;
-            ptrace::setregs(self.pid(), regs)?;
-            ptrace::step(self.pid(), None)?;
-            match self.wait(None)? {
-                Status::Exited(exit_code) => return Ok(Status::Exited(exit_code)),
-                Status::Signaled(signal) => return Ok(Status::Signaled(signal)),
-                Status::Stopped(_, _) => {
-                    self.write_byte(breakpoint.addr, 0xcc)?;
+            ptrace::step(self.tid(), None)?;
+            let status = self.wait_following_forks(breakpoints, None, |tid| ptrace::step(tid, None))?;
+            if let Status::Stopped(..) | Status::Forked(..) = status {
+                self.write_byte(breakpoint.addr, 0xcc)?;
+            }
+            Ok(status)
+        } else {
+            ptrace::step(self.tid(), self.pending_signal.take())?;
+            self.wait_following_forks(breakpoints, None, |tid| ptrace::step(tid, None))
+        }
+    }
+
+    /// Runs until a called function returns to `return_addr` in the frame whose stack pointer is
+    /// `frame_rsp`. Recursive calls returning to the same address in deeper frames are skipped.
+    fn finish_call(
+        &mut self,
+        return_addr: usize,
+        frame_rsp: usize,
+        breakpoints: &HashMap<usize, Option<Breakpoint>>,
+    ) -> Result<Status, nix::Error> {
+        loop {
+            let orig_byte = self.write_byte(return_addr, 0xcc)?;
+            ptrace::cont(self.tid(), None)?;
+            let temporary = Some((return_addr, orig_byte));
+            let status = self.wait_following_forks(breakpoints, temporary, |tid| ptrace::cont(tid, None))?;
+            if let Status::Stopped(..) | Status::Forked(..) = status {
+                self.write_byte(return_addr, orig_byte)?;
+            }
+            match status {
+                Status::Stopped(signal::Signal::SIGTRAP, rip) if rip == return_addr + 1 => {
+                    let mut regs = ptrace::getregs(self.tid())?;
+                    regs.rip = return_addr as u64;
+                    ptrace::setregs(self.tid(), regs)?;
+                    if regs.rsp as usize == frame_rsp {
+                        return Ok(Status::Stopped(signal::Signal::SIGTRAP, return_addr));
+                    }
+                    if let status @ (Status::Exited(_) | Status::Signaled(_)) = self.step_instruction(breakpoints)? {
+                        return Ok(status);
+                    }
                 }
+                status => return self.rewind_breakpoint(status, breakpoints),
             }
         }
-        ptrace::cont(self.pid(), None)?;
-        self.wait(None)
+    }
+
+    /// After the inferior traps on a breakpoint, rip points one byte past the 0xcc. Moves rip back
+    /// to the breakpoint address so that the original instruction is executed on resume.
+    fn rewind_breakpoint(&mut self, status: Status, breakpoints: &HashMap<usize, Option<Breakpoint>>) -> Result<Status, nix::Error> {
+        if let Status::Stopped(signal::Signal::SIGTRAP, rip) = status {
+            if let Some(breakpoint) = installed_breakpoint(breakpoints, rip - 1) {
+                let mut regs = ptrace::getregs(self.tid())?;
+                regs.rip = breakpoint.addr as u64;
+                ptrace::setregs(self.tid(), regs)?;
+                return Ok(Status::Stopped(signal::Signal::SIGTRAP, breakpoint.addr));
+            }
+        }
+        Ok(status)
     }
 
     pub fn kill(&mut self) {
+        self.release_threads();
         println!("Killing running inferior (pid {})", self.pid());
-        self.child.kill().unwrap();
+        signal::kill(self.pid(), signal::Signal::SIGKILL).unwrap();
         self.wait(None).unwrap();
     }
 
-    pub fn print_backtrace(&self, debug_data: &DwarfData) -> Result<(), nix::Error> {
-        let regs = ptrace::getregs(self.pid())?;
-        let mut instruction_ptr: usize = regs.rip as usize;
-        let mut base_ptr: usize = regs.rbp as usize;
+    /// Restores the original bytes at our breakpoints and stops tracing the process, letting it
+    /// run normally.
+    pub fn detach(&mut self, breakpoints: &HashMap<usize, Option<Breakpoint>>) -> Result<(), nix::Error> {
+        for addr in breakpoints.keys() {
+            if let Some(breakpoint) = installed_breakpoint(breakpoints, *addr) {
+                self.write_byte(*addr, breakpoint.orig_byte)?;
+            }
+        }
+        self.release_threads();
+        ptrace::detach(self.pid(), None)
+    }
+
+    /// Returns the stack frames from the innermost one outwards, by following the chain of saved
+    /// frame pointers up to main (or wherever the chain ends).
+    pub fn frames(&self, debug_data: &DwarfData) -> Result<Vec<Frame>, nix::Error> {
+        let regs = ptrace::getregs(self.tid())?;
+        let mut frame = Frame { rip: regs.rip as usize, rsp: regs.rsp as usize, rbp: regs.rbp as usize };
+        let mut frames = Vec::new();
         loop {
-            let line = debug_data.get_line_from_addr(instruction_ptr).unwrap();
-            let function = debug_data.get_function_from_addr(instruction_ptr).unwrap();
-            println!("{} ({})", function, line);
-            if function == "main" {
-                break;
+            // Code without debug info, like libc after attaching to a process that's blocked in a
+            // system call, may not use rbp as a frame pointer. Following rbp then skips straight to
+            // the nearest caller that does.
+            let is_main = debug_data.get_line_from_addr(frame.rip).is_some()
+                && debug_data.get_function_from_addr(frame.rip).as_deref() == Some("main");
+            let rbp = frame.rbp;
+            frames.push(frame);
+            if is_main || rbp == 0 {
+                return Ok(frames);
+            }
+            // The caller's frame ends where this one's starts, just above the return address. At
+            // the first instruction of a function (such as a breakpoint in a shared library), rbp
+            // is still the caller's, so the chain can lead off the stack; stop there.
+            let (rip, caller_rbp) = match (
+                ptrace::read(self.pid(), (rbp + 8) as ptrace::AddressType),
+                ptrace::read(self.pid(), rbp as ptrace::AddressType),
+            ) {
+                (Ok(rip), Ok(caller_rbp)) => (rip as usize, caller_rbp as usize),
+                _ => return Ok(frames),
+            };
+            frame = Frame { rip, rsp: rbp + 16, rbp: caller_rbp };
+        }
+    }
+
+    /// Prints the call stack. If `full` is set, also prints each frame's local variables.
+    pub fn print_backtrace(&self, debug_data: &DwarfData, full: bool) -> Result<(), nix::Error> {
+        for frame in self.frames(debug_data)? {
+            let line = debug_data.get_line_from_addr(frame.rip);
+            let function = debug_data.get_function_from_addr(frame.rip);
+            match (function, line) {
+                (Some(function), Some(line)) => {
+                    println!("{} ({})", function, line);
+                    if full {
+                        self.print_locals(debug_data, frame.rip, frame.rsp, frame.rbp, "    ");
+                    }
+                }
+                _ => println!("{:#x} in ??", frame.rip),
             }
-            instruction_ptr = ptrace::read(self.pid(), (base_ptr + 8) as ptrace::AddressType)? as usize;
-            base_ptr = ptrace::read(self.pid(), base_ptr as ptrace::AddressType)? as usize;
         }
         Ok(())
     }
 
+    /// Returns the name and formatted value of each local variable and parameter of the function
+    /// containing `rip`, given the stack and frame pointers of its frame.
+    pub fn locals(&self, debug_data: &DwarfData, rip: usize, rsp: usize, rbp: usize) -> Vec<(String, String)> {
+        debug_data
+            .get_locals_for_frame(rip, rbp)
+            .into_iter()
+            .map(|(name, addr, entity_type)| {
+                // The frame runs from the canonical frame address down to the stack pointer, plus
+                // the red zone below it that leaf functions keep their locals in
+                let value = if addr >= rbp + 16 || addr < rsp.saturating_sub(128) {
+                    "<optimized out>".to_string()
+                } else {
+                    match self.read_variable(&entity_type, addr) {
+                        Ok(value) => value,
+                        Err(_) => format!("<error: cannot access memory at address {:#x}>", addr),
+                    }
+                };
+                (name, value)
+            })
+            .collect()
+    }
+
+    /// Prints the local variables and parameters of the function containing `rip`, given the stack
+    /// and frame pointers of its frame. Each line starts with `indent`.
+    pub fn print_locals(&self, debug_data: &DwarfData, rip: usize, rsp: usize, rbp: usize, indent: &str) {
+        let locals = self.locals(debug_data, rip, rsp, rbp);
+        if locals.is_empty() {
+            println!("{}No locals.", indent);
+            return;
+        }
+        let width = locals.iter().map(|(name, _)| name.len()).max().unwrap();
+        for (name, value) in locals {
+            println!("{}{:<width$} = {}", indent, name, value, width = width);
+        }
+    }
+
     pub fn write_byte(&mut self, addr: usize, val: u8) -> Result<u8, nix::Error> {
-        let aligned_addr = align_addr_to_word(addr);
-        let byte_offset = addr - aligned_addr;
-        let word = ptrace::read(self.pid(), aligned_addr as ptrace::AddressType)? as u64;
-        let orig_byte = (word >> 8 * byte_offset) & 0xff;
-        let masked_word = word & !(0xff << 8 * byte_offset);
-        let updated_word = masked_word | ((val as u64) << 8 * byte_offset);
-        unsafe {
-            ptrace::write(
-                self.pid(),
-                aligned_addr as ptrace::AddressType,
-                updated_word as *mut std::ffi::c_void,
-            )?;
+        write_process_byte(self.pid(), addr, val)
+    }
+
+    /// Reads `len` bytes of the inferior's memory starting at `addr`. Breakpoint instructions are
+    /// replaced with the original bytes, so the caller sees the program as it was compiled.
+    pub fn read_memory(
+        &self,
+        addr: usize,
+        len: usize,
+        breakpoints: &HashMap<usize, Option<Breakpoint>>,
+    ) -> Result<Vec<u8>, nix::Error> {
+        let mut bytes = Vec::with_capacity(len + size_of::<usize>());
+        let mut word_addr = align_addr_to_word(addr);
+        while word_addr < addr + len {
+            let word = ptrace::read(self.pid(), word_addr as ptrace::AddressType)? as u64;
+            bytes.extend_from_slice(&word.to_le_bytes());
+            word_addr += size_of::<usize>();
+        }
+        let start = addr - align_addr_to_word(addr);
+        let mut bytes = bytes[start..start + len].to_vec();
+        for (offset, byte) in bytes.iter_mut().enumerate() {
+            if let Some(breakpoint) = installed_breakpoint(breakpoints, addr + offset) {
+                *byte = breakpoint.orig_byte;
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Returns the address of the instruction the inferior is stopped at.
+    pub fn get_rip(&self) -> Result<usize, nix::Error> {
+        Ok(self.get_registers()?.rip as usize)
+    }
+
+    /// Reads a `size`-byte little-endian value (at most 8 bytes) from the inferior's memory.
+    pub fn read_value(&self, addr: usize, size: usize) -> Result<u64, nix::Error> {
+        let word = ptrace::read(self.pid(), addr as ptrace::AddressType)? as u64;
+        Ok(if size >= 8 { word } else { word & ((1 << (size * 8)) - 1) })
+    }
+
+    /// Reads a variable of the given type and formats its value.
+    pub fn read_variable(&self, entity_type: &Type, addr: usize) -> Result<String, nix::Error> {
+        Ok(entity_type.format_value(self.read_value(addr, entity_type.value_size())?))
+    }
+
+    pub fn get_registers(&self) -> Result<libc::user_regs_struct, nix::Error> {
+        ptrace::getregs(self.tid())
+    }
+
+    /// Sets the register called `name` (one of the names from register_values) to `value`.
+    /// Returns false if there is no such register.
+    pub fn set_register(&mut self, name: &str, value: u64) -> Result<bool, nix::Error> {
+        let mut regs = self.get_registers()?;
+        match register_mut(&mut regs, name) {
+            Some(register) => *register = value,
+            None => return Ok(false),
         }
-        Ok(orig_byte as u8)
+        ptrace::setregs(self.tid(), regs)?;
+        Ok(true)
     }
 
     /// Returns the pid of this inferior.
     pub fn pid(&self) -> Pid {
-        nix::unistd::Pid::from_raw(self.child.id() as i32)
+        self.pid
+    }
+
+    /// Returns the TID of the selected thread.
+    pub fn tid(&self) -> Pid {
+        self.selected_tid
+    }
+
+    /// Returns the threads found by the last refresh_threads, main thread first.
+    pub fn threads(&self) -> &[Pid] {
+        &self.threads
+    }
+
+    /// Looks up the threads of the process in /proc/PID/task, attaching to any we aren't tracing
+    /// yet so that their registers can be read. They stay stopped until the process is resumed.
+    pub fn refresh_threads(&mut self) -> Result<(), nix::Error> {
+        let task_dir = std::fs::read_dir(format!("/proc/{}/task", self.pid)).map_err(|_| nix::Error::ESRCH)?;
+        let mut tids: Vec<Pid> = task_dir
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .map(Pid::from_raw)
+            .collect();
+        tids.sort_by_key(|tid| (*tid != self.pid, tid.as_raw()));
+        let mut threads = Vec::new();
+        for tid in tids {
+            if tid != self.pid && !self.attached_threads.contains(&tid) {
+                match ptrace::attach(tid) {
+                    Ok(()) => {}
+                    // The thread exited after we listed it
+                    Err(nix::Error::ESRCH) => continue,
+                    Err(err) => return Err(err),
+                }
+                // Threads other than the main one only show up in waitpid with __WALL
+                waitpid(tid, Some(WaitPidFlag::__WALL))?;
+                self.attached_threads.push(tid);
+            }
+            threads.push(tid);
+        }
+        self.threads = threads;
+        if !self.threads.contains(&self.selected_tid) {
+            self.selected_tid = self.pid;
+        }
+        Ok(())
+    }
+
+    /// Selects the thread that register reads and single-steps apply to. It must be one of the
+    /// threads returned by threads().
+    pub fn select_thread(&mut self, tid: Pid) {
+        self.selected_tid = tid;
+    }
+
+    /// Returns the address of the instruction a thread is stopped at.
+    pub fn thread_rip(&self, tid: Pid) -> Result<usize, nix::Error> {
+        Ok(ptrace::getregs(tid)?.rip as usize)
+    }
+
+    /// Lets the threads refresh_threads attached to run again, untraced, and selects the main
+    /// thread.
+    fn release_threads(&mut self) {
+        for tid in self.attached_threads.drain(..) {
+            let _ = ptrace::detach(tid, None);
+        }
+        self.threads = vec![self.pid];
+        self.selected_tid = self.pid;
+    }
+
+    /// Waits like wait, following any forks along the way. Unless forks are being caught, the
+    /// inferior is restarted after each one with `restart`, which is given the TID to restart.
+    /// `temporary` is a breakpoint the caller has written that isn't in `breakpoints`, with the
+    /// byte it replaced.
+    fn wait_following_forks(
+        &mut self,
+        breakpoints: &HashMap<usize, Option<Breakpoint>>,
+        temporary: Option<(usize, u8)>,
+        restart: impl Fn(Pid) -> Result<(), nix::Error>,
+    ) -> Result<Status, nix::Error> {
+        loop {
+            match self.wait(None)? {
+                Status::Forked(child, rip) => {
+                    self.follow_fork(child, breakpoints, temporary)?;
+                    if self.stop_at_forks {
+                        return Ok(Status::Forked(child, rip));
+                    }
+                    restart(self.tid())?;
+                }
+                status => return Ok(status),
+            }
+        }
     }
 
-    /// Calls waitpid on this inferior and returns a Status to indicate the state of the process
-    /// after the waitpid call.
+    /// Takes control of whichever side of a fork we are following, and detaches from the other
+    /// one. The new process starts out with a copy of our breakpoints, which are taken out of the
+    /// side we let go so that it doesn't trap on them untraced.
+    fn follow_fork(
+        &mut self,
+        child: Pid,
+        breakpoints: &HashMap<usize, Option<Breakpoint>>,
+        temporary: Option<(usize, u8)>,
+    ) -> Result<(), nix::Error> {
+        println!("[New process {}]", child);
+        // The kernel attaches us to the new process, which starts out stopped with SIGSTOP
+        waitpid(child, Some(WaitPidFlag::__WALL))?;
+        let released = match self.follow_fork_mode {
+            FollowForkMode::Parent => {
+                println!("[Detaching after fork from child process {}]", child);
+                child
+            }
+            FollowForkMode::Child => {
+                let parent = self.pid;
+                self.release_threads();
+                println!("[Attaching after process {} fork to child process {}]", parent, child);
+                self.pid = child;
+                self.threads = vec![child];
+                self.selected_tid = child;
+                parent
+            }
+        };
+        for addr in breakpoints.keys() {
+            if let Some(breakpoint) = installed_breakpoint(breakpoints, *addr) {
+                write_process_byte(released, *addr, breakpoint.orig_byte)?;
+            }
+        }
+        if let Some((addr, orig_byte)) = temporary {
+            write_process_byte(released, addr, orig_byte)?;
+        }
+        ptrace::detach(released, None)
+    }
+
+    /// Calls waitpid on the selected thread of this inferior and returns a Status to indicate the
+    /// state of the process after the waitpid call.
     pub fn wait(&self, options: Option<WaitPidFlag>) -> Result<Status, nix::Error> {
-        Ok(match waitpid(self.pid(), options)? {
+        let options = if self.tid() == self.pid() {
+            options
+        } else {
+            Some(options.unwrap_or(WaitPidFlag::empty()) | WaitPidFlag::__WALL)
+        };
+        Ok(match waitpid(self.tid(), options)? {
             WaitStatus::Exited(_pid, exit_code) => Status::Exited(exit_code),
             WaitStatus::Signaled(_pid, signal, _core_dumped) => Status::Signaled(signal),
             WaitStatus::Stopped(_pid, signal) => {
-                let regs = ptrace::getregs(self.pid())?;
+                let regs = ptrace::getregs(self.tid())?;
                 Status::Stopped(signal, regs.rip as usize)
             }
+            WaitStatus::PtraceSyscall(_pid) => {
+                let regs = ptrace::getregs(self.tid())?;
+                Status::SyscallStop(regs.rip as usize)
+            }
+            WaitStatus::PtraceEvent(_pid, _signal, event) if event == ptrace::Event::PTRACE_EVENT_FORK as i32 => {
+                let child = Pid::from_raw(ptrace::getevent(self.tid())? as i32);
+                let regs = ptrace::getregs(self.tid())?;
+                Status::Forked(child, regs.rip as usize)
+            }
             other => panic!("waitpid returned unexpected status: {:?}", other),
         })
     }
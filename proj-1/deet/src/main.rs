@@ -1,24 +1,113 @@
+mod condition;
+mod dap;
 mod debugger;
 mod debugger_command;
 mod dwarf_data;
 mod gimli_wrapper;
+mod highlight;
 mod inferior;
+mod maps;
+mod remote;
+mod syscalls;
+mod tui;
 
 use crate::debugger::Debugger;
+use crate::highlight::ColorMode;
+use crate::inferior::FollowForkMode;
+use crate::tui::Tui;
 use nix::sys::signal::{signal, SigHandler, Signal};
 use std::env;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} <target program>", args[0]);
+    let usage = || {
+        println!(
+            "Usage: {} [--remote <addr:port> | --tui | --dap <port>] [--color auto|always|never] [--follow-fork-mode parent|child] <target program>",
+            args[0]
+        );
         std::process::exit(1);
+    };
+    let mut remote_addr = None;
+    let mut use_tui = false;
+    let mut dap_port = None;
+    let mut color = ColorMode::Auto;
+    let mut follow_fork_mode = FollowForkMode::Parent;
+    let mut target = None;
+    let mut arg_iter = args.iter().skip(1);
+    while let Some(arg) = arg_iter.next() {
+        let color_arg = match arg.strip_prefix("--color=") {
+            Some(value) => Some(value),
+            None if arg == "--color" => Some(arg_iter.next().unwrap_or_else(usage).as_str()),
+            None => None,
+        };
+        if let Some(value) = color_arg {
+            color = value.parse().unwrap_or_else(|err| {
+                println!("{}", err);
+                std::process::exit(1);
+            });
+        } else if arg == "--follow-fork-mode" {
+            follow_fork_mode = arg_iter.next().unwrap_or_else(usage).parse().unwrap_or_else(|err| {
+                println!("{}", err);
+                std::process::exit(1);
+            });
+        } else if arg == "--tui" {
+            use_tui = true;
+        } else if arg == "--dap" {
+            let port = arg_iter.next().unwrap_or_else(usage);
+            dap_port = Some(port.parse::<u16>().unwrap_or_else(|err| {
+                println!("Invalid port {}: {}", port, err);
+                std::process::exit(1);
+            }));
+        } else if arg == "--remote" {
+            remote_addr = Some(arg_iter.next().unwrap_or_else(usage));
+        } else if target.is_none() {
+            target = Some(arg);
+        } else {
+            usage();
+        }
+    }
+    let target = target.unwrap_or_else(usage);
+    // A stub's output goes to the remote debugger, so there is no screen to draw the interface on,
+    // and a DAP client replaces both
+    if [remote_addr.is_some(), use_tui, dap_port.is_some()].iter().filter(|&&mode| mode).count() > 1 {
+        usage();
     }
-    let target = &args[1];
 
     // Disable handling of ctrl+c in this process (so that ctrl+c only gets delivered to child
     // processes)
     unsafe { signal(Signal::SIGINT, SigHandler::SigIgn) }.expect("Error disabling SIGINT handling");
 
-    Debugger::new(target).run();
+    if let Some(port) = dap_port {
+        let debug_data = debugger::load_debug_data(target);
+        if let Err(err) = dap::serve(port, target, debug_data) {
+            println!("DAP server error: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut debugger = Debugger::new(target);
+    debugger.set_follow_fork_mode(follow_fork_mode);
+    if let Some(addr) = remote_addr {
+        match remote::serve(addr) {
+            Ok(conn) => debugger.serve_remote(conn),
+            Err(err) => {
+                println!("Could not accept a remote debugger on {}: {}", addr, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    if use_tui {
+        match Tui::start() {
+            Ok(tui) => debugger.use_tui(tui),
+            Err(err) => {
+                println!("Could not start the full-screen interface: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    // Decided after any remote debugger connects or the full-screen interface starts, since output
+    // then goes to them rather than straight to our terminal
+    debugger.set_color(color.enabled());
+    debugger.run();
 }
@@ -0,0 +1,164 @@
+//! The inferior's memory map, read from /proc/PID/maps, for `info proc mappings` and for finding
+//! functions in the shared libraries it has loaded.
+
+use object::{Object, ObjectSymbol, SymbolKind};
+use std::collections::HashSet;
+
+const EXECUTABLE_COLOR: &str = "\x1b[1;31m";
+const WRITABLE_COLOR: &str = "\x1b[33m";
+const FILE_COLOR: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
+
+/// One line of /proc/PID/maps
+pub struct MemoryRegion {
+    pub start: usize,
+    pub end: usize,
+    /// Permission letters, e.g. "r-xp" (the last is p for private or s for shared)
+    pub perms: String,
+    /// Offset into the mapped file
+    pub offset: usize,
+    /// Device of the mapped file, as major:minor
+    pub dev: String,
+    pub inode: u64,
+    /// Mapped file, or a pseudo-path like [heap] or [stack]. Empty for anonymous mappings
+    pub path: String,
+}
+
+impl MemoryRegion {
+    /// Parses a line like
+    /// `55d0c2a00000-55d0c2a21000 r-xp 00001000 08:01 1048601    /usr/bin/prog`.
+    fn parse(line: &str) -> Option<MemoryRegion> {
+        // Fields are separated by single spaces, then the path is padded into a column. Paths can
+        // contain spaces, so the path is the whole rest of the line.
+        let mut fields = line.splitn(6, ' ');
+        let (start, end) = fields.next()?.split_once('-')?;
+        let perms = fields.next()?.to_string();
+        let offset = fields.next()?;
+        let dev = fields.next()?.to_string();
+        let inode = fields.next()?.parse().ok()?;
+        let path = fields.next().unwrap_or("").trim().to_string();
+        Some(MemoryRegion {
+            start: usize::from_str_radix(start, 16).ok()?,
+            end: usize::from_str_radix(end, 16).ok()?,
+            perms,
+            offset: usize::from_str_radix(offset, 16).ok()?,
+            dev,
+            inode,
+            path,
+        })
+    }
+
+    fn is_executable(&self) -> bool {
+        self.perms.contains('x')
+    }
+
+    fn is_writable(&self) -> bool {
+        self.perms.contains('w')
+    }
+
+    /// Returns whether the region maps a file, as opposed to anonymous memory or a pseudo-path
+    fn is_file_backed(&self) -> bool {
+        self.inode != 0
+    }
+
+    /// Returns a short description of what the region probably holds.
+    fn kind(&self) -> &'static str {
+        if self.is_executable() {
+            "code"
+        } else if self.path == "[stack]" {
+            "stack"
+        } else if self.path == "[heap]" {
+            "heap"
+        } else if self.is_writable() {
+            "data"
+        } else {
+            "read-only"
+        }
+    }
+}
+
+/// Reads the memory map of the process with the given pid.
+pub fn read_mappings(pid: nix::unistd::Pid) -> std::io::Result<Vec<MemoryRegion>> {
+    let maps = std::fs::read_to_string(format!("/proc/{}/maps", pid))?;
+    Ok(maps.lines().filter_map(MemoryRegion::parse).collect())
+}
+
+/// Looks for a function defined by one of the shared libraries in `regions`, returning its address
+/// in the inferior. Libraries don't have to be built with debugging information, since only their
+/// symbol tables are read.
+pub fn find_library_function(regions: &[MemoryRegion], name: &str) -> Option<usize> {
+    let mut searched = HashSet::new();
+    for region in regions {
+        // The mapping at offset 0 is where the library was loaded, and symbol addresses are
+        // relative to it
+        if !region.is_file_backed() || region.offset != 0 || !region.path.contains(".so") {
+            continue;
+        }
+        if !searched.insert(&region.path) {
+            continue;
+        }
+        let data = match std::fs::read(&region.path) {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        let file = match object::File::parse(&*data) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        let found = file.dynamic_symbols().chain(file.symbols()).find(|symbol| {
+            symbol.kind() == SymbolKind::Text && symbol.is_definition() && symbol.name() == Ok(name)
+        });
+        if let Some(symbol) = found {
+            return Some(region.start + symbol.address() as usize);
+        }
+    }
+    None
+}
+
+/// Prints the regions as a table. If `color` is set, executable regions, writable regions and file
+/// names are coloured.
+pub fn print_mappings(regions: &[MemoryRegion], color: bool) {
+    println!(
+        "{:<18} {:<18} {:>10} {:<5} {:>10} {:<6} {:<9} Path",
+        "Start", "End", "Size", "Perms", "Offset", "Dev", "Kind"
+    );
+    for region in regions {
+        let row = format!(
+            "0x{:016x} 0x{:016x} {:>#10x} {:<5} {:>#10x} {:<6} {:<9}",
+            region.start,
+            region.end,
+            region.end - region.start,
+            region.perms,
+            region.offset,
+            region.dev,
+            region.kind()
+        );
+        let path = if region.is_file_backed() {
+            // Lines get long, so name the file and leave its directory for the end
+            match region.path.rsplit_once('/') {
+                Some((dir, name)) => format!("{} ({}/)", name, dir),
+                None => region.path.clone(),
+            }
+        } else {
+            region.path.clone()
+        };
+        // Anonymous regions have no path, so trim the padding after their kind
+        if !color {
+            println!("{}", format!("{} {}", row, path).trim_end());
+            continue;
+        }
+        let row = if region.is_executable() {
+            format!("{}{}{}", EXECUTABLE_COLOR, row, RESET)
+        } else if region.is_writable() {
+            format!("{}{}{}", WRITABLE_COLOR, row, RESET)
+        } else {
+            row
+        };
+        let path = if region.is_file_backed() {
+            format!("{}{}{}", FILE_COLOR, path, RESET)
+        } else {
+            path
+        };
+        println!("{}", format!("{} {}", row, path).trim_end());
+    }
+}
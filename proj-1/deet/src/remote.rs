@@ -0,0 +1,76 @@
+//! Remote debugging. `deet --remote ADDR TARGET` runs as a stub: it waits for a debugger to connect
+//! to ADDR, then reads commands from the connection one line at a time and sends back everything it
+//! (and the program being debugged) prints. `target remote ADDR` in another deet connects to a stub
+//! and passes commands typed at its prompt through to it.
+//!
+//! After the output of each command, the stub sends a NUL byte to say that it is ready for the next
+//! one, so the client knows when to show its prompt.
+
+use nix::unistd::dup2;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc;
+use std::thread;
+
+/// Sent by the stub once it has finished handling a command
+pub const READY: u8 = 0;
+
+/// Waits for a debugger to connect to `addr`, then sends our standard output and error to it (which
+/// the programs we run inherit). Returns the connection to read commands from.
+pub fn serve(addr: &str) -> io::Result<BufReader<TcpStream>> {
+    let listener = TcpListener::bind(addr)?;
+    println!("Listening for a remote debugger on {}", addr);
+    let (conn, peer_addr) = listener.accept()?;
+    println!("Remote debugger connected from {}", peer_addr);
+    io::stdout().flush()?;
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO].iter() {
+        dup2(conn.as_raw_fd(), *fd)?;
+    }
+    Ok(BufReader::new(conn))
+}
+
+/// Connects to a stub at `addr` and passes commands from `read_line` to it, printing its output,
+/// until the stub quits or the connection is lost. `read_line` returns None when the user wants to
+/// quit.
+pub fn connect(addr: &str, mut read_line: impl FnMut() -> Option<String>) -> io::Result<()> {
+    let mut conn = TcpStream::connect(addr)?;
+    println!("Remote debugging using {}", addr);
+
+    // Print the stub's output as it arrives, and tell the input loop whenever the stub is ready for
+    // another command. The sender is dropped when the connection closes.
+    let mut reader = conn.try_clone()?;
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let output = thread::spawn(move || {
+        let mut buffer = [0_u8; 4096];
+        let mut stdout = io::stdout();
+        while let Ok(len @ 1..) = reader.read(&mut buffer) {
+            for chunk in buffer[..len].split_inclusive(|&byte| byte == READY) {
+                match chunk.split_last() {
+                    Some((&READY, text)) => {
+                        let _ = stdout.write_all(text);
+                        let _ = stdout.flush();
+                        if ready_tx.send(()).is_err() {
+                            return;
+                        }
+                    }
+                    _ => {
+                        let _ = stdout.write_all(chunk);
+                        let _ = stdout.flush();
+                    }
+                }
+            }
+        }
+    });
+
+    while ready_rx.recv().is_ok() {
+        // Like at the local prompt, ctrl+d quits
+        let line = read_line().unwrap_or_else(|| "quit".to_string());
+        if writeln!(conn, "{}", line).is_err() {
+            break;
+        }
+    }
+    let _ = output.join();
+    println!("Remote connection closed.");
+    Ok(())
+}
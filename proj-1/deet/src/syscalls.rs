@@ -0,0 +1,137 @@
+//! Names and numbers of Linux system calls on x86-64, for `catch syscall`. This isn't every system
+//! call, just the ones programs commonly make.
+
+use std::collections::HashMap;
+
+/// Returns a map from system call names to their numbers.
+pub fn syscall_numbers() -> HashMap<&'static str, u64> {
+    [
+        ("read", libc::SYS_read),
+        ("write", libc::SYS_write),
+        ("open", libc::SYS_open),
+        ("close", libc::SYS_close),
+        ("stat", libc::SYS_stat),
+        ("fstat", libc::SYS_fstat),
+        ("lstat", libc::SYS_lstat),
+        ("poll", libc::SYS_poll),
+        ("lseek", libc::SYS_lseek),
+        ("mmap", libc::SYS_mmap),
+        ("mprotect", libc::SYS_mprotect),
+        ("munmap", libc::SYS_munmap),
+        ("brk", libc::SYS_brk),
+        ("rt_sigaction", libc::SYS_rt_sigaction),
+        ("rt_sigprocmask", libc::SYS_rt_sigprocmask),
+        ("rt_sigreturn", libc::SYS_rt_sigreturn),
+        ("ioctl", libc::SYS_ioctl),
+        ("pread64", libc::SYS_pread64),
+        ("pwrite64", libc::SYS_pwrite64),
+        ("readv", libc::SYS_readv),
+        ("writev", libc::SYS_writev),
+        ("access", libc::SYS_access),
+        ("pipe", libc::SYS_pipe),
+        ("select", libc::SYS_select),
+        ("sched_yield", libc::SYS_sched_yield),
+        ("mremap", libc::SYS_mremap),
+        ("msync", libc::SYS_msync),
+        ("mincore", libc::SYS_mincore),
+        ("madvise", libc::SYS_madvise),
+        ("dup", libc::SYS_dup),
+        ("dup2", libc::SYS_dup2),
+        ("pause", libc::SYS_pause),
+        ("nanosleep", libc::SYS_nanosleep),
+        ("alarm", libc::SYS_alarm),
+        ("getpid", libc::SYS_getpid),
+        ("sendfile", libc::SYS_sendfile),
+        ("socket", libc::SYS_socket),
+        ("connect", libc::SYS_connect),
+        ("accept", libc::SYS_accept),
+        ("sendto", libc::SYS_sendto),
+        ("recvfrom", libc::SYS_recvfrom),
+        ("sendmsg", libc::SYS_sendmsg),
+        ("recvmsg", libc::SYS_recvmsg),
+        ("shutdown", libc::SYS_shutdown),
+        ("bind", libc::SYS_bind),
+        ("listen", libc::SYS_listen),
+        ("getsockname", libc::SYS_getsockname),
+        ("getpeername", libc::SYS_getpeername),
+        ("socketpair", libc::SYS_socketpair),
+        ("setsockopt", libc::SYS_setsockopt),
+        ("getsockopt", libc::SYS_getsockopt),
+        ("clone", libc::SYS_clone),
+        ("fork", libc::SYS_fork),
+        ("vfork", libc::SYS_vfork),
+        ("execve", libc::SYS_execve),
+        ("exit", libc::SYS_exit),
+        ("wait4", libc::SYS_wait4),
+        ("kill", libc::SYS_kill),
+        ("uname", libc::SYS_uname),
+        ("fcntl", libc::SYS_fcntl),
+        ("flock", libc::SYS_flock),
+        ("fsync", libc::SYS_fsync),
+        ("truncate", libc::SYS_truncate),
+        ("ftruncate", libc::SYS_ftruncate),
+        ("getdents", libc::SYS_getdents),
+        ("getcwd", libc::SYS_getcwd),
+        ("chdir", libc::SYS_chdir),
+        ("fchdir", libc::SYS_fchdir),
+        ("rename", libc::SYS_rename),
+        ("mkdir", libc::SYS_mkdir),
+        ("rmdir", libc::SYS_rmdir),
+        ("link", libc::SYS_link),
+        ("unlink", libc::SYS_unlink),
+        ("symlink", libc::SYS_symlink),
+        ("readlink", libc::SYS_readlink),
+        ("chmod", libc::SYS_chmod),
+        ("fchmod", libc::SYS_fchmod),
+        ("chown", libc::SYS_chown),
+        ("umask", libc::SYS_umask),
+        ("gettimeofday", libc::SYS_gettimeofday),
+        ("getrlimit", libc::SYS_getrlimit),
+        ("getuid", libc::SYS_getuid),
+        ("getgid", libc::SYS_getgid),
+        ("geteuid", libc::SYS_geteuid),
+        ("getegid", libc::SYS_getegid),
+        ("setpgid", libc::SYS_setpgid),
+        ("getppid", libc::SYS_getppid),
+        ("setsid", libc::SYS_setsid),
+        ("prctl", libc::SYS_prctl),
+        ("arch_prctl", libc::SYS_arch_prctl),
+        ("gettid", libc::SYS_gettid),
+        ("tkill", libc::SYS_tkill),
+        ("futex", libc::SYS_futex),
+        ("getdents64", libc::SYS_getdents64),
+        ("set_tid_address", libc::SYS_set_tid_address),
+        ("clock_gettime", libc::SYS_clock_gettime),
+        ("clock_nanosleep", libc::SYS_clock_nanosleep),
+        ("exit_group", libc::SYS_exit_group),
+        ("epoll_wait", libc::SYS_epoll_wait),
+        ("epoll_ctl", libc::SYS_epoll_ctl),
+        ("tgkill", libc::SYS_tgkill),
+        ("waitid", libc::SYS_waitid),
+        ("openat", libc::SYS_openat),
+        ("mkdirat", libc::SYS_mkdirat),
+        ("unlinkat", libc::SYS_unlinkat),
+        ("renameat", libc::SYS_renameat),
+        ("readlinkat", libc::SYS_readlinkat),
+        ("newfstatat", libc::SYS_newfstatat),
+        ("pselect6", libc::SYS_pselect6),
+        ("ppoll", libc::SYS_ppoll),
+        ("set_robust_list", libc::SYS_set_robust_list),
+        ("epoll_pwait", libc::SYS_epoll_pwait),
+        ("accept4", libc::SYS_accept4),
+        ("eventfd2", libc::SYS_eventfd2),
+        ("epoll_create1", libc::SYS_epoll_create1),
+        ("dup3", libc::SYS_dup3),
+        ("pipe2", libc::SYS_pipe2),
+        ("prlimit64", libc::SYS_prlimit64),
+        ("getrandom", libc::SYS_getrandom),
+        ("memfd_create", libc::SYS_memfd_create),
+        ("execveat", libc::SYS_execveat),
+        ("statx", libc::SYS_statx),
+        ("rseq", libc::SYS_rseq),
+        ("clone3", libc::SYS_clone3),
+    ]
+    .iter()
+    .map(|&(name, number)| (name, number as u64))
+    .collect()
+}
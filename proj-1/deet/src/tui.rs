@@ -0,0 +1,361 @@
+//! Full-screen interface for `deet --tui`. The screen is split into three panels: the source around
+//! the line the program is stopped at, the registers, and the commands along with their output.
+//! Commands work exactly as they do at the normal prompt; only the way their output is shown
+//! changes.
+//!
+//! To make that possible, our standard output and error (which the programs we run inherit) are
+//! replaced with a pipe, and everything that comes through it is added to the command panel.
+
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{cursor, execute};
+use nix::fcntl::OFlag;
+use nix::unistd::{close, dup, dup2, pipe2};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::Terminal;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Most lines of output the command panel remembers
+const MAX_OUTPUT_LINES: usize = 10000;
+/// Width of the registers panel, including its borders
+const REGISTERS_WIDTH: u16 = 28;
+
+/// Source file shown in the source panel
+pub struct SourceView {
+    pub file: String,
+    pub lines: Vec<String>,
+    /// Line the program is stopped at, counting from 1
+    pub current: usize,
+}
+
+pub struct Tui {
+    screen: Arc<Mutex<Screen>>,
+    /// Copies of our original standard output and error, put back when the interface closes
+    saved_fds: [RawFd; 2],
+}
+
+/// How far we are through an ANSI escape sequence. The command panel can't show them, so they are
+/// dropped from the output.
+#[derive(Clone, Copy)]
+enum Escape {
+    None,
+    Started,
+    ControlSequence,
+}
+
+/// Command being typed at the prompt
+struct Input {
+    prompt: String,
+    text: Vec<char>,
+    cursor: usize,
+}
+
+/// Everything that is drawn on the screen. It is shared between the thread reading commands and
+/// the thread collecting output, which redraws as output arrives (even while the program runs).
+struct Screen {
+    terminal: Terminal<CrosstermBackend<File>>,
+    source: Option<SourceView>,
+    registers: Vec<(&'static str, u64)>,
+    output: Vec<String>,
+    /// Output after the last newline
+    partial: Vec<u8>,
+    escape: Escape,
+    /// Set while we are waiting for a command
+    input: Option<Input>,
+    /// Number of lines the command panel is scrolled back by
+    scroll: usize,
+    /// Number of lines that fit in the command panel, as of the last time it was drawn
+    output_height: usize,
+    /// Set once the terminal has been given back, after which nothing more is drawn
+    closed: bool,
+}
+
+impl Tui {
+    /// Takes over the terminal, and starts collecting our output into the command panel.
+    pub fn start() -> io::Result<Tui> {
+        io::stdout().flush()?;
+        io::stderr().flush()?;
+        let saved_fds = [dup(libc::STDOUT_FILENO)?, dup(libc::STDERR_FILENO)?];
+        let mut tty = unsafe { File::from_raw_fd(dup(saved_fds[0])?) };
+        execute!(tty, EnterAlternateScreen)?;
+        let terminal = Terminal::new(CrosstermBackend::new(tty))?;
+
+        let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC)?;
+        for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO].iter() {
+            dup2(write_fd, *fd)?;
+        }
+        close(write_fd)?;
+
+        let screen = Arc::new(Mutex::new(Screen {
+            terminal,
+            source: None,
+            registers: Vec::new(),
+            output: Vec::new(),
+            partial: Vec::new(),
+            escape: Escape::None,
+            input: None,
+            scroll: 0,
+            output_height: 0,
+            closed: false,
+        }));
+        let output_screen = screen.clone();
+        let mut output = unsafe { File::from_raw_fd(read_fd) };
+        thread::spawn(move || {
+            use std::io::Read;
+            let mut buffer = [0_u8; 4096];
+            while let Ok(len @ 1..) = output.read(&mut buffer) {
+                let mut screen = output_screen.lock().unwrap();
+                screen.add_output(&buffer[..len]);
+                screen.draw();
+            }
+        });
+        screen.lock().unwrap().draw();
+        Ok(Tui { screen, saved_fds })
+    }
+
+    /// Replaces the contents of the source and registers panels.
+    pub fn set_panels(&mut self, source: Option<SourceView>, registers: Vec<(&'static str, u64)>) {
+        let mut screen = self.screen.lock().unwrap();
+        screen.source = source;
+        screen.registers = registers;
+        screen.draw();
+    }
+
+    /// Shows `prompt` at the bottom of the command panel and reads a line typed after it. Up and
+    /// down go through `history`, and page up and page down scroll the panel. Returns None if the
+    /// user presses ctrl+d on an empty line.
+    pub fn read_line(&mut self, prompt: &str, history: &[String]) -> io::Result<Option<String>> {
+        // The terminal is only in raw mode while we read a command, so that ctrl+c still interrupts
+        // the program while it runs. Anything typed in the meantime was echoed over the panels, so
+        // redraw them from scratch.
+        terminal::enable_raw_mode()?;
+        {
+            let mut screen = self.screen.lock().unwrap();
+            screen.input = Some(Input {
+                prompt: prompt.to_string(),
+                text: Vec::new(),
+                cursor: 0,
+            });
+            screen.scroll = 0;
+            screen.terminal.clear()?;
+            screen.draw();
+        }
+        let line = self.read_keys(history);
+        self.screen.lock().unwrap().input = None;
+        terminal::disable_raw_mode()?;
+        if let Ok(Some(line)) = &line {
+            // Goes through our output like everything else, so that it stays in order
+            println!("{}{}", prompt, line);
+        }
+        line
+    }
+
+    fn read_keys(&mut self, history: &[String]) -> io::Result<Option<String>> {
+        let mut history_pos = history.len();
+        loop {
+            let key = match event::read()? {
+                Event::Key(key) if key.kind != KeyEventKind::Release => key,
+                Event::Resize(..) => {
+                    self.screen.lock().unwrap().draw();
+                    continue;
+                }
+                _ => continue,
+            };
+            let mut screen = self.screen.lock().unwrap();
+            let page = (screen.output_height / 2).max(1);
+            let input = screen.input.as_mut().unwrap();
+            let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+            match key.code {
+                KeyCode::Enter => return Ok(Some(input.text.iter().collect())),
+                KeyCode::Char('d') if ctrl && input.text.is_empty() => return Ok(None),
+                KeyCode::Char('c') if ctrl => screen.add_output(b"Type \"quit\" to exit\n"),
+                KeyCode::Char('a') if ctrl => input.cursor = 0,
+                KeyCode::Char('e') if ctrl => input.cursor = input.text.len(),
+                KeyCode::Char(_) if ctrl => continue,
+                KeyCode::Char(c) => {
+                    input.text.insert(input.cursor, c);
+                    input.cursor += 1;
+                }
+                KeyCode::Backspace if input.cursor > 0 => {
+                    input.cursor -= 1;
+                    input.text.remove(input.cursor);
+                }
+                KeyCode::Delete if input.cursor < input.text.len() => {
+                    input.text.remove(input.cursor);
+                }
+                KeyCode::Left => input.cursor = input.cursor.saturating_sub(1),
+                KeyCode::Right => input.cursor = (input.cursor + 1).min(input.text.len()),
+                KeyCode::Home => input.cursor = 0,
+                KeyCode::End => input.cursor = input.text.len(),
+                KeyCode::Up | KeyCode::Down => {
+                    history_pos = match key.code {
+                        KeyCode::Up => history_pos.saturating_sub(1),
+                        _ => (history_pos + 1).min(history.len()),
+                    };
+                    input.text = match history.get(history_pos) {
+                        Some(line) => line.chars().collect(),
+                        None => Vec::new(),
+                    };
+                    input.cursor = input.text.len();
+                }
+                KeyCode::PageUp => screen.scroll += page,
+                KeyCode::PageDown => screen.scroll = screen.scroll.saturating_sub(page),
+                _ => continue,
+            }
+            screen.draw();
+        }
+    }
+}
+
+impl Drop for Tui {
+    /// Gives the terminal back, and sends our output to it again.
+    fn drop(&mut self) {
+        let _ = io::stdout().flush();
+        let mut screen = self.screen.lock().unwrap_or_else(|err| err.into_inner());
+        screen.closed = true;
+        let _ = terminal::disable_raw_mode();
+        let _ = execute!(screen.terminal.backend_mut(), LeaveAlternateScreen, cursor::Show);
+        for (saved_fd, fd) in self.saved_fds.iter().zip([libc::STDOUT_FILENO, libc::STDERR_FILENO].iter()) {
+            let _ = dup2(*saved_fd, *fd);
+            let _ = close(*saved_fd);
+        }
+    }
+}
+
+impl Screen {
+    /// Adds output to the command panel, leaving out escape sequences and control characters.
+    fn add_output(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.escape = match (self.escape, byte) {
+                (Escape::None, 0x1b) => Escape::Started,
+                (Escape::None, b'\n') => {
+                    let line = String::from_utf8_lossy(&self.partial).into_owned();
+                    self.output.push(line);
+                    self.partial.clear();
+                    Escape::None
+                }
+                (Escape::None, b'\t') => {
+                    let spaces = 8 - self.partial.len() % 8;
+                    self.partial.extend(std::iter::repeat_n(b' ', spaces));
+                    Escape::None
+                }
+                (Escape::None, byte) => {
+                    if byte >= 0x20 && byte != 0x7f {
+                        self.partial.push(byte);
+                    }
+                    Escape::None
+                }
+                (Escape::Started, b'[') => Escape::ControlSequence,
+                (Escape::ControlSequence, 0x40..=0x7e) | (Escape::Started, _) => Escape::None,
+                (Escape::ControlSequence, _) => Escape::ControlSequence,
+            };
+        }
+        if self.output.len() > MAX_OUTPUT_LINES {
+            self.output.drain(..self.output.len() - MAX_OUTPUT_LINES);
+        }
+    }
+
+    fn draw(&mut self) {
+        if self.closed {
+            return;
+        }
+        // Borrowed separately, since the closure passed to draw can't borrow all of self
+        let Screen {
+            terminal,
+            source,
+            registers,
+            output,
+            partial,
+            input,
+            scroll,
+            output_height,
+            ..
+        } = self;
+        let _ = terminal.draw(|frame| {
+            let [top, bottom] = Layout::vertical([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(frame.area());
+            let [source_area, registers_area] =
+                Layout::horizontal([Constraint::Min(0), Constraint::Length(REGISTERS_WIDTH)]).areas(top);
+
+            // Source, centred on the current line
+            let (title, source_lines) = match source {
+                Some(source) => {
+                    let height = source_area.height.saturating_sub(2) as usize;
+                    let current = source.current.saturating_sub(1);
+                    let first = current
+                        .saturating_sub(height / 2)
+                        .min(source.lines.len().saturating_sub(height));
+                    let lines = source
+                        .lines
+                        .iter()
+                        .enumerate()
+                        .skip(first)
+                        .take(height)
+                        .map(|(index, text)| {
+                            let text = text.replace('\t', "    ");
+                            if index == current {
+                                Line::styled(
+                                    format!("=> {:<4} {}", index + 1, text),
+                                    Style::new().fg(Color::Yellow).add_modifier(Modifier::BOLD),
+                                )
+                            } else {
+                                Line::raw(format!("   {:<4} {}", index + 1, text))
+                            }
+                        })
+                        .collect();
+                    (format!(" {} ", source.file), lines)
+                }
+                None => (" Source ".to_string(), vec![Line::raw("No source to show.")]),
+            };
+            frame.render_widget(Paragraph::new(source_lines).block(Block::bordered().title(title)), source_area);
+
+            let register_lines: Vec<Line> = if registers.is_empty() {
+                vec![Line::raw("Not running.")]
+            } else {
+                registers
+                    .iter()
+                    .map(|(name, value)| Line::raw(format!("{:<7} {:#018x}", name, value)))
+                    .collect()
+            };
+            frame.render_widget(
+                Paragraph::new(register_lines).block(Block::bordered().title(" Registers ")),
+                registers_area,
+            );
+
+            // Output, with the prompt (if any) on the last line
+            let block = Block::bordered().title(" Commands ");
+            let inner = block.inner(bottom);
+            let height = inner.height as usize;
+            *output_height = height;
+            let mut lines: Vec<Cow<str>> = output[output.len().saturating_sub(height + *scroll)..]
+                .iter()
+                .map(|line| Cow::from(line.as_str()))
+                .collect();
+            if !partial.is_empty() {
+                lines.push(String::from_utf8_lossy(partial));
+            }
+            if let Some(input) = input {
+                lines.push(format!("{}{}", input.prompt, input.text.iter().collect::<String>()).into());
+            }
+            *scroll = (*scroll).min(lines.len().saturating_sub(height));
+            let end = lines.len() - *scroll;
+            let start = end.saturating_sub(height);
+            let visible: Vec<Line> = lines[start..end].iter().map(|line| Line::raw(line.clone())).collect();
+            let visible_len = visible.len() as u16;
+            frame.render_widget(Paragraph::new(visible).block(block), bottom);
+            if let (Some(input), 0, 1..) = (input, *scroll, visible_len) {
+                let x = inner.x + (input.prompt.chars().count() + input.cursor) as u16;
+                frame.set_cursor_position((x.min(inner.right().saturating_sub(1)), inner.y + visible_len - 1));
+            }
+        });
+    }
+}
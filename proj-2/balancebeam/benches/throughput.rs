@@ -0,0 +1,75 @@
+//! Measures how many requests per second balancebeam can proxy when many clients hammer it at
+//! once, with rate limiting enabled so that every request goes through the rate limiter as well as
+//! upstream selection. Run with `cargo bench`.
+//!
+//! Each client connects from its own loopback address, so the rate limiter sees many different
+//! IPs, like it would in production.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const NUM_UPSTREAMS: usize = 4;
+const NUM_CLIENTS: u8 = 64;
+const DURATION: Duration = Duration::from_secs(10);
+
+#[tokio::main]
+async fn main() {
+    init_logging();
+    // Per-request logging in balancebeam would otherwise dominate the measurement
+    std::env::set_var("RUST_LOG", "warn");
+
+    let mut upstreams = Vec::new();
+    for _ in 0..NUM_UPSTREAMS {
+        upstreams.push(EchoServer::new().await);
+    }
+    let upstream_addresses: Vec<&str> = upstreams.iter().map(|upstream| upstream.address.as_str()).collect();
+    let balancebeam = BalanceBeam::new(&upstream_addresses, None, Some(usize::MAX)).await;
+
+    let completed = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    let mut clients = Vec::new();
+    for client_num in 1..=NUM_CLIENTS {
+        let url = format!("http://{}/", balancebeam.address);
+        let completed = completed.clone();
+        clients.push(tokio::spawn(async move {
+            let client = reqwest::Client::builder()
+                .local_address(IpAddr::V4(Ipv4Addr::new(127, 0, 1, client_num)))
+                .build()
+                .unwrap();
+            while start.elapsed() < DURATION {
+                let response = client
+                    .get(&url)
+                    .header("x-sent-by", "balancebeam-bench")
+                    .send()
+                    .await
+                    .expect("Error sending request to balancebeam");
+                assert_eq!(response.status().as_u16(), 200);
+                response.bytes().await.expect("Error reading response body");
+                completed.fetch_add(1, Ordering::Relaxed);
+            }
+        }));
+    }
+    for client in clients {
+        client.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    let completed = completed.load(Ordering::Relaxed);
+    println!(
+        "{} clients, {} upstreams: {} requests in {:.1}s ({:.0} requests/s)",
+        NUM_CLIENTS,
+        NUM_UPSTREAMS,
+        completed,
+        elapsed.as_secs_f64(),
+        completed as f64 / elapsed.as_secs_f64()
+    );
+    for upstream in upstreams {
+        Box::new(upstream).stop().await;
+    }
+}
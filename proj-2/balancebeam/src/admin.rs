@@ -0,0 +1,176 @@
+//! An HTTP endpoint for inspecting and adjusting the proxy while it runs, served on the address
+//! given with --admin-bind. It is meant for operators, so it should not be reachable by clients.
+//!
+//! - `GET /canary` shows each group's canary percentage, along with request and error counts for
+//!   its stable upstreams and its canaries.
+//! - `PUT /canary/GROUP` with a percentage (0-100) as the body changes the percentage of the
+//!   group's requests that go to its canaries.
+//! - `GET /stats` shows request, response and latency counts and the requests in flight for each
+//!   upstream server, followed by the number of requests queued in each group.
+//! - `GET /upstreams` shows whether each upstream server is alive, and whether it is active,
+//!   draining or disabled.
+//! - `PUT /upstreams/ADDRESS` with `active`, `draining` or `disabled` as the body changes that for
+//!   the upstream at ADDRESS, in every group it belongs to. Draining upstreams get no new requests
+//!   but are still health checked; disabled ones aren't health checked either.
+
+use crate::{config, request, response, ProxyState};
+use http::StatusCode;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Accepts admin connections until the process exits.
+pub async fn serve(listener: TcpListener, state: Arc<ProxyState>) {
+    loop {
+        if let Ok((stream, _)) = listener.accept().await {
+            let state = state.clone();
+            tokio::spawn(async move {
+                handle_connection(stream, &state).await;
+            });
+        }
+    }
+}
+
+async fn handle_connection(mut conn: TcpStream, state: &ProxyState) {
+    loop {
+        let response = match request::read_from_stream(&mut conn, &state.request_limits).await {
+            Ok(request) => handle_request(&request, state),
+            Err(request::Error::IncompleteRequest(0)) | Err(request::Error::ConnectionError(_)) => return,
+            Err(error) => {
+                log::debug!("Error parsing admin request: {:?}", error);
+                response::make_http_error(StatusCode::BAD_REQUEST)
+            }
+        };
+        if let Err(error) = response::write_to_stream(&response, &mut conn).await {
+            log::warn!("Failed to send admin response: {}", error);
+            return;
+        }
+    }
+}
+
+fn handle_request(request: &http::Request<Vec<u8>>, state: &ProxyState) -> http::Response<Vec<u8>> {
+    let canary_path = request
+        .uri()
+        .path()
+        .strip_prefix("/canary")
+        .filter(|rest| rest.is_empty() || rest.starts_with('/'));
+    if request.uri().path() == "/stats" {
+        return match *request.method() {
+            http::Method::GET => response::make_text_response(StatusCode::OK, state.stats_table()),
+            _ => response::make_http_error(StatusCode::METHOD_NOT_ALLOWED),
+        };
+    }
+    if let Some(upstreams_path) = request.uri().path().strip_prefix("/upstreams") {
+        return handle_upstreams_request(request, upstreams_path, state);
+    }
+    let config = state.config();
+    match (request.method(), canary_path) {
+        (&http::Method::GET, Some("")) => {
+            let mut body = String::new();
+            for group in config.upstream_groups.iter().filter(|group| group.has_canaries()) {
+                body.push_str(&group.canary_summary());
+                body.push('\n');
+            }
+            response::make_text_response(StatusCode::OK, body)
+        }
+        (&http::Method::PUT, Some(group_path)) if group_path.starts_with('/') => {
+            let name = &group_path[1..];
+            let group = match config.group(name) {
+                Some(group) if group.has_canaries() => group,
+                Some(_) => {
+                    return response::make_text_response(
+                        StatusCode::CONFLICT,
+                        format!("Group {} has no canaries\n", name),
+                    )
+                }
+                None => return response::make_http_error(StatusCode::NOT_FOUND),
+            };
+            let percentage = std::str::from_utf8(request.body())
+                .ok()
+                .and_then(|body| body.trim().parse::<u8>().ok());
+            let percentage = match percentage {
+                Some(percentage) if percentage <= 100 => percentage,
+                _ => {
+                    return response::make_text_response(
+                        StatusCode::BAD_REQUEST,
+                        "Expected a percentage from 0 to 100\n".to_string(),
+                    )
+                }
+            };
+            let previous = group.canary_percentage.swap(percentage, Ordering::SeqCst);
+            log::info!(
+                "Canary percentage for group {} changed from {}% to {}%",
+                group.name,
+                previous,
+                percentage
+            );
+            response::make_text_response(StatusCode::OK, format!("{}\n", group.canary_summary()))
+        }
+        (_, Some(_)) => response::make_http_error(StatusCode::METHOD_NOT_ALLOWED),
+        _ => response::make_http_error(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Handles requests under /upstreams, given the rest of the path after that
+fn handle_upstreams_request(
+    request: &http::Request<Vec<u8>>,
+    upstreams_path: &str,
+    state: &ProxyState,
+) -> http::Response<Vec<u8>> {
+    match (request.method(), upstreams_path) {
+        (&http::Method::GET, "") => {
+            response::make_text_response(StatusCode::OK, upstream_summary(state, None))
+        }
+        (&http::Method::PUT, address) if address.len() > 1 && address.starts_with('/') => {
+            let address = &address[1..];
+            let admin_state = match std::str::from_utf8(request.body()) {
+                Ok(body) => body.trim().parse::<config::AdminState>(),
+                Err(_) => Err("expected active, draining or disabled".to_string()),
+            };
+            let admin_state = match admin_state {
+                Ok(admin_state) => admin_state,
+                Err(err) => {
+                    return response::make_text_response(StatusCode::BAD_REQUEST, format!("{}\n", err))
+                }
+            };
+            let mut found = false;
+            for group in &state.config().upstream_groups {
+                for (upstream_idx, upstream_address) in group.upstream_addresses.iter().enumerate() {
+                    if upstream_address == address {
+                        group.set_admin_state(upstream_idx, admin_state);
+                        found = true;
+                    }
+                }
+            }
+            if !found {
+                return response::make_http_error(StatusCode::NOT_FOUND);
+            }
+            response::make_text_response(StatusCode::OK, upstream_summary(state, Some(address)))
+        }
+        _ if upstreams_path.is_empty() || upstreams_path.starts_with('/') => {
+            response::make_http_error(StatusCode::METHOD_NOT_ALLOWED)
+        }
+        _ => response::make_http_error(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Lists the health and administrative state of every upstream server, or of just the one at
+/// `address`, one line per upstream in each group
+fn upstream_summary(state: &ProxyState, address: Option<&str>) -> String {
+    let mut summary = String::new();
+    for group in &state.config().upstream_groups {
+        for (upstream_idx, upstream_address) in group.upstream_addresses.iter().enumerate() {
+            if address.is_some_and(|address| address != upstream_address) {
+                continue;
+            }
+            summary.push_str(&format!(
+                "{} {} {} {}\n",
+                group.name,
+                upstream_address,
+                if group.is_alive(upstream_idx) { "alive" } else { "dead" },
+                group.admin_state(upstream_idx)
+            ));
+        }
+    }
+    summary
+}
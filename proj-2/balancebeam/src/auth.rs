@@ -0,0 +1,76 @@
+//! HTTP Basic authentication, enforced when --basic-auth-file is given. The file is in htpasswd
+//! style: one `username:bcrypt-hash` per line. Blank lines and lines starting with `#` are ignored.
+
+use base64::Engine;
+use std::collections::HashMap;
+
+/// Value of the WWW-Authenticate header sent with 401 responses
+pub const CHALLENGE: &str = "Basic realm=\"balancebeam\"";
+
+pub struct BasicAuth {
+    /// Password hash for each user
+    users: HashMap<String, String>,
+    /// Hash that passwords of unknown users are checked against, so that a wrong username takes as
+    /// long to reject as a wrong password
+    dummy_hash: String,
+}
+
+impl BasicAuth {
+    pub fn load(path: &str) -> Result<BasicAuth, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format!("Could not read basic auth file {}: {}", path, err))?;
+        let mut users = HashMap::new();
+        let mut cost = bcrypt::DEFAULT_COST;
+        for (line_num, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (username, hash) = line
+                .split_once(':')
+                .ok_or_else(|| format!("{}:{}: expected username:hash", path, line_num + 1))?;
+            let parts: bcrypt::HashParts = hash
+                .parse()
+                .map_err(|err| format!("{}:{}: invalid bcrypt hash: {}", path, line_num + 1, err))?;
+            cost = parts.get_cost();
+            users.insert(username.to_string(), hash.to_string());
+        }
+        if users.is_empty() {
+            return Err(format!("Basic auth file {} has no users", path));
+        }
+        let dummy_hash = bcrypt::hash("", cost).map_err(|err| err.to_string())?;
+        Ok(BasicAuth { users, dummy_hash })
+    }
+
+    /// Returns whether the value of an Authorization header holds valid credentials. Checking a
+    /// password takes a while by design, so this should not be called while holding a lock or on
+    /// an async worker thread.
+    pub fn check(&self, authorization: &[u8]) -> bool {
+        let (username, password) = match parse_credentials(authorization) {
+            Some(credentials) => credentials,
+            None => return false,
+        };
+        // bcrypt::verify compares the hashes in constant time
+        match self.users.get(&username) {
+            Some(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            None => {
+                let _ = bcrypt::verify(password, &self.dummy_hash);
+                false
+            }
+        }
+    }
+}
+
+/// Decodes the username and password from the value of an Authorization header using the Basic
+/// scheme.
+fn parse_credentials(authorization: &[u8]) -> Option<(String, String)> {
+    let authorization = std::str::from_utf8(authorization).ok()?;
+    let (scheme, encoded) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
@@ -0,0 +1,160 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+/// A cached upstream response. http::Response isn't Clone, so we keep its parts and rebuild a
+/// response for every hit.
+struct CacheEntry {
+    status: http::StatusCode,
+    version: http::Version,
+    headers: http::HeaderMap,
+    body: Vec<u8>,
+    /// When the entry stops being fresh
+    expires: Instant,
+    /// Position of this entry in ResponseCache::lru
+    last_used: u64,
+    /// Approximate memory used by this entry, counted against the cache's byte budget
+    size: usize,
+}
+
+/// In-memory cache of responses to GET requests, evicting the least recently used entries when it
+/// grows past its byte budget.
+pub struct ResponseCache {
+    /// Total size of all entries that the cache may hold
+    max_bytes: usize,
+    /// Responses bigger than this are never cached
+    max_entry_bytes: usize,
+    /// Total size of the entries currently in the cache
+    used_bytes: usize,
+    entries: HashMap<String, CacheEntry>,
+    /// Keys of all entries ordered by when they were last used, oldest first
+    lru: BTreeMap<u64, String>,
+    /// Counter used to order entries in the LRU list
+    next_use: u64,
+}
+
+/// Returns the key that a request's response would be cached under, or None if the response to
+/// this request must not be cached. Only GET requests without credentials are cacheable.
+pub fn cache_key(request: &http::Request<Vec<u8>>, host: Option<&str>) -> Option<String> {
+    if request.method() != http::Method::GET || request.headers().contains_key("authorization") {
+        return None;
+    }
+    let path_and_query = request.uri().path_and_query()?.as_str();
+    Some(format!("{}{}", host.unwrap_or(""), path_and_query))
+}
+
+/// Returns how long a response may be cached for based on its Cache-Control header, or None if it
+/// may not be stored in a shared cache. Responses without an explicit max-age aren't cached.
+fn cache_ttl(response: &http::Response<Vec<u8>>) -> Option<Duration> {
+    if response.status() != http::StatusCode::OK {
+        return None;
+    }
+    let mut max_age = None;
+    let mut s_maxage = None;
+    for header_value in response.headers().get_all("cache-control") {
+        for directive in header_value.to_str().ok()?.split(',') {
+            let directive = directive.trim().to_ascii_lowercase();
+            match directive.split_once('=') {
+                Some(("max-age", value)) => max_age = value.trim_matches('"').parse::<u64>().ok(),
+                Some(("s-maxage", value)) => s_maxage = value.trim_matches('"').parse::<u64>().ok(),
+                // private and no-cache may have a field list, which still rules out storing the
+                // whole response
+                Some(("private", _)) | Some(("no-cache", _)) => return None,
+                None if directive == "no-store" || directive == "private" || directive == "no-cache" => {
+                    return None
+                }
+                _ => {}
+            }
+        }
+    }
+    // s-maxage overrides max-age for shared caches like us
+    match s_maxage.or(max_age) {
+        Some(0) | None => None,
+        Some(seconds) => Some(Duration::from_secs(seconds)),
+    }
+}
+
+impl ResponseCache {
+    pub fn new(max_bytes: usize, max_entry_bytes: usize) -> ResponseCache {
+        ResponseCache {
+            max_bytes,
+            max_entry_bytes: max_entry_bytes.min(max_bytes),
+            used_bytes: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            next_use: 0,
+        }
+    }
+
+    /// Returns a copy of the cached response for this key, if there is a fresh one.
+    pub fn get(&mut self, key: &str) -> Option<http::Response<Vec<u8>>> {
+        if self.entries.get(key)?.expires <= Instant::now() {
+            self.remove(key);
+            return None;
+        }
+        let use_id = self.next_use;
+        self.next_use += 1;
+        let entry = self.entries.get_mut(key)?;
+        self.lru.remove(&entry.last_used);
+        entry.last_used = use_id;
+        self.lru.insert(use_id, key.to_string());
+
+        let mut response = http::Response::builder()
+            .status(entry.status)
+            .version(entry.version)
+            .body(entry.body.clone())
+            .unwrap();
+        *response.headers_mut() = entry.headers.clone();
+        Some(response)
+    }
+
+    /// Stores a response if its headers allow caching and it fits in the cache, evicting the least
+    /// recently used entries to make room.
+    pub fn insert(&mut self, key: String, response: &http::Response<Vec<u8>>) {
+        let ttl = match cache_ttl(response) {
+            Some(ttl) => ttl,
+            None => return,
+        };
+        let headers_size: usize = response
+            .headers()
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        let size = key.len() + headers_size + response.body().len();
+        if size > self.max_entry_bytes {
+            return;
+        }
+
+        self.remove(&key);
+        while self.used_bytes + size > self.max_bytes {
+            let oldest_key = match self.lru.values().next() {
+                Some(oldest_key) => oldest_key.clone(),
+                None => break,
+            };
+            self.remove(&oldest_key);
+        }
+
+        let use_id = self.next_use;
+        self.next_use += 1;
+        self.lru.insert(use_id, key.clone());
+        self.used_bytes += size;
+        self.entries.insert(
+            key,
+            CacheEntry {
+                status: response.status(),
+                version: response.version(),
+                headers: response.headers().clone(),
+                body: response.body().clone(),
+                expires: Instant::now() + ttl,
+                last_used: use_id,
+                size,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+            self.used_bytes -= entry.size;
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Gzip compression of upstream responses for clients that accept it.
+
+use crate::response;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::Write;
+
+/// Bodies smaller than this aren't worth compressing
+const MIN_COMPRESS_SIZE: usize = 1024;
+
+/// Returns whether the client's Accept-Encoding header allows a gzip-encoded response.
+pub fn accepts_gzip(request: &http::Request<Vec<u8>>) -> bool {
+    let mut gzip_q = None;
+    let mut wildcard_q = None;
+    for header_value in request.headers().get_all("accept-encoding") {
+        let header_value = match header_value.to_str() {
+            Ok(header_value) => header_value,
+            Err(_) => continue,
+        };
+        for coding in header_value.split(',') {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap_or("").trim().to_ascii_lowercase();
+            // A missing or unparsable q-value counts as 1
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            match name.as_str() {
+                "gzip" | "x-gzip" => gzip_q = Some(q),
+                "*" => wildcard_q = Some(q),
+                _ => {}
+            }
+        }
+    }
+    gzip_q.or(wildcard_q).is_some_and(|q| q > 0.0)
+}
+
+/// Returns whether a response's content type is one that compresses well (text, and text-based
+/// formats like JSON and XML).
+fn is_compressible_type(response: &http::Response<Vec<u8>>) -> bool {
+    let content_type = match response.headers().get("content-type").map(|value| value.to_str()) {
+        Some(Ok(content_type)) => content_type,
+        _ => return false,
+    };
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json" | "application/javascript" | "application/xml"
+        )
+}
+
+/// Returns whether the response's headers allow compressing it, so that callers can decide whether
+/// to read its whole body before it arrives.
+pub fn may_compress(response: &http::Response<Vec<u8>>) -> bool {
+    let no_transform = response
+        .headers()
+        .get_all("cache-control")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.to_ascii_lowercase().contains("no-transform"));
+    response.status() != http::StatusCode::PARTIAL_CONTENT
+        && !response.headers().contains_key("content-encoding")
+        && !no_transform
+        && is_compressible_type(response)
+}
+
+/// Returns whether we may compress this response at all, regardless of what the client accepts.
+fn is_compressible(response: &http::Response<Vec<u8>>) -> bool {
+    may_compress(response) && response.body().len() >= MIN_COMPRESS_SIZE
+}
+
+/// Gzips the body of a compressible response if the client accepts gzip. Compressible responses
+/// get `Vary: Accept-Encoding` either way, so that caches downstream of us keep the two versions
+/// apart.
+pub fn compress_response(response: &mut http::Response<Vec<u8>>, accepts_gzip: bool) {
+    if !is_compressible(response) {
+        return;
+    }
+    response::extend_header_value(response, "vary", "Accept-Encoding");
+    if !accepts_gzip {
+        return;
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let compressed = match encoder.write_all(response.body()).and_then(|_| encoder.finish()) {
+        Ok(compressed) => compressed,
+        Err(err) => {
+            log::error!("Failed to compress response: {}", err);
+            return;
+        }
+    };
+    if compressed.len() >= response.body().len() {
+        return;
+    }
+    response
+        .headers_mut()
+        .insert("content-encoding", http::HeaderValue::from_static("gzip"));
+    response::set_body(response, compressed);
+}
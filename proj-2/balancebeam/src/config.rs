@@ -0,0 +1,740 @@
+//! Upstream groups and routing rules. These can be given on the command line, in a TOML config
+//! file passed with --config, or both. A config file looks like this:
+//!
+//! ```toml
+//! default_group = "web"
+//!
+//! [groups]
+//! api = ["127.0.0.1:8001", "127.0.0.1:8002"]
+//! web = ["127.0.0.1:9001"]
+//!
+//! [[routes]]
+//! prefix = "/api/"
+//! group = "api"
+//!
+//! [[routes]]
+//! host = "*.admin.example.com"
+//! upstreams = ["127.0.0.1:7001"]
+//! ```
+//!
+//! Any upstream address may be written as `ADDR=canary:PERCENT` to make it a canary: the canaries
+//! in a group share PERCENT% of the group's requests, and the other upstreams get the rest. Writing
+//! it as `ADDR=maxconn:N` sends it at most N requests at a time, like --max-upstream-requests but
+//! for that upstream alone. The two can be combined, as in `ADDR=canary:10=maxconn:5`. Writing
+//! `ADDR=backup` makes it a backup, which only gets requests while none of the other upstreams in
+//! its group are usable.
+//!
+//! The file may also set `max_requests_per_minute`, `max_bytes_per_minute`,
+//! `active_health_check_interval`, `active_health_check_dead_interval` and
+//! `active_health_check_path`, which take precedence over the command-line options of the same
+//! names. Durations are written as on the command line, like `"10s"`.
+//!
+//! Sending balancebeam SIGHUP reads the file again and switches to what it now says, without
+//! disturbing requests that are already under way. If the file has become invalid, the old
+//! configuration is kept.
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Name of the group made up of the upstreams passed with --upstream
+pub const DEFAULT_GROUP_NAME: &str = "default";
+
+/// Contents of the file passed with --config
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Upstream addresses for each named group
+    #[serde(default)]
+    pub groups: BTreeMap<String, Vec<String>>,
+    /// Rules for choosing a group based on the request
+    #[serde(default)]
+    pub routes: Vec<RouteSpec>,
+    /// Group for requests that don't match any route
+    pub default_group: Option<String>,
+    /// Overrides --max-requests-per-minute
+    pub max_requests_per_minute: Option<usize>,
+    /// Overrides --max-bytes-per-minute
+    pub max_bytes_per_minute: Option<u64>,
+    /// Overrides --active-health-check-interval
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub active_health_check_interval: Option<Duration>,
+    /// Overrides --active-health-check-dead-interval
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub active_health_check_dead_interval: Option<Duration>,
+    /// Overrides --active-health-check-path
+    pub active_health_check_path: Option<String>,
+}
+
+impl ConfigFile {
+    pub fn from_file(path: &str) -> Result<ConfigFile, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format!("Could not read config file {}: {}", path, err))?;
+        toml::from_str(&contents).map_err(|err| format!("Invalid config file {}: {}", path, err))
+    }
+}
+
+/// How to choose among the alive upstreams of a group
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    /// Pick an upstream uniformly at random
+    Random,
+    /// Pick two upstreams at random and use the one that has been responding faster
+    Latency,
+    /// Pick two upstreams at random and use the one with fewer requests in flight ("power of two
+    /// choices"), which avoids busy upstreams almost as well as looking at all of them would
+    P2c,
+}
+
+/// Whether an operator wants an upstream server used, set through the admin endpoint. This is kept
+/// apart from the upstream's health, which health checks keep changing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AdminState {
+    /// Used as normal while it is healthy
+    Active,
+    /// Sent no new requests, so that the ones in flight can finish, but still health checked
+    Draining,
+    /// Neither sent requests nor health checked
+    Disabled,
+}
+
+impl AdminState {
+    pub const ALL: [AdminState; 3] = [AdminState::Active, AdminState::Draining, AdminState::Disabled];
+}
+
+impl FromStr for AdminState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AdminState, String> {
+        AdminState::ALL
+            .iter()
+            .copied()
+            .find(|state| state.to_string() == s)
+            .ok_or_else(|| format!("expected active, draining or disabled, got \"{}\"", s))
+    }
+}
+
+impl fmt::Display for AdminState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AdminState::Active => "active",
+            AdminState::Draining => "draining",
+            AdminState::Disabled => "disabled",
+        })
+    }
+}
+
+/// An upstream server, along with the options that may follow its address
+#[derive(Debug)]
+pub struct UpstreamSpec {
+    /// Address, normalized with normalize_upstream_address
+    pub address: String,
+    /// Percentage of the group's requests that its canaries should get, if this is a canary
+    pub canary_percentage: Option<u8>,
+    /// Most requests to have in flight to this upstream at once, if it has a limit of its own
+    pub max_requests: Option<usize>,
+    /// Whether this upstream only stands in for the others when none of them are usable
+    pub backup: bool,
+}
+
+/// Parses an upstream given as `ADDR`, optionally followed by any of `=canary:PERCENT`,
+/// `=maxconn:N` and `=backup`.
+pub fn parse_upstream(upstream: &str) -> Result<UpstreamSpec, String> {
+    let mut parts = upstream.split('=');
+    let addr = parts.next().unwrap_or_default();
+    let invalid =
+        || format!("expected ADDR[=canary:PERCENT][=maxconn:N][=backup], got \"{}\"", upstream);
+    if addr.is_empty() {
+        return Err(invalid());
+    }
+    let mut spec = UpstreamSpec {
+        address: normalize_upstream_address(addr)?,
+        canary_percentage: None,
+        max_requests: None,
+        backup: false,
+    };
+    for option in parts {
+        if option == "backup" && !spec.backup {
+            spec.backup = true;
+        } else if let Some(percentage) = option.strip_prefix("canary:") {
+            match percentage.parse::<u8>() {
+                Ok(percentage) if percentage <= 100 && spec.canary_percentage.is_none() => {
+                    spec.canary_percentage = Some(percentage)
+                }
+                _ => {
+                    return Err(format!(
+                        "invalid canary upstream \"{}\" (expected ADDR=canary:PERCENT)",
+                        upstream
+                    ))
+                }
+            }
+        } else if let Some(max_requests) = option.strip_prefix("maxconn:") {
+            match max_requests.parse::<usize>() {
+                Ok(max_requests) if max_requests > 0 && spec.max_requests.is_none() => {
+                    spec.max_requests = Some(max_requests)
+                }
+                _ => {
+                    return Err(format!("invalid request limit \"{}\" (expected ADDR=maxconn:N)", upstream))
+                }
+            }
+        } else {
+            return Err(invalid());
+        }
+    }
+    if spec.backup && spec.canary_percentage.is_some() {
+        return Err(format!("upstream \"{}\" can't be both a canary and a backup", upstream));
+    }
+    Ok(spec)
+}
+
+/// Checks that an upstream address is an IP/port, HOST:PORT or unix:PATH, and writes IP addresses
+/// the standard way, so that an upstream looks the same in logs and statistics however it was
+/// given. IPv6 addresses have to be in brackets (like `[::1]:8080`), since otherwise their port
+/// can't be told apart from the rest of the address.
+pub fn normalize_upstream_address(addr: &str) -> Result<String, String> {
+    if addr.starts_with("unix:") {
+        return Ok(addr.to_string());
+    }
+    if let Ok(socket_addr) = addr.parse::<SocketAddr>() {
+        return Ok(socket_addr.to_string());
+    }
+    let invalid = || {
+        format!("invalid upstream address \"{}\" (expected IP:PORT, HOST:PORT or unix:PATH)", addr)
+    };
+    let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+    if host.is_empty() || host.starts_with('[') || port.parse::<u16>().is_err() {
+        return Err(invalid());
+    }
+    if host.contains(':') {
+        return Err(format!("IPv6 upstream address \"{}\" must be in brackets, like [::1]:8080", addr));
+    }
+    Ok(addr.to_string())
+}
+
+/// Parses a duration given on the command line, like `500ms`, `10s` or `2m`. A bare number is taken
+/// as seconds, which is how durations used to be given.
+pub fn parse_duration(duration: &str) -> Result<Duration, String> {
+    if let Ok(seconds) = duration.parse::<u64>() {
+        return Ok(Duration::from_secs(seconds));
+    }
+    humantime::parse_duration(duration)
+        .map_err(|err| format!("invalid duration \"{}\" ({}; expected e.g. 500ms, 10s or 2m)", duration, err))
+}
+
+/// Reads a duration from the config file, written the same way as on the command line
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let duration = String::deserialize(deserializer)?;
+    parse_duration(&duration).map(Some).map_err(serde::de::Error::custom)
+}
+
+/// Parses file permissions given on the command line in octal, like `660` or `0600`.
+pub fn parse_file_mode(mode: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(bits) if bits <= 0o7777 => Ok(bits),
+        _ => Err(format!("invalid file mode \"{}\" (expected octal permissions, e.g. 660)", mode)),
+    }
+}
+
+/// A named group of upstreams, given on the command line as `--group name=addr1,addr2`
+#[derive(Clone, Debug)]
+pub struct GroupSpec {
+    pub name: String,
+    pub upstreams: Vec<String>,
+}
+
+impl FromStr for GroupSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Only the first = separates the name, since upstreams with options contain one too
+        let (name, upstreams) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=ADDR[,ADDR...], got \"{}\"", s))?;
+        let upstreams: Vec<String> = upstreams
+            .split(',')
+            .filter(|addr| !addr.is_empty())
+            .map(|addr| addr.to_string())
+            .collect();
+        if name.is_empty() || upstreams.is_empty() {
+            return Err(format!("expected NAME=ADDR[,ADDR...], got \"{}\"", s));
+        }
+        Ok(GroupSpec {
+            name: name.to_string(),
+            upstreams,
+        })
+    }
+}
+
+/// A host name pattern: either an exact name, or `*.example.com` to match any subdomain
+#[derive(Clone, Debug, PartialEq)]
+pub enum HostPattern {
+    Exact(String),
+    /// Suffix (including the leading dot) that matching hosts must end with
+    Wildcard(String),
+}
+
+impl HostPattern {
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        match self {
+            HostPattern::Exact(name) => host == *name,
+            HostPattern::Wildcard(suffix) => host.len() > suffix.len() && host.ends_with(suffix),
+        }
+    }
+
+    /// Exact names are more specific than wildcards, and longer wildcards are more specific than
+    /// shorter ones
+    fn specificity(&self) -> (bool, usize) {
+        match self {
+            HostPattern::Exact(name) => (true, name.len()),
+            HostPattern::Wildcard(suffix) => (false, suffix.len()),
+        }
+    }
+}
+
+impl FromStr for HostPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        if let Some(suffix) = s.strip_prefix('*') {
+            if !suffix.starts_with('.') || suffix.len() < 2 {
+                return Err(format!("invalid wildcard host \"{}\"", s));
+            }
+            Ok(HostPattern::Wildcard(suffix.to_string()))
+        } else if s.is_empty() || s.contains('*') {
+            Err(format!("invalid host \"{}\"", s))
+        } else {
+            Ok(HostPattern::Exact(s))
+        }
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostPattern::Exact(name) => write!(f, "{}", name),
+            HostPattern::Wildcard(suffix) => write!(f, "*{}", suffix),
+        }
+    }
+}
+
+/// A range of IP addresses in CIDR notation, like `10.0.0.0/8` or `2001:db8::/32`. An address
+/// without a prefix length stands for just that address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid IP address \"{}\"", addr))?
+            .to_canonical();
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|&len| len <= max_len)
+                .ok_or_else(|| format!("invalid prefix length \"{}\"", prefix_len))?,
+            None => max_len,
+        };
+        Ok(IpNet { addr, prefix_len })
+    }
+}
+
+/// What to do with a client that no --allow-ip or --deny-ip rule matches
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum IpAction {
+    Allow,
+    Deny,
+}
+
+/// How to turn away a client whose IP address isn't allowed
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum DenyAction {
+    /// Close the connection without reading from it
+    Close,
+    /// Answer the client's first request with 403 Forbidden, then close the connection
+    Forbidden,
+}
+
+/// Rules deciding which client IP addresses may connect. Deny rules take precedence over allow
+/// rules, and addresses that neither matches get the default action.
+pub struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    default_action: IpAction,
+}
+
+impl IpFilter {
+    /// Without an explicit default, clients are allowed unless there are allow rules, in which
+    /// case only the addresses they list are.
+    pub fn new(allow: Vec<IpNet>, deny: Vec<IpNet>, default_action: Option<IpAction>) -> IpFilter {
+        let default_action = default_action.unwrap_or(if allow.is_empty() {
+            IpAction::Allow
+        } else {
+            IpAction::Deny
+        });
+        IpFilter {
+            allow,
+            deny,
+            default_action,
+        }
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(ip)) {
+            false
+        } else if self.allow.iter().any(|net| net.contains(ip)) {
+            true
+        } else {
+            self.default_action == IpAction::Allow
+        }
+    }
+}
+
+/// A CONNECT target that clients may tunnel to, given on the command line as
+/// `--allow-connect HOST:PORT`. HOST may be a wildcard like `*.example.com`, and PORT may be `*` to
+/// allow any port.
+#[derive(Clone, Debug)]
+pub struct ConnectPattern {
+    pub host: HostPattern,
+    /// None allows any port
+    pub port: Option<u16>,
+}
+
+impl ConnectPattern {
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        self.host.matches(host) && self.port.is_none_or(|allowed| allowed == port)
+    }
+}
+
+impl FromStr for ConnectPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected HOST:PORT, got \"{}\"", s))?;
+        let port = match port {
+            "*" => None,
+            port => Some(port.parse().map_err(|_| format!("invalid port \"{}\"", port))?),
+        };
+        Ok(ConnectPattern {
+            host: host.parse()?,
+            port,
+        })
+    }
+}
+
+/// Request methods that clients may use, given on the command line as a comma-separated list like
+/// `GET,POST`, or `*` to allow any method
+#[derive(Clone, Debug)]
+pub enum AllowedMethods {
+    Any,
+    Only(Vec<http::Method>),
+}
+
+impl AllowedMethods {
+    pub fn allows(&self, method: &http::Method) -> bool {
+        match self {
+            AllowedMethods::Any => true,
+            AllowedMethods::Only(methods) => methods.contains(method),
+        }
+    }
+}
+
+impl FromStr for AllowedMethods {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            return Ok(AllowedMethods::Any);
+        }
+        let mut methods = Vec::new();
+        for method in s.split(',') {
+            // Method names are case-sensitive, and the standard ones are all uppercase
+            let method: http::Method = method
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid method \"{}\"", method))?;
+            if !methods.contains(&method) {
+                methods.push(method);
+            }
+        }
+        Ok(AllowedMethods::Only(methods))
+    }
+}
+
+/// Lists the methods as an Allow header value
+impl fmt::Display for AllowedMethods {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllowedMethods::Any => write!(f, "*"),
+            AllowedMethods::Only(methods) => {
+                let names: Vec<&str> = methods.iter().map(http::Method::as_str).collect();
+                write!(f, "{}", names.join(", "))
+            }
+        }
+    }
+}
+
+/// A custom body for error responses with some status code, given on the command line as
+/// `--error-page 502=/path/to/502.html`
+#[derive(Clone, Debug)]
+pub struct ErrorPageSpec {
+    pub status: http::StatusCode,
+    pub path: String,
+}
+
+impl FromStr for ErrorPageSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (status, path) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected CODE=PATH, got \"{}\"", s))?;
+        let status = status
+            .parse::<u16>()
+            .ok()
+            .and_then(|code| http::StatusCode::from_u16(code).ok())
+            .filter(|status| status.is_client_error() || status.is_server_error())
+            .ok_or_else(|| format!("invalid error status code \"{}\"", status))?;
+        if path.is_empty() {
+            return Err(format!("expected CODE=PATH, got \"{}\"", s));
+        }
+        Ok(ErrorPageSpec {
+            status,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// A routing rule, given on the command line as `--route prefix=/api/,group=api` or
+/// `--route host=app.example.com,upstreams=ADDR[,ADDR...]`. A route may match on the Host
+/// header, the path prefix, or both, and sends matching requests either to a named group or to
+/// its own list of upstreams.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteSpec {
+    /// Requests whose Host header matches this pattern match the route
+    pub host: Option<String>,
+    /// Requests whose path starts with this prefix match the route
+    pub prefix: Option<String>,
+    /// Name of the group that matching requests are sent to
+    pub group: Option<String>,
+    /// Upstreams that matching requests are sent to, instead of a named group
+    #[serde(default)]
+    pub upstreams: Vec<String>,
+}
+
+impl FromStr for RouteSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut route = RouteSpec::default();
+        let mut in_upstreams = false;
+        for field in s.split(',') {
+            match field.split_once('=') {
+                Some(("host", value)) => route.host = Some(value.to_string()),
+                Some(("prefix", value)) => route.prefix = Some(value.to_string()),
+                Some(("group", value)) => route.group = Some(value.to_string()),
+                Some(("upstreams", value)) => {
+                    in_upstreams = true;
+                    route.upstreams.push(value.to_string());
+                }
+                // Upstream lists are comma separated, so everything after upstreams= that isn't
+                // another field is an upstream address
+                None if in_upstreams && !field.is_empty() => route.upstreams.push(field.to_string()),
+                Some((_, value))
+                    if in_upstreams
+                        && ["canary:", "maxconn:", "backup"].iter().any(|option| value.starts_with(option)) =>
+                {
+                    route.upstreams.push(field.to_string())
+                }
+                _ => return Err(format!("unrecognized route field \"{}\"", field)),
+            }
+        }
+        route.upstreams.retain(|addr| !addr.is_empty());
+        Ok(route)
+    }
+}
+
+/// A route with its group resolved to an index into the list of groups
+#[derive(Debug)]
+pub struct Route {
+    pub host: Option<HostPattern>,
+    pub prefix: String,
+    pub group: usize,
+}
+
+impl Route {
+    fn matches(&self, host: Option<&str>, path: &str) -> bool {
+        let host_matches = match (&self.host, host) {
+            (None, _) => true,
+            (Some(pattern), Some(host)) => pattern.matches(host),
+            (Some(_), None) => false,
+        };
+        host_matches && path.starts_with(&self.prefix)
+    }
+}
+
+/// Rules for picking the upstream group that handles a request
+#[derive(Debug)]
+pub struct Routes {
+    /// Routes, ordered so that the first matching route is the most specific one
+    routes: Vec<Route>,
+    /// Group for requests that don't match any route (None means respond with 404, or 421 if the
+    /// request's host isn't served by any host route)
+    default_group: Option<usize>,
+}
+
+impl Routes {
+    /// Combines the groups and routes from the command line and the config file. The upstreams
+    /// passed with --upstream form a group named "default". Returns the list of groups along with
+    /// routes referring to them by index.
+    pub fn build(
+        upstreams: Vec<String>,
+        groups: Vec<GroupSpec>,
+        routes: Vec<RouteSpec>,
+        default_group: Option<String>,
+        config_file: ConfigFile,
+    ) -> Result<(Vec<GroupSpec>, Routes), String> {
+        let mut all_groups: Vec<GroupSpec> = Vec::new();
+        if !upstreams.is_empty() {
+            all_groups.push(GroupSpec {
+                name: DEFAULT_GROUP_NAME.to_string(),
+                upstreams,
+            });
+        }
+        let file_groups = config_file
+            .groups
+            .into_iter()
+            .map(|(name, upstreams)| GroupSpec { name, upstreams });
+        for group in groups.into_iter().chain(file_groups) {
+            if all_groups.iter().any(|existing| existing.name == group.name) {
+                return Err(format!("Upstream group \"{}\" is defined more than once", group.name));
+            }
+            if group.upstreams.is_empty() {
+                return Err(format!("Upstream group \"{}\" has no upstreams", group.name));
+            }
+            all_groups.push(group);
+        }
+        let find_group = |groups: &[GroupSpec], name: &str| {
+            groups
+                .iter()
+                .position(|group| group.name == name)
+                .ok_or_else(|| format!("Unknown upstream group \"{}\"", name))
+        };
+        let mut all_routes = Vec::new();
+        for route in routes.into_iter().chain(config_file.routes) {
+            let host = route.host.as_deref().map(str::parse::<HostPattern>).transpose()?;
+            if host.is_none() && route.prefix.is_none() {
+                return Err("Routes need a host=, a prefix=, or both".to_string());
+            }
+            let group = match (route.group, route.upstreams.is_empty()) {
+                (Some(name), true) => find_group(&all_groups, &name)?,
+                (None, false) => {
+                    // Routes with their own upstreams get an anonymous group named after the route
+                    all_groups.push(GroupSpec {
+                        name: format!(
+                            "{}{}",
+                            host.as_ref().map(|host| host.to_string()).unwrap_or_default(),
+                            route.prefix.as_deref().unwrap_or_default()
+                        ),
+                        upstreams: route.upstreams,
+                    });
+                    all_groups.len() - 1
+                }
+                _ => return Err("Routes need exactly one of group= or upstreams=".to_string()),
+            };
+            all_routes.push(Route {
+                host,
+                prefix: route.prefix.unwrap_or_default(),
+                group,
+            });
+        }
+        if all_groups.is_empty() {
+            return Err("At least one upstream server must be specified using the --upstream \
+                option, --group, or a config file."
+                .to_string());
+        }
+
+        // The most specific route wins when routes overlap: host routes take precedence over
+        // path-only routes (exact hosts over wildcards), then the longest prefix wins
+        all_routes.sort_by_key(|route| {
+            std::cmp::Reverse((
+                route.host.as_ref().map(HostPattern::specificity),
+                route.prefix.len(),
+            ))
+        });
+
+        let default_group = match default_group.or(config_file.default_group) {
+            Some(name) => Some(find_group(&all_groups, &name)?),
+            None => find_group(&all_groups, DEFAULT_GROUP_NAME).ok(),
+        };
+
+        Ok((
+            all_groups,
+            Routes {
+                routes: all_routes,
+                default_group,
+            },
+        ))
+    }
+
+    /// Returns the index of the group that should handle a request for the given host (with any
+    /// port already removed) and path. If no route matches and there is no default group, returns
+    /// the status code to respond with instead.
+    pub fn select_group(&self, host: Option<&str>, path: &str) -> Result<usize, http::StatusCode> {
+        if let Some(route) = self.routes.iter().find(|route| route.matches(host, path)) {
+            return Ok(route.group);
+        }
+        if let Some(group) = self.default_group {
+            return Ok(group);
+        }
+        let host_routed = self.routes.iter().any(|route| route.host.is_some());
+        let host_known = self.routes.iter().any(|route| match (&route.host, host) {
+            (Some(pattern), Some(host)) => pattern.matches(host),
+            _ => false,
+        });
+        if host_routed && !host_known {
+            Err(http::StatusCode::MISDIRECTED_REQUEST)
+        } else {
+            Err(http::StatusCode::NOT_FOUND)
+        }
+    }
+}
@@ -1,55 +1,900 @@
+mod admin;
+mod auth;
+mod cache;
+mod compress;
+mod config;
+mod proxy_protocol;
+mod rate_limit;
 mod request;
 mod response;
+mod stats;
+mod upstream;
 
 use clap::Parser;
+use parking_lot::{Mutex, RwLock};
 use rand::{Rng, SeedableRng};
-use std::{collections::HashMap, sync::Arc};
-use tokio::{net::{TcpListener, TcpStream}, sync::RwLock, time};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::Notify;
+use tokio::{net::{TcpListener, TcpStream, UnixListener}, time};
+use upstream::UpstreamConn;
 
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
 /// provide a fancy way to automatically construct a command-line argument parser.
 #[derive(Parser, Debug)]
 #[command(about = "Fun with load balancing")]
 struct CmdOptions {
-    /// "IP/port to bind to"
+    /// "IP/port to bind to, or unix:PATH to listen on a Unix domain socket (clients are then
+    /// identified by their user ID, as unix:uid=UID)"
     #[arg(short, long, default_value = "0.0.0.0:1100")]
     bind: String,
-    /// "Upstream host to forward requests to"
+    /// "Permissions to give the socket file when binding to unix:PATH, in octal (e.g. 660)"
+    #[arg(long, value_parser = config::parse_file_mode)]
+    unix_socket_mode: Option<u32>,
+    #[command(flatten)]
+    reloadable: ReloadableOptions,
+    /// "Count the bodies of responses against --max-bytes-per-minute as well as those of requests"
+    #[arg(long)]
+    count_response_bytes: bool,
+    /// "Length of the window that --max-requests-per-minute and --max-bytes-per-minute count over"
+    #[arg(long, default_value = "1m", value_parser = config::parse_duration)]
+    rate_limit_window: time::Duration,
+    /// "Largest request line plus headers (in bytes) to accept from clients"
+    #[arg(long, default_value = "8000")]
+    max_header_bytes: usize,
+    /// "Most headers to accept in a client request"
+    #[arg(long, default_value = "32")]
+    max_headers: usize,
+    /// "Longest request target (in bytes) to accept from clients"
+    #[arg(long, default_value = "8000")]
+    max_uri_length: usize,
+    /// "Largest request body (in bytes) to accept from clients"
+    #[arg(long, default_value = "10000000")]
+    max_body_bytes: usize,
+    /// "Most requests to have in flight to each upstream server at once (ADDR=maxconn:N in --upstream
+    /// sets a lower limit for one upstream); once an upstream has this many, requests go to another
+    /// one, wait in the queue (see --queue-depth) or get 503 (0 = unlimited)"
+    #[arg(long, default_value = "0")]
+    max_upstream_requests: usize,
+    /// "Number of requests per group that may wait for an upstream to have room when every alive
+    /// upstream is at --max-upstream-requests; requests beyond that get 503 straight away"
+    #[arg(long, default_value = "0")]
+    queue_depth: usize,
+    /// "Longest time a request waits in the queue before getting 503"
+    #[arg(long, default_value = "1s", value_parser = config::parse_duration)]
+    queue_timeout: time::Duration,
+    /// "Close client connections after this many requests, so that clients reconnect and get
+    /// rebalanced (0 = unlimited)"
+    #[arg(long, default_value = "0")]
+    max_requests_per_connection: usize,
+    /// "Cache GET responses in up to this many bytes of memory (0 = no caching)"
+    #[arg(long, default_value = "0")]
+    cache_max_bytes: usize,
+    /// "Largest response (in bytes) that will be cached"
+    #[arg(long, default_value = "1048576")]
+    cache_max_entry_bytes: usize,
+    /// "Allow CONNECT tunnels to HOST:PORT (HOST may be a wildcard like *.example.com, PORT may be
+    /// *); CONNECT is refused unless this is given"
+    #[arg(long)]
+    allow_connect: Vec<config::ConnectPattern>,
+    /// "Only serve requests whose Host header matches this (may be a wildcard like *.example.com);
+    /// others get 421. All hosts are served unless this is given."
+    #[arg(long)]
+    allowed_host: Vec<config::HostPattern>,
+    /// "Methods that clients may use (comma-separated, or * for any); others get 405. CONNECT is
+    /// governed by --allow-connect instead."
+    #[arg(long, default_value = "GET,POST,PUT,DELETE,HEAD,OPTIONS,PATCH")]
+    allowed_methods: config::AllowedMethods,
+    /// "Allow clients whose IP address is in this range (CIDR, like 10.0.0.0/8 or 2001:db8::/32)"
+    #[arg(long)]
+    allow_ip: Vec<config::IpNet>,
+    /// "Refuse clients whose IP address is in this range (CIDR); takes precedence over --allow-ip"
+    #[arg(long)]
+    deny_ip: Vec<config::IpNet>,
+    /// "What to do with clients that no --allow-ip or --deny-ip range matches (defaults to deny if
+    /// --allow-ip is given, allow otherwise)"
+    #[arg(long, value_enum)]
+    default_ip_action: Option<config::IpAction>,
+    /// "How to refuse clients whose IP address isn't allowed"
+    #[arg(long, value_enum, default_value = "close")]
+    deny_action: config::DenyAction,
+    /// "Require HTTP Basic authentication from clients, checking credentials against this
+    /// htpasswd-style file (username:bcrypt-hash per line)"
+    #[arg(long)]
+    basic_auth_file: Option<String>,
+    /// "Remove the Authorization header from authenticated requests before forwarding them"
+    #[arg(long)]
+    basic_auth_strip: bool,
+    /// "Name this proxy gives itself in Via headers, used to detect forwarding loops"
+    #[arg(long, default_value = "balancebeam")]
+    via_pseudonym: String,
+    /// "Gzip text responses for clients that accept it"
+    #[arg(long)]
+    compress: bool,
+    /// "How to choose which upstream in a group gets each connection"
+    #[arg(long, value_enum, default_value = "random")]
+    strategy: config::Strategy,
+    /// "Send a copy of proxied requests to this upstream, discarding its responses"
+    #[arg(long)]
+    mirror_upstream: Option<String>,
+    /// "Percentage of requests to copy to --mirror-upstream"
+    #[arg(long, default_value = "100", value_parser = clap::value_parser!(u8).range(0..=100))]
+    mirror_percentage: u8,
+    /// "Serve the HTML file at PATH as the body of error responses with status CODE (CODE=PATH); a
+    /// {status} placeholder in the file is replaced with the status"
+    #[arg(long)]
+    error_page: Vec<config::ErrorPageSpec>,
+    /// "IP/port to serve the admin endpoint on (disabled unless given)"
+    #[arg(long)]
+    admin_bind: Option<String>,
+    /// "Give up on an upstream that hasn't sent the head of its response this long after being
+    /// sent the request, answering 504 (e.g. 5s; no limit unless given)"
+    #[arg(long, value_parser = config::parse_duration)]
+    upstream_header_timeout: Option<time::Duration>,
+    /// "Give up on an upstream that goes this long without sending any of a response body, however
+    /// long the whole transfer takes (e.g. 30s; no limit unless given)"
+    #[arg(long, value_parser = config::parse_duration)]
+    upstream_idle_timeout: Option<time::Duration>,
+    /// "Give up on opening a connection to an upstream after this long, treating it like any other
+    /// failed connection (e.g. 500ms)"
+    #[arg(long, default_value = "3s", value_parser = config::parse_duration)]
+    upstream_connect_timeout: time::Duration,
+    /// "Send an idempotent request to a second upstream as well if the first hasn't sent the head of
+    /// its response this long after being sent it, using whichever answers first (e.g. 200ms;
+    /// disabled unless given)"
+    #[arg(long, value_parser = config::parse_duration)]
+    hedge_after: Option<time::Duration>,
+    /// "Methods whose requests are sent again on a new connection when a kept-alive upstream
+    /// connection turns out to have been closed (comma-separated, or * for any)"
+    #[arg(long, default_value = "GET,HEAD,OPTIONS,PUT,DELETE")]
+    retry_methods: config::AllowedMethods,
+    /// "Log a warning, with a breakdown of where the time went, for proxied requests that take
+    /// longer than this (e.g. 2s)"
+    #[arg(long, value_parser = config::parse_duration)]
+    slow_request_threshold: Option<time::Duration>,
+    /// "Expect every connection to start with a PROXY protocol (v1 or v2) header, and take the
+    /// client's address from it"
+    #[arg(long)]
+    accept_proxy_protocol: bool,
+    /// "Answer requests for this path (e.g. /__balancebeam/healthz) ourselves, with 200 if any
+    /// upstream is alive and 503 otherwise, instead of forwarding them"
+    #[arg(long)]
+    self_health_path: Option<String>,
+    /// "Answer requests for this path (e.g. /__balancebeam/ready) ourselves, like
+    /// --self-health-path, but with 503 until every upstream has been health checked once"
+    #[arg(long)]
+    self_ready_path: Option<String>,
+}
+
+/// Command-line options for what the config file can also set. They are kept for as long as we
+/// run, since reloading the config file builds the configuration from them again.
+#[derive(clap::Args, Debug)]
+struct ReloadableOptions {
+    /// "Upstream host to forward requests to, as IP/port or unix:PATH (ADDR=canary:PERCENT makes it
+    /// a canary that shares PERCENT% of requests with the other canaries; ADDR=maxconn:N sends it
+    /// at most N requests at a time; ADDR=backup is the same as --backup-upstream ADDR)"
     #[arg(short, long)]
     upstream: Vec<String>,
-    /// "Perform active health checks on this interval (in seconds)"
-    #[arg(long, default_value = "10")]
-    active_health_check_interval: usize,
+    /// "Upstream host that only gets requests while none of the --upstream hosts are alive, such as
+    /// a maintenance page server. It is health checked like the others."
+    #[arg(long)]
+    backup_upstream: Vec<String>,
+    /// "Perform active health checks on this interval (e.g. 500ms, 10s, 2m; a bare number is
+    /// seconds)"
+    #[arg(long, default_value = "10s", value_parser = config::parse_duration)]
+    active_health_check_interval: time::Duration,
+    /// "Perform active health checks on upstreams that are down on this interval (defaults to
+    /// --active-health-check-interval)"
+    #[arg(long, value_parser = config::parse_duration)]
+    active_health_check_dead_interval: Option<time::Duration>,
+    /// "Longest time to wait between health checks of an upstream that keeps failing them; the wait
+    /// doubles after each failed check, starting from the dead interval"
+    #[arg(long, default_value = "5m", value_parser = config::parse_duration)]
+    max_probe_backoff: time::Duration,
+    /// "Vary each wait between health checks by up to this fraction of it, chosen at random, so
+    /// that balancebeam instances with the same interval don't all probe at once"
+    #[arg(long, default_value = "0.1")]
+    active_health_check_jitter: f64,
     /// "Path to send request to for active health checks"
     #[arg(long, default_value = "/")]
     active_health_check_path: String,
-    /// "Maximum number of requests to accept per IP per minute (0 = unlimited)"
+    /// "Only count an upstream as healthy if the body of its health check response contains this
+    /// text"
+    #[arg(long)]
+    active_health_check_expect_body: Option<String>,
+    /// "Only count an upstream as healthy if the body of its health check response matches this
+    /// regular expression"
+    #[arg(long)]
+    active_health_check_expect_regex: Option<regex::Regex>,
+    /// "Maximum number of requests to accept per IP per rate limit window (0 = unlimited)"
     #[arg(long, default_value = "0")]
     max_requests_per_minute: usize,
+    /// "Maximum number of request body bytes to accept per IP per rate limit window (0 = unlimited)"
+    #[arg(long, default_value = "0")]
+    max_bytes_per_minute: u64,
+    /// "Named group of upstream hosts (NAME=ADDR[,ADDR...])"
+    #[arg(long)]
+    group: Vec<config::GroupSpec>,
+    /// "Route matching requests to a group or to their own upstreams
+    /// ([host=HOST,][prefix=PREFIX,]group=GROUP or ...,upstreams=ADDR[,ADDR...]); HOST may be a
+    /// wildcard like *.example.com"
+    #[arg(long)]
+    route: Vec<config::RouteSpec>,
+    /// "Group for requests that match no route (defaults to the --upstream hosts, if any;
+    /// otherwise such requests get 404, or 421 for hosts that no route serves)"
+    #[arg(long)]
+    default_group: Option<String>,
+    /// "TOML file defining upstream groups and routes, which is read again on SIGHUP"
+    #[arg(long)]
+    config: Option<String>,
+}
+
+/// Health information about a group of upstream servers that requests can be routed to. The health
+/// fields are atomics so that request handlers and health checks can share the group without a
+/// lock.
+struct UpstreamGroup {
+    /// Name of the group, used for logging
+    name: String,
+    /// Addresses of servers that we are proxying to
+    upstream_addresses: Vec<String>,
+    /// Whether each upstream server is a canary
+    upstream_is_canary: Vec<bool>,
+    /// Most requests each upstream server may have in flight at once, given with ADDR=maxconn:N,
+    /// on top of --max-upstream-requests (0 = no limit of its own)
+    upstream_max_requests: Vec<usize>,
+    /// Whether each upstream server is a backup
+    upstream_is_backup: Vec<bool>,
+    /// Indices of the stable upstream servers and of the canaries, in that order. Backups are in
+    /// neither.
+    upstream_sets: [Vec<usize>; 2],
+    /// Indices of the backup upstream servers, which are only used while none of the others are
+    /// usable
+    backup_upstreams: Vec<usize>,
+    /// Percentage of requests that are sent to the canaries. This can be changed through the admin
+    /// endpoint while we run.
+    canary_percentage: AtomicU8,
+    /// Percentage of requests sent to the canaries according to the configuration, which
+    /// canary_percentage starts out as
+    configured_canary_percentage: u8,
+    /// Flags that indicate whether the upstream server is alive
+    upstream_address_flags: Vec<AtomicBool>,
+    /// Whether each upstream server is active, draining or disabled, as an index into
+    /// AdminState::ALL. Only active upstreams get new requests, whatever their health.
+    upstream_admin_states: Vec<AtomicU8>,
+    /// Number of alive upstream servers
+    upstream_address_alive_num: AtomicUsize,
+    /// Number of health checks in a row that each upstream server has failed
+    upstream_failed_probes: Vec<AtomicU32>,
+    /// Whether each upstream server has been health checked yet
+    upstream_probed: Vec<AtomicBool>,
+    /// Exponentially weighted moving average of each upstream server's response time, in
+    /// microseconds (0 until the first response)
+    upstream_latency_ewma: Vec<AtomicU64>,
+    /// Number of requests forwarded to the stable upstreams and to the canaries
+    set_requests: [AtomicU64; 2],
+    /// Number of those requests that failed or got a 5xx response
+    set_errors: [AtomicU64; 2],
+    /// Request, response and latency counts for each upstream server. They are shared with the
+    /// group that replaces this one when the configuration is reloaded, so that they carry on.
+    upstream_stats: Vec<Arc<stats::UpstreamStats>>,
+    /// Number of requests currently being forwarded to each upstream server. Like the statistics,
+    /// these are shared across reloads, so that requests still using the old configuration count
+    /// towards the limit.
+    upstream_in_flight: Vec<Arc<AtomicUsize>>,
+    /// Number of requests waiting for an upstream server to have room for them
+    queued: AtomicUsize,
+    /// Wakes a queued request when a request finishes
+    capacity_freed: Notify,
+}
+
+/// A request's place on an upstream server, counted in the server's in-flight requests until it is
+/// dropped
+struct InFlight<'a> {
+    group: &'a UpstreamGroup,
+    upstream_idx: usize,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.group.upstream_in_flight[self.upstream_idx].fetch_sub(1, Ordering::SeqCst);
+        // A queued request counts itself before checking for room, so if there's no queued
+        // request to see here, any that arrives later will see the room we just made
+        if self.group.queued.load(Ordering::SeqCst) > 0 {
+            self.group.capacity_freed.notify_one();
+        }
+    }
+}
+
+/// Response time recorded for a request that failed, so that failing upstreams look slow
+const FAILURE_LATENCY: time::Duration = time::Duration::from_secs(10);
+
+/// Largest request body that is mirrored. Mirroring a body means holding all of it in memory, so
+/// requests with bigger bodies are only sent to the primary upstream.
+const MAX_MIRRORED_BODY_SIZE: usize = 1000000;
+
+impl UpstreamGroup {
+    fn new(spec: config::GroupSpec) -> Result<UpstreamGroup, String> {
+        let upstream_address_num = spec.upstreams.len();
+        let mut upstream_addresses = Vec::new();
+        let mut upstream_is_canary = Vec::new();
+        let mut upstream_max_requests = Vec::new();
+        let mut upstream_is_backup = Vec::new();
+        let mut canary_percentage = None;
+        for upstream in &spec.upstreams {
+            let upstream = config::parse_upstream(upstream)?;
+            if let Some(percentage) = upstream.canary_percentage {
+                if canary_percentage.is_some_and(|existing| existing != percentage) {
+                    return Err(format!(
+                        "Canaries in upstream group \"{}\" have different percentages",
+                        spec.name
+                    ));
+                }
+                canary_percentage = Some(percentage);
+            }
+            upstream_addresses.push(upstream.address);
+            upstream_is_canary.push(upstream.canary_percentage.is_some());
+            upstream_max_requests.push(upstream.max_requests.unwrap_or(0));
+            upstream_is_backup.push(upstream.backup);
+        }
+        let set = |canary: bool| {
+            (0..upstream_address_num)
+                .filter(|idx| !upstream_is_backup[*idx] && upstream_is_canary[*idx] == canary)
+                .collect()
+        };
+        Ok(UpstreamGroup {
+            name: spec.name,
+            upstream_addresses,
+            upstream_sets: [set(false), set(true)],
+            backup_upstreams: (0..upstream_address_num).filter(|idx| upstream_is_backup[*idx]).collect(),
+            upstream_is_canary,
+            upstream_is_backup,
+            upstream_max_requests,
+            canary_percentage: AtomicU8::new(canary_percentage.unwrap_or(0)),
+            configured_canary_percentage: canary_percentage.unwrap_or(0),
+            upstream_address_flags: (0..upstream_address_num).map(|_| AtomicBool::new(true)).collect(),
+            upstream_admin_states: (0..upstream_address_num).map(|_| AtomicU8::new(0)).collect(),
+            upstream_address_alive_num: AtomicUsize::new(upstream_address_num),
+            upstream_failed_probes: (0..upstream_address_num).map(|_| AtomicU32::new(0)).collect(),
+            upstream_probed: (0..upstream_address_num).map(|_| AtomicBool::new(false)).collect(),
+            upstream_latency_ewma: (0..upstream_address_num).map(|_| AtomicU64::new(0)).collect(),
+            set_requests: Default::default(),
+            set_errors: Default::default(),
+            upstream_stats: (0..upstream_address_num).map(|_| Default::default()).collect(),
+            upstream_in_flight: (0..upstream_address_num).map(|_| Default::default()).collect(),
+            queued: AtomicUsize::new(0),
+            capacity_freed: Notify::new(),
+        })
+    }
+
+    /// Takes over the state of the upstream servers that were in this group before the configuration
+    /// was reloaded, so that they keep their health, administrative state and statistics. Upstreams
+    /// that weren't in the group before start out dead, and get no requests until a health check
+    /// finds them alive. The canary percentage and each set's request counts carry on too, unless
+    /// the canaries or their configured percentage have changed, which starts the canary afresh.
+    fn inherit(&mut self, previous: Option<&UpstreamGroup>) {
+        if let Some(previous) = previous {
+            self.inherit_canary(previous);
+        }
+        for upstream_idx in 0..self.upstream_addresses.len() {
+            let address = &self.upstream_addresses[upstream_idx];
+            let found = previous.and_then(|previous| {
+                let previous_idx = previous.upstream_addresses.iter().position(|other| other == address)?;
+                Some((previous, previous_idx))
+            });
+            let (previous, previous_idx) = match found {
+                Some(found) => found,
+                None => {
+                    *self.upstream_address_flags[upstream_idx].get_mut() = false;
+                    continue;
+                }
+            };
+            *self.upstream_address_flags[upstream_idx].get_mut() = previous.is_alive(previous_idx);
+            *self.upstream_admin_states[upstream_idx].get_mut() = previous.admin_state(previous_idx) as u8;
+            *self.upstream_failed_probes[upstream_idx].get_mut() =
+                previous.upstream_failed_probes[previous_idx].load(Ordering::SeqCst);
+            *self.upstream_probed[upstream_idx].get_mut() =
+                previous.upstream_probed[previous_idx].load(Ordering::SeqCst);
+            *self.upstream_latency_ewma[upstream_idx].get_mut() =
+                previous.upstream_latency_ewma[previous_idx].load(Ordering::SeqCst);
+            self.upstream_stats[upstream_idx] = previous.upstream_stats[previous_idx].clone();
+            self.upstream_in_flight[upstream_idx] = previous.upstream_in_flight[previous_idx].clone();
+        }
+        let alive_num = (0..self.upstream_addresses.len()).filter(|idx| self.is_alive(*idx)).count();
+        *self.upstream_address_alive_num.get_mut() = alive_num;
+    }
+
+    /// Takes over the canary percentage and the request counts of the group this one replaces, if
+    /// it has the same canaries with the same configured percentage.
+    fn inherit_canary(&mut self, previous: &UpstreamGroup) {
+        let canaries = |group: &UpstreamGroup| {
+            let mut addresses: Vec<String> =
+                group.upstream_sets[1].iter().map(|idx| group.upstream_addresses[*idx].clone()).collect();
+            addresses.sort();
+            addresses
+        };
+        let same_canary = canaries(self) == canaries(previous)
+            && self.configured_canary_percentage == previous.configured_canary_percentage;
+        if !same_canary {
+            if previous.has_canaries() {
+                log::info!(
+                    "Canary configuration of group {} changed, so its canary percentage was reset from {}% \
+                    to {}% and its request counts cleared",
+                    self.name,
+                    previous.canary_percentage.load(Ordering::SeqCst),
+                    self.configured_canary_percentage
+                );
+            }
+            return;
+        }
+        *self.canary_percentage.get_mut() = previous.canary_percentage.load(Ordering::SeqCst);
+        for set in 0..2 {
+            *self.set_requests[set].get_mut() = previous.set_requests[set].load(Ordering::SeqCst);
+            *self.set_errors[set].get_mut() = previous.set_errors[set].load(Ordering::SeqCst);
+        }
+    }
+
+    fn has_canaries(&self) -> bool {
+        !self.upstream_sets[1].is_empty()
+    }
+
+    /// Counts a request forwarded to an upstream server towards its set's totals.
+    fn record_result(&self, upstream_idx: usize, ok: bool) {
+        let set = self.upstream_is_canary[upstream_idx] as usize;
+        self.set_requests[set].fetch_add(1, Ordering::SeqCst);
+        if !ok {
+            self.set_errors[set].fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Describes the canary percentage and how each set of upstreams has been doing, for the admin
+    /// endpoint.
+    fn canary_summary(&self) -> String {
+        let set_summary = |set: usize| {
+            format!(
+                "{} requests ({} errors)",
+                self.set_requests[set].load(Ordering::SeqCst),
+                self.set_errors[set].load(Ordering::SeqCst)
+            )
+        };
+        format!(
+            "{}: canary {}%, stable {}, canary {}",
+            self.name,
+            self.canary_percentage.load(Ordering::SeqCst),
+            set_summary(0),
+            set_summary(1)
+        )
+    }
+
+    /// Folds a response time into an upstream server's moving average, weighting the new sample by
+    /// 1/4.
+    fn record_latency(&self, upstream_idx: usize, latency: time::Duration) {
+        let sample = (latency.as_micros() as u64).max(1);
+        let _ = self.upstream_latency_ewma[upstream_idx].fetch_update(Ordering::SeqCst, Ordering::SeqCst, |ewma| {
+            Some(if ewma == 0 { sample } else { (ewma * 3 + sample) / 4 })
+        });
+    }
+
+    /// Picks a random usable canary or stable upstream server, other than `exclude`, or None if
+    /// there is none.
+    fn pick_random(&self, canary: bool, exclude: Option<usize>, rng: &mut impl Rng) -> Option<usize> {
+        let set = &self.upstream_sets[canary as usize];
+        pick_alive(set, |upstream_idx| Some(upstream_idx) != exclude && self.is_usable(upstream_idx), rng)
+    }
+
+    /// Picks a usable upstream server according to the strategy, or None if there is none.
+    fn pick_upstream(&self, strategy: config::Strategy, rng: &mut impl Rng) -> Option<usize> {
+        // Choose between the canaries and the stable upstreams first, using the other set if none
+        // of the chosen one are usable
+        let mut canary = rng.gen_range(0..100) < self.canary_percentage.load(Ordering::SeqCst);
+        let first = match self.pick_random(canary, None, rng) {
+            Some(first) => first,
+            None => {
+                canary = !canary;
+                match self.pick_random(canary, None, rng) {
+                    Some(first) => first,
+                    // With no other upstream to send it to, the request goes to a backup
+                    None => return pick_alive(&self.backup_upstreams, |idx| self.is_usable(idx), rng),
+                }
+            }
+        };
+        if strategy == config::Strategy::Random {
+            return Some(first);
+        }
+        // Compare with a second, different upstream in the same set, if there is one
+        let second = match self.pick_random(canary, Some(first), rng) {
+            Some(second) => second,
+            None => return Some(first),
+        };
+        // Ties go to the first, which was picked at random anyway
+        let load = |upstream_idx: usize| match strategy {
+            config::Strategy::P2c => self.upstream_in_flight[upstream_idx].load(Ordering::SeqCst) as u64,
+            _ => self.upstream_latency_ewma[upstream_idx].load(Ordering::SeqCst),
+        };
+        Some(if load(second) < load(first) { second } else { first })
+    }
+
+    /// Takes a place for a request on an upstream server, unless it already has `max_requests`
+    /// requests in flight (0 = no limit), or as many as its own limit allows.
+    fn try_reserve(&self, upstream_idx: usize, max_requests: usize) -> Option<InFlight<'_>> {
+        let max_requests = match (self.upstream_max_requests[upstream_idx], max_requests) {
+            (0, max_requests) => max_requests,
+            (own_max, 0) => own_max,
+            (own_max, max_requests) => own_max.min(max_requests),
+        };
+        self.upstream_in_flight[upstream_idx]
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |in_flight| {
+                (max_requests == 0 || in_flight < max_requests).then_some(in_flight + 1)
+            })
+            .ok()?;
+        Some(InFlight { group: self, upstream_idx })
+    }
+
+    /// Picks a usable upstream server according to the strategy and takes a place on it for a
+    /// request. If the one picked already has as many requests in flight as it may (see
+    /// try_reserve), any other usable upstream with room will do, canary or not.
+    fn reserve_upstream(
+        &self,
+        strategy: config::Strategy,
+        max_requests: usize,
+        rng: &mut impl Rng,
+    ) -> Result<InFlight<'_>, UpstreamError> {
+        let upstream_idx = self.pick_upstream(strategy, rng).ok_or(UpstreamError::NoneAlive)?;
+        if let Some(in_flight) = self.try_reserve(upstream_idx, max_requests) {
+            return Ok(in_flight);
+        }
+        self.reserve_any(None, max_requests, rng).ok_or(UpstreamError::Saturated)
+    }
+
+    /// Takes a place for a request on any upstream server in service with room for it other than
+    /// `exclude`, canary or not, trying them in turn from a random one.
+    fn reserve_any(
+        &self,
+        exclude: Option<usize>,
+        max_requests: usize,
+        rng: &mut impl Rng,
+    ) -> Option<InFlight<'_>> {
+        let upstream_num = self.upstream_addresses.len();
+        let start = rng.gen_range(0..upstream_num);
+        (0..upstream_num)
+            .map(|offset| (start + offset) % upstream_num)
+            .filter(|upstream_idx| Some(*upstream_idx) != exclude && self.in_service(*upstream_idx))
+            .find_map(|upstream_idx| self.try_reserve(upstream_idx, max_requests))
+    }
+
+    fn is_alive(&self, upstream_idx: usize) -> bool {
+        self.upstream_address_flags[upstream_idx].load(Ordering::SeqCst)
+    }
+
+    fn admin_state(&self, upstream_idx: usize) -> config::AdminState {
+        config::AdminState::ALL[self.upstream_admin_states[upstream_idx].load(Ordering::SeqCst) as usize]
+    }
+
+    /// Changes whether an upstream server is active, draining or disabled, logging the change
+    fn set_admin_state(&self, upstream_idx: usize, admin_state: config::AdminState) {
+        let previous = self.upstream_admin_states[upstream_idx].swap(admin_state as u8, Ordering::SeqCst);
+        let previous = config::AdminState::ALL[previous as usize];
+        if previous != admin_state {
+            log::info!(
+                "Upstream {} in group {} is now {} (was {})",
+                self.upstream_addresses[upstream_idx],
+                self.name,
+                admin_state,
+                previous
+            );
+        }
+    }
+
+    /// Whether an upstream server can be sent new requests: it has to be both alive and active
+    fn is_usable(&self, upstream_idx: usize) -> bool {
+        self.is_alive(upstream_idx) && self.admin_state(upstream_idx) == config::AdminState::Active
+    }
+
+    /// Whether any upstream server that isn't a backup can be sent new requests
+    fn primary_usable(&self) -> bool {
+        self.upstream_sets.iter().flatten().any(|upstream_idx| self.is_usable(*upstream_idx))
+    }
+
+    /// Whether an upstream server should be sent new requests: it has to be usable, and a backup
+    /// only stands in while no other upstream is
+    fn in_service(&self, upstream_idx: usize) -> bool {
+        self.is_usable(upstream_idx) && (!self.upstream_is_backup[upstream_idx] || !self.primary_usable())
+    }
+
+    /// Marks an upstream server as alive or dead, keeping the alive count in sync
+    fn set_alive(&self, upstream_idx: usize, alive: bool) {
+        // Only the caller that actually flips the flag adjusts the count, so concurrent updates
+        // can't count the same change twice
+        if self.upstream_address_flags[upstream_idx].swap(alive, Ordering::SeqCst) == alive {
+            return;
+        }
+        if alive {
+            if self.upstream_address_alive_num.fetch_add(1, Ordering::SeqCst) == 0 {
+                log::info!("Group {} has an alive upstream again", self.name);
+            }
+        } else if self.upstream_address_alive_num.fetch_sub(1, Ordering::SeqCst) == 1 {
+            log::warn!("All upstreams in group {} are dead", self.name);
+        }
+        if self.upstream_is_backup[upstream_idx] || self.backup_upstreams.is_empty() {
+            return;
+        }
+        let primaries_alive = self.upstream_sets.iter().flatten().filter(|idx| self.is_alive(**idx)).count();
+        match (alive, primaries_alive) {
+            (true, 1) => log::info!("Group {} has an alive primary upstream again", self.name),
+            (false, 0) => log::warn!("All primary upstreams in group {} are dead; using backups", self.name),
+            _ => {}
+        }
+    }
+}
+
+/// Picks one of the upstream servers in `candidates` that `is_alive` accepts, uniformly at random, or
+/// None if it accepts none of them. The alive ones are gathered first, so that dead upstreams cost
+/// nothing more than checking their flags, however many of them there are.
+fn pick_alive(candidates: &[usize], is_alive: impl Fn(usize) -> bool, rng: &mut impl Rng) -> Option<usize> {
+    let alive: Vec<usize> = candidates.iter().copied().filter(|idx| is_alive(*idx)).collect();
+    if alive.is_empty() {
+        return None;
+    }
+    Some(alive[rng.gen_range(0..alive.len())])
+}
+
+/// Why connect_to_upstream couldn't open a connection
+#[derive(Debug)]
+enum UpstreamError {
+    /// Every upstream in the group was already marked dead
+    NoneAlive,
+    /// The upstreams we tried all failed to connect, leaving none alive
+    AllFailed,
+    /// Every alive upstream already had as many requests in flight as it may, and the request
+    /// couldn't wait (or waited too long) for one to finish
+    Saturated,
 }
 
 /// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
 /// to, what servers have failed, rate limiting counts, etc.)
 ///
-/// You should add fields to this struct in later milestones.
+/// The state is shared between connections without a global lock: most of the configuration never
+/// changes after startup, the part that SIGHUP reloads is swapped out whole behind a lock that is
+/// only held long enough to clone an Arc, and the parts that do change (upstream health, rate
+/// limiting counts, the cache) handle their own synchronization.
 struct ProxyState {
+    /// Upstream groups, routes and other settings that can be reloaded from the config file
+    config: RwLock<Arc<RuntimeConfig>>,
+    /// Command-line options that the configuration is built from again when it is reloaded
+    reloadable_options: ReloadableOptions,
+    /// Request and byte counts for each IP (Milestone 5)
+    rate_limiter: rate_limit::RateLimiter,
+    /// Whether response bodies count against the byte limit
+    count_response_bytes: bool,
+    /// How often the rate limiting counts are reset
+    rate_limit_window: time::Duration,
+    /// Limits on the size of client requests' heads
+    request_limits: request::Limits,
+    /// Number of requests each upstream server may have in flight at once (0 = unlimited)
+    max_upstream_requests: usize,
+    /// Number of requests per group that may wait for an upstream server to have room
+    queue_depth: usize,
+    /// How long a request may wait for an upstream server to have room
+    queue_timeout: time::Duration,
+    /// Number of requests a client can send on one connection before we close it (0 = unlimited)
+    max_requests_per_connection: usize,
+    /// Cached responses to GET requests, if caching is enabled
+    response_cache: Option<Mutex<cache::ResponseCache>>,
+    /// Whether to gzip responses for clients that accept it
+    compress: bool,
+    /// Name we add to Via headers. Requests that already carry it have looped back to us.
+    via_pseudonym: String,
+    /// Hosts that requests may be addressed to. Any host is allowed if this is empty.
+    allowed_hosts: Vec<config::HostPattern>,
+    /// Methods that requests may use
+    allowed_methods: config::AllowedMethods,
+    /// Which client IP addresses may connect
+    ip_filter: config::IpFilter,
+    /// How to turn away clients that may not connect
+    deny_action: config::DenyAction,
+    /// Users that clients must authenticate as, if authentication is required. It is behind an Arc
+    /// so that checking passwords can be moved off the async worker threads.
+    basic_auth: Option<Arc<auth::BasicAuth>>,
+    /// Whether to remove the Authorization header from requests before forwarding them
+    basic_auth_strip: bool,
+    /// Targets that clients may open CONNECT tunnels to. CONNECT is disabled if this is empty.
+    connect_allowlist: Vec<config::ConnectPattern>,
+    /// Custom bodies for error responses
+    error_pages: response::ErrorPages,
+    /// How to choose among the upstreams of a group
+    strategy: config::Strategy,
+    /// Shadow upstream that gets copies of requests, whose responses are thrown away
+    mirror_upstream: Option<String>,
+    /// Percentage of requests that are copied to the mirror upstream
+    mirror_percentage: u8,
+    /// How long an upstream has to send the head of its response once it has the request
+    upstream_header_timeout: Option<time::Duration>,
+    /// How long an upstream may go without sending any of a response body
+    upstream_idle_timeout: Option<time::Duration>,
+    /// How long opening a connection to an upstream may take
+    upstream_connect_timeout: time::Duration,
+    /// How long an upstream has to send the head of its response before the request is hedged
+    hedge_after: Option<time::Duration>,
+    /// Methods that are safe to send again when a kept-alive upstream connection turns out to be dead
+    retry_methods: config::AllowedMethods,
+    /// Proxied requests that take longer than this are logged as slow
+    slow_request_threshold: Option<time::Duration>,
+    /// Whether connections start with a PROXY protocol header giving the client's real address
+    accept_proxy_protocol: bool,
+    /// Path at which we report whether we can serve requests
+    self_health_path: Option<String>,
+    /// Path at which we report whether we can serve requests and have checked every upstream
+    self_ready_path: Option<String>,
+}
+
+/// The part of the configuration that SIGHUP reloads from the config file. A reload replaces it as
+/// a whole, and each request keeps using the configuration it was routed with until it is done, so
+/// requests already under way aren't disturbed: upstreams that were removed finish the requests they
+/// have, but get no new ones.
+struct RuntimeConfig {
+    /// Groups of servers that we are proxying to
+    upstream_groups: Vec<UpstreamGroup>,
+    /// Rules for choosing the group that handles a request
+    routes: config::Routes,
+    /// How upstream servers are health checked
+    health_check: HealthCheckSettings,
+    /// Maximum number of requests an individual IP can make per rate limit window (0 = unlimited)
+    max_requests_per_minute: usize,
+    /// Maximum number of body bytes an individual IP can send per rate limit window (0 = unlimited)
+    max_bytes_per_minute: u64,
+}
+
+impl RuntimeConfig {
+    /// Finds the upstream group with the given name
+    fn group(&self, name: &str) -> Option<&UpstreamGroup> {
+        self.upstream_groups.iter().find(|group| group.name == name)
+    }
+}
+
+/// How upstream servers are health checked
+struct HealthCheckSettings {
     /// How frequently we check whether upstream servers are alive (Milestone 4)
-    #[allow(dead_code)]
-    active_health_check_interval: usize,
+    interval: time::Duration,
+    /// How frequently we check whether dead upstream servers have come back
+    dead_interval: time::Duration,
+    /// Upper bound on the backed-off health check interval for upstreams that keep failing
+    max_backoff: time::Duration,
+    /// Fraction by which each wait between health checks is randomly lengthened or shortened
+    jitter: f64,
     /// Where we should send requests when doing active health checks (Milestone 4)
-    #[allow(dead_code)]
-    active_health_check_path: String,
-    /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
-    #[allow(dead_code)]
-    max_requests_per_minute: usize,
-    /// Addresses of servers that we are proxying to
-    upstream_addresses: Vec<String>,
-    /// Flags that indicate whether the upstream server is alive
-    upstream_address_flags: Vec<bool>,
-    /// Number of alive upstream servers
-    upstream_address_alive_num: usize,
-    /// Counter for each IP
-    rate_limiting_counter: HashMap<String, usize>,
+    path: String,
+    /// Text that health check responses must contain
+    expect_body: Option<String>,
+    /// Pattern that health check responses must match
+    expect_regex: Option<regex::Regex>,
+}
+
+/// Builds the configuration from the command-line options and the config file, if there is one.
+/// When reloading, `previous` is the configuration in use until now, whose upstreams carry their
+/// state over into the new one (see UpstreamGroup::inherit).
+fn load_config(
+    options: &ReloadableOptions,
+    previous: Option<&RuntimeConfig>,
+) -> Result<RuntimeConfig, String> {
+    let mut config_file = match &options.config {
+        Some(path) => config::ConfigFile::from_file(path)?,
+        None => config::ConfigFile::default(),
+    };
+    let interval = config_file.active_health_check_interval.unwrap_or(options.active_health_check_interval);
+    let dead_interval = config_file
+        .active_health_check_dead_interval
+        .or(options.active_health_check_dead_interval)
+        .unwrap_or(interval);
+    if interval.is_zero() || dead_interval.is_zero() {
+        return Err("Active health check intervals must be greater than zero".to_string());
+    }
+    if !(0.0..1.0).contains(&options.active_health_check_jitter) {
+        return Err("--active-health-check-jitter must be at least 0 and less than 1".to_string());
+    }
+    let health_check = HealthCheckSettings {
+        interval,
+        dead_interval,
+        max_backoff: options.max_probe_backoff,
+        jitter: options.active_health_check_jitter,
+        path: config_file
+            .active_health_check_path
+            .take()
+            .unwrap_or_else(|| options.active_health_check_path.clone()),
+        expect_body: options.active_health_check_expect_body.clone(),
+        expect_regex: options.active_health_check_expect_regex.clone(),
+    };
+    let max_requests_per_minute =
+        config_file.max_requests_per_minute.unwrap_or(options.max_requests_per_minute);
+    let max_bytes_per_minute = config_file.max_bytes_per_minute.unwrap_or(options.max_bytes_per_minute);
+
+    let backup_upstreams = options.backup_upstream.iter().map(|addr| format!("{}=backup", addr));
+    let (groups, routes) = config::Routes::build(
+        options.upstream.iter().cloned().chain(backup_upstreams).collect(),
+        options.group.clone(),
+        options.route.clone(),
+        options.default_group.clone(),
+        config_file,
+    )?;
+    let mut upstream_groups = Vec::new();
+    for spec in groups {
+        let mut group = UpstreamGroup::new(spec)?;
+        if let Some(previous) = previous {
+            group.inherit(previous.group(&group.name));
+        }
+        upstream_groups.push(group);
+    }
+    Ok(RuntimeConfig {
+        upstream_groups,
+        routes,
+        health_check,
+        max_requests_per_minute,
+        max_bytes_per_minute,
+    })
+}
+
+impl ProxyState {
+    /// The configuration as it is now. Anything that has to stay consistent while it works, like a
+    /// request being forwarded, should hold on to this rather than asking again.
+    fn config(&self) -> Arc<RuntimeConfig> {
+        self.config.read().clone()
+    }
+
+    /// Whether any upstream server in any group is alive
+    fn any_alive(&self) -> bool {
+        self.config()
+            .upstream_groups
+            .iter()
+            .any(|group| group.upstream_address_alive_num.load(Ordering::SeqCst) > 0)
+    }
+
+    /// Whether every upstream server in every group has been health checked at least once
+    fn all_probed(&self) -> bool {
+        self.config()
+            .upstream_groups
+            .iter()
+            .all(|group| group.upstream_probed.iter().all(|probed| probed.load(Ordering::SeqCst)))
+    }
+
+    /// Answers a request for the self health or readiness path, or returns None if the request is
+    /// for some other path.
+    fn self_check_response(&self, request: &http::Request<Vec<u8>>) -> Option<http::Response<Vec<u8>>> {
+        let path = Some(request.uri().path());
+        let waiting = if path == self.self_health_path.as_deref() {
+            false
+        } else if path == self.self_ready_path.as_deref() {
+            !self.all_probed()
+        } else {
+            return None;
+        };
+        let (status, body) = if waiting {
+            (http::StatusCode::SERVICE_UNAVAILABLE, "Waiting for the first health checks\n")
+        } else if self.any_alive() {
+            (http::StatusCode::OK, "OK\n")
+        } else {
+            (http::StatusCode::SERVICE_UNAVAILABLE, "No upstreams are alive\n")
+        };
+        Some(response::make_text_response(status, body.to_string()))
+    }
+
+    /// Formats the statistics of every upstream server as a table, followed by the number of
+    /// requests queued in each group, for SIGUSR2 and the admin endpoint.
+    fn stats_table(&self) -> String {
+        let config = self.config();
+        let mut table = stats::format_table(config.upstream_groups.iter().flat_map(|group| {
+            (0..group.upstream_addresses.len()).map(move |upstream_idx| stats::Row {
+                group: &group.name,
+                address: &group.upstream_addresses[upstream_idx],
+                stats: &group.upstream_stats[upstream_idx],
+                in_flight: group.upstream_in_flight[upstream_idx].load(Ordering::SeqCst),
+            })
+        }));
+        for group in &config.upstream_groups {
+            table.push_str(&format!("Queued in {}: {}\n", group.name, group.queued.load(Ordering::SeqCst)));
+        }
+        table
+    }
 }
 
 #[tokio::main]
@@ -57,20 +902,66 @@ async fn main() {
     // Initialize the logging library. You can print log messages using the `log` macros:
     // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
     // just looks a little prettier.
-    if let Err(_) = std::env::var("RUST_LOG") {
+    if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "debug");
     }
     pretty_env_logger::init();
 
     // Parse the command line arguments passed to this program
     let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
+    let config = match load_config(&options.reloadable, None) {
+        Ok(config) => config,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    if options.rate_limit_window.is_zero() {
+        log::error!("--rate-limit-window must be greater than zero");
+        std::process::exit(1);
+    }
+    if options.max_header_bytes == 0 || options.max_headers == 0 || options.max_uri_length == 0 {
+        log::error!("--max-header-bytes, --max-headers and --max-uri-length must be at least 1");
+        std::process::exit(1);
+    }
+    if options.via_pseudonym.is_empty()
+        || options.via_pseudonym.contains(|c: char| c.is_whitespace() || c == ',')
+    {
+        log::error!("--via-pseudonym must be a single token without spaces or commas");
         std::process::exit(1);
     }
 
+    let mirror_upstream = options.mirror_upstream.as_deref().map(config::normalize_upstream_address);
+    let mirror_upstream = match mirror_upstream.transpose() {
+        Ok(mirror_upstream) => mirror_upstream,
+        Err(err) => {
+            log::error!("--mirror-upstream: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let basic_auth = match &options.basic_auth_file {
+        Some(path) => match auth::BasicAuth::load(path) {
+            Ok(basic_auth) => Some(Arc::new(basic_auth)),
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let error_pages = match response::ErrorPages::load(&options.error_page) {
+        Ok(error_pages) => error_pages,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
     // Start listening for connections
-    let listener = match TcpListener::bind(&options.bind).await {
+    let listener = match bind_listener(&options.bind, options.unix_socket_mode).await {
         Ok(listener) => listener,
         Err(err) => {
             log::error!("Could not bind to {}: {}", options.bind, err);
@@ -78,102 +969,512 @@ async fn main() {
         }
     };
     log::info!("Listening for requests on {}", options.bind);
+    let admin_listener = match &options.admin_bind {
+        Some(admin_bind) => match TcpListener::bind(admin_bind).await {
+            Ok(listener) => {
+                log::info!("Serving the admin endpoint on {}", admin_bind);
+                Some(listener)
+            }
+            Err(err) => {
+                log::error!("Could not bind to {}: {}", admin_bind, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
 
     // Handle incoming connections
-    let upstream_address_num = options.upstream.len();
-    let state = Arc::new(RwLock::new(ProxyState {
-        upstream_addresses: options.upstream,
-        active_health_check_interval: options.active_health_check_interval,
-        active_health_check_path: options.active_health_check_path,
-        max_requests_per_minute: options.max_requests_per_minute,
-        upstream_address_flags: vec![true; upstream_address_num],
-        upstream_address_alive_num: upstream_address_num,
-        rate_limiting_counter: HashMap::new(),
-    }));
-
-    let state_ref = state.clone();
-    tokio::spawn(async move {
-        active_health_check(&state_ref).await;
+    let state = Arc::new(ProxyState {
+        rate_limiter: rate_limit::RateLimiter::new(
+            config.max_requests_per_minute,
+            config.max_bytes_per_minute,
+        ),
+        config: RwLock::new(Arc::new(config)),
+        reloadable_options: options.reloadable,
+        count_response_bytes: options.count_response_bytes,
+        rate_limit_window: options.rate_limit_window,
+        request_limits: request::Limits {
+            max_header_bytes: options.max_header_bytes,
+            max_headers: options.max_headers,
+            max_uri_length: options.max_uri_length,
+            max_body_bytes: options.max_body_bytes,
+        },
+        max_upstream_requests: options.max_upstream_requests,
+        queue_depth: options.queue_depth,
+        queue_timeout: options.queue_timeout,
+        max_requests_per_connection: options.max_requests_per_connection,
+        response_cache: match options.cache_max_bytes {
+            0 => None,
+            max_bytes => Some(Mutex::new(cache::ResponseCache::new(
+                max_bytes,
+                options.cache_max_entry_bytes,
+            ))),
+        },
+        compress: options.compress,
+        via_pseudonym: options.via_pseudonym,
+        allowed_hosts: options.allowed_host,
+        allowed_methods: options.allowed_methods,
+        ip_filter: config::IpFilter::new(options.allow_ip, options.deny_ip, options.default_ip_action),
+        deny_action: options.deny_action,
+        basic_auth,
+        basic_auth_strip: options.basic_auth_strip,
+        connect_allowlist: options.allow_connect,
+        error_pages,
+        strategy: options.strategy,
+        mirror_upstream,
+        mirror_percentage: options.mirror_percentage,
+        upstream_header_timeout: options.upstream_header_timeout,
+        upstream_idle_timeout: options.upstream_idle_timeout,
+        upstream_connect_timeout: options.upstream_connect_timeout,
+        hedge_after: options.hedge_after,
+        retry_methods: options.retry_methods,
+        slow_request_threshold: options.slow_request_threshold,
+        accept_proxy_protocol: options.accept_proxy_protocol,
+        self_health_path: options.self_health_path,
+        self_ready_path: options.self_ready_path,
     });
 
+    active_health_check(&state, &state.config());
+
     let state_ref = state.clone();
     tokio::spawn(async move {
         rate_limiting_counter_clear(&state_ref).await;
     });
 
-    loop {
-        if let Ok((stream, _)) = listener.accept().await {
+    // Listen for SIGUSR2 and SIGHUP before accepting connections, since their default action would
+    // kill us
+    match signal(SignalKind::user_defined2()) {
+        Ok(signals) => {
             let state_ref = state.clone();
             tokio::spawn(async move {
-                handle_connection(stream, &state_ref).await;
+                log_stats_on_signal(signals, &state_ref).await;
             });
         }
+        Err(err) => log::warn!("Could not listen for SIGUSR2: {}", err),
+    }
+    match signal(SignalKind::hangup()) {
+        Ok(signals) => {
+            let state_ref = state.clone();
+            tokio::spawn(async move {
+                reload_on_signal(signals, &state_ref).await;
+            });
+        }
+        Err(err) => log::warn!("Could not listen for SIGHUP: {}", err),
+    }
+
+    if let Some(admin_listener) = admin_listener {
+        let state_ref = state.clone();
+        tokio::spawn(async move {
+            admin::serve(admin_listener, state_ref).await;
+        });
+    }
+
+    match listener {
+        Listener::Tcp(listener) => serve_tcp(listener, state).await,
+        Listener::Unix(listener) => serve_unix(listener, state).await,
     }
 }
 
-async fn connect_to_upstream(state: &RwLock<ProxyState>) -> Result<TcpStream, std::io::Error> {
-    let mut rng = rand::rngs::StdRng::from_entropy();
-    // let upstream_idx = rng.gen_range(0..state.upstream_addresses.len());
-    // let upstream_ip = &state.upstream_addresses[upstream_idx];
-    // TcpStream::connect(upstream_ip).await.or_else(|err| {
-    //     log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
-    //     Err(err)
-    // })
-    // TODO: implement failover (milestone 3)
+/// Socket that clients connect to
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Binds to an IP/port, or to a Unix domain socket if the address is unix:PATH.
+async fn bind_listener(bind: &str, unix_socket_mode: Option<u32>) -> std::io::Result<Listener> {
+    let path = match bind.strip_prefix("unix:") {
+        Some(path) => path,
+        None => return Ok(Listener::Tcp(TcpListener::bind(bind).await?)),
+    };
+    // A socket file left behind by an instance that didn't shut down cleanly would make binding
+    // fail. Remove it, but only if nothing is accepting connections on it any more.
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() && std::os::unix::net::UnixStream::connect(path).is_err() {
+            log::info!("Removing stale socket file {}", path);
+            std::fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = unix_socket_mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(Listener::Unix(listener))
+}
+
+/// Accepts TCP connections, refusing those from IP addresses that aren't allowed.
+async fn serve_tcp(listener: TcpListener, state: Arc<ProxyState>) {
     loop {
-        let state_r = state.read().await;
-        if state_r.upstream_address_alive_num == 0 {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "No alive upstream addresses"));
+        if let Ok((stream, client_addr)) = listener.accept().await {
+            // Requests and responses are written in several small pieces, which Nagle's algorithm
+            // would hold back waiting for delayed ACKs
+            let _ = stream.set_nodelay(true);
+            let state_ref = state.clone();
+            // A listener bound to [::] sees IPv4 clients as IPv4-mapped IPv6 addresses, which
+            // would neither match IPv4 ranges nor share rate limits with the same client arriving
+            // over IPv4
+            let client_ip = client_addr.ip().to_canonical();
+            if !state.ip_filter.allows(client_ip) {
+                log::info!("Refusing connection from {}", client_ip);
+                if state.deny_action == config::DenyAction::Forbidden {
+                    tokio::spawn(async move {
+                        refuse_connection(stream, &client_ip.to_string(), &state_ref).await;
+                    });
+                }
+                continue;
+            }
+            tokio::spawn(async move {
+                handle_connection(stream, Some(client_ip.to_string()), &state_ref).await;
+            });
         }
-        let upstream_idx = rng.gen_range(0..state_r.upstream_addresses.len());
-        if !state_r.upstream_address_flags[upstream_idx] {
-            continue;
+    }
+}
+
+/// Accepts connections on a Unix domain socket. Clients are identified by the user ID of the
+/// process on the other end, which stands in for the IP address in rate limiting, logging and
+/// X-Forwarded-For.
+async fn serve_unix(listener: UnixListener, state: Arc<ProxyState>) {
+    loop {
+        if let Ok((stream, _)) = listener.accept().await {
+            let peer_id = stream.peer_cred().ok().map(|cred| format!("unix:uid={}", cred.uid()));
+            let state_ref = state.clone();
+            tokio::spawn(async move {
+                handle_connection(stream, peer_id, &state_ref).await;
+            });
         }
-        let upstream_ip = &state_r.upstream_addresses[upstream_idx];
-        match TcpStream::connect(upstream_ip).await {
-            Ok(stream) => {
-                return Ok(stream);
-            }
+    }
+}
+
+/// Opens a connection to an alive upstream server in the group that has room for another request,
+/// returning the index of the upstream along with the connection and the request's place on it.
+async fn connect_to_upstream<'a>(
+    state: &ProxyState,
+    group: &'a UpstreamGroup,
+) -> Result<(usize, UpstreamConn, InFlight<'a>), UpstreamError> {
+    let mut rng = rand::rngs::StdRng::from_entropy();
+    let mut tried_any = false;
+    // Each failed attempt marks an upstream dead, so there's no use in trying more upstreams than
+    // were alive to begin with. Stopping there also keeps us from going round forever if health
+    // checks keep bringing upstreams back.
+    let attempts = group.upstream_address_alive_num.load(Ordering::SeqCst).max(1);
+    for _ in 0..attempts {
+        let reserved = match group.reserve_upstream(state.strategy, state.max_upstream_requests, &mut rng) {
+            Err(UpstreamError::Saturated) => wait_for_upstream(state, group, &mut rng).await,
+            reserved => reserved,
+        };
+        let in_flight = match reserved {
+            Ok(in_flight) => in_flight,
+            Err(UpstreamError::NoneAlive) if tried_any => return Err(UpstreamError::AllFailed),
+            Err(error) => return Err(error),
+        };
+        let upstream_idx = in_flight.upstream_idx;
+        let upstream_ip = &group.upstream_addresses[upstream_idx];
+        match UpstreamConn::connect(upstream_ip, state.upstream_connect_timeout).await {
+            Ok(stream) => return Ok((upstream_idx, stream, in_flight)),
             Err(err) => {
                 log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
-                drop(state_r);
-                let mut state_w = state.write().await;
-                state_w.upstream_address_flags[upstream_idx] = false;
-                state_w.upstream_address_alive_num -= 1;
+                tried_any = true;
+                group.upstream_stats[upstream_idx].record_connect_failure();
+                group.record_latency(upstream_idx, FAILURE_LATENCY);
+                group.set_alive(upstream_idx, false);
             }
         }
     }
+    Err(UpstreamError::AllFailed)
+}
+
+/// Waits in the group's queue until an alive upstream server has room for another request, giving up
+/// once the queue timeout has passed. There is no waiting if the queue is already full.
+async fn wait_for_upstream<'a>(
+    state: &ProxyState,
+    group: &'a UpstreamGroup,
+    rng: &mut impl Rng,
+) -> Result<InFlight<'a>, UpstreamError> {
+    let joined = group.queued.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+        (queued < state.queue_depth).then_some(queued + 1)
+    });
+    if joined.is_err() {
+        return Err(UpstreamError::Saturated);
+    }
+    let deadline = time::Instant::now() + state.queue_timeout;
+    let reserved = loop {
+        // Created before checking for room, so that a request finishing in between still wakes us
+        let room_made = group.capacity_freed.notified();
+        match group.reserve_upstream(state.strategy, state.max_upstream_requests, rng) {
+            Err(UpstreamError::Saturated) => {}
+            reserved => break reserved,
+        }
+        if time::timeout_at(deadline, room_made).await.is_err() {
+            break Err(UpstreamError::Saturated);
+        }
+    };
+    group.queued.fetch_sub(1, Ordering::SeqCst);
+    reserved
+}
+
+/// A copy of a request sent to a second upstream server because the first was slow to answer it
+struct Hedge<'a> {
+    upstream_idx: usize,
+    conn: UpstreamConn,
+    in_flight: InFlight<'a>,
+    /// When the copy was sent
+    started: time::Instant,
+}
+
+/// Sends a copy of a request that `upstream_idx` is slow to answer to another usable upstream in the
+/// group with room for it. Returns None if there is no such upstream, or the copy couldn't be sent.
+async fn start_hedge<'a>(
+    state: &ProxyState,
+    group: &'a UpstreamGroup,
+    upstream_idx: usize,
+    request: &http::Request<Vec<u8>>,
+) -> Option<Hedge<'a>> {
+    let mut rng = rand::rngs::StdRng::from_entropy();
+    let in_flight = group.reserve_any(Some(upstream_idx), state.max_upstream_requests, &mut rng)?;
+    let hedge_idx = in_flight.upstream_idx;
+    let hedge_ip = &group.upstream_addresses[hedge_idx];
+    let mut conn = match UpstreamConn::connect(hedge_ip, state.upstream_connect_timeout).await {
+        Ok(conn) => conn,
+        Err(error) => {
+            log::error!("Failed to connect to upstream {}: {}", hedge_ip, error);
+            group.upstream_stats[hedge_idx].record_connect_failure();
+            group.record_latency(hedge_idx, FAILURE_LATENCY);
+            group.set_alive(hedge_idx, false);
+            return None;
+        }
+    };
+    let started = time::Instant::now();
+    if let Err(error) = request::write_to_stream(request, &mut conn).await {
+        log::error!("Failed to send hedged request to upstream {}: {}", hedge_ip, error);
+        group.record_latency(hedge_idx, FAILURE_LATENCY);
+        group.record_result(hedge_idx, false);
+        group.upstream_stats[hedge_idx].record_failure();
+        return None;
+    }
+    let slow_ip = &group.upstream_addresses[upstream_idx];
+    log::info!("Upstream {} is slow to answer; hedging to {}", slow_ip, hedge_ip);
+    group.upstream_stats[hedge_idx].record_hedge();
+    Some(Hedge { upstream_idx: hedge_idx, conn, in_flight, started })
 }
 
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+/// Reads the head of the response to a request from the upstream it was sent to. If the request may
+/// be hedged and the head hasn't arrived after --hedge-after, a copy goes to another upstream too,
+/// and the first to send a response wins. The hedge is returned if it won. Either way, the loser's
+/// read is dropped when this returns, and so is its connection if it was the hedge's.
+async fn read_head_hedged<'a>(
+    state: &ProxyState,
+    group: &'a UpstreamGroup,
+    upstream_idx: usize,
+    upstream_conn: &mut UpstreamConn,
+    request: &http::Request<Vec<u8>>,
+    may_hedge: bool,
+) -> Result<(http::Response<Vec<u8>>, Option<Hedge<'a>>), response::Error> {
+    let primary = response::read_head(upstream_conn, request.method());
+    tokio::pin!(primary);
+    let hedge_after = match state.hedge_after {
+        Some(hedge_after) if may_hedge => hedge_after,
+        _ => return primary.await.map(|response| (response, None)),
+    };
+    let started = tokio::select! {
+        head = &mut primary => return head.map(|response| (response, None)),
+        hedge = async {
+            time::sleep(hedge_after).await;
+            start_hedge(state, group, upstream_idx, request).await
+        } => hedge,
+    };
+    let Hedge { upstream_idx: hedge_idx, conn: mut hedge_conn, in_flight, started } = match started {
+        Some(hedge) => hedge,
+        None => return primary.await.map(|response| (response, None)),
+    };
+    // The hedge's read owns its connection, so that the connection can be handed back if it wins
+    let method = request.method().clone();
+    let hedged = async move {
+        let head = response::read_head(&mut hedge_conn, &method).await;
+        (head, hedge_conn)
+    };
+    tokio::pin!(hedged);
+    // If the first upstream fails, the hedge may still answer
+    let mut primary_error = None;
+    loop {
+        tokio::select! {
+            head = &mut primary, if primary_error.is_none() => match head {
+                Ok(response) => return Ok((response, None)),
+                Err(error) => primary_error = Some(error),
+            },
+            (head, conn) = &mut hedged => match head {
+                Ok(response) => {
+                    if let Some(error) = primary_error {
+                        log::error!("Error reading response from server: {:?}", error);
+                        group.record_latency(upstream_idx, FAILURE_LATENCY);
+                        group.record_result(upstream_idx, false);
+                        group.upstream_stats[upstream_idx].record_failure();
+                    }
+                    group.upstream_stats[hedge_idx].record_hedge_win();
+                    return Ok((response, Some(Hedge { upstream_idx: hedge_idx, conn, in_flight, started })));
+                }
+                Err(error) => {
+                    log::error!("Error reading hedged response from server: {:?}", error);
+                    group.record_latency(hedge_idx, FAILURE_LATENCY);
+                    group.record_result(hedge_idx, false);
+                    group.upstream_stats[hedge_idx].record_failure();
+                    return match primary_error {
+                        Some(error) => Err(error),
+                        None => primary.await.map(|response| (response, None)),
+                    };
+                }
+            },
+        }
+    }
+}
+
+/// Answers the first request from a client whose IP address isn't allowed with 403, then closes the
+/// connection.
+async fn refuse_connection(mut client_conn: TcpStream, client_ip: &str, state: &ProxyState) {
+    if request::read_head(&mut client_conn, &state.request_limits).await.is_ok() {
+        let response = state.error_pages.make_http_error(http::StatusCode::FORBIDDEN);
+        send_response(&mut client_conn, client_ip, response, true).await;
+    }
+}
+
+/// Sends the head of a response to the client, then streams the rest of its body from the upstream
+/// as it arrives. If `close` is set, the response tells the client that we are closing the
+/// connection after it. Returns the size of the body if the whole response was forwarded.
+#[allow(clippy::too_many_arguments)]
+async fn stream_response(
+    client_conn: &mut (impl AsyncWrite + Unpin),
+    client_ip: &str,
+    mut response: http::Response<Vec<u8>>,
+    upstream_conn: &mut UpstreamConn,
+    upstream_ip: &str,
+    remaining_body: response::RemainingBody,
+    idle_timeout: Option<time::Duration>,
+    close: bool,
+) -> Option<usize> {
+    if close {
+        response.headers_mut().insert("connection", http::HeaderValue::from_static("close"));
+    }
+    // A chunked body is decoded as it arrives and, unless we are about to close the connection,
+    // sent on in chunks of our own, so its framing replaces any Content-Length the upstream sent
+    let chunked = remaining_body == response::RemainingBody::Chunked;
+    let mut buffered = Vec::new();
+    if chunked {
+        buffered = std::mem::take(response.body_mut());
+        response.headers_mut().remove("content-length");
+        if !close {
+            response.headers_mut().insert("transfer-encoding", http::HeaderValue::from_static("chunked"));
+        }
+    }
+    let response_line = response::format_response_line(&response);
+    log::debug!("{} <- {} (streaming)", client_ip, response_line);
+    if let Err(error) = response::write_to_stream(&response, client_conn).await {
+        log::warn!("Failed to send response to client: {}", error);
+        return None;
+    }
+    let forwarded = if chunked {
+        response::forward_chunked_body(upstream_conn, client_conn, buffered, !close, idle_timeout).await
+    } else {
+        response::forward_body(upstream_conn, client_conn, remaining_body, idle_timeout).await
+    };
+    match forwarded {
+        Ok(streamed) => {
+            let body_bytes = response.body().len() + streamed;
+            log::info!("{} <- {} ({} body bytes)", client_ip, response_line, body_bytes);
+            Some(body_bytes)
+        }
+        // The client already has the head, so all we can do is cut the response short
+        Err(response::ForwardError::Upstream(error)) => {
+            log::error!("Error reading response body from upstream {}: {:?}", upstream_ip, error);
+            None
+        }
+        Err(response::ForwardError::Client(error)) => {
+            log::warn!("Failed to send response to client: {}", error);
+            None
+        }
+    }
+}
+
+/// Sends a response to the client. If `close` is set, the response tells the client that we are
+/// closing the connection after it.
+async fn send_response(
+    client_conn: &mut (impl AsyncWrite + Unpin),
+    client_ip: &str,
+    mut response: http::Response<Vec<u8>>,
+    close: bool,
+) {
+    if close {
+        response.headers_mut().insert("connection", http::HeaderValue::from_static("close"));
+    }
     log::info!("{} <- {}", client_ip, response::format_response_line(&response));
     if let Err(error) = response::write_to_stream(&response, client_conn).await {
         log::warn!("Failed to send response to client: {}", error);
-        return;
     }
 }
 
-async fn handle_connection(mut client_conn: TcpStream, state: &RwLock<ProxyState>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+/// Serves the requests a client sends on a connection. `peer_ip` identifies the client for rate
+/// limiting, logging and X-Forwarded-For; without it, the client is still served, but isn't rate
+/// limited. IP addresses must be in canonical form (see IpAddr::to_canonical), written the standard
+/// way, so that each client has exactly one identity.
+async fn handle_connection(
+    mut client_conn: impl AsyncRead + AsyncWrite + Unpin,
+    mut peer_ip: Option<String>,
+    state: &ProxyState,
+) {
+    // Behind a load balancer speaking the PROXY protocol, the connection comes from the load
+    // balancer, and the header it sends first tells us who the client really is
+    if state.accept_proxy_protocol {
+        match proxy_protocol::read_header(&mut client_conn).await {
+            Ok(Some(source)) => peer_ip = Some(source.ip().to_canonical().to_string()),
+            // The header doesn't name a client, so the connection is the load balancer's own
+            Ok(None) => {}
+            Err(error) => {
+                log::info!(
+                    "Rejecting connection from {}: bad PROXY protocol header: {:?}",
+                    peer_ip.as_deref().unwrap_or("unknown"),
+                    error
+                );
+                return;
+            }
+        }
+    }
+    let client_ip = peer_ip.as_deref().unwrap_or("unknown");
     log::info!("Connection received from {}", client_ip);
-
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(state).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+    // With --count-response-bytes, the bodies of proxied responses use up the client's byte
+    // allowance too. They can't be turned away once the upstream has sent them, but they stop the
+    // client's next requests.
+    let count_response_bytes = |bytes: usize| {
+        if let (true, Some(peer_ip)) = (state.count_response_bytes, &peer_ip) {
+            state.rate_limiter.add_bytes(peer_ip, bytes as u64);
         }
     };
-    let upstream_ip = upstream_conn.peer_addr().unwrap().ip().to_string();
+
+    // Connection to the upstream server, along with the configuration and group it belongs to. We
+    // open it once we know which group the first request is routed to, and reopen it if a later
+    // request on this connection is routed to a different group or the configuration has been
+    // reloaded.
+    let mut upstream: Option<(Arc<RuntimeConfig>, usize, usize, UpstreamConn, String)> = None;
+
+    // Number of requests the client has sent on this connection. Once it reaches the maximum, our
+    // response says that we are closing the connection, and the client has to reconnect (possibly
+    // getting routed to a different upstream) to send more.
+    let mut requests_received = 0;
+    let mut closing = false;
 
     // The client may now send us one or more requests. Keep trying to read requests until the
-    // client hangs up or we get an error.
+    // client hangs up, we get an error, or it has sent as many requests as it may.
     loop {
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
+        if closing {
+            log::debug!("Closing connection from {} after {} requests", client_ip, requests_received);
+            return;
+        }
+
+        // Read the head of a request from the client. Its body is streamed to the upstream later,
+        // rather than buffered here.
+        let request = request::read_head(&mut client_conn, &state.request_limits).await;
+        let request_received = time::Instant::now();
+        requests_received += 1;
+        let at_request_limit = requests_received == state.max_requests_per_connection;
+        closing = at_request_limit;
+        let mut request = match request {
             Ok(request) => request,
             // Handle case where client closed connection and is no longer sending requests
             Err(request::Error::IncompleteRequest(0)) => {
@@ -187,18 +1488,236 @@ async fn handle_connection(mut client_conn: TcpStream, state: &RwLock<ProxyState
             }
             Err(error) => {
                 log::debug!("Error parsing request: {:?}", error);
-                let response = response::make_http_error(match error {
+                // The rest of an oversized head or body, or a body we can't find the end of, is
+                // still waiting to be read, so the connection can't be used for another request
+                let unread = matches!(
+                    error,
+                    request::Error::HeadersTooLarge
+                        | request::Error::UriTooLong
+                        | request::Error::RequestBodyTooLarge
+                        | request::Error::UnsupportedTransferEncoding
+                        | request::Error::ConflictingBodyLength
+                );
+                let response = state.error_pages.make_http_error(match error {
                     request::Error::IncompleteRequest(_)
                     | request::Error::MalformedRequest(_)
+                    | request::Error::InvalidRequestTarget
                     | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
+                    | request::Error::ContentLengthMismatch
+                    | request::Error::ConflictingBodyLength => http::StatusCode::BAD_REQUEST,
+                    request::Error::UnsupportedTransferEncoding => http::StatusCode::NOT_IMPLEMENTED,
                     request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+                    request::Error::HeadersTooLarge => http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                    request::Error::UriTooLong => http::StatusCode::URI_TOO_LONG,
                     request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
                 });
-                send_response(&mut client_conn, &response).await;
+                send_response(&mut client_conn, client_ip, response, closing || unread).await;
+                if unread {
+                    return;
+                }
+                continue;
+            }
+        };
+
+        // The client may be done with the connection after this request, like an HTTP/1.0 client
+        // that didn't ask to keep it alive
+        let last_request = at_request_limit || !request::keeps_alive(&request);
+        closing = last_request;
+
+        // Until the body has been forwarded, whatever part of it didn't arrive with the head is
+        // still waiting on the connection. If we answer the request ourselves, we never read it,
+        // so the connection can't carry another request.
+        let mut unread_body = request::unread_body_len(&request);
+        if unread_body > 0 {
+            closing = true;
+        }
+
+        // Probes of our own health are answered before any other checks, since they come from
+        // orchestrators rather than clients
+        if let Some(response) = state.self_check_response(&request) {
+            send_response(&mut client_conn, client_ip, response, closing).await;
+            continue;
+        }
+
+        // Requests for hosts we don't serve (often scanners) are turned away before they cost an
+        // upstream anything or count against the client's rate limit. CONNECT requests name the
+        // tunnel target as their host, so the CONNECT allowlist covers them instead.
+        let host = request::get_host(&request);
+        if !state.allowed_hosts.is_empty() && request.method() != http::Method::CONNECT {
+            let allowed = match &host {
+                Some(host) => state.allowed_hosts.iter().any(|pattern| pattern.matches(host)),
+                None => false,
+            };
+            if !allowed {
+                log::debug!("Rejecting request from {} for host {:?}", client_ip, host);
+                let response = state.error_pages.make_http_error(http::StatusCode::MISDIRECTED_REQUEST);
+                send_response(&mut client_conn, client_ip, response, closing).await;
                 continue;
             }
+        }
+
+        // Without the client's address there's nothing to count its requests under
+        let body_bytes = (request.body().len() + unread_body) as u64;
+        let within_rate_limit = match &peer_ip {
+            Some(peer_ip) => state.rate_limiter.check(peer_ip, body_bytes),
+            None => true,
         };
+        if !within_rate_limit {
+            let response = state.error_pages.make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
+            send_response(&mut client_conn, client_ip, response, closing).await;
+            continue;
+        }
+
+        // Methods we don't allow are turned away after the rate limit has counted them, so that a
+        // client can't send them as fast as it likes
+        if request.method() != http::Method::CONNECT && !state.allowed_methods.allows(request.method()) {
+            log::debug!("Rejecting {} request from {}", request.method(), client_ip);
+            let mut response = state.error_pages.make_http_error(http::StatusCode::METHOD_NOT_ALLOWED);
+            let allow = state.allowed_methods.to_string();
+            response.headers_mut().insert(http::header::ALLOW, http::HeaderValue::from_str(&allow).unwrap());
+            send_response(&mut client_conn, client_ip, response, closing).await;
+            continue;
+        }
+
+        // Authentication comes after rate limiting, so that the limit also slows down clients
+        // guessing passwords
+        if let Some(basic_auth) = &state.basic_auth {
+            if !is_authorized(basic_auth, &request).await {
+                log::debug!("Rejecting unauthenticated request from {}", client_ip);
+                let mut response = state.error_pages.make_http_error(http::StatusCode::UNAUTHORIZED);
+                response
+                    .headers_mut()
+                    .insert("www-authenticate", http::HeaderValue::from_static(auth::CHALLENGE));
+                send_response(&mut client_conn, client_ip, response, closing).await;
+                continue;
+            }
+            if state.basic_auth_strip {
+                request.headers_mut().remove("authorization");
+            }
+        }
+
+        // CONNECT turns the connection into a tunnel to the requested host, bypassing the upstreams
+        if request.method() == http::Method::CONNECT {
+            handle_connect(&mut client_conn, client_ip, &request, state).await;
+            return;
+        }
+
+        // If we're already in the Via header, this request came back around to us, and forwarding
+        // it again would loop forever
+        let via_pseudonym = &state.via_pseudonym;
+        if request::via_contains(&request, via_pseudonym) {
+            log::warn!(
+                "Forwarding loop detected for {}: {:?}",
+                request::format_request_line(&request),
+                request.headers().get("via")
+            );
+            let response = state.error_pages.make_http_error(http::StatusCode::LOOP_DETECTED);
+            send_response(&mut client_conn, client_ip, response, closing).await;
+            continue;
+        }
+
+        // Pick the upstream group based on the Host header and request path. The request keeps
+        // using the configuration it was routed with, even if it is reloaded in the meantime.
+        let config = state.config();
+        let group_idx = match config.routes.select_group(host.as_deref(), request.uri().path()) {
+            Ok(group_idx) => group_idx,
+            Err(status) => {
+                log::debug!(
+                    "No route for {} (host {:?})",
+                    request::format_request_line(&request),
+                    host
+                );
+                let response = state.error_pages.make_http_error(status);
+                send_response(&mut client_conn, client_ip, response, closing).await;
+                continue;
+            }
+        };
+
+        // Responses are cached uncompressed, so decide whether to compress before either path
+        let compress = state.compress;
+        let accepts_gzip = compress && compress::accepts_gzip(&request);
+
+        // Serve the response from the cache if we can
+        let is_upgrade = request::is_upgrade_request(&request);
+        let cache_key = match state.response_cache {
+            Some(_) if !is_upgrade => cache::cache_key(&request, host.as_deref()),
+            _ => None,
+        };
+        if let Some(cache_key) = &cache_key {
+            let cached = state.response_cache.as_ref().unwrap().lock().get(cache_key);
+            if let Some(mut response) = cached {
+                log::debug!("Serving {} from cache", cache_key);
+                response.headers_mut().insert("x-cache", http::HeaderValue::from_static("HIT"));
+                if compress {
+                    compress::compress_response(&mut response, accepts_gzip);
+                }
+                send_response(&mut client_conn, client_ip, response, closing).await;
+                continue;
+            }
+        }
+
+        // Open a connection to a random destination server in that group, unless the one we have is
+        // to that group and its upstream has room for the request. The request keeps its place on
+        // the upstream until its response has been forwarded.
+        let connect_start = time::Instant::now();
+        let group = &config.upstream_groups[group_idx];
+        let reserved = match &upstream {
+            Some((upstream_config, upstream_group, upstream_idx, _, _))
+                if Arc::ptr_eq(upstream_config, &config) && *upstream_group == group_idx =>
+            {
+                // A draining upstream finishes the requests it has, but gets no more, even on
+                // connections it already has. Nor does a backup once another upstream can take
+                // over again.
+                let standing_down = group.upstream_is_backup[*upstream_idx] && group.primary_usable();
+                if group.admin_state(*upstream_idx) == config::AdminState::Active && !standing_down {
+                    group.try_reserve(*upstream_idx, state.max_upstream_requests)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        let reused = reserved.is_some();
+        let mut _in_flight = match reserved {
+            Some(in_flight) => in_flight,
+            None => match connect_to_upstream(state, group).await {
+                Ok((upstream_idx, stream, in_flight)) => {
+                    let upstream_ip = stream.peer_name(&group.upstream_addresses[upstream_idx]);
+                    upstream = Some((config.clone(), group_idx, upstream_idx, stream, upstream_ip));
+                    in_flight
+                }
+                // There's nothing to send the request to until a health check finds an upstream
+                // that has come back, so tell the client when that could next happen
+                Err(UpstreamError::NoneAlive) => {
+                    let mut response = state.error_pages.make_http_error(http::StatusCode::SERVICE_UNAVAILABLE);
+                    // Retry-After is in whole seconds, so round up
+                    let retry_after = config.health_check.dead_interval.as_secs_f64().ceil() as u64;
+                    response.headers_mut().insert("retry-after", http::HeaderValue::from(retry_after));
+                    send_response(&mut client_conn, client_ip, response, closing).await;
+                    return;
+                }
+                Err(UpstreamError::AllFailed) => {
+                    let response = state.error_pages.make_http_error(http::StatusCode::BAD_GATEWAY);
+                    send_response(&mut client_conn, client_ip, response, closing).await;
+                    return;
+                }
+                // The upstreams are busy rather than broken, so this connection can carry on once
+                // the client tries again
+                Err(UpstreamError::Saturated) => {
+                    log::info!(
+                        "No upstream in group {} has room for a request from {}",
+                        group.name,
+                        client_ip
+                    );
+                    let mut response = state.error_pages.make_http_error(http::StatusCode::SERVICE_UNAVAILABLE);
+                    response.headers_mut().insert("retry-after", http::HeaderValue::from_static("1"));
+                    send_response(&mut client_conn, client_ip, response, closing).await;
+                    continue;
+                }
+            },
+        };
+        let connect_time = connect_start.elapsed();
+        let (_, _, upstream_idx, upstream_conn, upstream_ip) = upstream.as_mut().unwrap();
         log::info!(
             "{} -> {}: {}",
             client_ip,
@@ -206,148 +1725,658 @@ async fn handle_connection(mut client_conn: TcpStream, state: &RwLock<ProxyState
             request::format_request_line(&request)
         );
 
-        if let Err(_) = rate_limiting_check(state, &client_ip).await {
-            let response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
-            send_response(&mut client_conn, &response).await;
-            continue;
-        }
+        // Connection-level headers are between the client and us, not for the upstream. An
+        // upgrade handshake is the exception, since it's asking the upstream to take over the
+        // connection.
+        let hop_by_hop = if is_upgrade {
+            request::UPGRADE_HOP_BY_HOP_HEADERS
+        } else {
+            request::HOP_BY_HOP_HEADERS
+        };
+        request::strip_hop_by_hop_headers(request.headers_mut(), hop_by_hop);
 
         // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
         // (We're the ones connecting directly to the upstream server, so without this header, the
         // upstream server will only know our IP, not the client's.)
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+        if let Some(peer_ip) = &peer_ip {
+            request::extend_header_value(&mut request, "x-forwarded-for", peer_ip);
+        }
+        let via = format!("{} {}", request::via_protocol(request.version()), via_pseudonym);
+        request::extend_header_value(&mut request, "via", &via);
+        // We always speak HTTP/1.1 to upstreams, so that our connections to them stay open. That's
+        // fine for HTTP/1.0 clients, since our responses to them never use chunked encoding: they
+        // have a Content-Length, or else end when we close the connection.
+        let client_version = request.version();
+        *request.version_mut() = http::Version::HTTP_11;
+
+        // Mirroring needs the whole body, so small bodies are read in full here. Larger ones are
+        // streamed to the upstream as usual and not mirrored.
+        let mirror = !is_upgrade && should_mirror(state);
+        if mirror && unread_body > 0 && unread_body <= MAX_MIRRORED_BODY_SIZE {
+            if let Err(error) = request::read_rest_of_body(&mut client_conn, &mut request).await {
+                log::debug!("Error reading request body from client: {:?}", error);
+                return;
+            }
+            unread_body = 0;
+        }
+
+        // Forward the request to the server, timing how long the upstream takes to respond. Once
+        // any of the body has been sent, the request can't be retried on another upstream, so
+        // failures from here on are reported to the client.
+        closing = last_request;
+        // A kept-alive connection may have been closed by the upstream while it sat idle, which we
+        // only find out by using it. A request we still hold all of, whose method is safe to send
+        // twice, is retried once on a fresh connection to the same upstream when that happens.
+        let mut can_retry = reused && unread_body == 0 && state.retry_methods.allows(request.method());
+        // A request that is slow to be answered may also be sent to another upstream if we hold all
+        // of it and it's safe to send twice
+        let may_hedge = unread_body == 0 && !is_upgrade && request.method().is_idempotent();
+        let (request_start, mut response, hedge) = loop {
+            let request_start = time::Instant::now();
+            let forwarded = match request::write_to_stream(&request, upstream_conn).await {
+                Ok(()) => request::forward_body(&mut client_conn, upstream_conn, unread_body).await,
+                Err(error) => Err(request::ForwardError::Upstream(error)),
+            };
+            let sent = match forwarded {
+                Ok(()) => true,
+                Err(request::ForwardError::Upstream(error)) if can_retry => {
+                    log::info!("Idle connection to upstream {} is dead ({}); retrying", upstream_ip, error);
+                    false
+                }
+                Err(request::ForwardError::Upstream(error)) => {
+                    log::error!("Failed to send request to upstream {}: {}", upstream_ip, error);
+                    group.record_latency(*upstream_idx, FAILURE_LATENCY);
+                    group.record_result(*upstream_idx, false);
+                    group.upstream_stats[*upstream_idx].record_failure();
+                    let response = state.error_pages.make_http_error(http::StatusCode::BAD_GATEWAY);
+                    send_response(&mut client_conn, client_ip, response, closing).await;
+                    return;
+                }
+                Err(request::ForwardError::Client(error)) => {
+                    // The upstream has a partial request that it will never get the rest of, so
+                    // neither connection can be reused
+                    log::info!("Error reading request body from client: {:?}", error);
+                    return;
+                }
+            };
+            if sent {
+                log::debug!("Forwarded request to server");
+
+                // Read the head of the server's response
+                let read_head =
+                    read_head_hedged(state, group, *upstream_idx, upstream_conn, &request, may_hedge);
+                let head = match state.upstream_header_timeout {
+                    Some(header_timeout) => time::timeout(header_timeout, read_head).await,
+                    None => Ok(read_head.await),
+                };
+                match head {
+                    Ok(Ok((response, hedge))) => break (request_start, response, hedge),
+                    // The upstream may still answer, so the connection can't be used for anything
+                    // else
+                    Err(_) => {
+                        log::error!("Timed out waiting for response from upstream {}", upstream_ip);
+                        group.record_latency(*upstream_idx, FAILURE_LATENCY);
+                        group.record_result(*upstream_idx, false);
+                        group.upstream_stats[*upstream_idx].record_failure();
+                        let response = state.error_pages.make_http_error(http::StatusCode::GATEWAY_TIMEOUT);
+                        send_response(&mut client_conn, client_ip, response, closing).await;
+                        return;
+                    }
+                    Ok(Err(error)) if can_retry && error.is_stale_connection() => {
+                        log::info!(
+                            "Idle connection to upstream {} is dead ({:?}); retrying",
+                            upstream_ip,
+                            error
+                        );
+                    }
+                    Ok(Err(error)) => {
+                        log::error!("Error reading response from server: {:?}", error);
+                        group.record_latency(*upstream_idx, FAILURE_LATENCY);
+                        group.record_result(*upstream_idx, false);
+                        group.upstream_stats[*upstream_idx].record_failure();
+                        let response = state.error_pages.make_http_error(http::StatusCode::BAD_GATEWAY);
+                        send_response(&mut client_conn, client_ip, response, closing).await;
+                        return;
+                    }
+                }
+            }
 
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
-            log::error!("Failed to send request to upstream {}: {}", upstream_ip, error);
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
+            // The connection was dead before we used it, which says nothing about the upstream
+            // itself, so only a failure to open a new one counts against it
+            can_retry = false;
+            let connect_timeout = state.upstream_connect_timeout;
+            match UpstreamConn::connect(&group.upstream_addresses[*upstream_idx], connect_timeout).await {
+                Ok(stream) => *upstream_conn = stream,
+                Err(error) => {
+                    log::error!("Failed to reconnect to upstream {}: {}", upstream_ip, error);
+                    group.record_latency(*upstream_idx, FAILURE_LATENCY);
+                    group.record_result(*upstream_idx, false);
+                    group.upstream_stats[*upstream_idx].record_failure();
+                    let response = state.error_pages.make_http_error(http::StatusCode::BAD_GATEWAY);
+                    send_response(&mut client_conn, client_ip, response, closing).await;
+                    return;
+                }
+            }
+        };
+        if mirror {
+            if unread_body == 0 {
+                mirror_request(state, &request);
+            } else {
+                log::debug!("Not mirroring request with a {} byte body", request.body().len() + unread_body);
+            }
+        }
+        let latency = request_start.elapsed();
+        let response_start = time::Instant::now();
+        let timings = RequestTimings { received: request_received, connect: connect_time, upstream: latency };
+        // If a hedge answered first, the upstream it beat may still send a response, so that
+        // connection is dropped along with the request's place there, and the hedge's takes over.
+        // The hedge upstream is only judged on how long it took itself.
+        let mut upstream_latency = latency;
+        if let Some(hedge) = hedge {
+            upstream_latency = hedge.started.elapsed();
+            let upstream_ip = hedge.conn.peer_name(&group.upstream_addresses[hedge.upstream_idx]);
+            upstream = Some((config.clone(), group_idx, hedge.upstream_idx, hedge.conn, upstream_ip));
+            _in_flight = hedge.in_flight;
+        }
+        let (_, _, upstream_idx, upstream_conn, upstream_ip) = upstream.as_mut().unwrap();
+        group.record_latency(*upstream_idx, upstream_latency);
+        group.record_result(*upstream_idx, !response.status().is_server_error());
+        group.upstream_stats[*upstream_idx].record_response(response.status(), upstream_latency);
+        let switching_protocols = is_upgrade && response.status() == http::StatusCode::SWITCHING_PROTOCOLS;
+        // Transfer-Encoding is about to be stripped, so find out how the body ends first
+        let remaining_body = response::remaining_body(&response, request.method());
+        request::strip_hop_by_hop_headers(
+            response.headers_mut(),
+            if switching_protocols { hop_by_hop } else { request::HOP_BY_HOP_HEADERS },
+        );
+        let via = format!("{} {}", request::via_protocol(response.version()), via_pseudonym);
+        response::extend_header_value(&mut response, "via", &via);
+
+        // After a 101 the connection no longer carries HTTP, so just pass bytes along both ways
+        if switching_protocols {
+            send_response(&mut client_conn, client_ip, response, false).await;
+            let (_, _, _, mut upstream_conn, upstream_ip) = upstream.unwrap();
+            log::debug!("Tunneling upgraded connection between {} and {}", client_ip, upstream_ip);
+            tunnel(&mut client_conn, &mut upstream_conn).await;
             return;
         }
-        log::debug!("Forwarded request to server");
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await {
-            Ok(response) => response,
-            Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
-                return;
+        // Caching and compression need the whole body, so it's read here if either might apply and
+        // it's small enough to hold. Anything else is streamed to the client as it arrives.
+        let wants_whole_body = cache_key.is_some() || (compress && compress::may_compress(&response));
+        let can_buffer = match remaining_body {
+            response::RemainingBody::Done => true,
+            // An absurd Content-Length mustn't wrap around into looking small
+            response::RemainingBody::Bytes(len) => {
+                response.body().len().saturating_add(len) <= response::MAX_BODY_SIZE
             }
+            response::RemainingBody::UntilClose | response::RemainingBody::Chunked => false,
         };
+        if !wants_whole_body || !can_buffer {
+            // A body without a Content-Length ends when the upstream closes the connection, and
+            // the client can only tell where it ends if we close ours too. The same goes for a
+            // chunked body sent to an HTTP/1.0 client, which wouldn't understand chunks.
+            let chunked_for_http10 = remaining_body == response::RemainingBody::Chunked
+                && client_version == http::Version::HTTP_10;
+            if remaining_body == response::RemainingBody::UntilClose || chunked_for_http10 {
+                closing = true;
+            }
+            let status = response.status();
+            let streamed = stream_response(
+                &mut client_conn,
+                client_ip,
+                response,
+                upstream_conn,
+                upstream_ip,
+                remaining_body,
+                state.upstream_idle_timeout,
+                closing,
+            )
+            .await;
+            log_if_slow(state, &timings, response_start, client_ip, upstream_ip, &request, status);
+            match streamed {
+                Some(body_bytes) => count_response_bytes(body_bytes),
+                None => return,
+            }
+            log::debug!("Forwarded response to client");
+            continue;
+        }
+        let idle_timeout = state.upstream_idle_timeout;
+        let read_body =
+            response::read_rest_of_body(upstream_conn, &mut response, request.method(), idle_timeout);
+        if let Err(error) = read_body.await {
+            log::error!("Error reading response body from server: {:?}", error);
+            let status = match error {
+                response::Error::IdleTimeout => http::StatusCode::GATEWAY_TIMEOUT,
+                _ => http::StatusCode::BAD_GATEWAY,
+            };
+            let response = state.error_pages.make_http_error(status);
+            send_response(&mut client_conn, client_ip, response, closing).await;
+            return;
+        }
+        if let Some(cache_key) = cache_key {
+            state.response_cache.as_ref().unwrap().lock().insert(cache_key, &response);
+            response.headers_mut().insert("x-cache", http::HeaderValue::from_static("MISS"));
+        }
+        if compress {
+            compress::compress_response(&mut response, accepts_gzip);
+        }
+
         // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
+        let status = response.status();
+        count_response_bytes(response.body().len());
+        send_response(&mut client_conn, client_ip, response, closing).await;
+        log_if_slow(state, &timings, response_start, client_ip, upstream_ip, &request, status);
         log::debug!("Forwarded response to client");
     }
 }
 
-async fn active_health_check(state: &RwLock<ProxyState>) {
-    let state_r = state.read().await;
-    let mut interval = time::interval(time::Duration::from_secs(state_r.active_health_check_interval as u64));
-    let len = state_r.upstream_addresses.len();
-    drop(state_r);
-    interval.tick().await;
+/// How long the parts of a proxied request took, for the slow request log
+struct RequestTimings {
+    /// When the head of the request had been read from the client
+    received: time::Instant,
+    /// Time spent opening a connection to the upstream (zero if the connection was reused)
+    connect: time::Duration,
+    /// Time from starting to send the request to the upstream until its response head arrived
+    upstream: time::Duration,
+}
+
+/// Logs a warning if a proxied request took longer than the slow request threshold in total, saying
+/// how long each part took. `response_start` is when we started on the response, after its head
+/// arrived from the upstream.
+fn log_if_slow(
+    state: &ProxyState,
+    timings: &RequestTimings,
+    response_start: time::Instant,
+    client_ip: &str,
+    upstream_ip: &str,
+    request: &http::Request<Vec<u8>>,
+    status: http::StatusCode,
+) {
+    let total = timings.received.elapsed();
+    if !matches!(state.slow_request_threshold, Some(threshold) if total > threshold) {
+        return;
+    }
+    log::warn!(
+        "Slow request from {} to {}: {} {} -> {} took {:.1?} (connect {:.1?}, upstream response {:.1?}, \
+        writing to client {:.1?})",
+        client_ip,
+        upstream_ip,
+        request.method(),
+        request.uri().path(),
+        status.as_u16(),
+        total,
+        timings.connect,
+        timings.upstream,
+        response_start.elapsed()
+    );
+}
+
+/// Checks the request's Authorization header. bcrypt is slow on purpose, so the check runs on the
+/// blocking thread pool rather than stalling other connections.
+async fn is_authorized(basic_auth: &Arc<auth::BasicAuth>, request: &http::Request<Vec<u8>>) -> bool {
+    let authorization = match request.headers().get("authorization") {
+        Some(value) => value.as_bytes().to_vec(),
+        None => return false,
+    };
+    let basic_auth = basic_auth.clone();
+    tokio::task::spawn_blocking(move || basic_auth.check(&authorization))
+        .await
+        .unwrap_or(false)
+}
+
+/// Opens a TCP connection to the target of a CONNECT request, if the allowlist permits it, and
+/// tunnels bytes between it and the client.
+async fn handle_connect(
+    client_conn: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    client_ip: &str,
+    request: &http::Request<Vec<u8>>,
+    state: &ProxyState,
+) {
+    // The request parser only accepts CONNECT requests with a host and port
+    let host = request.uri().host().unwrap().to_ascii_lowercase();
+    let port = request.uri().port_u16().unwrap();
+    let refusal = if state.connect_allowlist.is_empty() {
+        Some(http::StatusCode::METHOD_NOT_ALLOWED)
+    } else if !state.connect_allowlist.iter().any(|pattern| pattern.matches(&host, port)) {
+        Some(http::StatusCode::FORBIDDEN)
+    } else {
+        None
+    };
+    if let Some(status) = refusal {
+        log::info!("Refusing CONNECT from {} to {}:{}", client_ip, host, port);
+        send_response(client_conn, client_ip, state.error_pages.make_http_error(status), false).await;
+        return;
+    }
+
+    let target = format!("{}:{}", host, port);
+    let mut target_conn = match TcpStream::connect(&target).await {
+        Ok(target_conn) => target_conn,
+        Err(err) => {
+            log::error!("Failed to connect to CONNECT target {}: {}", target, err);
+            let response = state.error_pages.make_http_error(http::StatusCode::BAD_GATEWAY);
+            send_response(client_conn, client_ip, response, false).await;
+            return;
+        }
+    };
+    log::info!("{} -> {}: CONNECT tunnel established", client_ip, target);
+    if let Err(err) = client_conn.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await {
+        log::warn!("Failed to send response to client: {}", err);
+        return;
+    }
+    // The client may have sent the start of its TLS handshake right after the request
+    if !request.body().is_empty() {
+        if let Err(err) = target_conn.write_all(request.body()).await {
+            log::warn!("Failed to write to CONNECT target {}: {}", target, err);
+            return;
+        }
+    }
+    tunnel(client_conn, &mut target_conn).await;
+}
+
+/// Returns whether the next request should be mirrored: there is a mirror upstream, and the request
+/// is sampled.
+fn should_mirror(state: &ProxyState) -> bool {
+    state.mirror_upstream.is_some() && rand::thread_rng().gen_range(0..100) < state.mirror_percentage
+}
+
+/// Sends a copy of a request, which must have its whole body, to the mirror upstream on a separate
+/// task. The response is read and discarded, and nothing that goes wrong with the mirror affects
+/// the client.
+fn mirror_request(state: &ProxyState, request: &http::Request<Vec<u8>>) {
+    let mirror_upstream = state.mirror_upstream.clone().unwrap();
+    let connect_timeout = state.upstream_connect_timeout;
+    let mut request = request::clone_request(request);
+    request.headers_mut().insert("x-shadow", http::HeaderValue::from_static("true"));
+    tokio::spawn(async move {
+        let result = async {
+            let mut conn = UpstreamConn::connect(&mirror_upstream, connect_timeout)
+                .await
+                .map_err(|err| format!("{}", err))?;
+            request::write_to_stream(&request, &mut conn).await.map_err(|err| format!("{}", err))?;
+            response::read_from_stream(&mut conn, request.method()).await.map_err(|err| format!("{:?}", err))
+        }
+        .await;
+        match result {
+            Ok(response) => log::debug!(
+                "Mirror upstream {} responded to {}: {}",
+                mirror_upstream,
+                request::format_request_line(&request),
+                response.status()
+            ),
+            Err(err) => log::debug!("Failed to mirror request to {}: {}", mirror_upstream, err),
+        }
+    });
+}
+
+/// Copies bytes between the client and the upstream in both directions until either side closes.
+async fn tunnel(
+    client_conn: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    upstream_conn: &mut (impl AsyncRead + AsyncWrite + Unpin),
+) {
+    match tokio::io::copy_bidirectional(client_conn, upstream_conn).await {
+        Ok((to_upstream, to_client)) => log::debug!(
+            "Tunnel closed after {} bytes to the upstream and {} bytes to the client",
+            to_upstream,
+            to_client
+        ),
+        Err(error) => log::debug!("Tunnel closed with error: {}", error),
+    }
+}
+
+/// Starts a probe task for every upstream server in `config`. The tasks stop once the configuration
+/// is reloaded, and the reload starts new ones.
+fn active_health_check(state: &Arc<ProxyState>, config: &Arc<RuntimeConfig>) {
+    for (group_idx, group) in config.upstream_groups.iter().enumerate() {
+        for (upstream_idx, upstream_ip) in group.upstream_addresses.iter().enumerate() {
+            let state = state.clone();
+            let config = config.clone();
+            let upstream_ip = upstream_ip.clone();
+            tokio::spawn(async move {
+                probe_upstream(&state, &config, group_idx, upstream_idx, &upstream_ip).await;
+            });
+        }
+    }
+}
+
+/// Number of failed health checks in a row between reminders that an upstream is still dead
+const DEAD_UPSTREAM_REMINDER_PROBES: u32 = 10;
+
+/// Periodically checks whether an upstream server is alive, updating its alive flag. Upstreams
+/// that are marked dead are probed on the dead interval, so that they are put back into rotation
+/// soon after they recover, while healthy ones are probed on the normal interval. Each failed
+/// probe in a row doubles the wait before the next one, up to the maximum backoff, so that
+/// upstreams that are gone for good aren't probed constantly.
+///
+/// Waits are scaled by a random factor, picked again after every probe, so that instances with the
+/// same interval drift apart instead of probing every upstream at the same moment.
+async fn probe_upstream(
+    state: &ProxyState,
+    config: &Arc<RuntimeConfig>,
+    group_idx: usize,
+    upstream_idx: usize,
+    upstream_ip: &str,
+) {
+    let health_check = &config.health_check;
+    let interval = health_check.interval;
+    let dead_interval = health_check.dead_interval;
+    let max_backoff = health_check.max_backoff.max(dead_interval);
+    let jitter = health_check.jitter;
+    let group = &config.upstream_groups[group_idx];
+    let mut last_probe = time::Instant::now();
+    // Upstreams added by a reload get no requests until they have been found alive, so they are
+    // probed straight away. Otherwise, the first wait is anywhere up to a whole interval, so that
+    // instances started together start out apart.
+    let added = !group.is_alive(upstream_idx) && !group.upstream_probed[upstream_idx].load(Ordering::SeqCst);
+    let mut wait_scale = if added {
+        0.0
+    } else if jitter > 0.0 {
+        rand::thread_rng().gen_range(0.0..=1.0)
+    } else {
+        1.0
+    };
     loop {
-        interval.tick().await;
-        for upstream_idx in 0..len {
-            let state_r = state.read().await;
-            let upstream_ip = &state_r.upstream_addresses[upstream_idx];
-            let request = http::Request::builder()
-                .method(http::Method::GET)
-                .uri(&state_r.active_health_check_path)
-                .header("Host", upstream_ip)
-                .body(Vec::new())
-                .unwrap();
-            match TcpStream::connect(upstream_ip).await {
-                Ok(mut conn) => {
-                    if let Err(error) = request::write_to_stream(&request, &mut conn).await {
-                        log::error!("Failed to send request to upstream {}: {}", upstream_ip, error);
-                        drop(state_r);
-                        continue;
-                    }
-                    let response = match response::read_from_stream(&mut conn, request.method()).await {
-                        Ok(response) => response,
-                        Err(error) => {
-                            log::error!("Error reading response from server: {:?}", error);
-                            if !state_r.upstream_address_flags[upstream_idx] {
-                                drop(state_r);
-                                continue;
-                            }
-                            drop(state_r);
-                            {
-                                let mut state_w = state.write().await;
-                                state_w.upstream_address_flags[upstream_idx] = false;
-                                state_w.upstream_address_alive_num -= 1;
-                            }
-                            continue;
-                        }
-                    };
-                    match response.status().as_u16() {
-                        200 => {
-                            if state_r.upstream_address_flags[upstream_idx] {
-                                drop(state_r);
-                                continue;
-                            }
-                            drop(state_r);
-                            {
-                                let mut state_w = state.write().await;
-                                state_w.upstream_address_flags[upstream_idx] = true;
-                                state_w.upstream_address_alive_num += 1;
-                            }
-                        }
-                        status @ _ => {
-                            log::error!("Upstream server {} is not working: {}", upstream_ip, status);
-                            if !state_r.upstream_address_flags[upstream_idx] {
-                                drop(state_r);
-                                continue;
-                            }
-                            drop(state_r);
-                            {
-                                let mut state_w = state.write().await;
-                                state_w.upstream_address_flags[upstream_idx] = false;
-                                state_w.upstream_address_alive_num -= 1;
-                            }
-                        }
-                    }
-                }
-                Err(err) => {
-                    log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
-                    if !state_r.upstream_address_flags[upstream_idx] {
-                        drop(state_r);
-                        continue;
-                    }
-                    drop(state_r);
-                    {
-                        let mut state_w = state.write().await;
-                        state_w.upstream_address_flags[upstream_idx] = false;
-                        state_w.upstream_address_alive_num -= 1;
-                    }
-                }
+        // Once the configuration has been reloaded, a task for the new one takes over
+        if !Arc::ptr_eq(&state.config(), config) {
+            return;
+        }
+        let was_alive = group.is_alive(upstream_idx);
+        let failed_probes = group.upstream_failed_probes[upstream_idx].load(Ordering::SeqCst);
+        let wait = if was_alive {
+            interval
+        } else {
+            let doublings = failed_probes.saturating_sub(1).min(31);
+            dead_interval.saturating_mul(1 << doublings).min(max_backoff)
+        };
+        let next_probe = last_probe + wait.mul_f64(wait_scale);
+        let now = time::Instant::now();
+        if now < next_probe {
+            // Wake up at least every dead interval, in case a failed request marks the upstream
+            // dead in the meantime
+            time::sleep((next_probe - now).min(dead_interval)).await;
+            continue;
+        }
+        last_probe = now;
+        wait_scale = rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter);
+        if group.admin_state(upstream_idx) == config::AdminState::Disabled {
+            continue;
+        }
+
+        let result = check_upstream_health(health_check, upstream_ip, state.upstream_connect_timeout).await;
+        let alive = result.is_ok();
+        // This task is the only one that updates the failure count, so a plain store is enough
+        let failed_probes = if alive { 0 } else { failed_probes.saturating_add(1) };
+        group.upstream_failed_probes[upstream_idx].store(failed_probes, Ordering::SeqCst);
+        // Log state changes, plus an occasional reminder about upstreams that stay dead, rather
+        // than every failed probe
+        match result {
+            Ok(()) if !group.is_alive(upstream_idx) => {
+                log::info!("Upstream {} in group {} is now alive", upstream_ip, group.name);
+            }
+            Err(err) if group.is_alive(upstream_idx) => {
+                log::error!("Upstream {} in group {} is now dead: {}", upstream_ip, group.name, err);
+            }
+            Err(err) if failed_probes % DEAD_UPSTREAM_REMINDER_PROBES == 0 => {
+                log::warn!(
+                    "Upstream {} in group {} is still dead after {} health checks: {}",
+                    upstream_ip,
+                    group.name,
+                    failed_probes,
+                    err
+                );
+            }
+            _ => {}
+        }
+        group.set_alive(upstream_idx, alive);
+        group.upstream_probed[upstream_idx].store(true, Ordering::SeqCst);
+    }
+}
+
+/// Sends a request to the health check path of an upstream server. Returns Ok if it responded with
+/// 200 OK and a body with the expected content, or a description of what went wrong otherwise.
+async fn check_upstream_health(
+    health_check: &HealthCheckSettings,
+    upstream_ip: &str,
+    connect_timeout: time::Duration,
+) -> Result<(), String> {
+    let request = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(&health_check.path)
+        .header("Host", upstream::host_header(upstream_ip))
+        .body(Vec::new())
+        .unwrap();
+    let mut conn = UpstreamConn::connect(upstream_ip, connect_timeout)
+        .await
+        .map_err(|err| format!("failed to connect: {}", err))?;
+    request::write_to_stream(&request, &mut conn)
+        .await
+        .map_err(|err| format!("failed to send request: {}", err))?;
+    let response = response::read_from_stream(&mut conn, request.method())
+        .await
+        .map_err(|err| format!("error reading response: {:?}", err))?;
+    if response.status() != http::StatusCode::OK {
+        return Err(format!("health check returned {}", response.status().as_u16()));
+    }
+    let body = String::from_utf8_lossy(response.body());
+    if let Some(expected) = &health_check.expect_body {
+        if !body.contains(expected.as_str()) {
+            return Err(format!("health check response doesn't contain \"{}\"", expected));
+        }
+    }
+    if let Some(pattern) = &health_check.expect_regex {
+        if !pattern.is_match(&body) {
+            return Err(format!("health check response doesn't match /{}/", pattern));
+        }
+    }
+    Ok(())
+}
+
+/// Logs the statistics of every upstream server each time we get SIGUSR2.
+async fn log_stats_on_signal(mut signals: Signal, state: &ProxyState) {
+    while signals.recv().await.is_some() {
+        log::info!("Upstream statistics:\n{}", state.stats_table());
+    }
+}
+
+/// Reads the config file again each time we get SIGHUP, switching to the new configuration if it is
+/// valid and keeping the old one otherwise.
+async fn reload_on_signal(mut signals: Signal, state: &Arc<ProxyState>) {
+    while signals.recv().await.is_some() {
+        let path = match &state.reloadable_options.config {
+            Some(path) => path,
+            None => {
+                log::warn!("Got SIGHUP, but there is no config file to reload");
+                continue;
+            }
+        };
+        log::info!("Reloading the configuration from {}", path);
+        let previous = state.config();
+        let config = match load_config(&state.reloadable_options, Some(&previous)) {
+            Ok(config) => Arc::new(config),
+            Err(err) => {
+                log::error!("Keeping the old configuration: {}", err);
+                continue;
+            }
+        };
+        log_upstream_changes(&previous, &config);
+        state.rate_limiter.set_limits(config.max_requests_per_minute, config.max_bytes_per_minute);
+        *state.config.write() = config.clone();
+        active_health_check(state, &config);
+        log::info!("Configuration reloaded");
+    }
+}
+
+/// Logs the upstream servers that a reload adds to or removes from each group
+fn log_upstream_changes(previous: &RuntimeConfig, config: &RuntimeConfig) {
+    for group in &previous.upstream_groups {
+        let kept = config.group(&group.name);
+        for (upstream_idx, address) in group.upstream_addresses.iter().enumerate() {
+            if !kept.is_some_and(|kept| kept.upstream_addresses.contains(address)) {
+                log::info!(
+                    "Upstream {} was removed from group {}; draining its {} requests in flight",
+                    address,
+                    group.name,
+                    group.upstream_in_flight[upstream_idx].load(Ordering::SeqCst)
+                );
+            }
+        }
+    }
+    for group in &config.upstream_groups {
+        let existing = previous.group(&group.name);
+        for address in &group.upstream_addresses {
+            if !existing.is_some_and(|existing| existing.upstream_addresses.contains(address)) {
+                log::info!(
+                    "Upstream {} was added to group {}; it gets requests once it passes a health check",
+                    address,
+                    group.name
+                );
             }
         }
     }
 }
 
-async fn rate_limiting_counter_clear(state: &RwLock<ProxyState>) {
-    let mut interval = time::interval(time::Duration::from_secs(60));
+/// Resets the rate limiting counts at the start of every rate limit window.
+async fn rate_limiting_counter_clear(state: &ProxyState) {
+    let mut interval = time::interval(state.rate_limit_window);
     interval.tick().await;
     loop {
         interval.tick().await;
-        state.write().await.rate_limiting_counter.clear();
+        state.rate_limiter.clear();
     }
 }
 
-async fn rate_limiting_check(state: &RwLock<ProxyState>, client_ip: &String) -> Result<(), std::io::Error> {
-    if state.read().await.max_requests_per_minute == 0 {
-        return Ok(());
+#[cfg(test)]
+mod tests {
+    use super::pick_alive;
+    use rand::SeedableRng;
+    use std::collections::HashSet;
+
+    /// Picks from upstreams 0..flags.len() many times, returning the set of upstreams picked
+    fn picks(flags: &[bool]) -> HashSet<Option<usize>> {
+        let candidates: Vec<usize> = (0..flags.len()).collect();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(110);
+        (0..200).map(|_| pick_alive(&candidates, |idx| flags[idx], &mut rng)).collect()
     }
-    let mut state_w = state.write().await;
-    let count = state_w.rate_limiting_counter.entry(client_ip.to_string()).or_insert(0);
-    *count += 1;
-    if *count > state_w.max_requests_per_minute {
-        Err(std::io::Error::new(std::io::ErrorKind::Other, "Too many requests"))
-    } else {
-        Ok(())
+
+    #[test]
+    fn test_pick_alive() {
+        assert_eq!(picks(&[true, true, true]), HashSet::from([Some(0), Some(1), Some(2)]));
+        assert_eq!(picks(&[false, true, false, true]), HashSet::from([Some(1), Some(3)]));
+        let mut mostly_dead = [false; 20];
+        mostly_dead[13] = true;
+        assert_eq!(picks(&mostly_dead), HashSet::from([Some(13)]));
+        assert_eq!(picks(&[false; 5]), HashSet::from([None]));
+        assert_eq!(picks(&[]), HashSet::from([None]));
     }
-}
\ No newline at end of file
+
+    /// Only the candidates given are considered, whatever the flags of the others
+    #[test]
+    fn test_pick_alive_among_candidates() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(110);
+        for _ in 0..100 {
+            let picked = pick_alive(&[4, 6, 7], |idx| idx != 6, &mut rng);
+            assert!(picked == Some(4) || picked == Some(7));
+        }
+        assert_eq!(pick_alive(&[2, 5], |idx| idx == 3, &mut rng), None);
+    }
+}
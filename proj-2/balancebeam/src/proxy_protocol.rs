@@ -0,0 +1,152 @@
+//! The PROXY protocol, which load balancers in front of us use to tell us the address of the client
+//! they accepted a connection from. Both the text (v1) and binary (v2) versions of the header are
+//! accepted. See https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Length of the shortest v1 header, "PROXY UNKNOWN\r\n"
+const V1_MIN_LEN: usize = 15;
+/// Length of the longest v1 header, including the CRLF
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Length of the fixed part of a v2 header: the signature, version and command, address family,
+/// and the length of the rest
+const V2_FIXED_LEN: usize = 16;
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    /// The connection doesn't start with a PROXY protocol header
+    MissingHeader,
+    /// The connection was closed partway through the header
+    IncompleteHeader,
+    /// The header is malformed
+    #[allow(dead_code)]
+    InvalidHeader(&'static str),
+    /// Encountered an I/O error when reading from the client
+    #[allow(dead_code)]
+    ConnectionError(std::io::Error),
+}
+
+/// Result of parsing the bytes received so far
+enum Parsed {
+    /// At least this many more bytes are needed to finish the header
+    NeedMore(usize),
+    /// The header is complete. It holds the client's address, if it has one.
+    Header(Option<SocketAddr>),
+}
+
+/// Reads a PROXY protocol header from the start of a connection. Nothing past the header is read,
+/// so the request that follows can be read as usual. Returns the client's address, or None if the
+/// header doesn't carry one (as for health checks from the load balancer itself).
+pub async fn read_header(stream: &mut (impl AsyncRead + Unpin)) -> Result<Option<SocketAddr>, Error> {
+    let mut buffer = Vec::new();
+    loop {
+        match parse(&buffer)? {
+            Parsed::Header(source) => return Ok(source),
+            Parsed::NeedMore(needed) => {
+                let start = buffer.len();
+                buffer.resize(start + needed, 0);
+                match stream.read_exact(&mut buffer[start..]).await {
+                    Ok(_) => {}
+                    Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                        return Err(Error::IncompleteHeader)
+                    }
+                    Err(err) => return Err(Error::ConnectionError(err)),
+                }
+            }
+        }
+    }
+}
+
+fn parse(buffer: &[u8]) -> Result<Parsed, Error> {
+    if buffer.starts_with(V2_SIGNATURE) {
+        return parse_v2(buffer);
+    }
+    if buffer.starts_with(V1_PREFIX) {
+        return parse_v1(buffer);
+    }
+    let could_be = |prefix: &[u8]| prefix.starts_with(&buffer[..buffer.len().min(prefix.len())]);
+    if could_be(V2_SIGNATURE) || could_be(V1_PREFIX) {
+        // Either version could follow, and both are at least this long
+        Ok(Parsed::NeedMore(V1_MIN_LEN - buffer.len()))
+    } else {
+        Err(Error::MissingHeader)
+    }
+}
+
+/// Parses a header like "PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n". The end is only known
+/// once the CRLF arrives, so this asks for one byte at a time until then.
+fn parse_v1(buffer: &[u8]) -> Result<Parsed, Error> {
+    let end = match buffer.windows(2).position(|window| window == b"\r\n") {
+        Some(end) if end + 2 <= V1_MAX_LEN => end,
+        None if buffer.len() < V1_MAX_LEN => return Ok(Parsed::NeedMore(1)),
+        _ => return Err(Error::InvalidHeader("v1 header is too long")),
+    };
+    let line = std::str::from_utf8(&buffer[V1_PREFIX.len()..end])
+        .map_err(|_| Error::InvalidHeader("v1 header is not text"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        // The load balancer doesn't know (or won't say) where the connection came from
+        ["UNKNOWN", ..] => Ok(Parsed::Header(None)),
+        [protocol @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let parse_ip = |ip: &str| match ip.parse() {
+                Ok(IpAddr::V4(ip)) if *protocol == "TCP4" => Ok(IpAddr::V4(ip)),
+                Ok(IpAddr::V6(ip)) if *protocol == "TCP6" => Ok(IpAddr::V6(ip)),
+                _ => Err(Error::InvalidHeader("invalid address in v1 header")),
+            };
+            let parse_port = |text: &str| match text.parse::<u16>() {
+                // Leading zeros aren't allowed
+                Ok(port) if port.to_string() == text => Ok(port),
+                _ => Err(Error::InvalidHeader("invalid port in v1 header")),
+            };
+            let source = parse_ip(source)?;
+            parse_ip(destination)?;
+            let source_port = parse_port(source_port)?;
+            parse_port(destination_port)?;
+            Ok(Parsed::Header(Some(SocketAddr::new(source, source_port))))
+        }
+        _ => Err(Error::InvalidHeader("malformed v1 header")),
+    }
+}
+
+/// Parses a binary header: the signature, then a byte with the version and command, a byte with the
+/// address family and transport protocol, and the big-endian length of the addresses and any
+/// extensions that follow.
+fn parse_v2(buffer: &[u8]) -> Result<Parsed, Error> {
+    if buffer.len() < V2_FIXED_LEN {
+        return Ok(Parsed::NeedMore(V2_FIXED_LEN - buffer.len()));
+    }
+    let version_command = buffer[12];
+    if version_command >> 4 != 2 {
+        return Err(Error::InvalidHeader("unsupported v2 header version"));
+    }
+    let len = V2_FIXED_LEN + u16::from_be_bytes([buffer[14], buffer[15]]) as usize;
+    if buffer.len() < len {
+        return Ok(Parsed::NeedMore(len - buffer.len()));
+    }
+    let addresses = &buffer[V2_FIXED_LEN..len];
+    match (version_command & 0xf, buffer[13] >> 4) {
+        // LOCAL: a connection the load balancer opened itself, such as a health check
+        (0, _) => Ok(Parsed::Header(None)),
+        // PROXY over IPv4: source and destination addresses, then source and destination ports
+        (1, 1) if addresses.len() >= 12 => {
+            let source = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let source_port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Parsed::Header(Some(SocketAddr::new(source.into(), source_port))))
+        }
+        // PROXY over IPv6, laid out the same way
+        (1, 2) if addresses.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let source_port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Parsed::Header(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), source_port))))
+        }
+        (1, 1) | (1, 2) => Err(Error::InvalidHeader("v2 header is too short for its addresses")),
+        // Unspecified or Unix socket addresses, which don't give us an IP address
+        (1, _) => Ok(Parsed::Header(None)),
+        _ => Err(Error::InvalidHeader("unknown command in v2 header")),
+    }
+}
@@ -0,0 +1,94 @@
+use parking_lot::Mutex;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Number of independently locked shards the counters are split across. Requests from different
+/// IPs usually land in different shards, so they don't wait on each other.
+const NUM_SHARDS: usize = 16;
+
+/// What one client IP has used of its allowance in the current minute
+#[derive(Default)]
+struct ClientUsage {
+    requests: usize,
+    bytes: u64,
+}
+
+/// Counts requests and bytes per client IP over the current minute. The counters are split into
+/// shards, each behind its own lock, so that checking the limit doesn't serialize every request in
+/// the proxy. The limits can be changed while we run, when the configuration is reloaded.
+pub struct RateLimiter {
+    /// Maximum number of requests an individual IP can make in a minute (0 = unlimited)
+    max_requests_per_minute: AtomicUsize,
+    /// Maximum number of body bytes an individual IP can send (and, if response bytes are being
+    /// counted, receive) in a minute (0 = unlimited)
+    max_bytes_per_minute: AtomicU64,
+    shards: Vec<Mutex<HashMap<String, ClientUsage>>>,
+}
+
+impl RateLimiter {
+    pub fn new(max_requests_per_minute: usize, max_bytes_per_minute: u64) -> RateLimiter {
+        RateLimiter {
+            max_requests_per_minute: AtomicUsize::new(max_requests_per_minute),
+            max_bytes_per_minute: AtomicU64::new(max_bytes_per_minute),
+            shards: (0..NUM_SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    /// Changes the limits. What clients have used so far in the current minute still counts.
+    pub fn set_limits(&self, max_requests_per_minute: usize, max_bytes_per_minute: u64) {
+        self.max_requests_per_minute.store(max_requests_per_minute, Ordering::Relaxed);
+        self.max_bytes_per_minute.store(max_bytes_per_minute, Ordering::Relaxed);
+    }
+
+    fn shard(&self, client_ip: &str) -> &Mutex<HashMap<String, ClientUsage>> {
+        let mut hasher = DefaultHasher::new();
+        client_ip.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % NUM_SHARDS]
+    }
+
+    /// Counts a request from `client_ip` with a body of `body_bytes`, returning whether it is within
+    /// the limits. A request too big for what is left of the client's byte allowance is turned away
+    /// without using any of it, since it never reaches an upstream.
+    pub fn check(&self, client_ip: &str, body_bytes: u64) -> bool {
+        let max_requests = self.max_requests_per_minute.load(Ordering::Relaxed);
+        let max_bytes = self.max_bytes_per_minute.load(Ordering::Relaxed);
+        if max_requests == 0 && max_bytes == 0 {
+            return true;
+        }
+        let mut shard = self.shard(client_ip).lock();
+        let usage = match shard.get_mut(client_ip) {
+            Some(usage) => usage,
+            None => shard.entry(client_ip.to_string()).or_default(),
+        };
+        usage.requests += 1;
+        if max_requests != 0 && usage.requests > max_requests {
+            return false;
+        }
+        if max_bytes != 0 && usage.bytes + body_bytes > max_bytes {
+            return false;
+        }
+        usage.bytes += body_bytes;
+        true
+    }
+
+    /// Adds bytes sent to `client_ip` to its byte count, for clients' responses to count against
+    /// the byte limit too.
+    pub fn add_bytes(&self, client_ip: &str, bytes: u64) {
+        if self.max_bytes_per_minute.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut shard = self.shard(client_ip).lock();
+        if let Some(usage) = shard.get_mut(client_ip) {
+            usage.bytes += bytes;
+        }
+    }
+
+    /// Starts a new minute, resetting every IP's counts.
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            shard.lock().clear();
+        }
+    }
+}
@@ -1,25 +1,93 @@
 use std::cmp::min;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
-const MAX_NUM_HEADERS: usize = 32;
+/// Size of the chunks that request headers are read in
+const READ_CHUNK_SIZE: usize = 1024;
+
+/// Size of the chunks that request bodies are copied to the upstream in
+const BODY_CHUNK_SIZE: usize = 8192;
+
+/// Limits on the size of a request, so that a client can't make us buffer an unbounded amount of
+/// data before we find out whether the request is valid
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// Maximum size of the request line and headers together, in bytes
+    pub max_header_bytes: usize,
+    /// Maximum number of headers
+    pub max_headers: usize,
+    /// Maximum length of the request target in the request line, in bytes
+    pub max_uri_length: usize,
+    /// Maximum size of the request body, in bytes
+    pub max_body_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_header_bytes: 8000,
+            max_headers: 32,
+            max_uri_length: 8000,
+            max_body_bytes: 10000000,
+        }
+    }
+}
+
+/// Headers that only apply to a single connection (RFC 7230 section 6.1), which a proxy must not
+/// forward. Proxy-Connection isn't standard, but older clients still send it.
+pub const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Hop-by-hop headers to strip from a protocol upgrade handshake. Connection and Upgrade have to
+/// reach the other side for the upgrade to happen.
+pub const UPGRADE_HOP_BY_HOP_HEADERS: &[&str] = &[
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+];
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     /// Client hung up before sending a complete request. IncompleteRequest contains the number of
     /// bytes that were successfully read before the client hung up
     IncompleteRequest(usize),
     /// Client sent an invalid HTTP request. httparse::Error contains more details
+    #[allow(dead_code)]
     MalformedRequest(httparse::Error),
+    /// The request target isn't valid for the request method. CONNECT requests must use
+    /// authority-form (host:port), and other requests must not
+    InvalidRequestTarget,
     /// The Content-Length header is present, but does not contain a valid numeric value
     InvalidContentLength,
     /// The Content-Length header does not match the size of the request body that was sent
     ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
+    /// The request has a Transfer-Encoding header. We only know how to find the end of a body from
+    /// its Content-Length, so we can't forward it, or tell where the next request starts
+    UnsupportedTransferEncoding,
+    /// The request has both Transfer-Encoding and Content-Length headers, which disagree about where
+    /// its body ends (RFC 7230 section 3.3.3)
+    ConflictingBodyLength,
+    /// The request body is bigger than the body size limit
     RequestBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
+    /// The request line and headers are bigger than the header byte limit, or there are more
+    /// headers than the header count limit
+    HeadersTooLarge,
+    /// The request target is longer than the URI length limit
+    UriTooLong,
+    /// Encountered an I/O error when reading/writing a stream
     ConnectionError(std::io::Error),
 }
 
@@ -66,6 +134,111 @@ pub fn extend_header_value(
         .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
 }
 
+/// Makes a copy of a request, since http::Request doesn't implement Clone (its extensions can't be
+/// cloned, but we never use them).
+pub fn clone_request(request: &http::Request<Vec<u8>>) -> http::Request<Vec<u8>> {
+    let mut copy = http::Request::new(request.body().clone());
+    *copy.method_mut() = request.method().clone();
+    *copy.uri_mut() = request.uri().clone();
+    *copy.version_mut() = request.version();
+    *copy.headers_mut() = request.headers().clone();
+    copy
+}
+
+/// Returns whether the request's Connection header lists `option` (case-insensitively).
+fn has_connection_option(request: &http::Request<Vec<u8>>, option: &str) -> bool {
+    request
+        .headers()
+        .get_all("connection")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|listed| listed.trim().eq_ignore_ascii_case(option))
+}
+
+/// Returns whether the request asks to switch protocols (e.g. to WebSocket), which requires an
+/// Upgrade header and an "upgrade" option in the Connection header.
+pub fn is_upgrade_request(request: &http::Request<Vec<u8>>) -> bool {
+    request.headers().contains_key("upgrade") && has_connection_option(request, "upgrade")
+}
+
+/// Returns whether the client will send more requests on the connection after this one. HTTP/1.1
+/// connections stay open unless the client says "Connection: close", but HTTP/1.0 clients close
+/// theirs after one response unless they ask for "Connection: keep-alive".
+pub fn keeps_alive(request: &http::Request<Vec<u8>>) -> bool {
+    if request.version() == http::Version::HTTP_10 {
+        has_connection_option(request, "keep-alive")
+    } else {
+        !has_connection_option(request, "close")
+    }
+}
+
+/// Removes the given hop-by-hop headers from a request or response's headers, along with any headers
+/// named in its Connection header (as long as Connection itself is in the list). Callers that need
+/// to pass some of these headers through, such as Upgrade for WebSockets, can leave them out of
+/// `hop_by_hop`.
+pub fn strip_hop_by_hop_headers(headers: &mut http::HeaderMap, hop_by_hop: &[&str]) {
+    if hop_by_hop.contains(&"connection") {
+        let connection_options: Vec<String> = headers
+            .get_all("connection")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+        for name in connection_options {
+            headers.remove(name.as_str());
+        }
+    }
+    for name in hop_by_hop {
+        headers.remove(*name);
+    }
+}
+
+/// Returns the protocol version in the form used by the Via header ("1.1" for HTTP/1.1).
+pub fn via_protocol(version: http::Version) -> &'static str {
+    match version {
+        http::Version::HTTP_09 => "0.9",
+        http::Version::HTTP_10 => "1.0",
+        http::Version::HTTP_2 => "2",
+        http::Version::HTTP_3 => "3",
+        _ => "1.1",
+    }
+}
+
+/// Returns whether a request has already passed through a proxy calling itself `pseudonym`, going
+/// by the received-by field of each entry in its Via header.
+pub fn via_contains(request: &http::Request<Vec<u8>>, pseudonym: &str) -> bool {
+    request
+        .headers()
+        .get_all("via")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|entry| entry.split_whitespace().nth(1) == Some(pseudonym))
+}
+
+/// Returns the host a request is addressed to, taken from the Host header (or the request target,
+/// if it is in absolute form). The port is removed and the name is lowercased. Returns None if the
+/// request doesn't name a host.
+pub fn get_host(request: &http::Request<Vec<u8>>) -> Option<String> {
+    let host = match request.headers().get("host") {
+        Some(header_value) => header_value.to_str().ok()?,
+        None => request.uri().host()?,
+    };
+    let host = if host.starts_with('[') {
+        // IPv6 literal, e.g. [::1]:8080
+        &host[..=host.find(']')?]
+    } else {
+        host.split(':').next()?
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some(host.to_ascii_lowercase())
+}
+
 /// Attempts to parse the data in the supplied buffer as an HTTP request. Returns one of the
 /// following:
 ///
@@ -74,60 +247,105 @@ pub fn extend_header_value(
 /// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
 ///
 /// You won't need to touch this function.
-fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
-    let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
+#[allow(clippy::type_complexity)]
+fn parse_request(buffer: &[u8], max_headers: usize) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
+    let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
     let mut req = httparse::Request::new(&mut headers);
-    let res = req.parse(buffer).or_else(|err| Err(Error::MalformedRequest(err)))?;
+    let res = req.parse(buffer).map_err(|err| match err {
+        httparse::Error::TooManyHeaders => Error::HeadersTooLarge,
+        err => Error::MalformedRequest(err),
+    })?;
 
     if let httparse::Status::Complete(len) = res {
+        let version = if req.version == Some(0) { http::Version::HTTP_10 } else { http::Version::HTTP_11 };
         let mut request = http::Request::builder()
             .method(req.method.unwrap())
             .uri(req.path.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(version);
         for header in req.headers {
             request = request.header(header.name, header.value);
         }
-        let request = request.body(Vec::new()).unwrap();
+        let request = request.body(Vec::new()).or(Err(Error::InvalidRequestTarget))?;
+        let uri = request.uri();
+        let is_authority_form =
+            uri.scheme().is_none() && uri.host().is_some() && uri.path_and_query().is_none();
+        let valid_target = if request.method() == http::Method::CONNECT {
+            is_authority_form && uri.port_u16().is_some()
+        } else {
+            !is_authority_form
+        };
+        if !valid_target {
+            return Err(Error::InvalidRequestTarget);
+        }
         Ok(Some((request, len)))
     } else {
         Ok(None)
     }
 }
 
+/// Checks the length of the request target in a (possibly incomplete) request line at the start of
+/// the buffer, returning UriTooLong as soon as it is known to be over the limit.
+fn check_uri_length(buffer: &[u8], max_uri_length: usize) -> Result<(), Error> {
+    let line = match buffer.iter().position(|&byte| byte == b'\n') {
+        Some(line_end) => &buffer[..line_end],
+        None => buffer,
+    };
+    // The target is everything between the method and the version
+    let target = match line.iter().position(|&byte| byte == b' ') {
+        Some(method_end) => &line[method_end + 1..],
+        None => return Ok(()),
+    };
+    let target_len = target.iter().position(|&byte| byte == b' ').unwrap_or(target.len());
+    if target_len > max_uri_length {
+        Err(Error::UriTooLong)
+    } else {
+        Ok(())
+    }
+}
+
 /// Reads an HTTP request from the provided stream, waiting until a complete set of headers is sent.
 /// This function only reads the request line and headers; the read_body function can subsequently
 /// be called in order to read the request body (for a POST request).
 ///
-/// Returns Ok(http::Request) if a valid request is received, or Error if not.
+/// Returns Ok(http::Request) if a valid request is received, or Error if not. The limits are
+/// checked as the request arrives, so we never buffer more than the header byte limit.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+async fn read_headers(
+    stream: &mut (impl AsyncRead + Unpin),
+    limits: &Limits,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
-    let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    let mut request_buffer = Vec::new();
+    let mut chunk = [0_u8; READ_CHUNK_SIZE];
     loop {
-        // Read bytes from the connection into the buffer, starting at position bytes_read
+        let budget = limits.max_header_bytes - request_buffer.len();
+        if budget == 0 {
+            return Err(Error::HeadersTooLarge);
+        }
+        // Read bytes from the connection, never more than the limit allows
         let new_bytes = stream
-            .read(&mut request_buffer[bytes_read..])
+            .read(&mut chunk[..min(READ_CHUNK_SIZE, budget)])
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete request
-            return Err(Error::IncompleteRequest(bytes_read));
+            return Err(Error::IncompleteRequest(request_buffer.len()));
         }
-        bytes_read += new_bytes;
+        request_buffer.extend_from_slice(&chunk[..new_bytes]);
+        check_uri_length(&request_buffer, limits.max_uri_length)?;
 
         // See if we've read a valid request so far
-        if let Some((mut request, headers_len)) = parse_request(&request_buffer[..bytes_read])? {
+        if let Some((mut request, headers_len)) = parse_request(&request_buffer, limits.max_headers)? {
             // We've read a complete set of headers. However, if this was a POST request, a request
             // body might have been included as well, and we might have read part of the body out of
             // the stream into header_buffer. We need to add those bytes to the Request body so that
             // we don't lose them
             request
                 .body_mut()
-                .extend_from_slice(&request_buffer[headers_len..bytes_read]);
+                .extend_from_slice(&request_buffer[headers_len..]);
             return Ok(request);
         }
     }
@@ -139,7 +357,7 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>,
 ///
 /// You will need to modify this function in Milestone 2.
 async fn read_body(
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncRead + Unpin),
     request: &mut http::Request<Vec<u8>>,
     content_length: usize,
 ) -> Result<(), Error> {
@@ -148,7 +366,7 @@ async fn read_body(
         // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
         // space to read that body.)
         let mut buffer = vec![0_u8; min(512, content_length)];
-        let bytes_read = stream.read(&mut buffer).await.or_else(|err| Err(Error::ConnectionError(err)))?;
+        let bytes_read = stream.read(&mut buffer).await.map_err(Error::ConnectionError)?;
 
         // Make sure the client is still sending us bytes
         if bytes_read == 0 {
@@ -175,22 +393,105 @@ async fn read_body(
     Ok(())
 }
 
+/// Reads the head of an HTTP request from a stream, checking its Content-Length against the body
+/// size limit. The returned request's body holds whatever part of the body arrived along with the
+/// headers; the rest is left on the stream (see unread_body_len).
+pub async fn read_head(
+    stream: &mut (impl AsyncRead + Unpin),
+    limits: &Limits,
+) -> Result<http::Request<Vec<u8>>, Error> {
+    let request = read_headers(stream, limits).await?;
+    // Transfer-Encoding is hop-by-hop, so it would be stripped before forwarding, leaving the body
+    // on the connection to be read as the next request
+    if request.headers().contains_key("transfer-encoding") {
+        return Err(if request.headers().contains_key("content-length") {
+            Error::ConflictingBodyLength
+        } else {
+            Error::UnsupportedTransferEncoding
+        });
+    }
+    if let Some(content_length) = get_content_length(&request)? {
+        if content_length > limits.max_body_bytes {
+            return Err(Error::RequestBodyTooLarge);
+        }
+        if request.body().len() > content_length {
+            log::debug!("Client sent more bytes than we expected based on the given content length!");
+            return Err(Error::ContentLengthMismatch);
+        }
+    }
+    Ok(request)
+}
+
+/// Returns how many bytes of the body of a request returned by read_head are still waiting on the
+/// stream.
+pub fn unread_body_len(request: &http::Request<Vec<u8>>) -> usize {
+    match get_content_length(request) {
+        Ok(Some(content_length)) => content_length.saturating_sub(request.body().len()),
+        _ => 0,
+    }
+}
+
+/// Reads the rest of the body of a request returned by read_head into the request.
+pub async fn read_rest_of_body(
+    stream: &mut (impl AsyncRead + Unpin),
+    request: &mut http::Request<Vec<u8>>,
+) -> Result<(), Error> {
+    if let Some(content_length) = get_content_length(request)? {
+        read_body(stream, request, content_length).await?;
+    }
+    Ok(())
+}
+
 /// This function reads and returns an HTTP request from a stream, returning an Error if the client
 /// closes the connection prematurely or sends an invalid request.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
-    // Read headers
-    let mut request = read_headers(stream).await?;
-    // Read body if the client supplied the Content-Length header (which it does for POST requests)
-    if let Some(content_length) = get_content_length(&request)? {
-        if content_length > MAX_BODY_SIZE {
-            return Err(Error::RequestBodyTooLarge);
-        } else {
-            read_body(stream, &mut request, content_length).await?;
+pub async fn read_from_stream(
+    stream: &mut (impl AsyncRead + Unpin),
+    limits: &Limits,
+) -> Result<http::Request<Vec<u8>>, Error> {
+    let mut request = read_head(stream, limits).await?;
+    read_rest_of_body(stream, &mut request).await?;
+    Ok(request)
+}
+
+/// Error from forward_body, saying which side of the copy failed
+#[derive(Debug)]
+pub enum ForwardError {
+    /// Reading the body from the client failed, or the client hung up partway through it
+    Client(Error),
+    /// Writing the body to the upstream failed
+    Upstream(std::io::Error),
+}
+
+/// Copies the part of a request body that read_head left on the client's stream to the upstream,
+/// one chunk at a time, so that only a chunk of the body is ever held in memory. `remaining` is the
+/// number of bytes left to copy, as returned by unread_body_len.
+pub async fn forward_body(
+    client: &mut (impl AsyncRead + Unpin),
+    upstream: &mut (impl AsyncWrite + Unpin),
+    mut remaining: usize,
+) -> Result<(), ForwardError> {
+    let mut buffer = vec![0_u8; min(BODY_CHUNK_SIZE, remaining)];
+    while remaining > 0 {
+        // Never read past the end of the body, since the client may have sent its next request
+        // right after it
+        let chunk_len = min(buffer.len(), remaining);
+        let bytes_read = client
+            .read(&mut buffer[..chunk_len])
+            .await
+            .map_err(|err| ForwardError::Client(Error::ConnectionError(err)))?;
+        if bytes_read == 0 {
+            log::debug!("Client hung up with {} bytes of the request body left to send", remaining);
+            return Err(ForwardError::Client(Error::ContentLengthMismatch));
         }
+        upstream
+            .write_all(&buffer[..bytes_read])
+            .await
+            .map_err(ForwardError::Upstream)?;
+        remaining -= bytes_read;
     }
-    Ok(request)
+    Ok(())
 }
 
 /// This function serializes a request to bytes and writes those bytes to the provided stream.
@@ -198,18 +499,18 @@ pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Ve
 /// You will need to modify this function in Milestone 2.
 pub async fn write_to_stream(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncWrite + Unpin),
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_request_line(request).into_bytes()).await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(format_request_line(request).as_bytes()).await?;
+    stream.write_all(b"\r\n").await?; // \r\n
     for (header_name, header_value) in request.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream.write_all(format!("{}: ", header_name).as_bytes()).await?;
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?; // \r\n
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await?;
+    stream.write_all(b"\r\n").await?;
+    if !request.body().is_empty() {
+        stream.write_all(request.body()).await?;
     }
     Ok(())
 }
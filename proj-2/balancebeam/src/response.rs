@@ -1,24 +1,75 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use crate::config::ErrorPageSpec;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
+pub const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
+/// Size of the chunks that streamed response bodies are copied to the client in
+const BODY_CHUNK_SIZE: usize = 8192;
+
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
-    /// Client hung up before sending a complete request
-    IncompleteResponse,
+    /// Server hung up before sending a complete response. Contains the number of bytes received
+    IncompleteResponse(usize),
     /// Client sent an invalid HTTP request. httparse::Error contains more details
+    #[allow(dead_code)]
     MalformedResponse(httparse::Error),
     /// The Content-Length header is present, but does not contain a valid numeric value
     InvalidContentLength,
-    /// The Content-Length header does not match the size of the request body that was sent
-    ContentLengthMismatch,
+    /// The server hung up before sending as much of the body as its Content-Length said. Contains
+    /// the number of bytes that were missing
+    #[allow(dead_code)]
+    TruncatedBody(usize),
+    /// The server sent more of the body than its Content-Length said
+    OverlongBody,
     /// The request body is bigger than MAX_BODY_SIZE
     ResponseBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
+    /// Encountered an I/O error when reading/writing a stream
+    #[allow(dead_code)]
     ConnectionError(std::io::Error),
+    /// The server sent nothing for longer than the idle timeout partway through the body
+    IdleTimeout,
+    /// A chunked body has a chunk size line, chunk ending or trailer that isn't valid
+    MalformedChunk,
+    /// The server hung up before sending the last chunk of a chunked body
+    UnterminatedChunkedBody,
+}
+
+/// Reads from the stream like AsyncReadExt::read, giving up if nothing arrives within
+/// `idle_timeout`.
+async fn read_within(
+    stream: &mut (impl AsyncRead + Unpin),
+    buffer: &mut [u8],
+    idle_timeout: Option<Duration>,
+) -> Result<usize, Error> {
+    let read = match idle_timeout {
+        Some(idle_timeout) => tokio::time::timeout(idle_timeout, stream.read(buffer))
+            .await
+            .map_err(|_| Error::IdleTimeout)?,
+        None => stream.read(buffer).await,
+    };
+    read.map_err(Error::ConnectionError)
+}
+
+impl Error {
+    /// Returns whether this error from reading a response head means the connection was already
+    /// dead when the request was sent: the server hung up (or reset the connection) without sending
+    /// a byte. Servers close idle keep-alive connections whenever they like, so a request sent on
+    /// one that has sat idle can fail this way through no fault of the server.
+    pub fn is_stale_connection(&self) -> bool {
+        match self {
+            Error::IncompleteResponse(0) => true,
+            Error::ConnectionError(error) => matches!(
+                error.kind(),
+                std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::BrokenPipe
+            ),
+            _ => false,
+        }
+    }
 }
 
 /// Extracts the Content-Length header value from the provided response. Returns Ok(Some(usize)) if
@@ -29,20 +80,136 @@ pub enum Error {
 fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize>, Error> {
     // Look for content-length header
     if let Some(header_value) = response.headers().get("content-length") {
-        // If it exists, parse it as a usize (or return InvalidResponseFormat if it can't be parsed as such)
-        Ok(Some(
-            header_value
-                .to_str()
-                .or(Err(Error::InvalidContentLength))?
-                .parse::<usize>()
-                .or(Err(Error::InvalidContentLength))?,
-        ))
+        // If it exists, parse it as a usize (or return InvalidResponseFormat if it can't be parsed as
+        // such). Content-Length is nothing but digits, though parse would also take a leading +.
+        let header_value = header_value.to_str().or(Err(Error::InvalidContentLength))?;
+        if header_value.is_empty() || !header_value.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(Error::InvalidContentLength);
+        }
+        Ok(Some(header_value.parse::<usize>().or(Err(Error::InvalidContentLength))?))
     } else {
         // If it doesn't exist, return None
         Ok(None)
     }
 }
 
+/// Returns whether the response's body is chunked, which it is if chunked is the last of the codings
+/// in its Transfer-Encoding header. A chunked body's size is only known once the last chunk has
+/// arrived, so any Content-Length is ignored (RFC 7230 section 3.3.3).
+fn is_chunked(response: &http::Response<Vec<u8>>) -> bool {
+    let last_coding = response
+        .headers()
+        .get_all("transfer-encoding")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .rfind(|coding| !coding.is_empty());
+    matches!(last_coding, Some(coding) if coding.eq_ignore_ascii_case("chunked"))
+}
+
+/// A chunked body (RFC 7230 section 4.1) being read from the upstream, decoded back into the bytes
+/// of the body one piece at a time. Chunk extensions and trailers are thrown away.
+struct ChunkedBody<'a, S> {
+    stream: &'a mut S,
+    /// Bytes read from the stream but not decoded yet
+    buffer: Vec<u8>,
+    idle_timeout: Option<Duration>,
+    /// Bytes of the current chunk's data that haven't been returned yet
+    chunk_remaining: usize,
+    /// Whether the CRLF that ends the current chunk's data still has to be read
+    chunk_ending: bool,
+    /// Whether the last chunk and the trailers have been read
+    done: bool,
+}
+
+impl<'a, S: AsyncRead + Unpin> ChunkedBody<'a, S> {
+    /// Starts decoding a chunked body, given the part of it that was read along with the head.
+    fn new(stream: &'a mut S, buffered: Vec<u8>, idle_timeout: Option<Duration>) -> Self {
+        ChunkedBody {
+            stream,
+            buffer: buffered,
+            idle_timeout,
+            chunk_remaining: 0,
+            chunk_ending: false,
+            done: false,
+        }
+    }
+
+    /// Reads more of the body from the stream into the buffer.
+    async fn fill(&mut self) -> Result<(), Error> {
+        let mut chunk = vec![0_u8; BODY_CHUNK_SIZE];
+        let bytes_read = read_within(self.stream, &mut chunk, self.idle_timeout).await?;
+        if bytes_read == 0 {
+            return Err(Error::UnterminatedChunkedBody);
+        }
+        self.buffer.extend_from_slice(&chunk[..bytes_read]);
+        Ok(())
+    }
+
+    /// Takes a line ending in CRLF off the front of the buffer, reading more if needed, and returns
+    /// it without the CRLF.
+    async fn read_line(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            if let Some(line_end) = self.buffer.windows(2).position(|pair| pair == b"\r\n") {
+                let mut line: Vec<u8> = self.buffer.drain(..line_end + 2).collect();
+                line.truncate(line_end);
+                return Ok(line);
+            }
+            // A size line or trailer is never anywhere near this long
+            if self.buffer.len() > MAX_HEADERS_SIZE {
+                return Err(Error::MalformedChunk);
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Returns the next piece of the body, or None once the last chunk and the trailers have been
+    /// read. Anything the server sent after them is an error, as the stream couldn't be reused.
+    async fn next(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        loop {
+            if self.done {
+                return Ok(None);
+            }
+            if self.chunk_remaining > 0 {
+                if self.buffer.is_empty() {
+                    self.fill().await?;
+                }
+                let piece_len = self.buffer.len().min(self.chunk_remaining);
+                self.chunk_remaining -= piece_len;
+                return Ok(Some(self.buffer.drain(..piece_len).collect()));
+            }
+            let line = self.read_line().await?;
+            if self.chunk_ending {
+                if !line.is_empty() {
+                    return Err(Error::MalformedChunk);
+                }
+                self.chunk_ending = false;
+                continue;
+            }
+            // The size is in hex, and may be followed by extensions after a semicolon
+            let size = std::str::from_utf8(&line)
+                .ok()
+                .and_then(|line| line.split(';').next())
+                .map(str::trim)
+                .filter(|size| !size.is_empty() && size.bytes().all(|byte| byte.is_ascii_hexdigit()))
+                .and_then(|size| usize::from_str_radix(size, 16).ok())
+                .ok_or(Error::MalformedChunk)?;
+            if size > 0 {
+                self.chunk_remaining = size;
+                self.chunk_ending = true;
+                continue;
+            }
+            // The last chunk is followed by trailers, if any, and a blank line
+            while !self.read_line().await?.is_empty() {}
+            self.done = true;
+            if !self.buffer.is_empty() {
+                return Err(Error::OverlongBody);
+            }
+        }
+    }
+}
+
 /// Attempts to parse the data in the supplied buffer as an HTTP response. Returns one of the
 /// following:
 ///
@@ -52,12 +219,13 @@ fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize
 ///   Err(Error)
 ///
 /// You won't need to touch this function.
+#[allow(clippy::type_complexity)]
 fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut resp = httparse::Response::new(&mut headers);
     let res = resp
         .parse(buffer)
-        .or_else(|err| Err(Error::MalformedResponse(err)))?;
+        .map_err(Error::MalformedResponse)?;
 
     if let httparse::Status::Complete(len) = res {
         let mut response = http::Response::builder()
@@ -80,7 +248,7 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 /// Returns Ok(http::Response) if a valid response is received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+async fn read_headers(stream: &mut (impl AsyncRead + Unpin)) -> Result<http::Response<Vec<u8>>, Error> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
@@ -91,10 +259,10 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
         let new_bytes = stream
             .read(&mut response_buffer[bytes_read..])
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete response
-            return Err(Error::IncompleteResponse);
+            return Err(Error::IncompleteResponse(bytes_read));
         }
         bytes_read += new_bytes;
 
@@ -113,36 +281,54 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
 
 /// This function reads the body for a response from the stream. If the Content-Length header is
 /// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
+/// Each read must complete within `idle_timeout`, if one is given.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>>) -> Result<(), Error> {
+async fn read_body(
+    stream: &mut (impl AsyncRead + Unpin),
+    response: &mut http::Response<Vec<u8>>,
+    idle_timeout: Option<Duration>,
+) -> Result<(), Error> {
     // The response may or may not supply a Content-Length header. If it provides the header, then
     // we want to read that number of bytes; if it does not, we want to keep reading bytes until
     // the connection is closed.
+    if is_chunked(response) {
+        // The body is decoded, so it is sent on with a Content-Length instead
+        let buffered = std::mem::take(response.body_mut());
+        let mut chunked = ChunkedBody::new(stream, buffered, idle_timeout);
+        let mut body = Vec::new();
+        while let Some(piece) = chunked.next().await? {
+            if body.len() + piece.len() > MAX_BODY_SIZE {
+                return Err(Error::ResponseBodyTooLarge);
+            }
+            body.extend_from_slice(&piece);
+        }
+        response.headers_mut().remove("transfer-encoding");
+        set_body(response, body);
+        return Ok(());
+    }
     let content_length = get_content_length(response)?;
 
     while content_length.is_none() || response.body().len() < content_length.unwrap() {
         let mut buffer = [0_u8; 512];
-        let bytes_read = stream
-            .read(&mut buffer)
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        let bytes_read = read_within(stream, &mut buffer, idle_timeout).await?;
         if bytes_read == 0 {
             // The server has hung up!
-            if content_length.is_none() {
+            match content_length {
                 // We've reached the end of the response
-                break;
-            } else {
+                None => break,
                 // Content-Length was set, but the server hung up before we managed to read that
                 // number of bytes
-                return Err(Error::ContentLengthMismatch);
+                Some(content_length) => {
+                    return Err(Error::TruncatedBody(content_length - response.body().len()))
+                }
             }
         }
 
         // Make sure the server doesn't send more bytes than it promised to send
         if content_length.is_some() && response.body().len() + bytes_read > content_length.unwrap()
         {
-            return Err(Error::ContentLengthMismatch);
+            return Err(Error::OverlongBody);
         }
 
         // Make sure server doesn't send more bytes than we allow
@@ -156,48 +342,233 @@ async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>
     Ok(())
 }
 
+/// How much of a response's body is left on the upstream stream after its head has been read
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RemainingBody {
+    /// The body (if any) has been read along with the head
+    Done,
+    /// This many bytes of the body, going by Content-Length, haven't been read yet
+    Bytes(usize),
+    /// The body has no Content-Length, so it ends when the upstream closes the connection
+    UntilClose,
+    /// The body is chunked, so it ends with its last chunk. Part of it may have been read along
+    /// with the head, still in chunks.
+    Chunked,
+}
+
+/// Returns whether a response to this request method can have a body. A response may have a body
+/// as long as it is not responding to a HEAD request and as long as the response status code is
+/// not 1xx, 204 (no content), or 304 (not modified).
+fn has_body(response: &http::Response<Vec<u8>>, request_method: &http::Method) -> bool {
+    !(request_method == http::Method::HEAD
+        || response.status().as_u16() < 200
+        || response.status() == http::StatusCode::NO_CONTENT
+        || response.status() == http::StatusCode::NOT_MODIFIED)
+}
+
+/// Reads the head of an HTTP response from a stream. The returned response's body holds whatever
+/// part of the body arrived along with the headers; remaining_body says what is left on the stream.
+pub async fn read_head(
+    stream: &mut (impl AsyncRead + Unpin),
+    request_method: &http::Method,
+) -> Result<http::Response<Vec<u8>>, Error> {
+    let response = read_headers(stream).await?;
+    if has_body(&response, request_method) && !is_chunked(&response) {
+        if let Some(content_length) = get_content_length(&response)? {
+            if response.body().len() > content_length {
+                return Err(Error::OverlongBody);
+            }
+        }
+    }
+    Ok(response)
+}
+
+/// Returns how much of the body of a response returned by read_head is still waiting on the stream.
+/// This has to be asked before Transfer-Encoding is stripped from the response.
+pub fn remaining_body(response: &http::Response<Vec<u8>>, request_method: &http::Method) -> RemainingBody {
+    if !has_body(response, request_method) {
+        return RemainingBody::Done;
+    }
+    if is_chunked(response) {
+        return RemainingBody::Chunked;
+    }
+    // Any other transfer coding leaves the body to end when the connection does
+    if response.headers().contains_key("transfer-encoding") {
+        return RemainingBody::UntilClose;
+    }
+    match get_content_length(response) {
+        Ok(Some(content_length)) if content_length > response.body().len() => {
+            RemainingBody::Bytes(content_length - response.body().len())
+        }
+        Ok(Some(_)) | Err(_) => RemainingBody::Done,
+        Ok(None) => RemainingBody::UntilClose,
+    }
+}
+
 /// This function reads and returns an HTTP response from a stream, returning an Error if the server
 /// closes the connection prematurely or sends an invalid response.
 ///
 /// You will need to modify this function in Milestone 2.
 pub async fn read_from_stream(
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncRead + Unpin),
     request_method: &http::Method,
 ) -> Result<http::Response<Vec<u8>>, Error> {
-    let mut response = read_headers(stream).await?;
-    // A response may have a body as long as it is not responding to a HEAD request and as long as
-    // the response status code is not 1xx, 204 (no content), or 304 (not modified).
-    if !(request_method == http::Method::HEAD
-        || response.status().as_u16() < 200
-        || response.status() == http::StatusCode::NO_CONTENT
-        || response.status() == http::StatusCode::NOT_MODIFIED)
-    {
-        read_body(stream, &mut response).await?;
-    }
+    let mut response = read_head(stream, request_method).await?;
+    read_rest_of_body(stream, &mut response, request_method, None).await?;
     Ok(response)
 }
 
+/// Reads the rest of the body of a response returned by read_head into the response, giving up if
+/// the server goes quiet for longer than `idle_timeout`. A chunked body is decoded, and given a
+/// Content-Length in place of its Transfer-Encoding.
+pub async fn read_rest_of_body(
+    stream: &mut (impl AsyncRead + Unpin),
+    response: &mut http::Response<Vec<u8>>,
+    request_method: &http::Method,
+    idle_timeout: Option<Duration>,
+) -> Result<(), Error> {
+    if remaining_body(response, request_method) != RemainingBody::Done {
+        read_body(stream, response, idle_timeout).await?;
+    }
+    Ok(())
+}
+
+/// Error from forward_body, saying which side of the copy failed
+#[derive(Debug)]
+pub enum ForwardError {
+    /// Reading the body from the upstream failed, or it hung up before sending all of it
+    Upstream(Error),
+    /// Writing the body to the client failed
+    Client(std::io::Error),
+}
+
+/// Copies the rest of a response body from the upstream to the client as it arrives, one chunk at
+/// a time, so that only a chunk of it is ever held in memory. Returns the number of bytes copied.
+/// The transfer as a whole may take as long as it needs, but the upstream must send each chunk
+/// within `idle_timeout`.
+///
+/// If the upstream sends more than Content-Length said, only the declared length is copied, and
+/// OverlongBody is returned so that the upstream connection isn't used again. (Extra bytes are only
+/// noticed if they arrive along with the end of the body.) Chunked bodies go through
+/// forward_chunked_body instead.
+pub async fn forward_body(
+    upstream: &mut (impl AsyncRead + Unpin),
+    client: &mut (impl AsyncWrite + Unpin),
+    remaining: RemainingBody,
+    idle_timeout: Option<Duration>,
+) -> Result<usize, ForwardError> {
+    let mut remaining = match remaining {
+        RemainingBody::Done => return Ok(0),
+        RemainingBody::Bytes(len) => Some(len),
+        RemainingBody::UntilClose => None,
+        RemainingBody::Chunked => unreachable!("Chunked bodies are forwarded by forward_chunked_body"),
+    };
+    // One byte more than the last chunk of the body, to see whether anything follows it
+    let mut buffer = vec![0_u8; BODY_CHUNK_SIZE + 1];
+    let mut forwarded = 0;
+    while remaining != Some(0) {
+        let chunk_len = remaining.map_or(BODY_CHUNK_SIZE, |remaining| remaining.min(BODY_CHUNK_SIZE));
+        let read_len = if remaining == Some(chunk_len) { chunk_len + 1 } else { chunk_len };
+        let bytes_read = read_within(upstream, &mut buffer[..read_len], idle_timeout)
+            .await
+            .map_err(ForwardError::Upstream)?;
+        if bytes_read == 0 {
+            if let Some(remaining) = remaining {
+                return Err(ForwardError::Upstream(Error::TruncatedBody(remaining)));
+            }
+            // The upstream closing the connection marks the end of the body
+            break;
+        }
+        let body_bytes = bytes_read.min(chunk_len);
+        client
+            .write_all(&buffer[..body_bytes])
+            .await
+            .map_err(ForwardError::Client)?;
+        forwarded += body_bytes;
+        remaining = remaining.map(|remaining| remaining - body_bytes);
+        if bytes_read > body_bytes {
+            return Err(ForwardError::Upstream(Error::OverlongBody));
+        }
+    }
+    Ok(forwarded)
+}
+
+/// Like forward_body, for a chunked body, given the part of it that was read along with the head.
+/// The body is decoded as it arrives, so that we know when it ends. If `rechunk` is set, it is sent
+/// on to the client in chunks again; otherwise it is sent as is, and the client will only know it
+/// has ended when the connection is closed. Returns the number of body bytes copied.
+pub async fn forward_chunked_body(
+    upstream: &mut (impl AsyncRead + Unpin),
+    client: &mut (impl AsyncWrite + Unpin),
+    buffered: Vec<u8>,
+    rechunk: bool,
+    idle_timeout: Option<Duration>,
+) -> Result<usize, ForwardError> {
+    let mut chunked = ChunkedBody::new(upstream, buffered, idle_timeout);
+    let mut forwarded = 0;
+    while let Some(piece) = chunked.next().await.map_err(ForwardError::Upstream)? {
+        if rechunk {
+            let size_line = format!("{:x}\r\n", piece.len());
+            client.write_all(size_line.as_bytes()).await.map_err(ForwardError::Client)?;
+        }
+        client.write_all(&piece).await.map_err(ForwardError::Client)?;
+        if rechunk {
+            client.write_all(b"\r\n").await.map_err(ForwardError::Client)?;
+        }
+        forwarded += piece.len();
+    }
+    if rechunk {
+        client.write_all(b"0\r\n\r\n").await.map_err(ForwardError::Client)?;
+    }
+    Ok(forwarded)
+}
+
 /// This function serializes a response to bytes and writes those bytes to the provided stream.
 ///
 /// You will need to modify this function in Milestone 2.
 pub async fn write_to_stream(
     response: &http::Response<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncWrite + Unpin),
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_response_line(response).into_bytes()).await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(format_response_line(response).as_bytes()).await?;
+    stream.write_all(b"\r\n").await?; // \r\n
     for (header_name, header_value) in response.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream.write_all(format!("{}: ", header_name).as_bytes()).await?;
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?; // \r\n
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if response.body().len() > 0 {
-        stream.write(response.body()).await?;
+    stream.write_all(b"\r\n").await?;
+    if !response.body().is_empty() {
+        stream.write_all(response.body()).await?;
     }
     Ok(())
 }
 
+/// Replaces the body of a response, updating Content-Length to match.
+pub fn set_body(response: &mut http::Response<Vec<u8>>, body: Vec<u8>) {
+    response
+        .headers_mut()
+        .insert("content-length", http::HeaderValue::from(body.len()));
+    *response.body_mut() = body;
+}
+
+/// Appends a value to a comma-separated response header, adding the header if it isn't present.
+pub fn extend_header_value(
+    response: &mut http::Response<Vec<u8>>,
+    name: &'static str,
+    extend_value: &str,
+) {
+    let new_value = match response.headers().get(name) {
+        Some(existing_value) => {
+            [existing_value.as_bytes(), b", ", extend_value.as_bytes()].concat()
+        }
+        None => extend_value.as_bytes().to_owned(),
+    };
+    response
+        .headers_mut()
+        .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
+}
+
 pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
     format!(
         "{:?} {} {}",
@@ -210,12 +581,15 @@ pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
 /// This is a helper function that creates an http::Response containing an HTTP error that can be
 /// sent to a client.
 pub fn make_http_error(status: http::StatusCode) -> http::Response<Vec<u8>> {
-    let body = format!(
-        "HTTP {} {}",
-        status.as_u16(),
-        status.canonical_reason().unwrap_or("")
+    make_text_response(
+        status,
+        format!("HTTP {} {}", status.as_u16(), status.canonical_reason().unwrap_or("")),
     )
-    .into_bytes();
+}
+
+/// Creates a plain text response with the given body.
+pub fn make_text_response(status: http::StatusCode, body: String) -> http::Response<Vec<u8>> {
+    let body = body.into_bytes();
     http::Response::builder()
         .status(status)
         .header("Content-Type", "text/plain")
@@ -224,3 +598,39 @@ pub fn make_http_error(status: http::StatusCode) -> http::Response<Vec<u8>> {
         .body(body)
         .unwrap()
 }
+
+/// HTML bodies for error responses, for the status codes that operators have given custom pages
+/// for with --error-page
+#[derive(Default)]
+pub struct ErrorPages {
+    pages: HashMap<http::StatusCode, Vec<u8>>,
+}
+
+impl ErrorPages {
+    /// Reads the page for each status code, filling in any `{status}` placeholders with the status
+    /// (e.g. "502 Bad Gateway").
+    pub fn load(specs: &[ErrorPageSpec]) -> Result<ErrorPages, String> {
+        let mut pages = HashMap::new();
+        for spec in specs {
+            let page = std::fs::read_to_string(&spec.path)
+                .map_err(|err| format!("Could not read error page {}: {}", spec.path, err))?;
+            let page = page.replace("{status}", &spec.status.to_string());
+            pages.insert(spec.status, page.into_bytes());
+        }
+        Ok(ErrorPages { pages })
+    }
+
+    /// Like make_http_error, but uses the custom page for the status code if there is one.
+    pub fn make_http_error(&self, status: http::StatusCode) -> http::Response<Vec<u8>> {
+        match self.pages.get(&status) {
+            Some(page) => http::Response::builder()
+                .status(status)
+                .header("Content-Type", "text/html")
+                .header("Content-Length", page.len().to_string())
+                .version(http::Version::HTTP_11)
+                .body(page.clone())
+                .unwrap(),
+            None => make_http_error(status),
+        }
+    }
+}
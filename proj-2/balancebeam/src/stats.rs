@@ -0,0 +1,122 @@
+//! Per-upstream request statistics. They are kept in atomics so that connection handlers can update
+//! them without a lock, and are reported on SIGUSR2 and through the admin endpoint.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Counters for the requests forwarded to one upstream server
+#[derive(Default)]
+pub struct UpstreamStats {
+    /// Requests sent to the upstream
+    requests: AtomicU64,
+    /// Responses by status class: 1xx, 2xx, 3xx, 4xx and 5xx
+    responses: [AtomicU64; 5],
+    /// Requests that were sent but got no response, because the connection broke or the response
+    /// couldn't be parsed
+    failures: AtomicU64,
+    /// Attempts to connect to the upstream that failed
+    connect_failures: AtomicU64,
+    /// Requests sent to the upstream as hedges, because the upstream first sent them was slow to
+    /// answer, and how many of those it answered first
+    hedges: AtomicU64,
+    hedge_wins: AtomicU64,
+    /// Response times of the responses, in microseconds
+    latency_min: AtomicU64,
+    latency_max: AtomicU64,
+    latency_total: AtomicU64,
+}
+
+impl UpstreamStats {
+    pub fn record_connect_failure(&self) {
+        self.connect_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a request sent to the upstream as a hedge.
+    pub fn record_hedge(&self) {
+        self.hedges.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a hedge that the upstream answered before the upstream first sent the request did.
+    pub fn record_hedge_win(&self) {
+        self.hedge_wins.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a request that was sent but got no usable response.
+    pub fn record_failure(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a request that got a response with the given status, after `latency`.
+    pub fn record_response(&self, status: http::StatusCode, latency: Duration) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let class = (status.as_u16() / 100).clamp(1, 5) as usize;
+        self.responses[class - 1].fetch_add(1, Ordering::Relaxed);
+        // Zero means no responses yet, so the fastest possible response counts as 1µs
+        let micros = (latency.as_micros() as u64).max(1);
+        let _ = self.latency_min.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |min| {
+            if min == 0 || micros < min {
+                Some(micros)
+            } else {
+                None
+            }
+        });
+        self.latency_max.fetch_max(micros, Ordering::Relaxed);
+        self.latency_total.fetch_add(micros, Ordering::Relaxed);
+    }
+}
+
+/// One upstream server's line in the statistics table
+pub struct Row<'a> {
+    pub group: &'a str,
+    pub address: &'a str,
+    pub stats: &'a UpstreamStats,
+    /// Requests being forwarded to the upstream right now
+    pub in_flight: usize,
+}
+
+/// Formats the statistics of each upstream server as a table, one row per upstream.
+pub fn format_table<'a>(rows: impl Iterator<Item = Row<'a>>) -> String {
+    let mut table = format!(
+        concat!(
+            "{:<10} {:<22} {:>8} {:>6} {:>6} {:>6} {:>6} {:>6} {:>8} {:>8} ",
+            "{:>8} {:>8} {:>8} {:>8} {:>8} {:>8}\n"
+        ),
+        "Group", "Upstream", "Requests", "1xx", "2xx", "3xx", "4xx", "5xx", "Failed", "ConnFail", "Hedged",
+        "HedgeWon", "Min ms", "Avg ms", "Max ms", "InFlight"
+    );
+    for Row { group, address, stats, in_flight } in rows {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let responses: u64 = stats.responses.iter().map(load).sum();
+        let millis = |micros: u64| format!("{:.1}", micros as f64 / 1000.0);
+        let (min, avg, max) = match load(&stats.latency_total).checked_div(responses) {
+            Some(avg) => (millis(load(&stats.latency_min)), millis(avg), millis(load(&stats.latency_max))),
+            None => ("-".to_string(), "-".to_string(), "-".to_string()),
+        };
+        let _ = writeln!(
+            table,
+            concat!(
+                "{:<10} {:<22} {:>8} {:>6} {:>6} {:>6} {:>6} {:>6} {:>8} {:>8} ",
+                "{:>8} {:>8} {:>8} {:>8} {:>8} {:>8}"
+            ),
+            group,
+            address,
+            load(&stats.requests),
+            load(&stats.responses[0]),
+            load(&stats.responses[1]),
+            load(&stats.responses[2]),
+            load(&stats.responses[3]),
+            load(&stats.responses[4]),
+            load(&stats.failures),
+            load(&stats.connect_failures),
+            load(&stats.hedges),
+            load(&stats.hedge_wins),
+            min,
+            avg,
+            max,
+            in_flight
+        );
+    }
+    table
+}
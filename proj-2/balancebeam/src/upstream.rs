@@ -0,0 +1,95 @@
+//! Connections to upstream servers. An upstream listens either on a TCP port or, when its address is
+//! given as `unix:PATH`, on a Unix domain socket, and the rest of the proxy handles both alike.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpStream, UnixStream};
+
+pub enum UpstreamConn {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl UpstreamConn {
+    /// Connects to an upstream address, which is an IP/port or `unix:PATH`. Gives up with a
+    /// TimedOut error if the connection isn't open within `connect_timeout`, rather than waiting
+    /// minutes for the OS to give up on an upstream that never answers.
+    pub async fn connect(address: &str, connect_timeout: Duration) -> io::Result<UpstreamConn> {
+        let connect = async {
+            match address.strip_prefix("unix:") {
+                Some(path) => Ok(UpstreamConn::Unix(UnixStream::connect(path).await?)),
+                None => {
+                    let stream = TcpStream::connect(address).await?;
+                    // Requests are written in several small pieces, which Nagle's algorithm would
+                    // hold back waiting for delayed ACKs
+                    let _ = stream.set_nodelay(true);
+                    Ok(UpstreamConn::Tcp(stream))
+                }
+            }
+        };
+        match tokio::time::timeout(connect_timeout, connect).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("connection timed out after {:?}", connect_timeout),
+            )),
+        }
+    }
+
+    /// Names the upstream end of the connection for logs: its IP address, or the address the
+    /// connection was opened with for Unix sockets (or if the IP address can't be read).
+    pub fn peer_name(&self, address: &str) -> String {
+        match self {
+            UpstreamConn::Tcp(stream) => match stream.peer_addr() {
+                Ok(addr) => addr.ip().to_string(),
+                Err(_) => address.to_string(),
+            },
+            UpstreamConn::Unix(_) => address.to_string(),
+        }
+    }
+}
+
+/// Returns a Host header value for requests we make to an upstream ourselves, such as health checks.
+/// A socket path isn't a valid host, so Unix socket upstreams get `localhost`.
+pub fn host_header(address: &str) -> &str {
+    if address.starts_with("unix:") {
+        "localhost"
+    } else {
+        address
+    }
+}
+
+impl AsyncRead for UpstreamConn {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamConn::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamConn::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamConn {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamConn::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamConn::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamConn::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamConn::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamConn::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamConn::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
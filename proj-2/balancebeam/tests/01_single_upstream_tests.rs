@@ -69,7 +69,7 @@ async fn test_multiple_requests_per_connection() {
                 );
                 let path = format!("/conn-{}/req-{}", task_num, req_num);
                 let response_text = client
-                    .get(&format!("http://{}{}", balancebeam_shared.address, path))
+                    .get(format!("http://{}{}", balancebeam_shared.address, path))
                     .header("x-sent-by", "balancebeam-tests")
                     .send()
                     .await
@@ -99,3 +99,33 @@ async fn test_multiple_requests_per_connection() {
 
     log::info!("All done :)");
 }
+
+/// Clients that reset their connection as soon as it's accepted shouldn't make the connection
+/// handler panic, and balancebeam should keep serving other clients.
+#[tokio::test]
+async fn test_connection_reset_after_accept() {
+    let (balancebeam, upstream) = setup().await;
+
+    log::info!("Opening connections and resetting them straight away");
+    for _ in 0..50 {
+        let conn = tokio::net::TcpStream::connect(&balancebeam.address)
+            .await
+            .expect("Failed to connect to balancebeam");
+        // Closing with a zero linger time sends a RST instead of a FIN
+        conn.set_zero_linger().unwrap();
+        drop(conn);
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    let panics = balancebeam.output_containing("panicked");
+    assert!(panics.is_empty(), "balancebeam panicked: {:?}", panics);
+
+    log::info!("Checking that other clients are still served");
+    let response_text = balancebeam
+        .get("/after-resets")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /after-resets HTTP/1.1"));
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
@@ -271,7 +271,7 @@ async fn test_rate_limiting() {
     for i in 0..num_extra_requests {
         let client = reqwest::Client::new();
         let response = client
-            .get(&format!("http://{}/overboard-{}", balancebeam.address, i))
+            .get(format!("http://{}/overboard-{}", balancebeam.address, i))
             .header("x-sent-by", "balancebeam-tests")
             .send()
             .await
@@ -294,3 +294,42 @@ async fn test_rate_limiting() {
 
     log::info!("All done :)");
 }
+
+/// With a short rate limit window, a client that hit the limit should be let back in once the
+/// window is over.
+#[tokio::test]
+async fn test_rate_limit_window() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--max-requests-per-minute",
+        "2",
+        "--rate-limit-window",
+        "1s",
+    ])
+    .await;
+    let get_status = |path: &'static str| {
+        let url = format!("http://{}{}", balancebeam.address, path);
+        async move {
+            reqwest::get(url)
+                .await
+                .expect("Error sending request to balancebeam")
+                .status()
+                .as_u16()
+        }
+    };
+
+    // The window may roll over while we send these, so sending one over the limit may not be
+    // enough to see a 429
+    let mut statuses = Vec::new();
+    for _ in 0..4 {
+        statuses.push(get_status("/").await);
+    }
+    assert!(statuses.contains(&429), "Expected a 429, got {:?}", statuses);
+
+    sleep(Duration::from_millis(1500)).await;
+    assert_eq!(get_status("/").await, 200);
+    log::info!("All done :)");
+}
@@ -0,0 +1,195 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+async fn get_status(balancebeam: &BalanceBeam, path: &str) -> u16 {
+    let client = reqwest::Client::new();
+    client
+        .get(format!("http://{}{}", balancebeam.address, path))
+        .header("x-sent-by", "balancebeam-tests")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Route /api/ and /api/v2/ to their own groups and everything else to the --upstream hosts, and
+/// make sure each request lands on the right upstream. /api/v2/ overlaps /api/, so the longer
+/// prefix should win.
+#[tokio::test]
+async fn test_path_prefix_routing() {
+    init_logging();
+    let web = EchoServer::new().await;
+    let api = EchoServer::new().await;
+    let api_v2 = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream".to_string(),
+        web.address.clone(),
+        "--group".to_string(),
+        format!("api={}", api.address),
+        "--group".to_string(),
+        format!("api_v2={}", api_v2.address),
+        "--route".to_string(),
+        "prefix=/api/,group=api".to_string(),
+        "--route".to_string(),
+        "prefix=/api/v2/,group=api_v2".to_string(),
+    ])
+    .await;
+
+    for path in ["/", "/index.html", "/api", "/api/users", "/api/v1/users", "/api/v2/users"] {
+        log::info!("Sending a request for {}", path);
+        let response_text = balancebeam
+            .get(path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    log::info!("Checking that each group received the requests routed to it");
+    assert_eq!(Box::new(web).stop().await, 3);
+    assert_eq!(Box::new(api).stop().await, 2);
+    assert_eq!(Box::new(api_v2).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Without --upstream or --default-group, requests that match no route get a 404 and never reach
+/// an upstream.
+#[tokio::test]
+async fn test_unrouted_request_gets_404() {
+    init_logging();
+    let api = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--group".to_string(),
+        format!("api={}", api.address),
+        "--route".to_string(),
+        "prefix=/api/,group=api".to_string(),
+    ])
+    .await;
+
+    assert_eq!(get_status(&balancebeam, "/api/users").await, 200);
+    assert_eq!(get_status(&balancebeam, "/other").await, 404);
+
+    log::info!("Checking that only the routed request reached the upstream");
+    assert_eq!(Box::new(api).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Groups, routes, and the default group can also come from a config file.
+#[tokio::test]
+async fn test_routing_from_config_file() {
+    init_logging();
+    let web = EchoServer::new().await;
+    let api = EchoServer::new().await;
+    let config_path = std::env::temp_dir().join(format!(
+        "balancebeam-routing-test-{}.toml",
+        std::process::id()
+    ));
+    std::fs::write(
+        &config_path,
+        format!(
+            "default_group = \"web\"\n\n\
+            [groups]\n\
+            web = [\"{}\"]\n\
+            api = [\"{}\"]\n\n\
+            [[routes]]\n\
+            prefix = \"/api/\"\n\
+            group = \"api\"\n",
+            web.address, api.address
+        ),
+    )
+    .expect("Could not write config file");
+    let balancebeam =
+        BalanceBeam::new_with_args(&[std::ffi::OsStr::new("--config"), config_path.as_os_str()])
+            .await;
+
+    assert_eq!(get_status(&balancebeam, "/api/users").await, 200);
+    assert_eq!(get_status(&balancebeam, "/home").await, 200);
+    assert_eq!(get_status(&balancebeam, "/about").await, 200);
+
+    assert_eq!(Box::new(web).stop().await, 2);
+    assert_eq!(Box::new(api).stop().await, 1);
+    let _ = std::fs::remove_file(&config_path);
+
+    log::info!("All done :)");
+}
+
+async fn get_with_host(balancebeam: &BalanceBeam, host: &str, path: &str) -> reqwest::Response {
+    let client = reqwest::Client::new();
+    client
+        .get(format!("http://{}{}", balancebeam.address, path))
+        .header("Host", host)
+        .header("x-sent-by", "balancebeam-tests")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+}
+
+/// Route by Host header, including a wildcard route, and make sure unmatched hosts get 421 when
+/// there is no default group. Host matching should ignore case and the port.
+#[tokio::test]
+async fn test_host_routing() {
+    init_logging();
+    let app = EchoServer::new().await;
+    let admin = EchoServer::new().await;
+    let tenants = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--route".to_string(),
+        format!("host=app.example.com,upstreams={}", app.address),
+        "--route".to_string(),
+        format!("host=admin.example.com,upstreams={}", admin.address),
+        "--route".to_string(),
+        format!("host=*.example.com,upstreams={}", tenants.address),
+    ])
+    .await;
+
+    for host in ["app.example.com", "APP.Example.com:1100"] {
+        let response = get_with_host(&balancebeam, host, "/").await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    let response = get_with_host(&balancebeam, "admin.example.com", "/").await;
+    assert_eq!(response.status().as_u16(), 200);
+    for host in ["foo.example.com", "a.b.example.com:8080"] {
+        let response = get_with_host(&balancebeam, host, "/").await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    log::info!("Sending requests for hosts that no route serves");
+    for host in ["example.com", "other.org"] {
+        let response = get_with_host(&balancebeam, host, "/").await;
+        assert_eq!(response.status().as_u16(), 421);
+    }
+
+    assert_eq!(Box::new(app).stop().await, 2);
+    assert_eq!(Box::new(admin).stop().await, 1);
+    assert_eq!(Box::new(tenants).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// Unmatched hosts fall back to the default group when there is one.
+#[tokio::test]
+async fn test_host_routing_default_group() {
+    init_logging();
+    let app = EchoServer::new().await;
+    let fallback = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream".to_string(),
+        fallback.address.clone(),
+        "--route".to_string(),
+        format!("host=app.example.com,upstreams={}", app.address),
+    ])
+    .await;
+
+    let response = get_with_host(&balancebeam, "app.example.com", "/").await;
+    assert_eq!(response.status().as_u16(), 200);
+    let response = get_with_host(&balancebeam, "unknown.example.com", "/").await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    assert_eq!(Box::new(app).stop().await, 1);
+    assert_eq!(Box::new(fallback).stop().await, 1);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+async fn setup(cache_control: &str) -> (BalanceBeam, EchoServer) {
+    init_logging();
+    let upstream = EchoServer::new_with_response_headers(&[("Cache-Control", cache_control)]).await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--cache-max-bytes",
+        "1000000",
+    ])
+    .await;
+    (balancebeam, upstream)
+}
+
+async fn get(balancebeam: &BalanceBeam, path: &str, authorization: Option<&str>) -> (String, String) {
+    let client = reqwest::Client::new();
+    let mut request = client
+        .get(format!("http://{}{}", balancebeam.address, path))
+        .header("x-sent-by", "balancebeam-tests");
+    if let Some(authorization) = authorization {
+        request = request.header("Authorization", authorization);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let x_cache = response
+        .headers()
+        .get("x-cache")
+        .map(|value| value.to_str().unwrap().to_string())
+        .unwrap_or_default();
+    let text = response
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    (x_cache, text)
+}
+
+/// A second identical GET should be served from the cache without reaching the upstream, until the
+/// entry's max-age runs out.
+#[tokio::test]
+async fn test_cache_hit_and_expiry() {
+    let (balancebeam, upstream) = setup("public, max-age=2").await;
+
+    let (x_cache, first_text) = get(&balancebeam, "/cached?x=1", None).await;
+    assert_eq!(x_cache, "MISS");
+    let (x_cache, second_text) = get(&balancebeam, "/cached?x=1", None).await;
+    assert_eq!(x_cache, "HIT");
+    assert_eq!(first_text, second_text);
+
+    log::info!("A different query string is a different cache entry");
+    let (x_cache, _) = get(&balancebeam, "/cached?x=2", None).await;
+    assert_eq!(x_cache, "MISS");
+
+    log::info!("Waiting for the cached response to expire");
+    sleep(Duration::from_secs(3)).await;
+    let (x_cache, _) = get(&balancebeam, "/cached?x=1", None).await;
+    assert_eq!(x_cache, "MISS");
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+
+    log::info!("All done :)");
+}
+
+/// Responses marked no-store or private, and responses to requests with credentials, must never be
+/// served from the cache.
+#[tokio::test]
+async fn test_uncacheable_responses() {
+    for cache_control in ["no-store", "private, max-age=60"] {
+        let (balancebeam, upstream) = setup(cache_control).await;
+        for _ in 0..2 {
+            let (x_cache, _) = get(&balancebeam, "/uncacheable", None).await;
+            assert_eq!(x_cache, "MISS");
+        }
+        assert_eq!(Box::new(upstream).stop().await, 2);
+    }
+
+    let (balancebeam, upstream) = setup("max-age=60").await;
+    for _ in 0..2 {
+        let (x_cache, _) = get(&balancebeam, "/secret", Some("Basic dXNlcjpwYXNz")).await;
+        assert_eq!(x_cache, "");
+    }
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
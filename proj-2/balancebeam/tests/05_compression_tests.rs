@@ -0,0 +1,68 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use flate2::read::GzDecoder;
+use std::io::Read;
+
+async fn setup(content_type: &str) -> (BalanceBeam, EchoServer) {
+    init_logging();
+    let upstream = EchoServer::new_with_response_headers(&[("Content-Type", content_type)]).await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&["--upstream", &upstream.address, "--compress"]).await;
+    (balancebeam, upstream)
+}
+
+/// Sends a POST with a large, repetitive body (which the echo server sends back) and returns the
+/// response.
+async fn post_large_body(balancebeam: &BalanceBeam, accept_encoding: &str) -> (reqwest::Response, String) {
+    let body = "All work and no play makes Jack a dull boy.\n".repeat(1000);
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/", balancebeam.address))
+        .header("x-sent-by", "balancebeam-tests")
+        .header("Accept-Encoding", accept_encoding)
+        .body(body.clone())
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    (response, body)
+}
+
+/// A large text response should come back gzipped, smaller than the original, and decode to the
+/// same text.
+#[tokio::test]
+async fn test_text_response_is_compressed() {
+    let (balancebeam, upstream) = setup("text/plain; charset=utf-8").await;
+
+    let (response, body) = post_large_body(&balancebeam, "br, gzip;q=0.8").await;
+    assert_eq!(response.headers()["content-encoding"], "gzip");
+    assert_eq!(response.headers()["vary"], "Accept-Encoding");
+    let compressed = response.bytes().await.expect("Error reading response body");
+    assert!(compressed.len() < body.len());
+    let mut decoded = String::new();
+    GzDecoder::new(&compressed[..])
+        .read_to_string(&mut decoded)
+        .expect("Response body is not valid gzip");
+    assert!(decoded.ends_with(&body));
+
+    log::info!("Clients that don't accept gzip get the uncompressed body");
+    let (response, body) = post_large_body(&balancebeam, "gzip;q=0, identity").await;
+    assert!(response.headers().get("content-encoding").is_none());
+    assert!(response.text().await.unwrap().ends_with(&body));
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Images are already compressed, so they should pass through untouched.
+#[tokio::test]
+async fn test_image_is_not_compressed() {
+    let (balancebeam, upstream) = setup("image/png").await;
+
+    let (response, body) = post_large_body(&balancebeam, "gzip").await;
+    assert!(response.headers().get("content-encoding").is_none());
+    assert!(response.headers().get("vary").is_none());
+    assert!(response.text().await.unwrap().ends_with(&body));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
@@ -0,0 +1,161 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+/// Sends `request` on a new connection and reads everything balancebeam sends back until it closes
+/// the connection (or goes quiet, which counts as a failure).
+async fn send_raw(balancebeam: &BalanceBeam, request: &str) -> String {
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), conn.read_to_end(&mut response))
+        .await
+        .expect("balancebeam left the connection open")
+        .expect("Error reading from balancebeam");
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+/// Hop-by-hop headers, and headers that the client names in its Connection header, should not be
+/// forwarded to the upstream. End-to-end headers should still make it through.
+#[tokio::test]
+async fn test_request_hop_by_hop_headers_stripped() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let response_text = reqwest::Client::new()
+        .get(format!("http://{}/", balancebeam.address))
+        .header("x-sent-by", "balancebeam-tests")
+        .header("Connection", "keep-alive, X-Connection-Scoped")
+        .header("X-Connection-Scoped", "secret")
+        .header("Keep-Alive", "timeout=5")
+        .header("Proxy-Connection", "keep-alive")
+        .header("TE", "trailers")
+        .header("X-End-To-End", "hello")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    let forwarded = response_text.to_ascii_lowercase();
+    for header in ["connection:", "x-connection-scoped:", "keep-alive:", "proxy-connection:", "te:"] {
+        assert!(!forwarded.contains(header), "{} was forwarded:\n{}", header, response_text);
+    }
+    assert!(forwarded.contains("x-end-to-end: hello"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// The same goes for hop-by-hop headers in upstream responses.
+#[tokio::test]
+async fn test_response_hop_by_hop_headers_stripped() {
+    init_logging();
+    let upstream = EchoServer::new_with_response_headers(&[
+        ("Connection", "X-Upstream-Scoped"),
+        ("X-Upstream-Scoped", "secret"),
+        ("Keep-Alive", "timeout=5"),
+        ("X-End-To-End", "hello"),
+    ])
+    .await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/", balancebeam.address))
+        .header("x-sent-by", "balancebeam-tests")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response.headers().get("x-upstream-scoped").is_none());
+    assert!(response.headers().get("keep-alive").is_none());
+    assert_eq!(response.headers()["x-end-to-end"], "hello");
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Forwarded requests and responses should carry our Via entry, appended to any existing one.
+#[tokio::test]
+async fn test_via_header_added() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/", balancebeam.address))
+        .header("x-sent-by", "balancebeam-tests")
+        .header("Via", "1.0 corporate-proxy")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.headers()["via"], "1.1 balancebeam");
+    let response_text = response.text().await.unwrap();
+    assert!(response_text.contains("via: 1.0 corporate-proxy, 1.1 balancebeam"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A balancebeam that forwards to itself should notice the request coming back around and respond
+/// with 508 instead of looping forever.
+#[tokio::test]
+async fn test_forwarding_loop_detected() {
+    init_logging();
+    let address = random_address();
+    let balancebeam = BalanceBeam::new_at_address(address.clone(), &["--upstream", &address]).await;
+
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/", balancebeam.address))
+        .header("x-sent-by", "balancebeam-tests")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 508);
+
+    log::info!("All done :)");
+}
+
+/// Transfer-Encoding is hop-by-hop, and we can't find the end of a chunked body, so a request that
+/// uses it is refused and the connection closed. Otherwise the body would be read as a request of
+/// its own, smuggled past everything we check.
+#[tokio::test]
+async fn test_transfer_encoding_rejected() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let smuggled = "GET /smuggled HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    let chunked_body = format!("{:x}\r\n{}\r\n0\r\n\r\n", smuggled.len(), smuggled);
+    let response = send_raw(
+        &balancebeam,
+        &format!(
+            "POST /upload HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n{}",
+            chunked_body
+        ),
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 501"), "Unexpected response: {}", response);
+    assert_eq!(response.matches("HTTP/1.1 ").count(), 1, "Unexpected responses: {}", response);
+
+    // With a Content-Length as well, the two headers disagree about where the body ends
+    let response = send_raw(
+        &balancebeam,
+        &format!(
+            "POST /upload HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\
+            Content-Length: {}\r\n\r\n{}",
+            chunked_body.len(),
+            chunked_body
+        ),
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 400"), "Unexpected response: {}", response);
+    assert_eq!(response.matches("HTTP/1.1 ").count(), 1, "Unexpected responses: {}", response);
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
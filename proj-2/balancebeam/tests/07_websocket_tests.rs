@@ -0,0 +1,82 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Starts a WebSocket server that echoes every message back to the sender, returning its address.
+async fn start_websocket_echo_server() -> String {
+    let address = random_address();
+    let listener = TcpListener::bind(&address)
+        .await
+        .expect("Could not bind WebSocket echo server");
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut websocket = match tokio_tungstenite::accept_async(stream).await {
+                    Ok(websocket) => websocket,
+                    Err(err) => {
+                        log::error!("WebSocket handshake failed: {}", err);
+                        return;
+                    }
+                };
+                while let Some(Ok(message)) = websocket.next().await {
+                    if message.is_close() || websocket.send(message).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// A WebSocket connection made through balancebeam should complete the handshake and carry frames
+/// in both directions, while ordinary requests keep working.
+#[tokio::test]
+async fn test_websocket_passthrough() {
+    init_logging();
+    let websocket_address = start_websocket_echo_server().await;
+    let http_upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream".to_string(),
+        http_upstream.address.clone(),
+        "--route".to_string(),
+        format!("prefix=/chat,upstreams={}", websocket_address),
+    ])
+    .await;
+
+    let (mut websocket, response) =
+        tokio_tungstenite::connect_async(format!("ws://{}/chat", balancebeam.address))
+            .await
+            .expect("WebSocket handshake through balancebeam failed");
+    assert_eq!(response.status().as_u16(), 101);
+
+    for text in ["hello", "through", "the proxy"] {
+        websocket
+            .send(Message::Text(text.to_string()))
+            .await
+            .expect("Error sending WebSocket message");
+        let reply = websocket
+            .next()
+            .await
+            .expect("WebSocket closed unexpectedly")
+            .expect("Error reading WebSocket message");
+        assert_eq!(reply, Message::Text(text.to_string()));
+    }
+    let binary = vec![0_u8, 1, 2, 255];
+    websocket.send(Message::Binary(binary.clone())).await.unwrap();
+    assert_eq!(websocket.next().await.unwrap().unwrap(), Message::Binary(binary));
+    websocket.close(None).await.expect("Error closing WebSocket");
+
+    log::info!("Checking that plain HTTP requests still work");
+    let response_text = balancebeam
+        .get("/index.html")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /index.html HTTP/1.1"));
+    assert_eq!(Box::new(http_upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,79 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Sends a CONNECT request for `target` and returns the connection along with the response head.
+async fn send_connect(balancebeam: &BalanceBeam, target: &str) -> (TcpStream, String) {
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    conn.write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target).as_bytes())
+        .await
+        .unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0_u8; 1];
+        if conn.read(&mut byte).await.unwrap() == 0 {
+            break;
+        }
+        head.push(byte[0]);
+    }
+    (conn, String::from_utf8(head).unwrap())
+}
+
+/// An allowed CONNECT should get 200 and then carry raw bytes to the target. Here the target is an
+/// HTTP server, so we speak HTTP through the tunnel.
+#[tokio::test]
+async fn test_connect_tunnel() {
+    init_logging();
+    let target = EchoServer::new().await;
+    let port = target.address.rsplit_once(':').unwrap().1.to_string();
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream".to_string(),
+        "127.0.0.1:1".to_string(),
+        "--allow-connect".to_string(),
+        format!("127.0.0.1:{}", port),
+    ])
+    .await;
+
+    let (mut conn, head) = send_connect(&balancebeam, &target.address).await;
+    assert!(head.starts_with("HTTP/1.1 200"), "Unexpected response: {}", head);
+    conn.write_all(b"GET /tunneled HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.contains("GET /tunneled HTTP/1.1"));
+    // balancebeam doesn't touch tunneled traffic, so there should be no Via or X-Forwarded-For
+    assert!(!response.to_ascii_lowercase().contains("x-forwarded-for"));
+
+    assert_eq!(Box::new(target).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Targets missing from the allowlist get 403, and CONNECT is refused entirely with 405 when no
+/// allowlist is given.
+#[tokio::test]
+async fn test_connect_refused() {
+    init_logging();
+    let target = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &target.address,
+        "--allow-connect",
+        "*.example.com:443",
+    ])
+    .await;
+    let (_, head) = send_connect(&balancebeam, &target.address).await;
+    assert!(head.starts_with("HTTP/1.1 403"), "Unexpected response: {}", head);
+
+    let balancebeam = BalanceBeam::new(&[&target.address], None, None).await;
+    let (_, head) = send_connect(&balancebeam, &target.address).await;
+    assert!(head.starts_with("HTTP/1.1 405"), "Unexpected response: {}", head);
+
+    assert_eq!(Box::new(target).stop().await, 0);
+    log::info!("All done :)");
+}
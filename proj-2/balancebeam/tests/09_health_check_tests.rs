@@ -0,0 +1,302 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, ErrorServer, Server};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Response};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Starts a server that answers every request with 200 OK and the given body, like a health
+/// endpoint that reports on the backend's dependencies. Returns its address.
+async fn fixed_body_server(body: &'static str) -> String {
+    let address = random_address();
+    let service = make_service_fn(move |_| async move {
+        Ok::<_, hyper::Error>(service_fn(move |_| async move {
+            Ok::<_, hyper::Error>(Response::new(Body::from(body)))
+        }))
+    });
+    tokio::spawn(hyper::Server::bind(&address.parse().unwrap()).serve(service));
+    address
+}
+
+/// Sends requests to balancebeam, returning the body of each response
+async fn get_bodies(balancebeam: &BalanceBeam, n_requests: usize) -> Vec<String> {
+    let mut bodies = Vec::new();
+    for _ in 0..n_requests {
+        bodies.push(balancebeam.get("/").await.expect("Error sending request to balancebeam"));
+    }
+    bodies
+}
+
+async fn get_status(balancebeam: &BalanceBeam) -> u16 {
+    reqwest::Client::new()
+        .get(format!("http://{}/", balancebeam.address))
+        .header("x-sent-by", "balancebeam-tests")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// With a long health check interval but a short dead interval, an upstream that was down should
+/// be back in rotation within a couple of seconds of coming up.
+#[tokio::test]
+async fn test_dead_upstream_recovers_quickly() {
+    init_logging();
+    let upstream_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream_address,
+        "--active-health-check-interval",
+        "60",
+        "--active-health-check-dead-interval",
+        "1",
+    ])
+    .await;
+
+    log::info!("Sending a request while the upstream is down, which should mark it dead");
+    assert_eq!(get_status(&balancebeam).await, 502);
+
+    log::info!("Starting the upstream and waiting for the next probe");
+    let upstream = EchoServer::new_at_address(upstream_address).await;
+    sleep(Duration::from_secs(3)).await;
+    assert_eq!(get_status(&balancebeam).await, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// An upstream that keeps failing health checks should be probed less and less often: after the
+/// first failure at ~1s, probes should come at ~2s, ~4s, then every 4s (the maximum backoff).
+#[tokio::test]
+async fn test_dead_upstream_probes_back_off() {
+    init_logging();
+    let upstream = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--active-health-check-interval",
+        "1",
+        "--active-health-check-dead-interval",
+        "1",
+        "--max-probe-backoff",
+        "4",
+    ])
+    .await;
+
+    log::info!("Waiting for a few rounds of health checks");
+    sleep(Duration::from_millis(9500)).await;
+    drop(balancebeam);
+
+    let num_probes = Box::new(upstream).stop().await;
+    log::info!("Upstream received {} health checks", num_probes);
+    assert!(
+        (3..=5).contains(&num_probes),
+        "Expected about 4 health checks with backoff, got {}",
+        num_probes
+    );
+    log::info!("All done :)");
+}
+
+/// Once every upstream is known to be dead, requests should get 503 with a Retry-After telling the
+/// client when the next health check will be, rather than 502.
+#[tokio::test]
+async fn test_no_alive_upstreams() {
+    init_logging();
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &random_address(),
+        "--active-health-check-interval",
+        "60",
+        "--active-health-check-dead-interval",
+        "5",
+    ])
+    .await;
+
+    log::info!("Sending a request that finds out the upstream is down");
+    assert_eq!(get_status(&balancebeam).await, 502);
+
+    log::info!("Sending a request now that no upstreams are alive");
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(response.headers()["retry-after"], "5");
+    log::info!("All done :)");
+}
+
+/// Health check intervals can be given in milliseconds, so a dead upstream should be probed (and
+/// put back into rotation) within a fraction of a second.
+#[tokio::test]
+async fn test_subsecond_dead_interval() {
+    init_logging();
+    let upstream_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream_address,
+        "--active-health-check-interval",
+        "1m",
+        "--active-health-check-dead-interval",
+        "200ms",
+        "--max-probe-backoff",
+        "200ms",
+    ])
+    .await;
+
+    assert_eq!(get_status(&balancebeam).await, 502);
+    let upstream = EchoServer::new_at_address(upstream_address).await;
+    sleep(Duration::from_millis(600)).await;
+    assert_eq!(get_status(&balancebeam).await, 200);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Durations that can't be parsed should stop balancebeam from starting
+#[tokio::test]
+async fn test_invalid_interval() {
+    init_logging();
+    for interval in ["soon", "10 parsecs", "-1s", "0ms"] {
+        let mut balancebeam = BalanceBeam::new_with_args(&[
+            "--upstream",
+            &random_address(),
+            "--active-health-check-interval",
+            interval,
+        ])
+        .await;
+        let status = balancebeam.exit_status().expect("balancebeam should have exited");
+        assert!(!status.success(), "{} should be rejected", interval);
+    }
+    log::info!("All done :)");
+}
+
+/// With jitter, health checks should still come at the configured rate on average, even though
+/// each wait is a little longer or shorter.
+#[tokio::test]
+async fn test_jittered_health_checks() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let start = std::time::Instant::now();
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--active-health-check-interval",
+        "100ms",
+        "--active-health-check-jitter",
+        "0.5",
+    ])
+    .await;
+
+    log::info!("Waiting for a few rounds of health checks");
+    sleep(Duration::from_millis(1500)).await;
+    drop(balancebeam);
+    let expected = start.elapsed().as_millis() as usize / 100;
+
+    let num_probes = Box::new(upstream).stop().await;
+    log::info!("Upstream received {} health checks", num_probes);
+    assert!(
+        (expected / 2..=expected * 3 / 2).contains(&num_probes),
+        "Expected about {} health checks, got {}",
+        expected,
+        num_probes
+    );
+    log::info!("All done :)");
+}
+
+/// Jitter is a fraction of the interval, so it has to be at least 0 and less than 1
+#[tokio::test]
+async fn test_invalid_jitter() {
+    init_logging();
+    for jitter in ["1", "1.5", "-0.1", "abc"] {
+        let mut balancebeam = BalanceBeam::new_with_args(&[
+            "--upstream",
+            &random_address(),
+            "--active-health-check-jitter",
+            jitter,
+        ])
+        .await;
+        let status = balancebeam.exit_status().expect("balancebeam should have exited");
+        assert!(!status.success(), "{} should be rejected", jitter);
+    }
+    log::info!("All done :)");
+}
+
+/// An upstream whose health endpoint answers 200 but reports a broken dependency in its body should
+/// be taken out of rotation
+#[tokio::test]
+async fn test_health_check_expect_body() {
+    init_logging();
+    let healthy = fixed_body_server(r#"{"db":"up"}"#).await;
+    let unhealthy = fixed_body_server(r#"{"db":"down"}"#).await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &healthy,
+        "--upstream",
+        &unhealthy,
+        "--active-health-check-interval",
+        "1",
+        "--active-health-check-path",
+        "/health",
+        "--active-health-check-expect-body",
+        r#""db":"up""#,
+    ])
+    .await;
+
+    log::info!("Waiting for the health checks");
+    sleep(Duration::from_millis(1500)).await;
+    for body in get_bodies(&balancebeam, 10).await {
+        assert_eq!(body, r#"{"db":"up"}"#, "Request was sent to the unhealthy upstream");
+    }
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_health_check_expect_regex() {
+    init_logging();
+    let healthy = fixed_body_server(r#"{"db": "up", "cache": "up"}"#).await;
+    let unhealthy = fixed_body_server(r#"{"db": "down", "cache": "up"}"#).await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &healthy,
+        "--upstream",
+        &unhealthy,
+        "--active-health-check-interval",
+        "1",
+        "--active-health-check-expect-regex",
+        r#""db":\s*"up""#,
+    ])
+    .await;
+
+    log::info!("Waiting for the health checks");
+    sleep(Duration::from_millis(1500)).await;
+    for body in get_bodies(&balancebeam, 10).await {
+        assert_eq!(body, r#"{"db": "up", "cache": "up"}"#, "Request was sent to the unhealthy upstream");
+    }
+    log::info!("All done :)");
+}
+
+/// An empty body passes a plain health check, but can't contain the expected text
+#[tokio::test]
+async fn test_health_check_empty_body() {
+    init_logging();
+    let empty = fixed_body_server("").await;
+    let plain =
+        BalanceBeam::new_with_args(&["--upstream", &empty, "--active-health-check-interval", "1"]).await;
+    let expecting = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &empty,
+        "--active-health-check-interval",
+        "1",
+        "--active-health-check-expect-body",
+        "ok",
+    ])
+    .await;
+
+    log::info!("Waiting for the health checks");
+    sleep(Duration::from_millis(1500)).await;
+    assert_eq!(get_status(&plain).await, 200);
+    assert_eq!(get_status(&expecting).await, 503);
+    log::info!("All done :)");
+}
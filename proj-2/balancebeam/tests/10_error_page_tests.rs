@@ -0,0 +1,73 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+
+/// Writes an error page to a temporary file, returning its path
+fn write_error_page(contents: &str) -> std::path::PathBuf {
+    let name = format!("balancebeam-error-page-{}.html", random_address().replace([':', '.'], "-"));
+    let path = std::env::temp_dir().join(name);
+    std::fs::write(&path, contents).expect("Could not write error page");
+    path
+}
+
+/// Errors with a custom page should get it as HTML, with the status filled in, while other errors
+/// keep the plain text body and successful responses are untouched.
+#[tokio::test]
+async fn test_custom_error_page() {
+    init_logging();
+    let page = write_error_page("<h1>Sorry! {status}</h1>");
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--route",
+        &format!("prefix=/dead/,upstreams={}", random_address()),
+        "--error-page",
+        &format!("502={}", page.display()),
+    ])
+    .await;
+
+    log::info!("Sending a request to a live upstream");
+    let response_text = balancebeam.get("/").await.expect("Error sending request to balancebeam");
+    assert!(response_text.starts_with("GET / HTTP/1.1"));
+
+    log::info!("Sending a request to a dead upstream");
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/dead/", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    assert_eq!(response.headers()["content-type"], "text/html");
+    assert_eq!(response.text().await.unwrap(), "<h1>Sorry! 502 Bad Gateway</h1>");
+
+    log::info!("Sending a request that gets an error without a custom page");
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/", balancebeam.address))
+        .header("via", "1.1 balancebeam")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 508);
+    assert_eq!(response.headers()["content-type"], "text/plain");
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    std::fs::remove_file(page).unwrap();
+    log::info!("All done :)");
+}
+
+/// A missing error page should stop balancebeam from starting, rather than being ignored
+#[tokio::test]
+async fn test_missing_error_page() {
+    init_logging();
+    let mut balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &random_address(),
+        "--error-page",
+        "502=/nonexistent/502.html",
+    ])
+    .await;
+    let status = balancebeam.exit_status().expect("balancebeam should have exited");
+    assert!(!status.success());
+    log::info!("All done :)");
+}
@@ -0,0 +1,133 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::{Duration, Instant};
+
+/// With the latency strategy, traffic should shift away from an upstream that is much slower than
+/// the others.
+#[tokio::test]
+async fn test_latency_strategy_avoids_slow_upstream() {
+    init_logging();
+    let fast_upstream = EchoServer::new().await;
+    let slow_upstream = EchoServer::new_with_delay(Duration::from_millis(200)).await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &fast_upstream.address,
+        "--upstream",
+        &slow_upstream.address,
+        "--strategy",
+        "latency",
+    ])
+    .await;
+
+    let n_requests = 40;
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let fast_count = Box::new(fast_upstream).stop().await;
+    let slow_count = Box::new(slow_upstream).stop().await;
+    log::info!("Fast upstream got {} requests, slow upstream got {}", fast_count, slow_count);
+    assert_eq!(fast_count + slow_count, n_requests);
+    assert!(
+        slow_count <= n_requests / 4,
+        "Slow upstream got {} of {} requests",
+        slow_count,
+        n_requests
+    );
+    log::info!("All done :)");
+}
+
+/// Sends `clients` concurrent streams of `requests_per_client` requests, each on a new connection,
+/// and returns how long each request took, sorted.
+async fn request_latencies(
+    balancebeam: &BalanceBeam,
+    clients: usize,
+    requests_per_client: usize,
+) -> Vec<Duration> {
+    // Without pooling, every request gets its own connection, and so its own choice of upstream
+    let client = reqwest::Client::builder()
+        .pool_max_idle_per_host(0)
+        .build()
+        .unwrap();
+    let mut tasks = Vec::new();
+    for _ in 0..clients {
+        let client = client.clone();
+        let url = format!("http://{}/", balancebeam.address);
+        tasks.push(tokio::spawn(async move {
+            let mut latencies = Vec::new();
+            for _ in 0..requests_per_client {
+                let start = Instant::now();
+                let response = client.get(&url).send().await.expect("Error sending request");
+                assert_eq!(response.status(), 200);
+                latencies.push(start.elapsed());
+            }
+            latencies
+        }));
+    }
+    let mut latencies = Vec::new();
+    for task in tasks {
+        latencies.extend(task.await.unwrap());
+    }
+    latencies.sort();
+    latencies
+}
+
+/// With upstreams of very different speeds, power of two choices should keep most requests away
+/// from the slow one while it is busy, giving a much better tail latency than picking at random.
+#[tokio::test]
+async fn test_p2c_strategy_improves_tail_latency() {
+    init_logging();
+    let mut p90s = Vec::new();
+    for strategy in ["random", "p2c"].iter().copied() {
+        let fast_upstreams = vec![
+            EchoServer::new().await,
+            EchoServer::new().await,
+            EchoServer::new().await,
+        ];
+        let slow_upstream = EchoServer::new_with_delay(Duration::from_millis(300)).await;
+        let mut args = vec!["--strategy", strategy, "--upstream", &slow_upstream.address];
+        for upstream in &fast_upstreams {
+            args.push("--upstream");
+            args.push(&upstream.address);
+        }
+        let balancebeam = BalanceBeam::new_with_args(&args).await;
+
+        let latencies = request_latencies(&balancebeam, 8, 20).await;
+        let p90 = latencies[latencies.len() * 9 / 10];
+        let slow_count = Box::new(slow_upstream).stop().await;
+        log::info!(
+            "With {}, the slow upstream got {} of {} requests, and the 90th percentile latency was {:?}",
+            strategy,
+            slow_count,
+            latencies.len(),
+            p90
+        );
+        for upstream in fast_upstreams {
+            Box::new(upstream).stop().await;
+        }
+        p90s.push(p90);
+    }
+    assert!(p90s[0] >= Duration::from_millis(300), "Random should send plenty to the slow upstream");
+    assert!(p90s[1] < Duration::from_millis(150), "p2c should avoid the slow upstream while it's busy");
+    log::info!("All done :)");
+}
+
+/// With only one alive upstream, p2c has nothing to compare, and just uses that one.
+#[tokio::test]
+async fn test_p2c_strategy_single_upstream() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&["--strategy", "p2c", "--upstream", &upstream.address]).await;
+    for _ in 0..5 {
+        balancebeam.get("/").await.expect("Error sending request to balancebeam");
+    }
+    assert_eq!(Box::new(upstream).stop().await, 5);
+    log::info!("All done :)");
+}
@@ -0,0 +1,64 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Every request should reach the mirror as well as the primary upstream, and the client should
+/// only ever see the primary's response.
+#[tokio::test]
+async fn test_mirror_gets_copies() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mirror = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--mirror-upstream",
+        &mirror.address,
+    ])
+    .await;
+
+    let n_requests = 5;
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(!response_text.contains("x-shadow"));
+    }
+
+    // Mirrored requests are sent in the background, so give them a moment to arrive
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(Box::new(upstream).stop().await, n_requests);
+    assert_eq!(Box::new(mirror).stop().await, n_requests);
+    log::info!("All done :)");
+}
+
+/// A mirror that is down shouldn't affect clients at all
+#[tokio::test]
+async fn test_dead_mirror() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--mirror-upstream",
+        &random_address(),
+    ])
+    .await;
+
+    for i in 0..3 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+
+async fn send_requests(balancebeam: &BalanceBeam, n_requests: usize) {
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+}
+
+/// The canary should only get traffic once its percentage is raised through the admin endpoint,
+/// and the admin endpoint should count the requests each set got.
+#[tokio::test]
+async fn test_canary_percentage_adjustable() {
+    init_logging();
+    let stable_upstream = EchoServer::new().await;
+    let canary_upstream = EchoServer::new().await;
+    let admin_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &stable_upstream.address,
+        "--upstream",
+        &format!("{}=canary:0", canary_upstream.address),
+        "--admin-bind",
+        &admin_address,
+    ])
+    .await;
+
+    send_requests(&balancebeam, 5).await;
+
+    let client = reqwest::Client::new();
+    let canary_url = format!("http://{}/canary/default", admin_address);
+    let response = client
+        .put(&canary_url)
+        .body("100")
+        .send()
+        .await
+        .expect("Error sending request to the admin endpoint");
+    assert_eq!(response.status().as_u16(), 200);
+    let response = client
+        .put(&canary_url)
+        .body("250")
+        .send()
+        .await
+        .expect("Error sending request to the admin endpoint");
+    assert_eq!(response.status().as_u16(), 400);
+
+    send_requests(&balancebeam, 3).await;
+
+    let summary = client
+        .get(format!("http://{}/canary", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to the admin endpoint")
+        .text()
+        .await
+        .expect("Error reading admin response");
+    assert_eq!(
+        summary,
+        "default: canary 100%, stable 5 requests (0 errors), canary 3 requests (0 errors)\n"
+    );
+
+    assert_eq!(Box::new(stable_upstream).stop().await, 5);
+    assert_eq!(Box::new(canary_upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Requests should go to the stable upstreams when every canary is dead
+#[tokio::test]
+async fn test_dead_canary_falls_back_to_stable() {
+    init_logging();
+    let stable_upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &stable_upstream.address,
+        "--upstream",
+        &format!("{}=canary:100", random_address()),
+    ])
+    .await;
+
+    send_requests(&balancebeam, 3).await;
+
+    assert_eq!(Box::new(stable_upstream).stop().await, 3);
+    log::info!("All done :)");
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Reads one response from the connection, returning its head (lowercased) and body, or None if
+/// the connection was closed before a response arrived.
+async fn read_response(conn: &mut TcpStream) -> Option<(String, String)> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0_u8; 1];
+        match conn.read(&mut byte).await {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => head.push(byte[0]),
+        }
+    }
+    let head = String::from_utf8(head).unwrap().to_ascii_lowercase();
+    let content_length: usize = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .expect("Response has no Content-Length")
+        .trim()
+        .parse()
+        .unwrap();
+    let mut body = vec![0_u8; content_length];
+    conn.read_exact(&mut body).await.unwrap();
+    Some((head, String::from_utf8(body).unwrap()))
+}
+
+/// Once a client has sent the maximum number of requests on a connection, the last response should
+/// say the connection is closing, and balancebeam should close it instead of reading more.
+#[tokio::test]
+async fn test_connection_closed_after_max_requests() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let max_requests = 3;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream".to_string(),
+        upstream.address.clone(),
+        "--max-requests-per-connection".to_string(),
+        max_requests.to_string(),
+    ])
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    for i in 1..=max_requests + 1 {
+        conn.write_all(format!("GET /request-{} HTTP/1.1\r\nHost: example.com\r\n\r\n", i).as_bytes())
+            .await
+            .unwrap();
+        let response = read_response(&mut conn).await;
+        if i > max_requests {
+            assert!(response.is_none(), "Got a response to request {}", i);
+            break;
+        }
+        let (head, body) = response.unwrap_or_else(|| panic!("Connection closed before response {}", i));
+        assert!(head.starts_with("http/1.1 200"), "Unexpected response: {}", head);
+        assert!(body.contains(&format!("GET /request-{} HTTP/1.1", i)));
+        assert_eq!(head.contains("connection: close"), i == max_requests, "Unexpected response: {}", head);
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, max_requests);
+    log::info!("All done :)");
+}
@@ -0,0 +1,97 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Sends a raw request head and returns the status line of the response.
+async fn send_raw_request(balancebeam: &BalanceBeam, head: &str) -> String {
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    conn.write_all(head.as_bytes()).await.unwrap();
+    let mut status_line = Vec::new();
+    while !status_line.ends_with(b"\r\n") {
+        let mut byte = [0_u8; 1];
+        if conn.read(&mut byte).await.expect("Error reading response") == 0 {
+            break;
+        }
+        status_line.push(byte[0]);
+    }
+    String::from_utf8(status_line).unwrap().trim_end().to_string()
+}
+
+/// Returns a request head that is exactly `len` bytes long, padded out with a header.
+fn head_of_length(len: usize) -> String {
+    let head = |padding: usize| {
+        format!(
+            "GET / HTTP/1.1\r\nHost: example.com\r\nX-Padding: {}\r\n\r\n",
+            "a".repeat(padding)
+        )
+    };
+    let padding = len - head(0).len();
+    head(padding)
+}
+
+#[tokio::test]
+async fn test_max_header_bytes() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&["--upstream", &upstream.address, "--max-header-bytes", "200"]).await;
+
+    let status = send_raw_request(&balancebeam, &head_of_length(200)).await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let status = send_raw_request(&balancebeam, &head_of_length(201)).await;
+    assert_eq!(status, "HTTP/1.1 431 Request Header Fields Too Large");
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_max_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&["--upstream", &upstream.address, "--max-headers", "4"]).await;
+
+    let head = |n_headers: usize| {
+        let mut head = "GET / HTTP/1.1\r\nHost: example.com\r\n".to_string();
+        for i in 1..n_headers {
+            head.push_str(&format!("X-Header-{}: {}\r\n", i, i));
+        }
+        head + "\r\n"
+    };
+    let status = send_raw_request(&balancebeam, &head(4)).await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let status = send_raw_request(&balancebeam, &head(5)).await;
+    assert_eq!(status, "HTTP/1.1 431 Request Header Fields Too Large");
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_max_uri_length() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&["--upstream", &upstream.address, "--max-uri-length", "20"]).await;
+
+    let head = |uri_length: usize| {
+        format!(
+            "GET /{} HTTP/1.1\r\nHost: example.com\r\n\r\n",
+            "a".repeat(uri_length - 1)
+        )
+    };
+    let status = send_raw_request(&balancebeam, &head(20)).await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let status = send_raw_request(&balancebeam, &head(21)).await;
+    assert_eq!(status, "HTTP/1.1 414 URI Too Long");
+    // The request line doesn't have to be complete for us to know that it's too long
+    let status = send_raw_request(&balancebeam, &format!("GET /{}", "a".repeat(20))).await;
+    assert_eq!(status, "HTTP/1.1 414 URI Too Long");
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
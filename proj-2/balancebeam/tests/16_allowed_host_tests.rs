@@ -0,0 +1,65 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn get_with_host(balancebeam: &BalanceBeam, host: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(format!("http://{}/", balancebeam.address))
+        .header("Host", host)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+}
+
+/// Only requests for allowed hosts should reach the upstream, and rejected requests shouldn't count
+/// against the rate limit.
+#[tokio::test]
+async fn test_allowed_hosts() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--allowed-host",
+        "example.com",
+        "--allowed-host",
+        "*.example.org",
+        "--max-requests-per-minute",
+        "3",
+    ])
+    .await;
+
+    for host in ["scanner.invalid", "example.org", "www.example.com", "1.2.3.4"] {
+        let response = get_with_host(&balancebeam, host).await;
+        assert_eq!(response.status().as_u16(), 421, "Host {} should be rejected", host);
+    }
+    for host in ["example.com", "EXAMPLE.com:1100", "www.example.org"] {
+        let response = get_with_host(&balancebeam, host).await;
+        assert_eq!(response.status().as_u16(), 200, "Host {} should be allowed", host);
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// With allowed hosts configured, a request without a Host header can't match any of them.
+#[tokio::test]
+async fn test_missing_host_rejected() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&["--upstream", &upstream.address, "--allowed-host", "example.com"]).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    conn.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    let mut response = [0_u8; 12];
+    conn.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"HTTP/1.1 421");
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
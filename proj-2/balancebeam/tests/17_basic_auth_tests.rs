@@ -0,0 +1,119 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+
+/// Writes an htpasswd file with the given users to a temporary file, returning its path. Hashes use
+/// the lowest bcrypt cost to keep the tests fast.
+fn write_htpasswd(users: &[(&str, &str)]) -> std::path::PathBuf {
+    let name = format!("balancebeam-htpasswd-{}", random_address().replace([':', '.'], "-"));
+    let path = std::env::temp_dir().join(name);
+    let mut contents = String::from("# test users\n\n");
+    for (username, password) in users {
+        let hash = bcrypt::hash(password, 4).expect("Could not hash password");
+        contents += &format!("{}:{}\n", username, hash);
+    }
+    std::fs::write(&path, contents).expect("Could not write htpasswd file");
+    path
+}
+
+fn basic_credentials(credentials: &str) -> String {
+    use base64::Engine;
+    format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials))
+}
+
+async fn get_with_authorization(balancebeam: &BalanceBeam, authorization: Option<&str>) -> reqwest::Response {
+    let mut request = reqwest::Client::new().get(format!("http://{}/", balancebeam.address));
+    if let Some(authorization) = authorization {
+        request = request.header("Authorization", authorization);
+    }
+    request.send().await.expect("Error sending request to balancebeam")
+}
+
+/// Requests with valid credentials should be forwarded, with the Authorization header intact.
+#[tokio::test]
+async fn test_valid_credentials() {
+    init_logging();
+    let htpasswd = write_htpasswd(&[("alice", "wonderland"), ("bob", "b:uilder")]);
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--basic-auth-file",
+        htpasswd.to_str().unwrap(),
+    ])
+    .await;
+
+    for credentials in ["alice:wonderland", "bob:b:uilder"] {
+        let response = get_with_authorization(&balancebeam, Some(&basic_credentials(credentials))).await;
+        assert_eq!(response.status().as_u16(), 200, "{} should be accepted", credentials);
+        let body = response.text().await.unwrap();
+        assert!(body.contains("authorization: Basic"), "Authorization header should be forwarded");
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Missing, wrong and malformed credentials should all get 401 with a challenge, without reaching
+/// the upstream.
+#[tokio::test]
+async fn test_rejected_credentials() {
+    init_logging();
+    let htpasswd = write_htpasswd(&[("alice", "wonderland")]);
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--basic-auth-file",
+        htpasswd.to_str().unwrap(),
+    ])
+    .await;
+
+    let wrong_password = basic_credentials("alice:looking-glass");
+    let unknown_user = basic_credentials("mallory:wonderland");
+    let no_colon = basic_credentials("alicewonderland");
+    let authorizations = [
+        None,
+        Some(wrong_password.as_str()),
+        Some(unknown_user.as_str()),
+        Some(no_colon.as_str()),
+        Some("Basic !!!not-base64!!!"),
+        Some("Bearer YWxpY2U6d29uZGVybGFuZA=="),
+        Some("Basic"),
+    ];
+    for authorization in authorizations {
+        let response = get_with_authorization(&balancebeam, authorization).await;
+        assert_eq!(response.status().as_u16(), 401, "{:?} should be rejected", authorization);
+        assert_eq!(
+            response.headers().get("www-authenticate").unwrap(),
+            "Basic realm=\"balancebeam\""
+        );
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// With --basic-auth-strip, the upstream shouldn't see the client's credentials.
+#[tokio::test]
+async fn test_strip_authorization() {
+    init_logging();
+    let htpasswd = write_htpasswd(&[("alice", "wonderland")]);
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--basic-auth-file",
+        htpasswd.to_str().unwrap(),
+        "--basic-auth-strip",
+    ])
+    .await;
+
+    let response = get_with_authorization(&balancebeam, Some(&basic_credentials("alice:wonderland"))).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body = response.text().await.unwrap();
+    assert!(!body.to_lowercase().contains("authorization"), "Authorization header should be stripped");
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
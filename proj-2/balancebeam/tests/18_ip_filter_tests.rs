@@ -0,0 +1,122 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Sends a request on a fresh connection and returns the start of balancebeam's response, or
+/// nothing if it refused the connection outright.
+async fn raw_request(address: &str) -> String {
+    let mut conn = TcpStream::connect(address)
+        .await
+        .expect("Could not connect to balancebeam");
+    // The connection may already be closed, in which case writing fails
+    let _ = conn.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").await;
+    let mut response = [0_u8; 1024];
+    let len = conn.read(&mut response).await.unwrap_or(0);
+    String::from_utf8_lossy(&response[..len]).into_owned()
+}
+
+async fn start(upstream: &EchoServer, rules: &[&str]) -> BalanceBeam {
+    let mut args = vec!["--upstream", &upstream.address];
+    args.extend_from_slice(rules);
+    BalanceBeam::new_with_args(&args).await
+}
+
+/// Deny rules should win over allow rules for the addresses both cover, whichever is more specific.
+#[tokio::test]
+async fn test_deny_overrides_allow() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+
+    let balancebeam = start(&upstream, &["--allow-ip", "127.0.0.1/32", "--deny-ip", "127.0.0.0/8"]).await;
+    assert_eq!(raw_request(&balancebeam.address).await, "");
+    drop(balancebeam);
+
+    let balancebeam = start(&upstream, &["--allow-ip", "127.0.0.0/8", "--deny-ip", "127.0.0.1"]).await;
+    assert_eq!(raw_request(&balancebeam.address).await, "");
+    drop(balancebeam);
+
+    // Overlapping ranges where the deny rule doesn't cover us
+    let balancebeam = start(&upstream, &["--allow-ip", "127.0.0.0/8", "--deny-ip", "127.0.0.2/31"]).await;
+    assert!(raw_request(&balancebeam.address).await.starts_with("HTTP/1.1 200"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Clients that no rule matches get the default action, which is to deny them only if there are
+/// allow rules.
+#[tokio::test]
+async fn test_default_action() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+
+    let balancebeam = start(&upstream, &["--allow-ip", "10.0.0.0/8"]).await;
+    assert_eq!(raw_request(&balancebeam.address).await, "");
+    drop(balancebeam);
+
+    let balancebeam = start(&upstream, &["--deny-ip", "10.0.0.0/8"]).await;
+    assert!(raw_request(&balancebeam.address).await.starts_with("HTTP/1.1 200"));
+    drop(balancebeam);
+
+    let balancebeam = start(&upstream, &["--deny-ip", "10.0.0.0/8", "--default-ip-action", "deny"]).await;
+    assert_eq!(raw_request(&balancebeam.address).await, "");
+    drop(balancebeam);
+
+    let balancebeam = start(&upstream, &["--allow-ip", "10.0.0.0/8", "--default-ip-action", "allow"]).await;
+    assert!(raw_request(&balancebeam.address).await.starts_with("HTTP/1.1 200"));
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// With --deny-action forbidden, refused clients should get a 403 instead of a closed connection.
+#[tokio::test]
+async fn test_deny_action_forbidden() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = start(&upstream, &["--deny-ip", "127.0.0.0/8", "--deny-action", "forbidden"]).await;
+
+    let response = raw_request(&balancebeam.address).await;
+    assert!(response.starts_with("HTTP/1.1 403"), "Unexpected response: {:?}", response);
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Rules should work for IPv6 clients, and IPv4 rules shouldn't match them.
+#[tokio::test]
+async fn test_ipv6() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let ipv6_address = || format!("[::1]:{}", rand::thread_rng().gen_range(1024..32768));
+
+    let balancebeam = BalanceBeam::new_at_address(
+        ipv6_address(),
+        &["--upstream", &upstream.address, "--allow-ip", "::1/128", "--deny-ip", "127.0.0.0/8"],
+    )
+    .await;
+    assert!(raw_request(&balancebeam.address).await.starts_with("HTTP/1.1 200"));
+    drop(balancebeam);
+
+    let balancebeam = BalanceBeam::new_at_address(
+        ipv6_address(),
+        &["--upstream", &upstream.address, "--allow-ip", "::/0", "--deny-ip", "::1"],
+    )
+    .await;
+    assert_eq!(raw_request(&balancebeam.address).await, "");
+    drop(balancebeam);
+
+    // IPv4 rules don't cover IPv6 clients, so this one falls through to the default
+    let balancebeam = BalanceBeam::new_at_address(
+        ipv6_address(),
+        &["--upstream", &upstream.address, "--allow-ip", "0.0.0.0/0"],
+    )
+    .await;
+    assert_eq!(raw_request(&balancebeam.address).await, "");
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
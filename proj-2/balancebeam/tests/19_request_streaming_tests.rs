@@ -0,0 +1,187 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+/// Starts an upstream that reads each request's body without keeping it, reports how many body
+/// bytes it got, and answers with that count. Returns its address and a channel of the counts.
+async fn start_counting_upstream() -> (String, mpsc::UnboundedReceiver<usize>) {
+    let address = random_address();
+    let listener = TcpListener::bind(&address).await.expect("Could not bind upstream");
+    let (sender, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                // Handle requests until balancebeam hangs up
+                loop {
+                    // Read the head, a byte at a time so that no body bytes are read along with it
+                    let mut head = Vec::new();
+                    let mut byte = [0_u8; 1];
+                    while !head.ends_with(b"\r\n\r\n") {
+                        if conn.read(&mut byte).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        head.push(byte[0]);
+                    }
+                    let head = String::from_utf8_lossy(&head).to_lowercase();
+                    let content_length: usize = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length:"))
+                        .map(|value| value.trim().parse().unwrap())
+                        .unwrap_or(0);
+                    let mut received = 0;
+                    let mut buffer = vec![0_u8; 65536];
+                    while received < content_length {
+                        match conn.read(&mut buffer[..(content_length - received).min(65536)]).await {
+                            Ok(0) | Err(_) => break,
+                            Ok(len) => received += len,
+                        }
+                    }
+                    let _ = sender.send(received);
+                    if received < content_length {
+                        return;
+                    }
+                    let body = received.to_string();
+                    let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                    if conn.write_all(response.as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    (address, receiver)
+}
+
+/// Sends the head of a POST request with the given Content-Length.
+async fn send_post_head(conn: &mut TcpStream, content_length: usize) {
+    let head = format!(
+        "POST /upload HTTP/1.1\r\nHost: example.com\r\nContent-Length: {}\r\n\r\n",
+        content_length
+    );
+    conn.write_all(head.as_bytes()).await.unwrap();
+}
+
+/// A large upload should reach the upstream intact without balancebeam's memory use growing with
+/// it.
+#[tokio::test]
+async fn test_large_upload_memory_stays_flat() {
+    init_logging();
+    let (upstream_address, mut body_sizes) = start_counting_upstream().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&["--upstream", &upstream_address, "--max-body-bytes", "300000000"]).await;
+    let memory_before = balancebeam.peak_memory_kb();
+
+    let body_size = 200_000_000;
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    send_post_head(&mut conn, body_size).await;
+    let chunk = vec![b'x'; 65536];
+    let mut sent = 0;
+    while sent < body_size {
+        let len = chunk.len().min(body_size - sent);
+        conn.write_all(&chunk[..len]).await.unwrap();
+        sent += len;
+    }
+    let mut response = [0_u8; 1024];
+    let len = conn.read(&mut response).await.unwrap();
+    let response = String::from_utf8_lossy(&response[..len]);
+    assert!(response.starts_with("HTTP/1.1 200"), "Unexpected response: {:?}", response);
+    assert_eq!(body_sizes.recv().await, Some(body_size));
+
+    let growth_kb = balancebeam.peak_memory_kb().saturating_sub(memory_before);
+    log::info!("Peak memory grew by {} kB during the upload", growth_kb);
+    assert!(growth_kb < 20_000, "Peak memory grew by {} kB", growth_kb);
+    log::info!("All done :)");
+}
+
+/// Bodies bigger than --max-body-bytes should be refused before any of them is forwarded.
+#[tokio::test]
+async fn test_body_size_limit() {
+    init_logging();
+    let (upstream_address, mut body_sizes) = start_counting_upstream().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&["--upstream", &upstream_address, "--max-body-bytes", "1000"]).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    send_post_head(&mut conn, 1001).await;
+    let mut response = [0_u8; 1024];
+    let len = conn.read(&mut response).await.unwrap();
+    let response = String::from_utf8_lossy(&response[..len]);
+    assert!(response.starts_with("HTTP/1.1 413"), "Unexpected response: {:?}", response);
+    assert!(body_sizes.try_recv().is_err(), "Request shouldn't reach the upstream");
+    log::info!("All done :)");
+}
+
+/// A body that arrives in pieces should be forwarded whole, and the connection should then carry
+/// the next request.
+#[tokio::test]
+async fn test_body_in_pieces_then_next_request() {
+    init_logging();
+    let (upstream_address, mut body_sizes) = start_counting_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(&["--upstream", &upstream_address]).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    send_post_head(&mut conn, 30000).await;
+    for _ in 0..3 {
+        conn.write_all(&[b'x'; 10000]).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(body_sizes.recv().await, Some(30000));
+    let mut response = [0_u8; 1024];
+    let len = conn.read(&mut response).await.unwrap();
+    assert!(String::from_utf8_lossy(&response[..len]).starts_with("HTTP/1.1 200"));
+
+    send_post_head(&mut conn, 5).await;
+    conn.write_all(b"hello").await.unwrap();
+    assert_eq!(body_sizes.recv().await, Some(5));
+    let len = conn.read(&mut response).await.unwrap();
+    assert!(String::from_utf8_lossy(&response[..len]).starts_with("HTTP/1.1 200"));
+    log::info!("All done :)");
+}
+
+/// If the client hangs up partway through its body, the upstream connection should be dropped
+/// rather than left waiting for the rest.
+#[tokio::test]
+async fn test_client_hangs_up_mid_body() {
+    init_logging();
+    let (upstream_address, mut body_sizes) = start_counting_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(&["--upstream", &upstream_address]).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    send_post_head(&mut conn, 30000).await;
+    conn.write_all(&[b'x'; 10000]).await.unwrap();
+    drop(conn);
+
+    let received = tokio::time::timeout(std::time::Duration::from_secs(5), body_sizes.recv())
+        .await
+        .expect("The upstream connection should have been closed");
+    assert_eq!(received, Some(10000));
+    log::info!("All done :)");
+}
+
+/// Small bodies should still be mirrored, since they're cheap to hold on to, while large ones only
+/// go to the primary upstream.
+#[tokio::test]
+async fn test_mirror_small_bodies_only() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mirror = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&["--upstream", &upstream.address, "--mirror-upstream", &mirror.address]).await;
+
+    let response_text = balancebeam.post("/small", "hello").await.expect("Error sending request to balancebeam");
+    assert!(response_text.ends_with("hello"));
+    let large_body = "x".repeat(2_000_000);
+    let response_text = balancebeam.post("/large", &large_body).await.expect("Error sending request to balancebeam");
+    assert!(response_text.ends_with(&large_body));
+
+    // Mirrored requests are sent in the background, so give them a moment to arrive
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    assert_eq!(Box::new(mirror).stop().await, 1);
+    log::info!("All done :)");
+}
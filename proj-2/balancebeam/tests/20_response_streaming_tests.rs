@@ -0,0 +1,208 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout, Instant};
+
+/// Starts an upstream that answers every connection with the given head, then writes the body
+/// pieces from `body` with a pause between each, and finally hangs up.
+async fn start_trickling_upstream(
+    head: &'static str,
+    body: impl Iterator<Item = Vec<u8>> + Clone + Send + 'static,
+) -> String {
+    let address = random_address();
+    let listener = TcpListener::bind(&address).await.expect("Could not bind upstream");
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let body = body.clone();
+            tokio::spawn(async move {
+                let mut request = [0_u8; 4096];
+                let _ = conn.read(&mut request).await;
+                if conn.write_all(head.as_bytes()).await.is_err() {
+                    return;
+                }
+                for piece in body {
+                    if conn.write_all(&piece).await.is_err() {
+                        return;
+                    }
+                    sleep(Duration::from_millis(50)).await;
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Sends a GET request on a new connection to balancebeam.
+async fn send_get(balancebeam: &BalanceBeam) -> TcpStream {
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(b"GET /events HTTP/1.1\r\nHost: example.com\r\nAccept-Encoding: gzip\r\n\r\n")
+        .await
+        .unwrap();
+    conn
+}
+
+/// An endless body should reach the client piece by piece as the upstream sends it, even with
+/// compression on, since there's no end to wait for.
+#[tokio::test]
+async fn test_endless_body_arrives_incrementally() {
+    init_logging();
+    let events = (0..).map(|i| format!("data: event {}\n\n", i).into_bytes());
+    let upstream_address =
+        start_trickling_upstream("HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\n", events).await;
+    let balancebeam = BalanceBeam::new_with_args(&["--upstream", &upstream_address, "--compress"]).await;
+
+    let mut conn = send_get(&balancebeam).await;
+    let mut received = Vec::new();
+    let mut buffer = [0_u8; 4096];
+    let start = Instant::now();
+    while !String::from_utf8_lossy(&received).contains("data: event 3\n") {
+        let len = timeout(Duration::from_secs(2), conn.read(&mut buffer))
+            .await
+            .expect("Events should arrive as they are sent")
+            .unwrap();
+        assert_ne!(len, 0, "balancebeam closed the connection");
+        received.extend_from_slice(&buffer[..len]);
+    }
+    let received = String::from_utf8_lossy(&received);
+    assert!(received.starts_with("HTTP/1.1 200"));
+    assert!(received.contains("connection: close"), "A body that ends at close should close the connection");
+    assert!(!received.contains("content-encoding"));
+    assert!(start.elapsed() < Duration::from_secs(2));
+    log::info!("All done :)");
+}
+
+/// A download bigger than we'd ever buffer should be forwarded in full, and the connection should
+/// then carry another request.
+#[tokio::test]
+async fn test_large_download() {
+    init_logging();
+    let body_size: usize = 30_000_000;
+    let head = Box::leak(
+        format!("HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n", body_size)
+            .into_boxed_str(),
+    );
+    let pieces = std::iter::repeat_n(vec![b'x'; 1_000_000], body_size / 1_000_000);
+    let upstream_address = start_trickling_upstream(head, pieces).await;
+    let balancebeam = BalanceBeam::new_with_args(&["--upstream", &upstream_address]).await;
+    let memory_before = balancebeam.peak_memory_kb();
+
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let body = response.bytes().await.expect("Error reading response body");
+    assert_eq!(body.len(), body_size);
+
+    let growth_kb = balancebeam.peak_memory_kb().saturating_sub(memory_before);
+    log::info!("Peak memory grew by {} kB during the download", growth_kb);
+    assert!(growth_kb < 10_000, "Peak memory grew by {} kB", growth_kb);
+    log::info!("All done :)");
+}
+
+/// An upstream that hangs up partway through a streamed body should cut the client's response short
+/// rather than leave it waiting.
+#[tokio::test]
+async fn test_upstream_hangs_up_mid_body() {
+    init_logging();
+    let pieces = std::iter::once(b"only part of the body".to_vec());
+    let upstream_address =
+        start_trickling_upstream("HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\n", pieces).await;
+    let balancebeam = BalanceBeam::new_with_args(&["--upstream", &upstream_address]).await;
+
+    let mut conn = send_get(&balancebeam).await;
+    let mut received = Vec::new();
+    timeout(Duration::from_secs(5), conn.read_to_end(&mut received))
+        .await
+        .expect("balancebeam should close the connection")
+        .unwrap();
+    let received = String::from_utf8_lossy(&received);
+    assert!(received.starts_with("HTTP/1.1 200"));
+    assert!(received.ends_with("only part of the body"));
+    log::info!("All done :)");
+}
+
+/// Small responses that can be cached should still be cached, which means buffering them.
+#[tokio::test]
+async fn test_cacheable_responses_still_cached() {
+    init_logging();
+    let upstream = EchoServer::new_with_response_headers(&[("cache-control", "max-age=60")]).await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&["--upstream", &upstream.address, "--cache-max-bytes", "1000000"]).await;
+
+    for _ in 0..3 {
+        let response_text = balancebeam.get("/cached").await.expect("Error sending request to balancebeam");
+        assert!(response_text.contains("GET /cached HTTP/1.1"));
+    }
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that answers every request with a chunked "hello world", sent a chunk at a
+/// time, and keeps the connection open for the next request.
+async fn start_chunked_upstream() -> String {
+    let address = random_address();
+    let listener = TcpListener::bind(&address).await.expect("Could not bind upstream");
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut request = [0_u8; 4096];
+                while matches!(conn.read(&mut request).await, Ok(len) if len > 0) {
+                    let pieces = [
+                        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n",
+                        "6;name=value\r\n world\r\n",
+                        "0\r\nX-Trailer: ignored\r\n\r\n",
+                    ];
+                    for piece in pieces {
+                        if conn.write_all(piece.as_bytes()).await.is_err() {
+                            return;
+                        }
+                        sleep(Duration::from_millis(50)).await;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// A chunked body from an upstream that keeps its connection open should be decoded, so that we
+/// know where it ends, and reach the client in chunks of our own (or, for an HTTP/1.0 client, ending
+/// when we close the connection)
+#[tokio::test]
+async fn test_chunked_upstream_response() {
+    init_logging();
+    let upstream_address = start_chunked_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(&["--upstream", &upstream_address]).await;
+
+    let client = reqwest::Client::new();
+    for _ in 0..2 {
+        let request = client.get(format!("http://{}/", balancebeam.address)).send();
+        let response = timeout(Duration::from_secs(5), request)
+            .await
+            .expect("The response should end with its last chunk")
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.headers()["transfer-encoding"], "chunked");
+        let body = timeout(Duration::from_secs(5), response.text())
+            .await
+            .expect("The body should end with its last chunk")
+            .expect("Error reading response body");
+        assert_eq!(body, "hello world");
+    }
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(b"GET /old-client HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").await.unwrap();
+    let mut received = Vec::new();
+    timeout(Duration::from_secs(5), conn.read_to_end(&mut received))
+        .await
+        .expect("balancebeam should close the connection to end the body")
+        .unwrap();
+    let received = String::from_utf8_lossy(&received).to_ascii_lowercase();
+    assert!(!received.contains("transfer-encoding"), "Unexpected response: {}", received);
+    assert!(received.ends_with("\r\n\r\nhello world"), "Unexpected response: {}", received);
+    log::info!("All done :)");
+}
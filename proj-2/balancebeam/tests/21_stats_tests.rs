@@ -0,0 +1,115 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, ErrorServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Fetches the statistics table from the admin endpoint and returns the columns of the row for the
+/// given upstream
+async fn stats_row(admin_address: &str, upstream_address: &str) -> Vec<String> {
+    let table = reqwest::get(format!("http://{}/stats", admin_address))
+        .await
+        .expect("Error sending request to the admin endpoint")
+        .text()
+        .await
+        .expect("Error reading the admin endpoint's response");
+    log::info!("Upstream statistics:\n{}", table);
+    table
+        .lines()
+        .map(|line| line.split_whitespace().map(str::to_string).collect::<Vec<_>>())
+        .find(|columns| columns.get(1).map(String::as_str) == Some(upstream_address))
+        .expect("Upstream is missing from the statistics table")
+}
+
+/// An upstream that passes health checks but answers every request with a 500 should stand out in
+/// the statistics next to a healthy one.
+#[tokio::test]
+async fn test_stats_by_status_class() {
+    init_logging();
+    let good_upstream = EchoServer::new().await;
+    let bad_upstream = ErrorServer::new().await;
+    let admin_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &good_upstream.address,
+        "--upstream",
+        &bad_upstream.address,
+        "--admin-bind",
+        &admin_address,
+    ])
+    .await;
+
+    let n_requests = 20;
+    for i in 0..n_requests {
+        let _ = balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    // Columns: group, upstream, requests, 1xx, 2xx, 3xx, 4xx, 5xx, failed, connect failures, hedges,
+    // hedge wins, and min/avg/max latency
+    let good_row = stats_row(&admin_address, &good_upstream.address).await;
+    let bad_row = stats_row(&admin_address, &bad_upstream.address).await;
+    let count = |row: &[String], column: usize| row[column].parse::<usize>().unwrap();
+    assert_eq!(count(&good_row, 2) + count(&bad_row, 2), n_requests);
+    assert_eq!(count(&good_row, 2), count(&good_row, 4), "Echo server requests should all be 2xx");
+    assert_eq!(count(&bad_row, 2), count(&bad_row, 7), "Error server requests should all be 5xx");
+    assert_eq!(count(&good_row, 7), 0);
+    assert_eq!(count(&bad_row, 4), 0);
+    for row in [&good_row, &bad_row] {
+        if count(row, 2) > 0 {
+            let min: f64 = row[12].parse().unwrap();
+            let max: f64 = row[14].parse().unwrap();
+            assert!(min <= max, "Latency minimum {} is above the maximum {}", min, max);
+        }
+    }
+
+    assert_eq!(Box::new(good_upstream).stop().await + Box::new(bad_upstream).stop().await, n_requests);
+    log::info!("All done :)");
+}
+
+/// Failing to connect to an upstream should be counted, without counting as a request
+#[tokio::test]
+async fn test_stats_connect_failures() {
+    init_logging();
+    let dead_address = random_address();
+    let admin_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &dead_address,
+        "--admin-bind",
+        &admin_address,
+    ])
+    .await;
+
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+
+    let row = stats_row(&admin_address, &dead_address).await;
+    assert_eq!(row[2], "0", "No requests should have been sent");
+    assert_eq!(row[9], "1", "There should be one connect failure");
+    assert_eq!(row[12], "-", "There should be no latency without responses");
+    log::info!("All done :)");
+}
+
+/// SIGUSR2 should log the statistics, not kill balancebeam
+#[tokio::test]
+async fn test_stats_on_sigusr2() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    balancebeam.signal(nix::sys::signal::Signal::SIGUSR2);
+    sleep(Duration::from_millis(100)).await;
+    let response_text = balancebeam
+        .get("/after-signal")
+        .await
+        .expect("balancebeam should still be running after SIGUSR2");
+    assert!(response_text.contains("GET /after-signal HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::sleep;
+
+/// Starts an upstream that takes `delay` to answer requests for /slow, and answers anything else
+/// right away.
+async fn start_slow_upstream(delay: Duration) -> String {
+    let address = random_address();
+    let listener = TcpListener::bind(&address).await.expect("Could not bind upstream");
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0_u8; 4096];
+                loop {
+                    // A request may arrive in more than one read, and must only be answered once
+                    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match conn.read(&mut buffer).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(len) => request.extend_from_slice(&buffer[..len]),
+                        }
+                    }
+                    if request.starts_with(b"GET /slow ") {
+                        sleep(delay).await;
+                    }
+                    request.clear();
+                    let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                    if conn.write_all(response.as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Only the request that takes longer than the threshold should be logged as slow, with its
+/// breakdown
+#[tokio::test]
+async fn test_slow_request_logged() {
+    init_logging();
+    let upstream_address = start_slow_upstream(Duration::from_millis(500)).await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream_address,
+        "--slow-request-threshold",
+        "200ms",
+    ])
+    .await;
+
+    for path in ["/fast", "/slow", "/fast"] {
+        let response_text = balancebeam.get(path).await.expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "ok");
+    }
+    sleep(Duration::from_millis(100)).await;
+
+    let slow_entries = balancebeam.output_containing("Slow request");
+    assert_eq!(slow_entries.len(), 1, "Expected one slow request entry, got {:?}", slow_entries);
+    let entry = &slow_entries[0];
+    assert!(entry.contains("WARN"), "Slow requests should be logged as warnings: {}", entry);
+    assert!(entry.contains("GET /slow -> 200"), "Entry should describe the request: {}", entry);
+    for part in ["connect", "upstream response", "writing to client"] {
+        assert!(entry.contains(part), "Entry should include the time spent on {}: {}", part, entry);
+    }
+    log::info!("All done :)");
+}
+
+/// Without a threshold, nothing is logged as slow
+#[tokio::test]
+async fn test_slow_request_log_off_by_default() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    balancebeam.get("/").await.expect("Error sending request to balancebeam");
+    sleep(Duration::from_millis(100)).await;
+    assert!(balancebeam.output_containing("Slow request").is_empty());
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
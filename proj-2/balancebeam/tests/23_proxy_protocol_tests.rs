@@ -0,0 +1,213 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+
+/// Opens a connection, writes each chunk of the preamble separately (pausing in between so that
+/// balancebeam sees partial headers), then sends a request and returns the response. A rejected
+/// connection gives an empty string.
+async fn send_with_preamble(balancebeam: &BalanceBeam, preamble: &[&[u8]]) -> String {
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    for chunk in preamble {
+        conn.write_all(chunk).await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+    }
+    // balancebeam may have closed the connection already
+    let _ = conn.write_all(REQUEST).await;
+    read_response(&mut conn).await
+}
+
+/// Reads one response (balancebeam keeps the connection open afterwards), or whatever arrives
+/// before the connection closes
+async fn read_response(conn: &mut TcpStream) -> String {
+    let mut response = Vec::new();
+    let mut byte = [0_u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        match conn.read(&mut byte).await {
+            Ok(1) => response.push(byte[0]),
+            _ => return String::from_utf8_lossy(&response).to_string(),
+        }
+    }
+    let head = String::from_utf8_lossy(&response).to_lowercase();
+    let content_length: usize = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length: "))
+        .map_or(0, |len| len.trim().parse().unwrap());
+    let mut body = vec![0; content_length];
+    conn.read_exact(&mut body).await.expect("Error reading response body");
+    response.extend(body);
+    String::from_utf8_lossy(&response).to_string()
+}
+
+/// Builds a v2 header with the given command (0 = LOCAL, 1 = PROXY), address family (1 = IPv4,
+/// 2 = IPv6) and address block
+fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+    header.push(0x20 | command);
+    // Family in the high nibble, STREAM (TCP) in the low one
+    header.push((family << 4) | 1);
+    header.extend((addresses.len() as u16).to_be_bytes());
+    header.extend(addresses);
+    header
+}
+
+async fn start(upstream: &EchoServer, extra_args: &[&str]) -> BalanceBeam {
+    let mut args = vec!["--upstream", &upstream.address, "--accept-proxy-protocol"];
+    args.extend(extra_args);
+    BalanceBeam::new_with_args(&args).await
+}
+
+#[tokio::test]
+async fn test_v1_header() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = start(&upstream, &[]).await;
+
+    let response = send_with_preamble(&balancebeam, &[b"PROXY TCP4 192.0.2.1 127.0.0.1 56324 80\r\n"]).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "Unexpected response: {}", response);
+    assert!(response.contains("x-forwarded-for: 192.0.2.1\n"));
+
+    let response = send_with_preamble(&balancebeam, &[b"PROXY TCP6 2001:db8::1 ::1 56324 80\r\n"]).await;
+    assert!(response.contains("x-forwarded-for: 2001:db8::1\n"), "Unexpected response: {}", response);
+
+    // UNKNOWN leaves the load balancer's own address in place
+    let response = send_with_preamble(&balancebeam, &[b"PROXY UNKNOWN\r\n"]).await;
+    assert!(response.contains("x-forwarded-for: 127.0.0.1\n"), "Unexpected response: {}", response);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_v2_header() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = start(&upstream, &[]).await;
+
+    let ipv4 = v2_header(1, 1, &[192, 0, 2, 7, 127, 0, 0, 1, 0xdc, 0x04, 0, 80]);
+    let response = send_with_preamble(&balancebeam, &[&ipv4]).await;
+    assert!(response.contains("x-forwarded-for: 192.0.2.7\n"), "Unexpected response: {}", response);
+
+    let mut addresses = vec![0x20, 0x01, 0x0d, 0xb8];
+    addresses.extend([0; 11]);
+    addresses.push(2);
+    addresses.extend([0; 15]);
+    addresses.push(1);
+    addresses.extend([0xdc, 0x04, 0, 80]);
+    let ipv6 = v2_header(1, 2, &addresses);
+    let response = send_with_preamble(&balancebeam, &[&ipv6]).await;
+    assert!(response.contains("x-forwarded-for: 2001:db8::2\n"), "Unexpected response: {}", response);
+
+    // LOCAL connections are the load balancer's own, and any addresses in them are ignored
+    let local = v2_header(0, 1, &[192, 0, 2, 7, 127, 0, 0, 1, 0xdc, 0x04, 0, 80]);
+    let response = send_with_preamble(&balancebeam, &[&local]).await;
+    assert!(response.contains("x-forwarded-for: 127.0.0.1\n"), "Unexpected response: {}", response);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Headers that arrive a few bytes at a time should be put back together
+#[tokio::test]
+async fn test_header_split_across_writes() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = start(&upstream, &[]).await;
+
+    let response = send_with_preamble(
+        &balancebeam,
+        &[b"PROX", b"Y TCP4 192.0.2.1 127.0.0.1 5", b"6324 80\r", b"\n"],
+    )
+    .await;
+    assert!(response.contains("x-forwarded-for: 192.0.2.1\n"), "Unexpected response: {}", response);
+
+    let header = v2_header(1, 1, &[192, 0, 2, 9, 127, 0, 0, 1, 0xdc, 0x04, 0, 80]);
+    let chunks = [&header[..5], &header[5..14], &header[14..20], &header[20..]];
+    let response = send_with_preamble(&balancebeam, &chunks).await;
+    assert!(response.contains("x-forwarded-for: 192.0.2.9\n"), "Unexpected response: {}", response);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Connections without a valid header must not reach the upstream
+#[tokio::test]
+async fn test_invalid_headers_rejected() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = start(&upstream, &[]).await;
+
+    let invalid_preambles: Vec<Vec<u8>> = vec![
+        // No header at all
+        Vec::new(),
+        b"PROXY TCP4 192.0.2.1 127.0.0.1\r\n".to_vec(),
+        b"PROXY TCP4 2001:db8::1 ::1 56324 80\r\n".to_vec(),
+        b"PROXY TCP4 192.0.2.1 127.0.0.1 056324 80\r\n".to_vec(),
+        b"PROXY SCTP 192.0.2.1 127.0.0.1 56324 80\r\n".to_vec(),
+        // Unsupported v2 version
+        {
+            let mut header = v2_header(1, 1, &[192, 0, 2, 7, 127, 0, 0, 1, 0xdc, 0x04, 0, 80]);
+            header[12] = 0x11;
+            header
+        },
+        // Address block too short for IPv4 addresses
+        v2_header(1, 1, &[192, 0, 2, 7]),
+    ];
+    for preamble in invalid_preambles {
+        let response = send_with_preamble(&balancebeam, &[&preamble]).await;
+        assert_eq!(response, "", "Expected {:?} to be rejected", String::from_utf8_lossy(&preamble));
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// A client that hangs up partway through the header is dropped without a response
+#[tokio::test]
+async fn test_truncated_header() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = start(&upstream, &[]).await;
+
+    let header = v2_header(1, 1, &[192, 0, 2, 7, 127, 0, 0, 1, 0xdc, 0x04, 0, 80]);
+    for preamble in [&b"PROXY TCP4 192.0.2.1"[..], &header[..10], &header[..20]] {
+        let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+        conn.write_all(preamble).await.unwrap();
+        conn.shutdown().await.unwrap();
+        let response = read_response(&mut conn).await;
+        assert_eq!(response, "", "Unexpected response: {}", response);
+    }
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(balancebeam.output_containing("IncompleteHeader").len(), 3);
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// The rate limit should count requests by the address in the header, not the load balancer's
+#[tokio::test]
+async fn test_rate_limit_by_proxied_address() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = start(&upstream, &["--max-requests-per-minute", "2"]).await;
+
+    let from = |ip: &str| format!("PROXY TCP4 {} 127.0.0.1 56324 80\r\n", ip);
+    for _ in 0..2 {
+        let response = send_with_preamble(&balancebeam, &[from("192.0.2.1").as_bytes()]).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "Unexpected response: {}", response);
+    }
+    let response = send_with_preamble(&balancebeam, &[from("192.0.2.1").as_bytes()]).await;
+    assert!(response.starts_with("HTTP/1.1 429"), "Unexpected response: {}", response);
+    let response = send_with_preamble(&balancebeam, &[from("192.0.2.2").as_bytes()]).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "Unexpected response: {}", response);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
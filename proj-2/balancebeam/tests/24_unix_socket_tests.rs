@@ -0,0 +1,126 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+/// Returns a socket path in the temporary directory that no other test uses
+fn socket_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("balancebeam-{}-{}.sock", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+async fn start(upstream: &EchoServer, path: &Path, extra_args: &[&str]) -> BalanceBeam {
+    let mut args = vec!["--upstream", &upstream.address];
+    args.extend(extra_args);
+    BalanceBeam::new_at_address(format!("unix:{}", path.display()), &args).await
+}
+
+/// Sends a GET request over the socket and returns the response, which balancebeam closes the
+/// connection after since it is started with --max-requests-per-connection 1
+async fn get(path: &Path) -> String {
+    let mut conn = UnixStream::connect(path)
+        .await
+        .expect("Could not connect to balancebeam's socket");
+    conn.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").await.unwrap();
+    let mut response = Vec::new();
+    conn.read_to_end(&mut response).await.expect("Error reading response");
+    String::from_utf8_lossy(&response).to_string()
+}
+
+#[tokio::test]
+async fn test_unix_socket() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = socket_path("basic");
+    let balancebeam = start(&upstream, &path, &["--max-requests-per-connection", "1"]).await;
+
+    let response = get(&path).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "Unexpected response: {}", response);
+    // Clients are identified by their user ID
+    let uid = nix::unistd::getuid();
+    assert!(
+        response.contains(&format!("x-forwarded-for: unix:uid={}\n", uid)),
+        "Unexpected response: {}",
+        response
+    );
+
+    drop(balancebeam);
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// The rate limit should still apply, keyed by the client's user ID
+#[tokio::test]
+async fn test_unix_socket_rate_limit() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = socket_path("rate-limit");
+    let balancebeam = start(
+        &upstream,
+        &path,
+        &["--max-requests-per-connection", "1", "--max-requests-per-minute", "2"],
+    )
+    .await;
+
+    for _ in 0..2 {
+        let response = get(&path).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "Unexpected response: {}", response);
+    }
+    let response = get(&path).await;
+    assert!(response.starts_with("HTTP/1.1 429"), "Unexpected response: {}", response);
+
+    drop(balancebeam);
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// A socket file left behind by a previous instance shouldn't stop balancebeam from starting, and
+/// the socket should get the requested permissions
+#[tokio::test]
+async fn test_stale_socket_and_mode() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = socket_path("stale");
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+    let balancebeam = start(
+        &upstream,
+        &path,
+        &["--max-requests-per-connection", "1", "--unix-socket-mode", "600"],
+    )
+    .await;
+
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let response = get(&path).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "Unexpected response: {}", response);
+
+    drop(balancebeam);
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A socket that another instance is still listening on must not be taken over
+#[tokio::test]
+async fn test_socket_in_use() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = socket_path("in-use");
+    let _listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+    let mut balancebeam = start(&upstream, &path, &[]).await;
+
+    let status = balancebeam.exit_status().expect("balancebeam should have exited");
+    assert!(!status.success());
+    assert!(path.exists());
+
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
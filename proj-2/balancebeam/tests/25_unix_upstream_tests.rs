@@ -0,0 +1,147 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Returns a socket path in the temporary directory that no other test uses
+fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("balancebeam-upstream-{}-{}.sock", std::process::id(), name))
+}
+
+/// Fetches the statistics table from the admin endpoint and returns the number of failed connection
+/// attempts to the given upstream
+async fn connect_failures(admin_address: &str, upstream_address: &str) -> usize {
+    let table = reqwest::get(format!("http://{}/stats", admin_address))
+        .await
+        .expect("Error sending request to the admin endpoint")
+        .text()
+        .await
+        .expect("Error reading the admin endpoint's response");
+    log::info!("Upstream statistics:\n{}", table);
+    let row: Vec<&str> = table
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|columns| columns.get(1) == Some(&upstream_address))
+        .expect("Upstream is missing from the statistics table");
+    row[9].parse().unwrap()
+}
+
+/// Requests should be spread over Unix socket and TCP upstreams alike
+#[tokio::test]
+async fn test_mixed_upstreams() {
+    init_logging();
+    let path = socket_path("mixed");
+    let unix_upstream = EchoServer::new_at_unix_path(&path).await;
+    let tcp_upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&["--upstream", &unix_upstream.address, "--upstream", &tcp_upstream.address])
+            .await;
+
+    let n_requests = 20;
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    }
+
+    let unix_requests = Box::new(unix_upstream).stop().await;
+    let tcp_requests = Box::new(tcp_upstream).stop().await;
+    log::info!("Unix upstream got {} requests, TCP upstream got {}", unix_requests, tcp_requests);
+    assert!(unix_requests > 0 && tcp_requests > 0, "Both upstreams should have gotten requests");
+    assert_eq!(unix_requests + tcp_requests, n_requests);
+    let _ = std::fs::remove_file(&path);
+    log::info!("All done :)");
+}
+
+/// A Unix socket upstream that stops listening should fail over to the other upstreams, and count
+/// as a failed connection in the statistics
+#[tokio::test]
+async fn test_unix_upstream_failover() {
+    init_logging();
+    let path = socket_path("failover");
+    let unix_upstream = EchoServer::new_at_unix_path(&path).await;
+    let unix_address = unix_upstream.address.clone();
+    let tcp_upstream = EchoServer::new().await;
+    let admin_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &unix_address,
+        "--upstream",
+        &tcp_upstream.address,
+        "--admin-bind",
+        &admin_address,
+    ])
+    .await;
+
+    assert_eq!(Box::new(unix_upstream).stop().await, 0);
+    let n_requests = 10;
+    for i in 0..n_requests {
+        balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    assert!(connect_failures(&admin_address, &unix_address).await >= 1);
+
+    assert_eq!(Box::new(tcp_upstream).stop().await, n_requests);
+    let _ = std::fs::remove_file(&path);
+    log::info!("All done :)");
+}
+
+/// Active health checks should take a Unix socket upstream out of rotation when it goes away, and
+/// put it back once it is listening again
+#[tokio::test]
+async fn test_unix_upstream_health_checks() {
+    init_logging();
+    let path = socket_path("health");
+    let unix_upstream = EchoServer::new_at_unix_path(&path).await;
+    let unix_address = unix_upstream.address.clone();
+    let tcp_upstream = EchoServer::new().await;
+    let admin_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &unix_address,
+        "--upstream",
+        &tcp_upstream.address,
+        "--active-health-check-interval",
+        "1",
+        "--admin-bind",
+        &admin_address,
+    ])
+    .await;
+
+    log::info!("Stopping the Unix socket upstream and waiting for a health check to notice");
+    Box::new(unix_upstream).stop().await;
+    sleep(Duration::from_millis(1500)).await;
+    for i in 0..10 {
+        balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    // The health check took it out of rotation before any request tried it
+    assert_eq!(connect_failures(&admin_address, &unix_address).await, 0);
+
+    log::info!("Restarting the Unix socket upstream and waiting for a health check to notice");
+    let unix_upstream = EchoServer::new_at_unix_path(&path).await;
+    sleep(Duration::from_millis(2500)).await;
+    for i in 0..20 {
+        balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    let unix_requests = Box::new(unix_upstream).stop().await;
+    // Health checks are requests too, so this can't be exact
+    assert!(unix_requests > 2, "Unix upstream got only {} requests after recovering", unix_requests);
+
+    Box::new(tcp_upstream).stop().await;
+    let _ = std::fs::remove_file(&path);
+    log::info!("All done :)");
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+async fn send(balancebeam: &BalanceBeam, method: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .request(
+            reqwest::Method::from_bytes(method.as_bytes()).unwrap(),
+            format!("http://{}/", balancebeam.address),
+        )
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+}
+
+/// By default TRACE and custom methods are refused with a 405 listing the allowed methods, and the
+/// standard methods go through.
+#[tokio::test]
+async fn test_trace_rejected() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&["--upstream", &upstream.address]).await;
+
+    for method in ["TRACE", "PROPFIND"] {
+        let response = send(&balancebeam, method).await;
+        assert_eq!(response.status().as_u16(), 405, "{} should be rejected", method);
+        assert_eq!(
+            response.headers().get("allow").unwrap(),
+            "GET, POST, PUT, DELETE, HEAD, OPTIONS, PATCH"
+        );
+    }
+    for method in ["GET", "PUT", "PATCH", "OPTIONS"] {
+        let response = send(&balancebeam, method).await;
+        assert_eq!(response.status().as_u16(), 200, "{} should be allowed", method);
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// The Allow header should list exactly the configured methods, and `*` should let anything through
+#[tokio::test]
+async fn test_configured_methods() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&["--upstream", &upstream.address, "--allowed-methods", "GET,PURGE"]).await;
+
+    let response = send(&balancebeam, "POST").await;
+    assert_eq!(response.status().as_u16(), 405);
+    assert_eq!(response.headers().get("allow").unwrap(), "GET, PURGE");
+    let response = send(&balancebeam, "PURGE").await;
+    assert_eq!(response.status().as_u16(), 200);
+    drop(balancebeam);
+
+    let balancebeam =
+        BalanceBeam::new_with_args(&["--upstream", &upstream.address, "--allowed-methods", "*"]).await;
+    for method in ["TRACE", "PROPFIND"] {
+        let response = send(&balancebeam, method).await;
+        assert_eq!(response.status().as_u16(), 200, "{} should be allowed", method);
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Rejected requests don't reach the upstream, but still count against the rate limit
+#[tokio::test]
+async fn test_rejected_methods_rate_limited() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&["--upstream", &upstream.address, "--max-requests-per-minute", "2"]).await;
+
+    for _ in 0..2 {
+        assert_eq!(send(&balancebeam, "TRACE").await.status().as_u16(), 405);
+    }
+    assert_eq!(send(&balancebeam, "TRACE").await.status().as_u16(), 429);
+    assert_eq!(send(&balancebeam, "GET").await.status().as_u16(), 429);
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
@@ -0,0 +1,149 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout, Instant};
+
+/// Starts an upstream that reads a request on each connection, then writes each piece of the
+/// response after waiting the given delay. It keeps the connection open for a while afterwards, so
+/// that a stalled response isn't mistaken for one that ended.
+async fn start_scripted_upstream(script: Vec<(Duration, &'static str)>) -> String {
+    let address = random_address();
+    let listener = TcpListener::bind(&address).await.expect("Could not bind upstream");
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let script = script.clone();
+            tokio::spawn(async move {
+                let mut request = [0_u8; 4096];
+                let _ = conn.read(&mut request).await;
+                for (delay, piece) in script {
+                    sleep(delay).await;
+                    if conn.write_all(piece.as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+                sleep(Duration::from_secs(5)).await;
+            });
+        }
+    });
+    address
+}
+
+/// Sends a GET request to balancebeam and returns everything it sends back before closing the
+/// connection, along with how long that took.
+async fn get_until_closed(balancebeam: &BalanceBeam) -> (String, Duration) {
+    let start = Instant::now();
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    conn.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").await.unwrap();
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(4), conn.read_to_end(&mut response))
+        .await
+        .expect("balancebeam should have closed the connection")
+        .expect("Error reading response");
+    (String::from_utf8_lossy(&response).to_string(), start.elapsed())
+}
+
+/// An upstream that takes too long to send its response head should get the client a 504, while
+/// upstreams that answer promptly are unaffected
+#[tokio::test]
+async fn test_header_timeout() {
+    init_logging();
+    let slow_upstream = start_scripted_upstream(vec![(
+        Duration::from_secs(2),
+        "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+    )])
+    .await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&["--upstream", &slow_upstream, "--upstream-header-timeout", "500ms"]).await;
+
+    let (response, elapsed) = get_until_closed(&balancebeam).await;
+    assert!(response.starts_with("HTTP/1.1 504"), "Unexpected response: {}", response);
+    assert!(elapsed < Duration::from_millis(1500), "Timing out took {:?}", elapsed);
+    drop(balancebeam);
+
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&["--upstream", &upstream.address, "--upstream-header-timeout", "500ms"])
+            .await;
+    let response_text = balancebeam.get("/").await.expect("Error sending request to balancebeam");
+    assert!(response_text.starts_with("GET / HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A body that keeps trickling in may take longer than either timeout in total, as long as no gap
+/// between pieces is longer than the idle timeout
+#[tokio::test]
+async fn test_slow_body_within_idle_timeout() {
+    init_logging();
+    let mut script = vec![(Duration::ZERO, "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n")];
+    script.extend((0..10).map(|_| (Duration::from_millis(200), "x")));
+    let upstream_address = start_scripted_upstream(script).await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream_address,
+        "--upstream-header-timeout",
+        "500ms",
+        "--upstream-idle-timeout",
+        "500ms",
+    ])
+    .await;
+
+    let start = Instant::now();
+    let response_text = balancebeam.get("/").await.expect("Error sending request to balancebeam");
+    assert_eq!(response_text, "xxxxxxxxxx");
+    assert!(start.elapsed() > Duration::from_secs(1));
+    log::info!("All done :)");
+}
+
+/// A streamed body that stalls is cut off once the idle timeout passes, since the client already
+/// has the head
+#[tokio::test]
+async fn test_idle_timeout_while_streaming() {
+    init_logging();
+    let upstream_address = start_scripted_upstream(vec![
+        (Duration::ZERO, "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nxxxxx"),
+        (Duration::from_secs(3), "xxxxx"),
+    ])
+    .await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&["--upstream", &upstream_address, "--upstream-idle-timeout", "500ms"]).await;
+
+    let (response, elapsed) = get_until_closed(&balancebeam).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "Unexpected response: {}", response);
+    assert!(response.ends_with("\r\n\r\nxxxxx"), "Unexpected response: {}", response);
+    assert!(elapsed < Duration::from_secs(2), "Timing out took {:?}", elapsed);
+    log::info!("All done :)");
+}
+
+/// When the body is being read in full before anything is sent (here, to cache it), a stall gets
+/// the client a 504
+#[tokio::test]
+async fn test_idle_timeout_while_buffering() {
+    init_logging();
+    let upstream_address = start_scripted_upstream(vec![
+        (Duration::ZERO, "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nxxxxx"),
+        (Duration::from_secs(3), "xxxxx"),
+    ])
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream_address,
+        "--upstream-idle-timeout",
+        "500ms",
+        "--cache-max-bytes",
+        "100000",
+    ])
+    .await;
+
+    let (response, elapsed) = get_until_closed(&balancebeam).await;
+    assert!(response.starts_with("HTTP/1.1 504"), "Unexpected response: {}", response);
+    assert!(elapsed < Duration::from_secs(2), "Timing out took {:?}", elapsed);
+    log::info!("All done :)");
+}
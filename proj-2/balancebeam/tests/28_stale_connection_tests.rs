@@ -0,0 +1,96 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::sleep;
+
+/// Starts an upstream that answers one request on each connection and then closes it, without
+/// saying so in the response, the way a server drops keep-alive connections that have sat idle.
+/// Returns its address and a count of the connections it has accepted.
+async fn start_one_shot_upstream() -> (String, Arc<AtomicUsize>) {
+    let address = random_address();
+    let listener = TcpListener::bind(&address).await.expect("Could not bind upstream");
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0_u8; 4096];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    match conn.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(len) => request.extend_from_slice(&buffer[..len]),
+                    }
+                }
+                let _ = conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+            });
+        }
+    });
+    (address, connections)
+}
+
+/// Sends two requests on one client connection, pausing in between so that the upstream has closed
+/// the connection balancebeam kept for the first one. Returns the statuses of the responses.
+async fn send_two(balancebeam: &BalanceBeam, method: reqwest::Method) -> Vec<u16> {
+    let client = reqwest::Client::new();
+    let mut statuses = Vec::new();
+    for _ in 0..2 {
+        let response = client
+            .request(method.clone(), format!("http://{}/", balancebeam.address))
+            .body("body")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        statuses.push(response.status().as_u16());
+        sleep(Duration::from_millis(200)).await;
+    }
+    statuses
+}
+
+/// A GET sent on a connection the upstream has closed should be retried on a new one
+#[tokio::test]
+async fn test_retry_on_stale_connection() {
+    init_logging();
+    let (upstream_address, connections) = start_one_shot_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(&["--upstream", &upstream_address]).await;
+
+    assert_eq!(send_two(&balancebeam, reqwest::Method::GET).await, vec![200, 200]);
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+    log::info!("All done :)");
+}
+
+/// POST isn't safe to send twice, so it isn't retried unless configured to be
+#[tokio::test]
+async fn test_no_retry_for_post() {
+    init_logging();
+    let (upstream_address, _) = start_one_shot_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(&["--upstream", &upstream_address]).await;
+    assert_eq!(send_two(&balancebeam, reqwest::Method::POST).await, vec![200, 502]);
+    drop(balancebeam);
+
+    let balancebeam =
+        BalanceBeam::new_with_args(&["--upstream", &upstream_address, "--retry-methods", "GET,POST"]).await;
+    assert_eq!(send_two(&balancebeam, reqwest::Method::POST).await, vec![200, 200]);
+    log::info!("All done :)");
+}
+
+/// Retries shouldn't change anything for upstreams that keep their connections open
+#[tokio::test]
+async fn test_kept_alive_connection_reused() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&["--upstream", &upstream.address]).await;
+
+    assert_eq!(send_two(&balancebeam, reqwest::Method::GET).await, vec![200, 200]);
+    assert_eq!(balancebeam.output_containing("retrying").len(), 0);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
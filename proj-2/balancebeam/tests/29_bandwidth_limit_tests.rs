@@ -0,0 +1,93 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+async fn post(client: &reqwest::Client, balancebeam: &BalanceBeam, body: String) -> u16 {
+    client
+        .post(format!("http://{}/", balancebeam.address))
+        .body(body)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Many small requests fit in the byte allowance, but one big one doesn't. Being turned away doesn't
+/// use up any of the allowance, which comes back in the next window.
+#[tokio::test]
+async fn test_byte_limit() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--max-bytes-per-minute",
+        "1000",
+        "--rate-limit-window",
+        "2s",
+    ])
+    .await;
+    let client = reqwest::Client::new();
+
+    for _ in 0..30 {
+        assert_eq!(post(&client, &balancebeam, "x".repeat(20)).await, 200);
+    }
+    assert_eq!(post(&client, &balancebeam, "x".repeat(5000)).await, 429);
+    assert_eq!(post(&client, &balancebeam, "x".repeat(20)).await, 200);
+    // 620 bytes used so far
+    assert_eq!(post(&client, &balancebeam, "x".repeat(400)).await, 429);
+
+    sleep(Duration::from_millis(2500)).await;
+    assert_eq!(post(&client, &balancebeam, "x".repeat(900)).await, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 32);
+    log::info!("All done :)");
+}
+
+/// Responses only count against the allowance when asked to
+#[tokio::test]
+async fn test_count_response_bytes() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--max-bytes-per-minute",
+        "1000",
+    ])
+    .await;
+    // The echo server's responses are a couple of hundred bytes each
+    for _ in 0..20 {
+        balancebeam.get("/").await.expect("Error sending request to balancebeam");
+    }
+    drop(balancebeam);
+
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--max-bytes-per-minute",
+        "1000",
+        "--count-response-bytes",
+    ])
+    .await;
+    let client = reqwest::Client::new();
+    let mut statuses = Vec::new();
+    for _ in 0..20 {
+        let response = client
+            .get(format!("http://{}/", balancebeam.address))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        statuses.push(response.status().as_u16());
+    }
+    log::info!("Statuses: {:?}", statuses);
+    let allowed = statuses.iter().take_while(|&&status| status == 200).count();
+    assert!(allowed > 0 && allowed < 20, "Expected to run out of bytes partway through");
+    assert!(statuses[allowed..].iter().all(|&status| status == 429));
+
+    assert_eq!(Box::new(upstream).stop().await, 20 + allowed);
+    log::info!("All done :)");
+}
@@ -0,0 +1,142 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// Sends a GET, returning the status and how long the response took. Requests sent at the same time
+/// go on separate connections.
+async fn timed_get(client: reqwest::Client, address: String) -> (u16, Duration) {
+    let start = Instant::now();
+    let response = client
+        .get(format!("http://{}/", address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    if response.status() == 503 {
+        assert_eq!(response.headers().get("retry-after").unwrap(), "1");
+    }
+    (response.status().as_u16(), start.elapsed())
+}
+
+/// With a slow upstream that can take two requests at a time, a burst of six should see two served
+/// straight away, two served after waiting in the queue, and two turned away at once because the
+/// queue is full.
+#[tokio::test]
+async fn test_queue_burst() {
+    init_logging();
+    let upstream = EchoServer::new_with_delay(Duration::from_millis(1000)).await;
+    let admin_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--max-upstream-requests",
+        "2",
+        "--queue-depth",
+        "2",
+        "--queue-timeout",
+        "5s",
+        "--admin-bind",
+        &admin_address,
+    ])
+    .await;
+    let client = reqwest::Client::new();
+
+    let mut tasks = Vec::new();
+    for _ in 0..6 {
+        tasks.push(tokio::spawn(timed_get(client.clone(), balancebeam.address.clone())));
+        sleep(Duration::from_millis(20)).await;
+    }
+
+    // Partway through the first two requests, the queue should be full
+    sleep(Duration::from_millis(300)).await;
+    let stats = reqwest::get(format!("http://{}/stats", admin_address))
+        .await
+        .expect("Error sending request to the admin endpoint")
+        .text()
+        .await
+        .expect("Error reading the admin endpoint's response");
+    log::info!("Upstream statistics:\n{}", stats);
+    assert!(stats.contains("Queued in default: 2"));
+    let row: Vec<&str> = stats
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|columns| columns.get(1) == Some(&upstream.address.as_str()))
+        .expect("Upstream is missing from the statistics table");
+    assert_eq!(row[15], "2", "Two requests should be in flight");
+
+    let mut results = Vec::new();
+    for task in tasks {
+        results.push(task.await.unwrap());
+    }
+    log::info!("Results: {:?}", results);
+    let mut served: Vec<Duration> =
+        results.iter().filter(|(status, _)| *status == 200).map(|(_, elapsed)| *elapsed).collect();
+    served.sort();
+    assert_eq!(served.len(), 4);
+    assert!(served[3] >= Duration::from_millis(1900), "Queued requests should wait for room");
+    for (status, elapsed) in results.iter().filter(|(status, _)| *status != 200) {
+        assert_eq!(*status, 503);
+        assert!(*elapsed < Duration::from_millis(300), "Overflow should be turned away quickly");
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// A request that waits in the queue for longer than the queue timeout gets 503
+#[tokio::test]
+async fn test_queue_timeout() {
+    init_logging();
+    let upstream = EchoServer::new_with_delay(Duration::from_millis(1500)).await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--max-upstream-requests",
+        "1",
+        "--queue-depth",
+        "5",
+        "--queue-timeout",
+        "300ms",
+    ])
+    .await;
+    let client = reqwest::Client::new();
+
+    let first = tokio::spawn(timed_get(client.clone(), balancebeam.address.clone()));
+    sleep(Duration::from_millis(100)).await;
+    let (status, elapsed) = timed_get(client.clone(), balancebeam.address.clone()).await;
+    assert_eq!(status, 503);
+    assert!(elapsed >= Duration::from_millis(300), "Request should have waited in the queue");
+    assert_eq!(first.await.unwrap().0, 200);
+
+    // Now that the upstream is free again, requests go straight through
+    assert_eq!(timed_get(client.clone(), balancebeam.address.clone()).await.0, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Without a queue, requests beyond the limit are turned away at once
+#[tokio::test]
+async fn test_no_queue() {
+    init_logging();
+    let upstream = EchoServer::new_with_delay(Duration::from_millis(500)).await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--max-upstream-requests",
+        "1",
+    ])
+    .await;
+    let client = reqwest::Client::new();
+
+    let first = tokio::spawn(timed_get(client.clone(), balancebeam.address.clone()));
+    sleep(Duration::from_millis(100)).await;
+    let (status, elapsed) = timed_get(client.clone(), balancebeam.address.clone()).await;
+    assert_eq!(status, 503);
+    assert!(elapsed < Duration::from_millis(200));
+    assert_eq!(first.await.unwrap().0, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
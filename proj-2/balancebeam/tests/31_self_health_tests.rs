@@ -0,0 +1,72 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+async fn status_of(balancebeam: &BalanceBeam, path: &str) -> u16 {
+    reqwest::get(format!("http://{}{}", balancebeam.address, path))
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Health probes are answered by balancebeam itself, and don't count against the rate limit or need
+/// an allowed host
+#[tokio::test]
+async fn test_self_health_not_forwarded() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--self-health-path",
+        "/__balancebeam/healthz",
+        "--max-requests-per-minute",
+        "2",
+        "--allowed-host",
+        "example.com",
+    ])
+    .await;
+
+    for _ in 0..5 {
+        assert_eq!(status_of(&balancebeam, "/__balancebeam/healthz").await, 200);
+    }
+    // Only the exact path is answered
+    assert_eq!(status_of(&balancebeam, "/__balancebeam/healthz/more").await, 421);
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Readiness waits for the first round of health checks, and both paths report 503 once every
+/// upstream is dead
+#[tokio::test]
+async fn test_self_health_follows_upstreams() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--self-health-path",
+        "/healthz",
+        "--self-ready-path",
+        "/ready",
+        "--active-health-check-interval",
+        "3s",
+    ])
+    .await;
+
+    assert_eq!(status_of(&balancebeam, "/healthz").await, 200);
+    assert_eq!(status_of(&balancebeam, "/ready").await, 503);
+    sleep(Duration::from_millis(3000)).await;
+    assert_eq!(status_of(&balancebeam, "/ready").await, 200);
+
+    log::info!("Stopping the upstream");
+    assert!(Box::new(upstream).stop().await >= 1);
+    sleep(Duration::from_millis(3000)).await;
+    assert_eq!(status_of(&balancebeam, "/healthz").await, 503);
+    assert_eq!(status_of(&balancebeam, "/ready").await, 503);
+    log::info!("All done :)");
+}
@@ -0,0 +1,130 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Sets an upstream's administrative state through the admin endpoint, returning the status and body
+/// of the response
+async fn set_state(admin_address: &str, upstream_address: &str, admin_state: &str) -> (u16, String) {
+    let response = reqwest::Client::new()
+        .put(format!("http://{}/upstreams/{}", admin_address, upstream_address))
+        .body(admin_state.to_string())
+        .send()
+        .await
+        .expect("Error sending request to the admin endpoint");
+    let status = response.status().as_u16();
+    (status, response.text().await.unwrap())
+}
+
+/// A draining upstream gets no new requests until it is made active again
+#[tokio::test]
+async fn test_drain_and_reactivate() {
+    init_logging();
+    let draining_upstream = EchoServer::new().await;
+    let other_upstream = EchoServer::new().await;
+    let admin_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &draining_upstream.address,
+        "--upstream",
+        &other_upstream.address,
+        "--admin-bind",
+        &admin_address,
+    ])
+    .await;
+
+    let (status, body) = set_state(&admin_address, &draining_upstream.address, "draining").await;
+    assert_eq!(status, 200);
+    assert_eq!(body, format!("default {} alive draining\n", draining_upstream.address));
+    assert_eq!(balancebeam.output_containing("is now draining (was active)").len(), 1);
+    for _ in 0..10 {
+        balancebeam.get("/").await.expect("Error sending request to balancebeam");
+    }
+
+    let summary = reqwest::get(format!("http://{}/upstreams", admin_address))
+        .await
+        .expect("Error sending request to the admin endpoint")
+        .text()
+        .await
+        .unwrap();
+    assert!(summary.contains(&format!("default {} alive active\n", other_upstream.address)));
+
+    assert_eq!(set_state(&admin_address, &draining_upstream.address, "active").await.0, 200);
+    for _ in 0..20 {
+        balancebeam.get("/").await.expect("Error sending request to balancebeam");
+    }
+
+    // Invalid states and unknown upstreams are rejected
+    assert_eq!(set_state(&admin_address, &draining_upstream.address, "sleeping").await.0, 400);
+    assert_eq!(set_state(&admin_address, "127.0.0.1:1", "draining").await.0, 404);
+
+    let drained_requests = Box::new(draining_upstream).stop().await;
+    let other_requests = Box::new(other_upstream).stop().await;
+    assert!(drained_requests > 0 && drained_requests < 20, "Drained upstream got {}", drained_requests);
+    assert_eq!(drained_requests + other_requests, 30);
+    log::info!("All done :)");
+}
+
+/// Requests already in flight to a draining upstream finish, but later requests aren't sent to it,
+/// even on a connection balancebeam already has open to it
+#[tokio::test]
+async fn test_drain_finishes_in_flight() {
+    init_logging();
+    let upstream = EchoServer::new_with_delay(Duration::from_millis(1000)).await;
+    let admin_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--admin-bind",
+        &admin_address,
+    ])
+    .await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/", balancebeam.address);
+
+    let in_flight = tokio::spawn(client.get(&url).send());
+    sleep(Duration::from_millis(300)).await;
+    assert_eq!(set_state(&admin_address, &upstream.address, "draining").await.0, 200);
+    let response = in_flight.await.unwrap().expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+
+    // The client sends this on the connection it just used, which balancebeam had kept open to the
+    // upstream
+    let response = client.get(&url).send().await.expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 503);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Draining upstreams are still health checked, but disabled ones aren't
+#[tokio::test]
+async fn test_disabled_not_health_checked() {
+    init_logging();
+    let draining_upstream = EchoServer::new().await;
+    let disabled_upstream = EchoServer::new().await;
+    let admin_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &draining_upstream.address,
+        "--upstream",
+        &disabled_upstream.address,
+        "--admin-bind",
+        &admin_address,
+        "--active-health-check-interval",
+        "1s",
+    ])
+    .await;
+
+    assert_eq!(set_state(&admin_address, &draining_upstream.address, "draining").await.0, 200);
+    assert_eq!(set_state(&admin_address, &disabled_upstream.address, "disabled").await.0, 200);
+    sleep(Duration::from_millis(2500)).await;
+    // With nothing active, there's nowhere to send requests
+    let response = reqwest::get(format!("http://{}/", balancebeam.address)).await.unwrap();
+    assert_eq!(response.status().as_u16(), 503);
+
+    assert!(Box::new(draining_upstream).stop().await >= 2);
+    assert!(Box::new(disabled_upstream).stop().await <= 1);
+    log::info!("All done :)");
+}
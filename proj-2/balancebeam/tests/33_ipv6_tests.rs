@@ -0,0 +1,112 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+
+/// Starts balancebeam on a dual-stack listener, returning it along with URLs that reach it over IPv4
+/// and over IPv6
+async fn start_dual_stack(args: &[&str]) -> (BalanceBeam, String, String) {
+    let port = random_address().rsplit_once(':').unwrap().1.to_string();
+    let balancebeam = BalanceBeam::new_at_address(format!("[::]:{}", port), args).await;
+    let ipv4_url = format!("http://127.0.0.1:{}/", port);
+    let ipv6_url = format!("http://[::1]:{}/", port);
+    (balancebeam, ipv4_url, ipv6_url)
+}
+
+/// Sends a GET, returning the status and body of the response
+async fn get(url: &str) -> (u16, String) {
+    let response = reqwest::get(url).await.expect("Error sending request to balancebeam");
+    let status = response.status().as_u16();
+    (status, response.text().await.unwrap())
+}
+
+/// IPv4 clients of a dual-stack listener should be known by their IPv4 address rather than as
+/// IPv4-mapped IPv6 addresses, and should be rate limited separately from IPv6 clients
+#[tokio::test]
+async fn test_dual_stack_clients() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (_balancebeam, ipv4_url, ipv6_url) =
+        start_dual_stack(&["--upstream", &upstream.address, "--max-requests-per-minute", "2"]).await;
+
+    for _ in 0..2 {
+        let (status, body) = get(&ipv4_url).await;
+        assert_eq!(status, 200);
+        assert!(body.contains("x-forwarded-for: 127.0.0.1\n"), "Unexpected request: {}", body);
+    }
+    assert_eq!(get(&ipv4_url).await.0, 429);
+
+    for _ in 0..2 {
+        let (status, body) = get(&ipv6_url).await;
+        assert_eq!(status, 200);
+        assert!(body.contains("x-forwarded-for: ::1\n"), "Unexpected request: {}", body);
+    }
+    assert_eq!(get(&ipv6_url).await.0, 429);
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// IPv4 ranges should apply to IPv4 clients of a dual-stack listener
+#[tokio::test]
+async fn test_dual_stack_ip_filter() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (_balancebeam, ipv4_url, ipv6_url) = start_dual_stack(&[
+        "--upstream",
+        &upstream.address,
+        "--deny-ip",
+        "127.0.0.0/8",
+        "--deny-action",
+        "forbidden",
+    ])
+    .await;
+
+    assert_eq!(get(&ipv4_url).await.0, 403);
+    assert_eq!(get(&ipv6_url).await.0, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// IPv6 upstreams are written the standard way, however they are given
+#[tokio::test]
+async fn test_ipv6_upstream() {
+    init_logging();
+    let port = random_address().rsplit_once(':').unwrap().1.to_string();
+    let upstream = EchoServer::new_at_address(format!("[::1]:{}", port)).await;
+    let admin_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &format!("[0:0:0:0:0:0:0:1]:{}", port),
+        "--admin-bind",
+        &admin_address,
+    ])
+    .await;
+
+    balancebeam.get("/").await.expect("Error sending request to balancebeam");
+    let summary = reqwest::get(format!("http://{}/upstreams", admin_address))
+        .await
+        .expect("Error sending request to the admin endpoint")
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(summary, format!("default [::1]:{} alive active\n", port));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// An IPv6 upstream address without brackets is ambiguous, so balancebeam should refuse to start
+#[tokio::test]
+async fn test_unbracketed_ipv6_upstream_rejected() {
+    init_logging();
+    for upstream in ["::1:8080", "[::1]", "127.0.0.1", "example.com:http"] {
+        let mut balancebeam = BalanceBeam::new_with_args(&["--upstream", upstream]).await;
+        let status = balancebeam.exit_status().expect("balancebeam should have exited");
+        assert!(!status.success(), "{} should be rejected", upstream);
+    }
+    let mut balancebeam =
+        BalanceBeam::new_with_args(&["--upstream", "127.0.0.1:1", "--mirror-upstream", "::1:80"]).await;
+    assert!(!balancebeam.exit_status().expect("balancebeam should have exited").success());
+    log::info!("All done :)");
+}
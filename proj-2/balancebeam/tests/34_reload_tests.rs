@@ -0,0 +1,205 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Path for a config file that only this test uses
+fn config_path(test_name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "balancebeam-reload-test-{}-{}.toml",
+        test_name,
+        std::process::id()
+    ))
+}
+
+fn write_config(path: &Path, contents: &str) {
+    std::fs::write(path, contents).expect("Could not write config file");
+}
+
+async fn start_with_config(path: &Path) -> BalanceBeam {
+    BalanceBeam::new_with_args(&[std::ffi::OsStr::new("--config"), path.as_os_str()]).await
+}
+
+async fn get_status(client: &reqwest::Client, balancebeam_address: &str) -> u16 {
+    client
+        .get(format!("http://{}/", balancebeam_address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Swapping one upstream for another should send new requests to the new one, once it has passed
+/// a health check, while a slow request to the old one finishes undisturbed.
+#[tokio::test]
+async fn test_reload_upstreams() {
+    init_logging();
+    let old_upstream = EchoServer::new_with_delay(Duration::from_millis(1500)).await;
+    let new_upstream = EchoServer::new().await;
+    let path = config_path("upstreams");
+    write_config(
+        &path,
+        &format!("default_group = \"web\"\n\n[groups]\nweb = [\"{}\"]\n", old_upstream.address),
+    );
+    let balancebeam = start_with_config(&path).await;
+
+    let slow_request = {
+        let address = balancebeam.address.clone();
+        tokio::spawn(async move { get_status(&reqwest::Client::new(), &address).await })
+    };
+    sleep(Duration::from_millis(200)).await;
+    write_config(
+        &path,
+        &format!("default_group = \"web\"\n\n[groups]\nweb = [\"{}\"]\n", new_upstream.address),
+    );
+    balancebeam.signal(nix::sys::signal::Signal::SIGHUP);
+    sleep(Duration::from_millis(500)).await;
+    assert!(!balancebeam.output_containing("Configuration reloaded").is_empty());
+    assert!(!balancebeam
+        .output_containing(&format!("Upstream {} was removed from group web", old_upstream.address))
+        .is_empty());
+
+    let client = reqwest::Client::new();
+    for _ in 0..3 {
+        assert_eq!(get_status(&client, &balancebeam.address).await, 200);
+    }
+    assert_eq!(slow_request.await.unwrap(), 200, "The slow request should have finished");
+
+    assert_eq!(Box::new(old_upstream).stop().await, 1);
+    // The new upstream also got its first health check
+    assert_eq!(Box::new(new_upstream).stop().await, 4);
+    let _ = std::fs::remove_file(&path);
+    log::info!("All done :)");
+}
+
+/// A config file that has become invalid is ignored, and the old configuration stays in use.
+#[tokio::test]
+async fn test_reload_invalid_config() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = config_path("invalid");
+    write_config(&path, &format!("default_group = \"web\"\n\n[groups]\nweb = [\"{}\"]\n", upstream.address));
+    let mut balancebeam = start_with_config(&path).await;
+    let client = reqwest::Client::new();
+    assert_eq!(get_status(&client, &balancebeam.address).await, 200);
+
+    write_config(&path, "default_group = \"missing\"\n");
+    balancebeam.signal(nix::sys::signal::Signal::SIGHUP);
+    sleep(Duration::from_millis(300)).await;
+    assert!(!balancebeam.output_containing("Keeping the old configuration").is_empty());
+    assert!(balancebeam.exit_status().is_none(), "balancebeam should still be running");
+    assert_eq!(get_status(&client, &balancebeam.address).await, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    let _ = std::fs::remove_file(&path);
+    log::info!("All done :)");
+}
+
+/// Tunables like the rate limit take effect as soon as the configuration is reloaded, and what
+/// clients have used so far still counts.
+#[tokio::test]
+async fn test_reload_rate_limit() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = config_path("rate-limit");
+    let config = |limit: usize| {
+        format!(
+            "max_requests_per_minute = {}\ndefault_group = \"web\"\n\n[groups]\nweb = [\"{}\"]\n",
+            limit, upstream.address
+        )
+    };
+    write_config(&path, &config(3));
+    let balancebeam = start_with_config(&path).await;
+    let client = reqwest::Client::new();
+    for _ in 0..3 {
+        assert_eq!(get_status(&client, &balancebeam.address).await, 200);
+    }
+    assert_eq!(get_status(&client, &balancebeam.address).await, 429);
+
+    write_config(&path, &config(6));
+    balancebeam.signal(nix::sys::signal::Signal::SIGHUP);
+    sleep(Duration::from_millis(300)).await;
+    // Four requests have been counted so far, so two more fit under the new limit
+    assert_eq!(get_status(&client, &balancebeam.address).await, 200);
+    assert_eq!(get_status(&client, &balancebeam.address).await, 200);
+    assert_eq!(get_status(&client, &balancebeam.address).await, 429);
+
+    assert_eq!(Box::new(upstream).stop().await, 5);
+    let _ = std::fs::remove_file(&path);
+    log::info!("All done :)");
+}
+
+/// Fetches the canary summary for every group from the admin endpoint
+async fn canary_summary(client: &reqwest::Client, admin_address: &str) -> String {
+    client
+        .get(format!("http://{}/canary", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to the admin endpoint")
+        .text()
+        .await
+        .expect("Error reading admin response")
+}
+
+/// A canary percentage set through the admin endpoint, and the counts of requests each set got,
+/// survive a reload that keeps the same canaries. Changing the canary's configured percentage
+/// starts it afresh.
+#[tokio::test]
+async fn test_reload_keeps_canary() {
+    init_logging();
+    let stable_upstream = EchoServer::new().await;
+    let canary_upstream = EchoServer::new().await;
+    let path = config_path("canary");
+    let config = |percentage: u8| {
+        format!(
+            "default_group = \"web\"\n\n[groups]\nweb = [\"{}\", \"{}=canary:{}\"]\n",
+            stable_upstream.address, canary_upstream.address, percentage
+        )
+    };
+    write_config(&path, &config(0));
+    let admin_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(&[
+        std::ffi::OsStr::new("--config"),
+        path.as_os_str(),
+        std::ffi::OsStr::new("--admin-bind"),
+        std::ffi::OsStr::new(&admin_address),
+    ])
+    .await;
+    let client = reqwest::Client::new();
+    let response = client
+        .put(format!("http://{}/canary/web", admin_address))
+        .body("100")
+        .send()
+        .await
+        .expect("Error sending request to the admin endpoint");
+    assert_eq!(response.status().as_u16(), 200);
+    for _ in 0..2 {
+        assert_eq!(get_status(&client, &balancebeam.address).await, 200);
+    }
+
+    balancebeam.signal(nix::sys::signal::Signal::SIGHUP);
+    sleep(Duration::from_millis(300)).await;
+    assert!(!balancebeam.output_containing("Configuration reloaded").is_empty());
+    assert_eq!(get_status(&client, &balancebeam.address).await, 200);
+    assert_eq!(
+        canary_summary(&client, &admin_address).await,
+        "web: canary 100%, stable 0 requests (0 errors), canary 3 requests (0 errors)\n"
+    );
+
+    write_config(&path, &config(20));
+    balancebeam.signal(nix::sys::signal::Signal::SIGHUP);
+    sleep(Duration::from_millis(300)).await;
+    assert!(!balancebeam.output_containing("canary percentage was reset from 100% to 20%").is_empty());
+    assert_eq!(
+        canary_summary(&client, &admin_address).await,
+        "web: canary 20%, stable 0 requests (0 errors), canary 0 requests (0 errors)\n"
+    );
+
+    assert_eq!(Box::new(stable_upstream).stop().await, 0);
+    assert_eq!(Box::new(canary_upstream).stop().await, 3);
+    let _ = std::fs::remove_file(&path);
+    log::info!("All done :)");
+}
@@ -0,0 +1,168 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout};
+
+/// Starts an upstream that answers every request with `response` and then hangs up, whatever its
+/// Content-Length says. It keeps reading until balancebeam hangs up too, since closing a socket with
+/// unread data resets the connection, which could throw away the response before it is read.
+async fn start_raw_upstream(response: String) -> String {
+    let address = random_address();
+    let listener = TcpListener::bind(&address).await.expect("Could not bind upstream");
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let response = response.clone();
+            tokio::spawn(async move {
+                let mut request = [0_u8; 4096];
+                let _ = conn.read(&mut request).await;
+                let _ = conn.write_all(response.as_bytes()).await;
+                let _ = conn.shutdown().await;
+                let _ = conn.read_to_end(&mut Vec::new()).await;
+            });
+        }
+    });
+    address
+}
+
+/// Sends a GET for `path` on a new connection and reads everything balancebeam sends back until it closes
+/// the connection (or goes quiet, which counts as a failure).
+async fn get_raw(balancebeam: &BalanceBeam, path: &str) -> String {
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: example.com\r\nAccept-Encoding: gzip\r\n\r\n", path);
+    conn.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), conn.read_to_end(&mut response))
+        .await
+        .expect("balancebeam left the connection open")
+        .expect("Error reading from balancebeam");
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+/// Returns the body of a raw response, after the head
+fn body_of(response: &str) -> &str {
+    response.split_once("\r\n\r\n").map_or("", |(_, body)| body)
+}
+
+/// A body that stops short of its Content-Length is streamed as far as it goes, then the client's
+/// connection is closed so that it can see the body is incomplete
+#[tokio::test]
+async fn test_short_body_streamed() {
+    init_logging();
+    let upstream = start_raw_upstream(format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\n{}",
+        "x".repeat(400)
+    ))
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(&["--upstream", &upstream]).await;
+
+    let response = get_raw(&balancebeam, "/").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.to_ascii_lowercase().contains("content-length: 1000"));
+    assert_eq!(body_of(&response).len(), 400);
+    sleep(Duration::from_millis(100)).await;
+    assert!(!balancebeam.output_containing("TruncatedBody(600)").is_empty());
+    log::info!("All done :)");
+}
+
+/// When the whole body would have been read before responding (here, to compress it), a short
+/// body is answered with 502
+#[tokio::test]
+async fn test_short_body_buffered() {
+    init_logging();
+    let upstream = start_raw_upstream(format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 1000\r\n\r\n{}",
+        "x".repeat(400)
+    ))
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(&["--upstream", &upstream, "--compress"]).await;
+
+    let response = get_raw(&balancebeam, "/").await;
+    assert!(response.starts_with("HTTP/1.1 502"), "Got {:?}", response);
+    log::info!("All done :)");
+}
+
+/// Extra bytes after the declared length are never passed on. If they arrive with the head, the
+/// response is answered with 502; if the body is being streamed, the client gets exactly the
+/// declared body and then the connection is closed.
+#[tokio::test]
+async fn test_overlong_body() {
+    init_logging();
+    let small = start_raw_upstream(
+        "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789extra bytes".to_string(),
+    )
+    .await;
+    let body = "x".repeat(100000);
+    let large = start_raw_upstream(format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}extra bytes",
+        body.len(),
+        body
+    ))
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &small,
+        "--route",
+        &format!("prefix=/large,upstreams={}", large),
+    ])
+    .await;
+
+    let response = get_raw(&balancebeam, "/").await;
+    assert!(response.starts_with("HTTP/1.1 502"), "Got {:?}", response);
+    let response = get_raw(&balancebeam, "/large").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert_eq!(body_of(&response), body);
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(balancebeam.output_containing("OverlongBody").len(), 2);
+    log::info!("All done :)");
+}
+
+/// A Content-Length too big to ever buffer, or too big to be a number at all, mustn't take
+/// balancebeam down
+#[tokio::test]
+async fn test_absurd_content_length() {
+    init_logging();
+    let huge = start_raw_upstream(format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\nabc",
+        usize::MAX
+    ))
+    .await;
+    let invalid = start_raw_upstream(
+        "HTTP/1.1 200 OK\r\nContent-Length: 99999999999999999999999\r\n\r\nabc".to_string(),
+    )
+    .await;
+    let signed = start_raw_upstream("HTTP/1.1 200 OK\r\nContent-Length: +3\r\n\r\nabc".to_string()).await;
+    let upstream = EchoServer::new().await;
+    let mut balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--route",
+        &format!("prefix=/huge,upstreams={}", huge),
+        "--route",
+        &format!("prefix=/invalid,upstreams={}", invalid),
+        "--route",
+        &format!("prefix=/signed,upstreams={}", signed),
+        "--compress",
+    ])
+    .await;
+
+    let client = reqwest::Client::new();
+    let address = balancebeam.address.clone();
+    let url = |path: &str| format!("http://{}{}", address, path);
+    // The body is streamed, and ends when the upstream hangs up far short of its length
+    let response = get_raw(&balancebeam, "/huge").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "Got {:?}", response);
+    assert_eq!(body_of(&response), "abc");
+    for path in ["/invalid", "/signed"].iter().copied() {
+        let response = client.get(url(path)).send().await.unwrap();
+        assert_eq!(response.status(), 502);
+    }
+
+    assert!(balancebeam.exit_status().is_none(), "balancebeam should still be running");
+    assert_eq!(client.get(url("/")).send().await.unwrap().status(), 200);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
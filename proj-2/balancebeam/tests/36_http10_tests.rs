@@ -0,0 +1,136 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+/// Reads one response from the connection, returning its head (lowercased) and body, or None if
+/// the connection was closed before a response arrived.
+async fn read_response(conn: &mut TcpStream) -> Option<(String, String)> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0_u8; 1];
+        match conn.read(&mut byte).await {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => head.push(byte[0]),
+        }
+    }
+    let head = String::from_utf8(head).unwrap().to_ascii_lowercase();
+    let content_length: usize = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .expect("Response has no Content-Length")
+        .trim()
+        .parse()
+        .unwrap();
+    let mut body = vec![0_u8; content_length];
+    conn.read_exact(&mut body).await.unwrap();
+    Some((head, String::from_utf8(body).unwrap()))
+}
+
+/// Returns whether balancebeam closes the connection (rather than waiting for another request)
+async fn is_closed(conn: &mut TcpStream) -> bool {
+    let mut byte = [0_u8; 1];
+    matches!(timeout(Duration::from_secs(2), conn.read(&mut byte)).await, Ok(Ok(0)) | Ok(Err(_)))
+}
+
+/// An HTTP/1.0 request without a Host header is proxied, and the connection is closed after the
+/// response since the client didn't ask to keep it alive
+#[tokio::test]
+async fn test_http10_request() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&["--upstream", &upstream.address]).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(b"GET /old-client HTTP/1.0\r\n\r\n").await.unwrap();
+    let (head, body) = read_response(&mut conn).await.expect("No response to HTTP/1.0 request");
+    assert!(head.starts_with("http/1.1 200"), "Unexpected response: {}", head);
+    assert!(head.contains("connection: close"), "Unexpected response: {}", head);
+    // The upstream is still spoken to in HTTP/1.1, but the Via header says what the client spoke
+    assert!(body.starts_with("GET /old-client HTTP/1.1"), "Unexpected request: {}", body);
+    assert!(body.to_ascii_lowercase().contains("via: 1.0 balancebeam"), "Unexpected request: {}", body);
+    assert!(is_closed(&mut conn).await, "The connection should be closed after one response");
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// An HTTP/1.0 client that asks for keep-alive can send more requests on the same connection
+#[tokio::test]
+async fn test_http10_keep_alive() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&["--upstream", &upstream.address]).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for i in 1..=3 {
+        conn.write_all(format!("GET /request-{} HTTP/1.0\r\nConnection: keep-alive\r\n\r\n", i).as_bytes())
+            .await
+            .unwrap();
+        let (head, body) = read_response(&mut conn)
+            .await
+            .unwrap_or_else(|| panic!("Connection closed before response {}", i));
+        assert!(head.starts_with("http/1.1 200"), "Unexpected response: {}", head);
+        assert!(!head.contains("connection: close"), "Unexpected response: {}", head);
+        assert!(body.starts_with(&format!("GET /request-{} HTTP/1.1", i)));
+    }
+    // Without keep-alive, the next request is the last
+    conn.write_all(b"GET /last HTTP/1.0\r\n\r\n").await.unwrap();
+    let (head, _) = read_response(&mut conn).await.expect("No response to last request");
+    assert!(head.contains("connection: close"), "Unexpected response: {}", head);
+    assert!(is_closed(&mut conn).await, "The connection should be closed after the last response");
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// An HTTP/1.1 client that says "Connection: close" gets its connection closed after the response
+#[tokio::test]
+async fn test_http11_connection_close() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&["--upstream", &upstream.address]).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n").await.unwrap();
+    let (head, _) = read_response(&mut conn).await.expect("No response");
+    assert!(head.contains("connection: close"), "Unexpected response: {}", head);
+    assert!(is_closed(&mut conn).await, "The connection should be closed after the response");
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A response without a Content-Length reaches an HTTP/1.0 client that asked for keep-alive as a
+/// body that ends when the connection closes
+#[tokio::test]
+async fn test_http10_close_delimited_response() {
+    init_logging();
+    let upstream_address = random_address();
+    let listener = TcpListener::bind(&upstream_address).await.unwrap();
+    tokio::spawn(async move {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut request = [0_u8; 4096];
+        let _ = conn.read(&mut request).await;
+        let _ = conn.write_all(b"HTTP/1.1 200 OK\r\n\r\nuntil close").await;
+        let _ = conn.shutdown().await;
+        let _ = conn.read_to_end(&mut Vec::new()).await;
+    });
+    let balancebeam = BalanceBeam::new_with_args(&["--upstream", &upstream_address]).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").await.unwrap();
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), conn.read_to_end(&mut response))
+        .await
+        .expect("balancebeam left the connection open")
+        .unwrap();
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "Unexpected response: {}", response);
+    assert!(!response.to_ascii_lowercase().contains("transfer-encoding"));
+    assert!(response.ends_with("\r\n\r\nuntil close"), "Unexpected response: {}", response);
+    log::info!("All done :)");
+}
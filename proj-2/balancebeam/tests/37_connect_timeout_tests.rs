@@ -0,0 +1,81 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::time::{sleep, timeout};
+
+/// Starts a listener that never accepts connections, and fills its backlog so that the kernel
+/// ignores any more connection attempts, like a firewall that drops packets. Returns its address,
+/// along with everything that has to stay open to keep it that way.
+async fn start_black_hole() -> (String, TcpListener, Vec<TcpStream>) {
+    let address = random_address();
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(address.parse().unwrap()).unwrap();
+    let listener = socket.listen(0).unwrap();
+    let mut fillers = Vec::new();
+    loop {
+        match timeout(Duration::from_millis(200), TcpStream::connect(&address)).await {
+            Ok(Ok(stream)) => fillers.push(stream),
+            Ok(Err(err)) => panic!("Could not fill the black hole's backlog: {}", err),
+            // The backlog is full
+            Err(_) => break,
+        }
+    }
+    (address, listener, fillers)
+}
+
+/// A request for an upstream that never answers the connection fails over to another upstream
+/// once the connect timeout has passed, rather than waiting for the OS to give up
+#[tokio::test]
+async fn test_connect_timeout_failover() {
+    init_logging();
+    let (black_hole, _listener, _fillers) = start_black_hole().await;
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &black_hole,
+        "--upstream",
+        &upstream.address,
+        "--upstream-connect-timeout",
+        "1s",
+        "--active-health-check-interval",
+        "1h",
+    ])
+    .await;
+
+    let start = Instant::now();
+    for i in 0..10 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam.get(&path).await.expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert!(start.elapsed() < Duration::from_secs(5), "Failover took {:?}", start.elapsed());
+    sleep(Duration::from_millis(100)).await;
+    assert!(!balancebeam.output_containing("connection timed out after 1s").is_empty());
+
+    assert_eq!(Box::new(upstream).stop().await, 10);
+    log::info!("All done :)");
+}
+
+/// Health checks of an upstream that never answers the connection fail once the connect timeout
+/// has passed
+#[tokio::test]
+async fn test_connect_timeout_health_check() {
+    init_logging();
+    let (black_hole, _listener, _fillers) = start_black_hole().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &black_hole,
+        "--upstream-connect-timeout",
+        "500ms",
+        "--active-health-check-interval",
+        "1s",
+    ])
+    .await;
+
+    sleep(Duration::from_secs(1)).await;
+    let dead = format!("Upstream {} in group default is now dead: failed to connect", black_hole);
+    assert_eq!(balancebeam.output_containing(&dead).len(), 1);
+    log::info!("All done :)");
+}
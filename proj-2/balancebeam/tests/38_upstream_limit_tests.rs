@@ -0,0 +1,117 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// Sends a GET, returning the status and how long the response took. Requests sent at the same time
+/// go on separate connections.
+async fn timed_get(client: reqwest::Client, address: String) -> (u16, Duration) {
+    let start = Instant::now();
+    let response = client
+        .get(format!("http://{}/", address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    (response.status().as_u16(), start.elapsed())
+}
+
+/// Sends `count` requests at once, returning their statuses and how long each took, in the order
+/// they were sent
+async fn burst(balancebeam: &BalanceBeam, count: usize) -> Vec<(u16, Duration)> {
+    let client = reqwest::Client::new();
+    let mut tasks = Vec::new();
+    for _ in 0..count {
+        tasks.push(tokio::spawn(timed_get(client.clone(), balancebeam.address.clone())));
+        sleep(Duration::from_millis(20)).await;
+    }
+    let mut results = Vec::new();
+    for task in tasks {
+        results.push(task.await.unwrap());
+    }
+    log::info!("Results: {:?}", results);
+    results
+}
+
+/// An upstream with a limit of N gets N slow requests at once, and the next one is turned away
+/// straight away rather than piling onto it
+#[tokio::test]
+async fn test_upstream_limit_sheds_load() {
+    init_logging();
+    let upstream = EchoServer::new_with_delay(Duration::from_millis(1000)).await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&["--upstream", &format!("{}=maxconn:2", upstream.address)]).await;
+
+    let results = burst(&balancebeam, 3).await;
+    assert_eq!(results[0].0, 200);
+    assert_eq!(results[1].0, 200);
+    assert_eq!(results[2].0, 503);
+    assert!(results[2].1 < Duration::from_millis(300), "Overflow should be turned away quickly");
+
+    // Once the slow requests are done, there's room again
+    assert_eq!(burst(&balancebeam, 1).await[0].0, 200);
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// With queueing enabled, a request for an upstream at its limit waits for room instead
+#[tokio::test]
+async fn test_upstream_limit_queues() {
+    init_logging();
+    let upstream = EchoServer::new_with_delay(Duration::from_millis(1000)).await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &format!("{}=maxconn:1", upstream.address),
+        "--queue-depth",
+        "1",
+        "--queue-timeout",
+        "5s",
+    ])
+    .await;
+
+    let results = burst(&balancebeam, 3).await;
+    assert_eq!(results.iter().filter(|(status, _)| *status == 200).count(), 2);
+    let queued = results.iter().map(|(_, elapsed)| *elapsed).max().unwrap();
+    assert!(queued >= Duration::from_millis(1900), "The queued request should wait for room");
+    assert_eq!(results.iter().filter(|(status, _)| *status == 503).count(), 1);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Requests that a limited upstream has no room for go to the other upstreams in its group, and the
+/// lower of its own limit and --max-upstream-requests applies to it
+#[tokio::test]
+async fn test_upstream_limit_spills_over() {
+    init_logging();
+    let fragile = EchoServer::new_with_delay(Duration::from_millis(1000)).await;
+    let sturdy = EchoServer::new_with_delay(Duration::from_millis(1000)).await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--group",
+        &format!("web={}=maxconn:1,{}", fragile.address, sturdy.address),
+        "--default-group",
+        "web",
+        "--max-upstream-requests",
+        "3",
+    ])
+    .await;
+
+    let results = burst(&balancebeam, 5).await;
+    assert_eq!(results.iter().filter(|(status, _)| *status == 200).count(), 4);
+    assert_eq!(Box::new(fragile).stop().await, 1);
+    assert_eq!(Box::new(sturdy).stop().await, 3);
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_invalid_upstream_limit() {
+    init_logging();
+    for upstream in ["127.0.0.1:1=maxconn:0", "127.0.0.1:1=maxconn:x", "127.0.0.1:1=maxconn:1=maxconn:2"]
+        .iter()
+        .copied()
+    {
+        let mut balancebeam = BalanceBeam::new_with_args(&["--upstream", upstream]).await;
+        let status = balancebeam.exit_status().expect("balancebeam should have exited");
+        assert!(!status.success(), "{} should be rejected", upstream);
+    }
+    log::info!("All done :)");
+}
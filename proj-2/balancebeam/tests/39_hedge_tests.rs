@@ -0,0 +1,108 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// Fetches the statistics table from the admin endpoint and returns the hedges sent to the given
+/// upstream, and how many of them it answered first
+async fn hedge_counts(admin_address: &str, upstream_address: &str) -> (usize, usize) {
+    let table = reqwest::get(format!("http://{}/stats", admin_address))
+        .await
+        .expect("Error sending request to the admin endpoint")
+        .text()
+        .await
+        .expect("Error reading the admin endpoint's response");
+    log::info!("Upstream statistics:\n{}", table);
+    let row: Vec<&str> = table
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|columns| columns.get(1) == Some(&upstream_address))
+        .expect("Upstream is missing from the statistics table");
+    (row[10].parse().unwrap(), row[11].parse().unwrap())
+}
+
+/// A GET that the upstream it was sent to is slow to answer is answered by the other upstream
+/// instead, and the hedges show up in the statistics
+#[tokio::test]
+async fn test_hedge_slow_upstream() {
+    init_logging();
+    let slow_upstream = EchoServer::new_with_delay(Duration::from_secs(2)).await;
+    let fast_upstream = EchoServer::new().await;
+    let admin_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &slow_upstream.address,
+        "--upstream",
+        &fast_upstream.address,
+        "--hedge-after",
+        "200ms",
+        "--admin-bind",
+        &admin_address,
+    ])
+    .await;
+
+    for i in 0..10 {
+        let path = format!("/request-{}", i);
+        let start = Instant::now();
+        let response_text = balancebeam.get(&path).await.expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(start.elapsed() < Duration::from_secs(1), "Request took {:?}", start.elapsed());
+    }
+
+    sleep(Duration::from_millis(100)).await;
+    let hedged = balancebeam.output_containing("hedging to").len();
+    assert!(hedged > 0, "Some requests should have gone to the slow upstream first");
+    assert_eq!(hedge_counts(&admin_address, &fast_upstream.address).await, (hedged, hedged));
+    assert_eq!(hedge_counts(&admin_address, &slow_upstream.address).await, (0, 0));
+    assert_eq!(Box::new(fast_upstream).stop().await, 10);
+    log::info!("All done :)");
+}
+
+/// A request whose method isn't idempotent is left to the upstream it was sent to, however slow
+#[tokio::test]
+async fn test_hedge_skips_post() {
+    init_logging();
+    let upstream_1 = EchoServer::new_with_delay(Duration::from_millis(500)).await;
+    let upstream_2 = EchoServer::new_with_delay(Duration::from_millis(500)).await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream_1.address,
+        "--upstream",
+        &upstream_2.address,
+        "--hedge-after",
+        "100ms",
+    ])
+    .await;
+
+    for i in 0..3 {
+        let path = format!("/request-{}", i);
+        let response_text =
+            balancebeam.post(&path, "body").await.expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("POST {} HTTP/1.1", path)));
+    }
+
+    sleep(Duration::from_millis(100)).await;
+    assert!(balancebeam.output_containing("hedging to").is_empty(), "POSTs should not be hedged");
+    assert_eq!(Box::new(upstream_1).stop().await + Box::new(upstream_2).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// With only one upstream there is nowhere to send a hedge, so requests just wait for it
+#[tokio::test]
+async fn test_hedge_single_upstream() {
+    init_logging();
+    let upstream = EchoServer::new_with_delay(Duration::from_millis(500)).await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&["--upstream", &upstream.address, "--hedge-after", "100ms"]).await;
+
+    let start = Instant::now();
+    let response_text = balancebeam.get("/slow").await.expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /slow HTTP/1.1"));
+    assert!(start.elapsed() >= Duration::from_millis(500));
+
+    sleep(Duration::from_millis(100)).await;
+    assert!(balancebeam.output_containing("hedging to").is_empty());
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
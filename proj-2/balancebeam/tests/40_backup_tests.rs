@@ -0,0 +1,118 @@
+mod common;
+
+use common::{init_logging, random_address, BalanceBeam, EchoServer, Server};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Response};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Starts a server that answers every request with a maintenance page, standing in for a backup.
+/// Returns its address.
+async fn maintenance_server() -> String {
+    let address = random_address();
+    let service = make_service_fn(|_| async {
+        Ok::<_, hyper::Error>(service_fn(|_| async {
+            Ok::<_, hyper::Error>(Response::new(Body::from("maintenance")))
+        }))
+    });
+    tokio::spawn(hyper::Server::bind(&address.parse().unwrap()).serve(service));
+    address
+}
+
+/// Sends requests to balancebeam, returning how many of them the backup answered
+async fn count_backup_responses(balancebeam: &BalanceBeam, n_requests: usize) -> usize {
+    let mut from_backup = 0;
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam.get(&path).await.expect("Error sending request to balancebeam");
+        if response_text == "maintenance" {
+            from_backup += 1;
+        } else {
+            assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        }
+    }
+    from_backup
+}
+
+/// The backup gets nothing while a primary is alive, takes over when they are all dead, and hands
+/// back to a primary once it recovers
+#[tokio::test]
+async fn test_backup_takes_over() {
+    init_logging();
+    let primary_1 = EchoServer::new().await;
+    let primary_2 = EchoServer::new().await;
+    let primary_1_address = primary_1.address.clone();
+    let backup = maintenance_server().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &primary_1.address,
+        "--upstream",
+        &primary_2.address,
+        "--backup-upstream",
+        &backup,
+        "--active-health-check-interval",
+        "1s",
+    ])
+    .await;
+
+    assert_eq!(count_backup_responses(&balancebeam, 10).await, 0);
+
+    log::info!("Stopping both primaries");
+    Box::new(primary_1).stop().await;
+    Box::new(primary_2).stop().await;
+    assert_eq!(count_backup_responses(&balancebeam, 10).await, 10);
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(balancebeam.output_containing("All primary upstreams in group default are dead").len(), 1);
+
+    log::info!("Bringing a primary back");
+    let primary_1 = EchoServer::new_at_address(primary_1_address).await;
+    sleep(Duration::from_secs(2)).await;
+    assert_eq!(count_backup_responses(&balancebeam, 10).await, 0);
+    assert!(Box::new(primary_1).stop().await >= 10);
+    log::info!("All done :)");
+}
+
+/// Backups can also be given in groups, and don't take the load off primaries that are merely full
+#[tokio::test]
+async fn test_backup_in_group() {
+    init_logging();
+    let primary = EchoServer::new_with_delay(Duration::from_millis(500)).await;
+    let backup = maintenance_server().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--group",
+        &format!("web={}=maxconn:1,{}=backup", primary.address, backup),
+        "--default-group",
+        "web",
+    ])
+    .await;
+
+    let mut tasks = Vec::new();
+    for i in 0..2 {
+        let address = balancebeam.address.clone();
+        tasks.push(tokio::spawn(async move {
+            reqwest::get(format!("http://{}/request-{}", address, i)).await.unwrap().status().as_u16()
+        }));
+        sleep(Duration::from_millis(100)).await;
+    }
+    let mut statuses = Vec::new();
+    for task in tasks {
+        statuses.push(task.await.unwrap());
+    }
+    assert_eq!(statuses, vec![200, 503], "The second request should be turned away, not sent to the backup");
+    assert_eq!(Box::new(primary).stop().await, 1);
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_invalid_backup() {
+    init_logging();
+    for upstream in ["127.0.0.1:1=backup=backup", "127.0.0.1:1=canary:10=backup", "127.0.0.1:1=backupx"]
+        .iter()
+        .copied()
+    {
+        let mut balancebeam = BalanceBeam::new_with_args(&["--upstream", upstream]).await;
+        let status = balancebeam.exit_status().expect("balancebeam should have exited");
+        assert!(!status.success(), "{} should be rejected", upstream);
+    }
+    log::info!("All done :)");
+}
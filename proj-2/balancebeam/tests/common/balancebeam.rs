@@ -1,13 +1,14 @@
-use rand::Rng;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::{Child, Command};
 use tokio::time::sleep;
 
 pub struct BalanceBeam {
-    #[allow(dead_code)]
     child: Child, // process is killed when dropped (Command::kill_on_drop)
     pub address: String,
+    /// Lines balancebeam has printed so far, on stdout and stderr
+    output: Arc<Mutex<Vec<String>>>,
 }
 
 impl BalanceBeam {
@@ -19,37 +20,62 @@ impl BalanceBeam {
         path
     }
 
+    #[allow(dead_code)]
     pub async fn new(
         upstreams: &[&str],
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
     ) -> BalanceBeam {
-        let mut rng = rand::thread_rng();
-        let address = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
-        let mut cmd = Command::new(BalanceBeam::target_bin_path());
-        cmd.arg("--bind").arg(&address);
+        let mut args = Vec::new();
         for upstream in upstreams {
-            cmd.arg("--upstream").arg(upstream);
+            args.push("--upstream".to_string());
+            args.push(upstream.to_string());
         }
         if let Some(active_health_check_interval) = active_health_check_interval {
-            cmd.arg("--active-health-check-interval")
-                .arg(active_health_check_interval.to_string());
+            args.push("--active-health-check-interval".to_string());
+            args.push(active_health_check_interval.to_string());
         }
         if let Some(max_requests_per_minute) = max_requests_per_minute {
-            cmd.arg("--max-requests-per-minute")
-                .arg(max_requests_per_minute.to_string());
+            args.push("--max-requests-per-minute".to_string());
+            args.push(max_requests_per_minute.to_string());
+        }
+        BalanceBeam::new_with_args(&args).await
+    }
+
+    /// Starts balancebeam with arbitrary command-line arguments (in addition to --bind)
+    #[allow(dead_code)]
+    pub async fn new_with_args<S: AsRef<std::ffi::OsStr>>(args: &[S]) -> BalanceBeam {
+        BalanceBeam::new_at_address(crate::common::random_address(), args).await
+    }
+
+    /// Starts balancebeam bound to the given address, for tests that need to know the address
+    /// before balancebeam starts
+    #[allow(dead_code)]
+    pub async fn new_at_address<S: AsRef<std::ffi::OsStr>>(address: String, args: &[S]) -> BalanceBeam {
+        let mut cmd = Command::new(BalanceBeam::target_bin_path());
+        cmd.arg("--bind").arg(&address);
+        // Health checks are jittered by default, which would make the first one land anywhere in
+        // the first interval. Most tests count the requests their upstreams get, so turn jitter
+        // off unless the test is about it.
+        if !args.iter().any(|arg| arg.as_ref() == "--active-health-check-jitter") {
+            cmd.arg("--active-health-check-jitter").arg("0");
         }
+        cmd.args(args);
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
-        let mut child = cmd.spawn().expect(&format!(
-            "Could not execute balancebeam binary {}",
-            BalanceBeam::target_bin_path().to_str().unwrap()
-        ));
+        let mut child = cmd.spawn().unwrap_or_else(|_| {
+            panic!(
+                "Could not execute balancebeam binary {}",
+                BalanceBeam::target_bin_path().to_str().unwrap()
+            )
+        });
 
         // Print output from the child. We want to intercept and log this output (instead of letting
         // the child inherit stderr and print directly to the terminal) so that the output can be
         // suppressed if the test passes and displayed if it fails.
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let stdout_output = output.clone();
         let stdout = child
             .stdout
             .take()
@@ -62,8 +88,10 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stdout")
             {
                 println!("Balancebeam output: {}", line);
+                stdout_output.lock().unwrap().push(line);
             }
         });
+        let stderr_output = output.clone();
         let stderr = child
             .stderr
             .take()
@@ -76,19 +104,54 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stderr")
             {
                 println!("Balancebeam output: {}", line);
+                stderr_output.lock().unwrap().push(line);
             }
         });
 
         // Hack: wait for executable to start running
         sleep(Duration::from_secs(1)).await;
-        BalanceBeam { child, address }
+        BalanceBeam { child, address, output }
+    }
+
+    /// Returns balancebeam's exit status if it has exited (e.g. because it rejected its arguments)
+    #[allow(dead_code)]
+    pub fn exit_status(&mut self) -> Option<std::process::ExitStatus> {
+        self.child.try_wait().expect("Error checking whether balancebeam exited")
+    }
+
+    /// Returns the lines balancebeam has printed so far that contain `text`
+    #[allow(dead_code)]
+    pub fn output_containing(&self, text: &str) -> Vec<String> {
+        let output = self.output.lock().unwrap();
+        output.iter().filter(|line| line.contains(text)).cloned().collect()
+    }
+
+    /// Sends a signal to the balancebeam process
+    #[allow(dead_code)]
+    pub fn signal(&self, signal: nix::sys::signal::Signal) {
+        let pid = self.child.id().expect("balancebeam has exited");
+        nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid as i32), signal)
+            .expect("Could not send a signal to balancebeam");
+    }
+
+    /// Returns the most memory balancebeam has had resident at once so far, in kB
+    #[allow(dead_code)]
+    pub fn peak_memory_kb(&self) -> u64 {
+        let pid = self.child.id().expect("balancebeam has exited");
+        let status = std::fs::read_to_string(format!("/proc/{}/status", pid))
+            .expect("Could not read balancebeam's process status");
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmHWM:"))
+            .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+            .expect("balancebeam's process status has no VmHWM")
     }
 
     #[allow(dead_code)]
     pub async fn get(&self, path: &str) -> Result<String, reqwest::Error> {
         let client = reqwest::Client::new();
         client
-            .get(&format!("http://{}{}", self.address, path))
+            .get(format!("http://{}{}", self.address, path))
             .header("x-sent-by", "balancebeam-tests")
             .send()
             .await?
@@ -100,7 +163,7 @@ impl BalanceBeam {
     pub async fn post(&self, path: &str, body: &str) -> Result<String, reqwest::Error> {
         let client = reqwest::Client::new();
         client
-            .post(&format!("http://{}{}", self.address, path))
+            .post(format!("http://{}{}", self.address, path))
             .header("x-sent-by", "balancebeam-tests")
             .body(body.to_string())
             .send()
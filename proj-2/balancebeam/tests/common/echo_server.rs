@@ -2,13 +2,17 @@ use crate::common::server::Server;
 use async_trait::async_trait;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response};
-use rand::Rng;
 use std::sync::{atomic, Arc};
+use std::time::Duration;
 use tokio::sync::oneshot;
 
 #[derive(Debug)]
 struct ServerState {
     pub requests_received: atomic::AtomicUsize,
+    /// Extra headers added to every response
+    pub response_headers: Vec<(String, String)>,
+    /// How long to wait before responding
+    pub response_delay: Duration,
 }
 
 async fn echo(
@@ -18,6 +22,7 @@ async fn echo(
     server_state
         .requests_received
         .fetch_add(1, atomic::Ordering::SeqCst);
+    tokio::time::sleep(server_state.response_delay).await;
     let mut req_text = format!("{} {} {:?}\n", req.method(), req.uri(), req.version());
     for (header_name, header_value) in req.headers() {
         req_text += &format!(
@@ -29,7 +34,11 @@ async fn echo(
     req_text += "\n";
     let mut req_as_bytes = req_text.into_bytes();
     req_as_bytes.extend(hyper::body::to_bytes(req.into_body()).await?);
-    Ok(Response::new(Body::from(req_as_bytes)))
+    let mut response = Response::builder();
+    for (name, value) in &server_state.response_headers {
+        response = response.header(name, value);
+    }
+    Ok(response.body(Body::from(req_as_bytes)).unwrap())
 }
 
 pub struct EchoServer {
@@ -40,12 +49,79 @@ pub struct EchoServer {
 }
 
 impl EchoServer {
+    #[allow(dead_code)]
     pub async fn new() -> EchoServer {
-        let mut rng = rand::thread_rng();
-        EchoServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024..65535))).await
+        EchoServer::new_at_address(crate::common::random_address()).await
+    }
+
+    /// Starts an echo server that adds the given headers to every response
+    #[allow(dead_code)]
+    pub async fn new_with_response_headers(response_headers: &[(&str, &str)]) -> EchoServer {
+        EchoServer::new_with_options(crate::common::random_address(), response_headers, Duration::ZERO).await
     }
 
+    /// Starts an echo server that waits for the given delay before responding to each request
+    #[allow(dead_code)]
+    pub async fn new_with_delay(response_delay: Duration) -> EchoServer {
+        EchoServer::new_with_options(crate::common::random_address(), &[], response_delay).await
+    }
+
+    #[allow(dead_code)]
     pub async fn new_at_address(bind_addr_string: String) -> EchoServer {
+        EchoServer::new_with_options(bind_addr_string, &[], Duration::ZERO).await
+    }
+
+    /// Starts an echo server listening on a Unix domain socket at `path`, replacing any file that is
+    /// there. Its address is `unix:PATH`, which is how balancebeam is given such upstreams.
+    #[allow(dead_code)]
+    pub async fn new_at_unix_path(path: &std::path::Path) -> EchoServer {
+        let _ = std::fs::remove_file(path);
+        let listener = tokio::net::UnixListener::bind(path).expect("Could not bind Unix socket");
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+            response_headers: Vec::new(),
+            response_delay: Duration::ZERO,
+        });
+        let server_task_state = server_state.clone();
+        // hyper::Server only binds to TCP addresses, so accept connections ourselves and serve each
+        // one with hyper
+        let server_task = tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    _ = &mut shutdown_rx => break,
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            log::error!("Error in EchoServer: {}", e);
+                            break;
+                        }
+                    },
+                };
+                let connection_state = server_task_state.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |req| echo(connection_state.clone(), req));
+                    if let Err(e) = hyper::server::conn::Http::new().serve_connection(stream, service).await {
+                        log::debug!("EchoServer connection closed with error: {}", e);
+                    }
+                });
+            }
+        });
+
+        EchoServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: format!("unix:{}", path.display()),
+        }
+    }
+
+    async fn new_with_options(
+        bind_addr_string: String,
+        response_headers: &[(&str, &str)],
+        response_delay: Duration,
+    ) -> EchoServer {
         let bind_addr = bind_addr_string.parse().unwrap();
         // Create a one-shot channel that can be used to tell the server to shut down
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
@@ -53,6 +129,11 @@ impl EchoServer {
         // Start a separate server task
         let server_state = Arc::new(ServerState {
             requests_received: atomic::AtomicUsize::new(0),
+            response_headers: response_headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            response_delay,
         });
         let server_task_state = server_state.clone();
         let server_task = tokio::spawn(async move {
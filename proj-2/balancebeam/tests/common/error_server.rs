@@ -2,7 +2,6 @@ use crate::common::server::Server;
 use async_trait::async_trait;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Response};
-use rand::Rng;
 use std::sync::{atomic, Arc};
 use tokio::sync::oneshot;
 
@@ -22,6 +21,7 @@ async fn return_error() -> Result<Response<Body>, hyper::Error> {
 pub struct ErrorServer {
     shutdown_signal_sender: oneshot::Sender<()>,
     server_task: tokio::task::JoinHandle<()>,
+    #[allow(dead_code)]
     pub address: String,
     state: Arc<ServerState>,
 }
@@ -29,8 +29,7 @@ pub struct ErrorServer {
 impl ErrorServer {
     #[allow(dead_code)]
     pub async fn new() -> ErrorServer {
-        let mut rng = rand::thread_rng();
-        ErrorServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024..65535))).await
+        ErrorServer::new_at_address(crate::common::random_address()).await
     }
 
     #[allow(dead_code)]
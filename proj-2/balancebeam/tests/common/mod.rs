@@ -3,13 +3,22 @@ mod echo_server;
 mod error_server;
 mod server;
 
+use rand::Rng;
 use std::sync;
 
 pub use balancebeam::BalanceBeam;
 pub use echo_server::EchoServer;
+#[allow(unused_imports)]
 pub use error_server::ErrorServer;
 pub use server::Server;
 
+/// Returns a loopback address with a random port. Ports are picked below Linux's ephemeral port
+/// range (32768 and up) so that they don't collide with the local ports of outgoing connections.
+pub fn random_address() -> String {
+    let mut rng = rand::thread_rng();
+    format!("127.0.0.1:{}", rng.gen_range(1024..32768))
+}
+
 static INIT_TESTS: sync::Once = sync::Once::new();
 
 pub fn init_logging() {
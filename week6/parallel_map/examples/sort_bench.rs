@@ -0,0 +1,46 @@
+//! Compares parallel_sort_by with slice::sort_by on large inputs of random numbers. Run with
+//! `cargo run --release --example sort_bench`, optionally giving the number of elements.
+
+use parallel_map::parallel_sort_by;
+use rand::Rng;
+use std::cmp::Ordering;
+use std::env;
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 5;
+
+/// The comparator both sorts use, so that they do the same work per comparison
+fn compare(a: &u64, b: &u64) -> Ordering {
+    a.cmp(b)
+}
+
+/// Returns the average time `sort` takes over several runs on copies of `input`
+fn time_runs(input: &[u64], sort: impl Fn(Vec<u64>) -> Vec<u64>) -> Duration {
+    let mut total = Duration::ZERO;
+    for _ in 0..RUNS {
+        let input = input.to_vec();
+        let start = Instant::now();
+        sort(input);
+        total += start.elapsed();
+    }
+    total / RUNS
+}
+
+fn main() {
+    let num_elements: usize =
+        env::args().nth(1).and_then(|arg| arg.parse().ok()).unwrap_or(10_000_000);
+    let mut rng = rand::thread_rng();
+    let input: Vec<u64> = (0..num_elements).map(|_| rng.gen()).collect();
+    let std_sort = time_runs(&input, |mut v| {
+        v.sort_by(compare);
+        v
+    });
+    println!("{} elements: slice::sort_by {:?}", num_elements, std_sort);
+    for num_threads in [2, 4, 8, 16] {
+        let parallel = time_runs(&input, |v| parallel_sort_by(v, num_threads, compare));
+        println!(
+            "{} elements: parallel_sort_by on {} threads {:?}",
+            num_elements, num_threads, parallel
+        );
+    }
+}
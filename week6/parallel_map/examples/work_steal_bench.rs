@@ -0,0 +1,43 @@
+//! Compares parallel_map with parallel_map_work_steal on tasks that all take the same time and on
+//! tasks whose durations are heavily skewed. Run with `cargo run --release --example
+//! work_steal_bench`.
+
+use parallel_map::{parallel_map, parallel_map_work_steal};
+use std::time::{Duration, Instant};
+use std::{env, thread};
+
+const NUM_THREADS: usize = 8;
+const RUNS: u32 = 5;
+
+type MapFn = fn(Vec<u64>, usize, fn(u64) -> u64) -> Vec<u64>;
+
+/// Sleeps for the given number of milliseconds, standing in for a task that takes that long
+fn task(millis: u64) -> u64 {
+    thread::sleep(Duration::from_millis(millis));
+    millis
+}
+
+/// Returns the average time `map` takes over several runs on `durations`
+fn time_runs(durations: &[u64], map: MapFn) -> Duration {
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        map(durations.to_vec(), NUM_THREADS, task);
+    }
+    start.elapsed() / RUNS
+}
+
+fn main() {
+    let num_tasks: usize = env::args().nth(1).and_then(|arg| arg.parse().ok()).unwrap_or(256);
+    let uniform = vec![2; num_tasks];
+    // The first eighth of the tasks are slow, so they all start out on the same thread
+    let skewed: Vec<u64> =
+        (0..num_tasks).map(|idx| if idx < num_tasks / 8 { 20 } else { 1 }).collect();
+    for (name, durations) in [("uniform", &uniform), ("skewed", &skewed)] {
+        let channel = time_runs(durations, parallel_map);
+        let work_steal = time_runs(durations, parallel_map_work_steal);
+        println!(
+            "{:<8} {} tasks on {} threads: channel {:?}, work stealing {:?}",
+            name, num_tasks, NUM_THREADS, channel, work_steal
+        );
+    }
+}
@@ -0,0 +1,529 @@
+//! Functions that spread work over several threads. If the function one of them is given panics,
+//! the panic is passed on to the caller with its original payload, as parallel_map describes.
+
+mod pool;
+mod work_steal;
+
+pub use pool::ThreadPool;
+pub use work_steal::parallel_map_work_steal;
+
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use std::any::Any;
+use std::cmp;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Threads that apply `work` to inputs, which are all queued up for them when they start. Iterating
+/// gives the outputs in the order they are finished. Once they run out, the threads are joined, and
+/// if `work` panicked on any input, the panic is passed on with its original payload. A thread
+/// stops taking inputs as soon as `work` panics on one, though the others carry on until the inputs
+/// run out. If the iterator is dropped early, the threads stop once they have finished the inputs
+/// they are on.
+struct Workers<O> {
+    receiver: Receiver<O>,
+    panic_receiver: Receiver<Box<dyn Any + Send>>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl<O: Send + 'static> Workers<O> {
+    /// Starts `num_threads` threads, each with its own copy of `work`, and queues up `inputs` for
+    /// them. `work` is copied again for each input, since it may only be callable once.
+    fn start<I, W>(inputs: impl IntoIterator<Item = I>, num_threads: usize, work: W) -> Workers<O>
+    where
+        W: FnOnce(I) -> O + Send + Clone + 'static,
+        I: Send + 'static,
+    {
+        let mut threads = Vec::new();
+        let (sender1, receiver1) = crossbeam_channel::unbounded();
+        let (sender2, receiver2) = crossbeam_channel::unbounded();
+        let (panic_sender, panic_receiver) = crossbeam_channel::unbounded();
+        for _ in 0..num_threads {
+            let receiver1 = receiver1.clone();
+            let sender2 = sender2.clone();
+            let panic_sender = panic_sender.clone();
+            let work = work.clone();
+            threads.push(thread::spawn(move || {
+                while let Ok(input) = receiver1.recv() {
+                    let work = work.clone();
+                    match panic::catch_unwind(AssertUnwindSafe(move || work(input))) {
+                        Ok(output) => {
+                            if sender2.send(output).is_err() {
+                                // Nobody wants the outputs any more
+                                break;
+                            }
+                        }
+                        Err(payload) => {
+                            let _ = panic_sender.send(payload);
+                            break;
+                        }
+                    }
+                }
+            }));
+        }
+        for input in inputs {
+            sender1.send(input).unwrap();
+        }
+        Workers { receiver: receiver2, panic_receiver, threads }
+    }
+}
+
+impl<O> Iterator for Workers<O> {
+    type Item = O;
+
+    fn next(&mut self) -> Option<O> {
+        // The channel disconnects once every thread has finished
+        if let Ok(output) = self.receiver.recv() {
+            return Some(output);
+        }
+        for thread in self.threads.drain(..) {
+            thread.join().unwrap();
+        }
+        if let Ok(payload) = self.panic_receiver.try_recv() {
+            panic::resume_unwind(payload);
+        }
+        None
+    }
+}
+
+/// Applies `work` to every input on `num_threads` threads (see Workers), returning the outputs in
+/// the same order as the inputs.
+fn map_in_order<I, O, W>(inputs: impl IntoIterator<Item = I>, num_threads: usize, work: W) -> Vec<O>
+where
+    W: FnOnce(I) -> O + Send + Clone + 'static,
+    I: Send + 'static,
+    O: Send + 'static,
+{
+    let indexed = inputs.into_iter().enumerate();
+    let mut results: Vec<(usize, O)> =
+        Workers::start(indexed, num_threads, move |(index, input)| (index, work(input))).collect();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, output)| output).collect()
+}
+
+/// Applies `f` to every element of `input_vec` on `num_threads` threads, returning the results in
+/// the same order as the input.
+///
+/// If `f` panics, the panic is passed on to the calling thread, with its original payload, once
+/// the threads have finished. A thread stops taking elements as soon as `f` panics on one, though
+/// the other threads carry on until the input runs out.
+pub fn parallel_map<T, U, F>(input_vec: Vec<T>, num_threads: usize, f: F) -> Vec<U>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static + Default,
+{
+    map_in_order(input_vec, num_threads, f)
+}
+
+/// Like parallel_map, but uses one thread per CPU the process can run on (or 4, if that can't be
+/// told). That suits CPU-bound work; if `f` spends most of its time waiting on I/O, pass a larger
+/// `num_threads` to parallel_map instead.
+pub fn parallel_map_auto<T, U, F>(input_vec: Vec<T>, f: F) -> Vec<U>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static + Default,
+{
+    let num_threads = thread::available_parallelism().map_or(4, |threads| threads.get());
+    parallel_map(input_vec, num_threads, f)
+}
+
+/// Like parallel_map, but sends the elements to the threads `chunk_size` at a time, in chunks of
+/// consecutive elements, rather than one by one. That cuts down on channel traffic when `f` is
+/// cheap and `input_vec` is long, and each thread works through memory in order. A `chunk_size`
+/// of 0 is treated as 1.
+pub fn parallel_map_chunked<T, U, F>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    chunk_size: usize,
+    f: F,
+) -> Vec<U>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let len = input_vec.len();
+    let chunk_size = chunk_size.max(1);
+    let mut iter = input_vec.into_iter();
+    let chunks = (0..len)
+        .step_by(chunk_size)
+        .map(|chunk_start| (chunk_start, iter.by_ref().take(chunk_size).collect::<Vec<T>>()));
+    let map_chunk = move |(chunk_start, chunk): (usize, Vec<T>)| {
+        let mut outputs = Vec::with_capacity(chunk.len());
+        for val in chunk {
+            outputs.push(f(val));
+        }
+        (chunk_start, outputs)
+    };
+    let mut results: Vec<(usize, Vec<U>)> =
+        Workers::start(chunks, num_threads, map_chunk).collect();
+    results.sort_by_key(|(chunk_start, _)| *chunk_start);
+    let mut output_vec = Vec::with_capacity(len);
+    for (_, outputs) in results {
+        output_vec.extend(outputs);
+    }
+    output_vec
+}
+
+/// Like parallel_map, but also passes `f` the index of each element in `input_vec`.
+pub fn parallel_map_indexed<T, U, F>(input_vec: Vec<T>, num_threads: usize, f: F) -> Vec<U>
+where
+    F: FnOnce(usize, T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static + Default,
+{
+    map_in_order(input_vec.into_iter().enumerate(), num_threads, move |(index, val)| f(index, val))
+}
+
+/// Like parallel_map, but stops early once `cancel` is set. Each thread checks `cancel` before
+/// starting on another element, so elements already being processed still finish, but the rest
+/// are skipped and left as `U::default()` in the output.
+pub fn parallel_map_with_cancel<T, U, F>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    f: F,
+    cancel: Arc<AtomicBool>,
+) -> Vec<U>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static + Default,
+{
+    let outputs = map_in_order(input_vec, num_threads, move |val| {
+        if cancel.load(Ordering::Relaxed) {
+            None
+        } else {
+            Some(f(val))
+        }
+    });
+    outputs.into_iter().map(Option::unwrap_or_default).collect()
+}
+
+/// Like parallel_map, but gives up on any elements that haven't been started once `timeout` has
+/// passed, leaving them as `U::default()` in the output. See parallel_map_with_cancel.
+pub fn parallel_map_with_timeout<T, U, F>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    f: F,
+    timeout: Duration,
+) -> Vec<U>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static + Default,
+{
+    let cancel = Arc::new(AtomicBool::new(false));
+    let (done_sender, done_receiver) = crossbeam_channel::bounded::<()>(0);
+    let timer = {
+        let cancel = cancel.clone();
+        // Dropping done_sender wakes the timer up early when the map finishes in time
+        thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = done_receiver.recv_timeout(timeout) {
+                cancel.store(true, Ordering::Relaxed);
+            }
+        })
+    };
+    let output_vec = parallel_map_with_cancel(input_vec, num_threads, f, cancel);
+    drop(done_sender);
+    timer.join().unwrap();
+    output_vec
+}
+
+/// Like parallel_map, but calls `progress(completed, total)` each time an element has been
+/// processed. `progress` is called on the calling thread, as results are collected, so it can
+/// update something like a progress bar without any locking, and doesn't need to be Send.
+pub fn parallel_map_with_progress<T, U, F, P>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    f: F,
+    mut progress: P,
+) -> Vec<U>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static + Default,
+    P: FnMut(usize, usize),
+{
+    let total = input_vec.len();
+    let mut output_vec: Vec<U> = Vec::with_capacity(total);
+    output_vec.resize_with(total, Default::default);
+    let indexed = input_vec.into_iter().enumerate();
+    let workers = Workers::start(indexed, num_threads, move |(index, val)| (index, f(val)));
+    let mut completed = 0;
+    for (index, val) in workers {
+        output_vec[index] = val;
+        completed += 1;
+        progress(completed, total);
+    }
+    output_vec
+}
+
+/// Like parallel_map, but returns an iterator over the results in the order they are finished,
+/// rather than waiting for all of them and putting them back in input order. The threads keep
+/// working while the iterator is being consumed. If the iterator is dropped early, they stop once
+/// they have finished the elements they are on, and the rest are skipped.
+pub fn parallel_map_unordered<T, U, F>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    f: F,
+) -> impl Iterator<Item = U>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    Workers::start(input_vec, num_threads, f)
+}
+
+/// Keeps the elements of `input_vec` for which `f` returns true, testing them on `num_threads`
+/// threads. The elements that are kept stay in their original order.
+///
+/// Unlike parallel_map, `f` only borrows each element, since the element itself has to come back
+/// out when it is kept.
+pub fn parallel_filter<T, F>(input_vec: Vec<T>, num_threads: usize, f: F) -> Vec<T>
+where
+    F: FnOnce(&T) -> bool + Send + Copy + 'static,
+    T: Send + 'static,
+{
+    let results = map_in_order(input_vec, num_threads, move |val| {
+        let keep = f(&val);
+        (val, keep)
+    });
+    results.into_iter().filter(|(_, keep)| *keep).map(|(val, _)| val).collect()
+}
+
+/// Applies `f` to every element of `input_vec` on `num_threads` threads, keeping the results that
+/// are Some, in the same order as the input.
+pub fn parallel_filter_map<T, U, F>(input_vec: Vec<T>, num_threads: usize, f: F) -> Vec<U>
+where
+    F: FnOnce(T) -> Option<U> + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    map_in_order(input_vec, num_threads, f).into_iter().flatten().collect()
+}
+
+/// Applies `f` to every element of `input_vec` on `num_threads` threads, where `f` may return any
+/// number of outputs per element. The outputs are concatenated in the order of the inputs they
+/// came from.
+pub fn parallel_flat_map<T, U, F>(input_vec: Vec<T>, num_threads: usize, f: F) -> Vec<U>
+where
+    F: FnOnce(T) -> Vec<U> + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    map_in_order(input_vec, num_threads, f).into_iter().flatten().collect()
+}
+
+/// Applies the fallible function `f` to every element of `input_vec` on `num_threads` threads,
+/// returning the results in the same order as the input if they are all Ok. Otherwise, returns the
+/// error for the earliest element (in input order) that failed. Every element is still processed
+/// either way, but the other results are thrown away.
+pub fn parallel_try_map<T, U, E, F>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    f: F,
+) -> Result<Vec<U>, E>
+where
+    F: FnOnce(T) -> Result<U, E> + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+    E: Send + 'static,
+{
+    map_in_order(input_vec, num_threads, f).into_iter().collect()
+}
+
+/// Applies `f` to each pair of elements at the same position in `a` and `b` on `num_threads`
+/// threads, returning the results in the same order as the inputs.
+///
+/// Panics if `a` and `b` have different lengths.
+pub fn parallel_zip_map<A, B, C, F>(a: Vec<A>, b: Vec<B>, num_threads: usize, f: F) -> Vec<C>
+where
+    F: FnOnce(A, B) -> C + Send + Copy + 'static,
+    A: Send + 'static,
+    B: Send + 'static,
+    C: Send + 'static,
+{
+    assert_eq!(a.len(), b.len(), "parallel_zip_map needs inputs of the same length");
+    map_in_order(a.into_iter().zip(b), num_threads, move |(val_a, val_b)| f(val_a, val_b))
+}
+
+/// Splits `input_vec` into at most `num_threads` chunks of consecutive elements, all the same size
+/// except for the last. Fewer chunks are made if there aren't enough elements to go around.
+fn into_chunks<T>(input_vec: Vec<T>, num_threads: usize) -> Vec<Vec<T>> {
+    let chunk_size = input_vec.len().div_ceil(num_threads.max(1)).max(1);
+    let mut chunks = Vec::new();
+    let mut iter = input_vec.into_iter();
+    loop {
+        let chunk: Vec<T> = iter.by_ref().take(chunk_size).collect();
+        if chunk.is_empty() {
+            return chunks;
+        }
+        chunks.push(chunk);
+    }
+}
+
+/// Folds `input_vec` with `fold_fn` on up to `num_threads` threads. Each thread folds a chunk of
+/// consecutive elements starting from its own copy of `identity`, and the results are then
+/// combined with `combine_fn` on this thread, in the order of the chunks. `identity` must leave
+/// anything it is combined with unchanged, since it may be combined in any number of times.
+pub fn parallel_fold<T, B, F, G>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    identity: B,
+    fold_fn: F,
+    combine_fn: G,
+) -> B
+where
+    F: FnOnce(B, T) -> B + Send + Copy + 'static,
+    G: Fn(B, B) -> B,
+    T: Send + 'static,
+    B: Clone + Send + 'static,
+{
+    let chunk_identity = identity.clone();
+    let fold_chunk = move |chunk: Vec<T>| {
+        let mut acc = chunk_identity;
+        for val in chunk {
+            acc = fold_fn(acc, val);
+        }
+        acc
+    };
+    let chunks = into_chunks(input_vec, num_threads);
+    let chunk_num = chunks.len();
+    map_in_order(chunks, chunk_num, fold_chunk)
+        .into_iter()
+        .fold(identity, combine_fn)
+}
+
+/// Combines the elements of `input_vec` with `f` on up to `num_threads` threads, like
+/// Iterator::reduce. Returns None if `input_vec` is empty.
+pub fn parallel_reduce<T, F>(input_vec: Vec<T>, num_threads: usize, f: F) -> Option<T>
+where
+    F: Fn(T, T) -> T + Send + Copy + 'static,
+    T: Send + 'static,
+{
+    let chunks = into_chunks(input_vec, num_threads);
+    let chunk_num = chunks.len();
+    map_in_order(chunks, chunk_num, move |chunk: Vec<T>| chunk.into_iter().reduce(f))
+        .into_iter()
+        .flatten()
+        .reduce(f)
+}
+
+/// Computes the inclusive prefix scan of `input_vec` with `f` on up to `num_threads` threads: each
+/// element of the output is `f` applied across all the elements of the input up to and including
+/// the one at that position, starting from `identity`. `f` must be associative, and `identity`
+/// must leave anything it is combined with unchanged, since the elements are grouped differently
+/// than they would be in a sequential scan.
+///
+/// The scan happens in two phases. First, each chunk of consecutive elements is scanned on its own
+/// thread. Then each chunk but the first is corrected, in parallel again, by combining the total of
+/// all the chunks before it into each of its elements.
+///
+/// Like every other function here, this takes `num_threads` to say how many chunks to split the
+/// input into. It doesn't need `T: Default`, since every accumulator starts from `identity`.
+pub fn parallel_scan<T, F>(input_vec: Vec<T>, num_threads: usize, identity: T, f: F) -> Vec<T>
+where
+    F: Fn(T, T) -> T + Send + Sync + 'static,
+    T: Clone + Send + 'static,
+{
+    let f = Arc::new(f);
+    let scan_chunk = {
+        let f = f.clone();
+        let identity = identity.clone();
+        move |chunk: Vec<T>| {
+            let mut acc = identity;
+            let mut scanned = Vec::with_capacity(chunk.len());
+            for val in chunk {
+                acc = f(acc, val);
+                scanned.push(acc.clone());
+            }
+            scanned
+        }
+    };
+    let chunks = into_chunks(input_vec, num_threads);
+    let chunk_num = chunks.len();
+    let partial = map_in_order(chunks, chunk_num, scan_chunk);
+
+    let mut output_vec = Vec::new();
+    let mut corrections = Vec::new();
+    let mut offset = identity;
+    for (index, scanned) in partial.into_iter().enumerate() {
+        let chunk_total = scanned.last().cloned();
+        if index == 0 {
+            output_vec = scanned;
+        } else {
+            corrections.push((offset.clone(), scanned));
+        }
+        if let Some(chunk_total) = chunk_total {
+            offset = f(offset, chunk_total);
+        }
+    }
+    let correct_chunk = move |(chunk_offset, scanned): (T, Vec<T>)| {
+        scanned.into_iter().map(|val| f(chunk_offset.clone(), val)).collect::<Vec<T>>()
+    };
+    for corrected in map_in_order(corrections, chunk_num, correct_chunk) {
+        output_vec.extend(corrected);
+    }
+    output_vec
+}
+
+/// Sorts `v` with the comparator `cmp` on up to `num_threads` threads. Like slice::sort_by, the
+/// sort is stable: elements that compare equal keep their original order.
+///
+/// Each thread sorts a chunk of consecutive elements, then the sorted chunks are merged on this
+/// thread, neighbours first, so that every element is only merged about log2(num_threads) times.
+pub fn parallel_sort_by<T, F>(v: Vec<T>, num_threads: usize, cmp: F) -> Vec<T>
+where
+    F: Fn(&T, &T) -> cmp::Ordering + Send + Sync + 'static,
+    T: Send + 'static,
+{
+    let cmp = Arc::new(cmp);
+    let sort_chunk = {
+        let cmp = cmp.clone();
+        move |mut chunk: Vec<T>| {
+            chunk.sort_by(|a, b| cmp(a, b));
+            chunk
+        }
+    };
+    let chunks = into_chunks(v, num_threads);
+    let chunk_num = chunks.len();
+    let mut chunks = map_in_order(chunks, chunk_num, sort_chunk);
+    while chunks.len() > 1 {
+        let mut merged = Vec::with_capacity(chunks.len().div_ceil(2));
+        let mut iter = chunks.into_iter();
+        while let Some(left) = iter.next() {
+            merged.push(match iter.next() {
+                Some(right) => merge_by(left, right, &*cmp),
+                None => left,
+            });
+        }
+        chunks = merged;
+    }
+    chunks.pop().unwrap_or_default()
+}
+
+/// Merges two vectors that are each sorted by `cmp` into one. Elements of `left` go first when they
+/// compare equal to elements of `right`, which keeps the merge stable.
+fn merge_by<T, F>(left: Vec<T>, right: Vec<T>, cmp: &F) -> Vec<T>
+where
+    F: Fn(&T, &T) -> cmp::Ordering,
+{
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(l), Some(r)) = (left.peek(), right.peek()) {
+        if cmp(r, l) == cmp::Ordering::Less {
+            merged.push(right.next().unwrap());
+        } else {
+            merged.push(left.next().unwrap());
+        }
+    }
+    merged.extend(left);
+    merged.extend(right);
+    merged
+}
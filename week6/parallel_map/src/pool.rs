@@ -0,0 +1,80 @@
+use crossbeam_channel::Sender;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// What the pool's workers are sent: either something to run, or word to stop
+enum Message {
+    Run(Job),
+    Stop,
+}
+
+/// A fixed set of worker threads that `map` calls share, so that code calling `map` many times
+/// doesn't pay for spawning and joining threads each time. The workers stop when the pool is
+/// dropped.
+pub struct ThreadPool {
+    sender: Sender<Message>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    /// Starts a pool with `num_threads` workers (at least one).
+    pub fn new(num_threads: usize) -> ThreadPool {
+        let (sender, receiver) = crossbeam_channel::unbounded::<Message>();
+        let threads = (0..num_threads.max(1))
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || {
+                    while let Ok(Message::Run(job)) = receiver.recv() {
+                        job();
+                    }
+                })
+            })
+            .collect();
+        ThreadPool { sender, threads }
+    }
+
+    /// Applies `f` to every element of `input_vec` on the pool's workers, returning the results in
+    /// the same order as the input. Calls from several threads at once share the workers.
+    ///
+    /// If `f` panics, the panic is passed on to the caller, with its original payload, once every
+    /// element has been processed. The workers carry on, so the pool can still be used afterwards.
+    pub fn map<T, U, F>(&self, input_vec: Vec<T>, f: F) -> Vec<U>
+    where
+        F: FnOnce(T) -> U + Send + Copy + 'static,
+        T: Send + 'static,
+        U: Send + 'static,
+    {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        for (index, val) in input_vec.into_iter().enumerate() {
+            let sender = sender.clone();
+            let job = Box::new(move || {
+                let output = panic::catch_unwind(AssertUnwindSafe(move || f(val)));
+                sender.send((index, output)).unwrap();
+            });
+            self.sender.send(Message::Run(job)).unwrap();
+        }
+        // Each job holds a sender until it has run, so the results run out once they all have
+        drop(sender);
+        let mut results: Vec<(usize, thread::Result<U>)> = receiver.iter().collect();
+        results.sort_by_key(|(index, _)| *index);
+        // The panic for the earliest element wins if there was more than one
+        results
+            .into_iter()
+            .map(|(_, output)| output.unwrap_or_else(|payload| panic::resume_unwind(payload)))
+            .collect()
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // Each worker stops after taking one Stop, and any jobs already queued are run first
+        for _ in &self.threads {
+            self.sender.send(Message::Stop).unwrap();
+        }
+        for thread in self.threads.drain(..) {
+            thread.join().unwrap();
+        }
+    }
+}
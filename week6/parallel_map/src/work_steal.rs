@@ -0,0 +1,93 @@
+use crossbeam_deque::{Steal, Stealer, Worker};
+use rand::Rng;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+
+/// Applies `f` to every element of `input_vec` on `num_threads` threads, returning the results in
+/// the same order as the input, like parallel_map. Instead of sharing one channel of work, each
+/// thread starts with its own deque holding a run of consecutive elements, and once that is empty,
+/// it steals elements from the far end of a random other thread's deque. This keeps threads busy
+/// when some elements take much longer than others, without them contending on a single queue.
+///
+/// If `f` panics, the panic is passed on to the caller with its original payload, as in
+/// parallel_map. The thread it panicked on stops, and the others steal what it had left.
+pub fn parallel_map_work_steal<T, U, F>(input_vec: Vec<T>, num_threads: usize, f: F) -> Vec<U>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let num_threads = num_threads.max(1);
+    let chunk_size = input_vec.len().div_ceil(num_threads).max(1);
+    let mut chunks: Vec<Vec<(usize, T)>> = (0..num_threads).map(|_| Vec::new()).collect();
+    for (index, val) in input_vec.into_iter().enumerate() {
+        chunks[index / chunk_size].push((index, val));
+    }
+    // A LIFO worker pops from the end it pushes to, while stealers take from the other end. Pushing
+    // each run in reverse means that its owner works forwards from the start of the run, and
+    // thieves take from the end of it.
+    let workers: Vec<Worker<(usize, T)>> = chunks
+        .into_iter()
+        .map(|chunk| {
+            let worker = Worker::new_lifo();
+            for item in chunk.into_iter().rev() {
+                worker.push(item);
+            }
+            worker
+        })
+        .collect();
+    let stealers: Vec<Stealer<(usize, T)>> = workers.iter().map(Worker::stealer).collect();
+
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let (panic_sender, panic_receiver) = crossbeam_channel::unbounded();
+    let mut threads = Vec::new();
+    for (thread_idx, worker) in workers.into_iter().enumerate() {
+        let stealers = stealers.clone();
+        let sender = sender.clone();
+        let panic_sender = panic_sender.clone();
+        threads.push(thread::spawn(move || {
+            // No work is added once the threads start, so once there's none left to steal, there
+            // never will be again
+            while let Some((index, val)) = worker.pop().or_else(|| steal(&stealers, thread_idx)) {
+                match panic::catch_unwind(AssertUnwindSafe(move || f(val))) {
+                    Ok(output) => sender.send((index, output)).unwrap(),
+                    Err(payload) => {
+                        panic_sender.send(payload).unwrap();
+                        break;
+                    }
+                }
+            }
+        }));
+    }
+    drop(sender);
+    let mut results: Vec<(usize, U)> = receiver.iter().collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    if let Ok(payload) = panic_receiver.try_recv() {
+        panic::resume_unwind(payload);
+    }
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, val)| val).collect()
+}
+
+/// Steals an element from one of the other threads' deques, trying them in turn starting from a
+/// random one. Returns None once they are all empty.
+fn steal<T>(stealers: &[Stealer<T>], thread_idx: usize) -> Option<T> {
+    let start = rand::thread_rng().gen_range(0..stealers.len());
+    for offset in 0..stealers.len() {
+        let victim = (start + offset) % stealers.len();
+        if victim == thread_idx {
+            continue;
+        }
+        loop {
+            match stealers[victim].steal() {
+                Steal::Success(item) => return Some(item),
+                Steal::Empty => break,
+                // Lost a race with another thread; the deque may still have work
+                Steal::Retry => continue,
+            }
+        }
+    }
+    None
+}
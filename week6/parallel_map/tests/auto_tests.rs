@@ -0,0 +1,32 @@
+use parallel_map::parallel_map_auto;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::{thread, time};
+
+#[test]
+fn test_auto_keeps_order() {
+    let v: Vec<u64> = (0..100).collect();
+    let squares = parallel_map_auto(v, |num| num * num);
+    assert_eq!(squares, (0..100).map(|num| num * num).collect::<Vec<_>>());
+}
+
+/// Work is spread over as many threads as there are CPUs to run them
+#[test]
+fn test_auto_uses_available_threads() {
+    static THREADS: Mutex<Option<HashSet<thread::ThreadId>>> = Mutex::new(None);
+    let cpus = thread::available_parallelism().map_or(4, |cpus| cpus.get());
+    let v: Vec<usize> = (0..cpus * 4).collect();
+    parallel_map_auto(v, |num| {
+        thread::sleep(time::Duration::from_millis(50));
+        THREADS.lock().unwrap().get_or_insert_with(HashSet::new).insert(thread::current().id());
+        num
+    });
+    let threads = THREADS.lock().unwrap().take().unwrap();
+    assert!(threads.len() <= cpus);
+    assert!(threads.len() > cpus / 2, "Only {} of {} threads were used", threads.len(), cpus);
+}
+
+#[test]
+fn test_auto_empty() {
+    assert!(parallel_map_auto(Vec::<u64>::new(), |num| num).is_empty());
+}
@@ -0,0 +1,76 @@
+use parallel_map::{parallel_map_with_cancel, parallel_map_with_timeout};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{thread, time};
+
+#[test]
+fn test_cancel_not_set() {
+    let v: Vec<u64> = (0..20).collect();
+    let cancel = Arc::new(AtomicBool::new(false));
+    let squares = parallel_map_with_cancel(v, 6, |num| num * num, cancel);
+    assert_eq!(squares, (0..20).map(|num| num * num).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_cancel_already_set() {
+    let v: Vec<u64> = (1..=20).collect();
+    let cancel = Arc::new(AtomicBool::new(true));
+    let squares = parallel_map_with_cancel(v, 6, |num| num * num, cancel);
+    assert_eq!(squares, vec![0; 20]);
+}
+
+/// Cancelling partway through lets the elements in progress finish, and skips the rest
+#[test]
+fn test_cancel_midway() {
+    let v: Vec<u64> = (1..=20).collect();
+    let cancel = Arc::new(AtomicBool::new(false));
+    let canceller = {
+        let cancel = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(250));
+            cancel.store(true, Ordering::Relaxed);
+        })
+    };
+    let squares = parallel_map_with_cancel(
+        v,
+        2,
+        |num| {
+            thread::sleep(time::Duration::from_millis(100));
+            num * num
+        },
+        cancel,
+    );
+    canceller.join().unwrap();
+    // Each thread finishes the element it's on when the flag is set, at 300ms
+    assert_eq!(&squares[..6], &[1, 4, 9, 16, 25, 36]);
+    assert!(squares[6..].iter().all(|&square| square == 0));
+}
+
+#[test]
+fn test_timeout() {
+    let v: Vec<u64> = (1..=20).collect();
+    let start = Instant::now();
+    let squares = parallel_map_with_timeout(
+        v,
+        4,
+        |num| {
+            thread::sleep(time::Duration::from_millis(100));
+            num * num
+        },
+        Duration::from_millis(150),
+    );
+    assert!(start.elapsed() < Duration::from_millis(400), "Took {:?}", start.elapsed());
+    assert_eq!(&squares[..8], &(1..=8).map(|num| num * num).collect::<Vec<_>>()[..]);
+    assert!(squares[8..].iter().all(|&square| square == 0));
+}
+
+/// A map that finishes in time returns straight away, without waiting for the timeout
+#[test]
+fn test_timeout_not_reached() {
+    let v: Vec<u64> = (0..20).collect();
+    let start = Instant::now();
+    let squares = parallel_map_with_timeout(v, 4, |num| num * num, Duration::from_secs(10));
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(squares, (0..20).map(|num| num * num).collect::<Vec<_>>());
+}
@@ -0,0 +1,44 @@
+use parallel_map::parallel_map_chunked;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::{thread, time};
+
+#[test]
+fn test_chunked_keeps_order() {
+    let v: Vec<u64> = (0..1000).collect();
+    let squares = parallel_map_chunked(v, 4, 64, |num| num * num);
+    assert_eq!(squares, (0..1000).map(|num| num * num).collect::<Vec<_>>());
+}
+
+/// The last chunk may be shorter than the rest, and chunks may be bigger than the whole input
+#[test]
+fn test_chunked_uneven_sizes() {
+    for chunk_size in [0, 1, 3, 7, 10, 11, 100] {
+        let v: Vec<i32> = (0..10).collect();
+        let doubled = parallel_map_chunked(v, 3, chunk_size, |num| num * 2);
+        let expected: Vec<i32> = (0..10).map(|num| num * 2).collect();
+        assert_eq!(doubled, expected, "chunk size {}", chunk_size);
+    }
+}
+
+/// Each chunk is handled by a single thread, and the chunks are shared out between the threads
+#[test]
+fn test_chunked_spreads_chunks() {
+    static THREADS: Mutex<Option<HashSet<(u64, thread::ThreadId)>>> = Mutex::new(None);
+    let v: Vec<u64> = (0..40).collect();
+    parallel_map_chunked(v, 4, 10, |num| {
+        thread::sleep(time::Duration::from_millis(10));
+        let mut threads = THREADS.lock().unwrap();
+        threads.get_or_insert_with(HashSet::new).insert((num / 10, thread::current().id()));
+        num
+    });
+    let seen = THREADS.lock().unwrap().take().unwrap();
+    assert_eq!(seen.len(), 4, "Each chunk should have run on exactly one thread");
+    let threads: HashSet<_> = seen.iter().map(|(_, thread)| *thread).collect();
+    assert!(threads.len() > 1, "The chunks should have been spread over more than one thread");
+}
+
+#[test]
+fn test_chunked_empty() {
+    assert!(parallel_map_chunked(Vec::<u64>::new(), 4, 16, |num| num).is_empty());
+}
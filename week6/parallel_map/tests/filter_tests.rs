@@ -0,0 +1,53 @@
+use parallel_map::{parallel_filter, parallel_filter_map};
+use std::{thread, time};
+
+/// Makes later elements finish first, so that results arrive out of order
+fn sleep_for(num: u64) {
+    thread::sleep(time::Duration::from_millis(100 - num * 5));
+}
+
+#[test]
+fn test_filter_keeps_order() {
+    let v: Vec<u64> = (0..20).collect();
+    let evens = parallel_filter(v, 8, |num| {
+        sleep_for(*num);
+        num % 2 == 0
+    });
+    assert_eq!(evens, vec![0, 2, 4, 6, 8, 10, 12, 14, 16, 18]);
+}
+
+#[test]
+fn test_filter_non_copy_elements() {
+    let v: Vec<String> = ["apple", "kiwi", "banana", "fig", "cherry"].iter().map(|s| s.to_string()).collect();
+    let long = parallel_filter(v, 3, |s| s.len() > 4);
+    assert_eq!(long, vec!["apple", "banana", "cherry"]);
+}
+
+#[test]
+fn test_filter_edge_cases() {
+    assert!(parallel_filter(Vec::<u64>::new(), 4, |_| true).is_empty());
+    assert!(parallel_filter(vec![1, 2, 3], 2, |_| false).is_empty());
+    // More threads than elements
+    assert_eq!(parallel_filter(vec![3, 1, 2], 10, |_| true), vec![3, 1, 2]);
+}
+
+#[test]
+fn test_filter_map_keeps_order() {
+    let v: Vec<u64> = (0..20).collect();
+    let squares_of_odds = parallel_filter_map(v, 8, |num| {
+        sleep_for(num);
+        if num % 2 == 1 {
+            Some(num * num)
+        } else {
+            None
+        }
+    });
+    assert_eq!(squares_of_odds, vec![1, 9, 25, 49, 81, 121, 169, 225, 289, 361]);
+}
+
+#[test]
+fn test_filter_map_parses() {
+    let v = vec!["1", "two", "3", "", "5"];
+    let parsed = parallel_filter_map(v, 2, |s| s.parse::<i32>().ok());
+    assert_eq!(parsed, vec![1, 3, 5]);
+}
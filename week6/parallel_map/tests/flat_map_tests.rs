@@ -0,0 +1,29 @@
+use parallel_map::parallel_flat_map;
+use std::{thread, time};
+
+#[test]
+fn test_flat_map_keeps_order() {
+    let v: Vec<u64> = (0..12).collect();
+    // Each number n turns into n copies of itself, so 0 produces nothing and 1 produces one output
+    let repeated = parallel_flat_map(v, 5, |num| {
+        thread::sleep(time::Duration::from_millis(100 - num * 5));
+        vec![num; num as usize]
+    });
+    let expected: Vec<u64> = (0..12).flat_map(|num| vec![num; num as usize]).collect();
+    assert_eq!(repeated, expected);
+}
+
+#[test]
+fn test_flat_map_splits_words() {
+    let v = vec!["the quick", "", "brown", "fox jumps over"];
+    let words = parallel_flat_map(v, 3, |s| s.split_whitespace().map(String::from).collect());
+    assert_eq!(words, vec!["the", "quick", "brown", "fox", "jumps", "over"]);
+}
+
+#[test]
+fn test_flat_map_edge_cases() {
+    assert!(parallel_flat_map(Vec::<u64>::new(), 4, |num| vec![num]).is_empty());
+    assert!(parallel_flat_map(vec![1, 2, 3], 2, |_| Vec::<u64>::new()).is_empty());
+    // More threads than elements
+    assert_eq!(parallel_flat_map(vec![7, 8], 10, |num| vec![num, num + 1]), vec![7, 8, 8, 9]);
+}
@@ -0,0 +1,54 @@
+use parallel_map::{parallel_fold, parallel_reduce};
+
+#[test]
+fn test_fold_sum() {
+    let v: Vec<u64> = (1..=1000).collect();
+    let sum = parallel_fold(v, 7, 0, |acc, num| acc + num, |a, b| a + b);
+    assert_eq!(sum, 500500);
+}
+
+#[test]
+fn test_fold_product() {
+    let v: Vec<u64> = (1..=15).collect();
+    let product = parallel_fold(v, 4, 1, |acc, num| acc * num, |a, b| a * b);
+    assert_eq!(product, 1307674368000);
+}
+
+#[test]
+fn test_fold_max() {
+    let v = vec![3, -8, 41, 7, 0, 41, -100, 12, 9];
+    let max = parallel_fold(v, 3, i32::MIN, |acc, num| acc.max(num), |a, b| a.max(b));
+    assert_eq!(max, 41);
+}
+
+/// Chunks should be combined in order, so that operations that aren't commutative still work
+#[test]
+fn test_fold_keeps_order() {
+    let v: Vec<char> = "the quick brown fox".chars().collect();
+    let joined = parallel_fold(
+        v,
+        5,
+        String::new(),
+        |mut acc, c| {
+            acc.push(c);
+            acc
+        },
+        |a, b| a + &b,
+    );
+    assert_eq!(joined, "the quick brown fox");
+}
+
+#[test]
+fn test_fold_edge_cases() {
+    assert_eq!(parallel_fold(Vec::<u64>::new(), 4, 0, |acc, num| acc + num, |a, b| a + b), 0);
+    // More threads than elements
+    assert_eq!(parallel_fold(vec![5, 6], 10, 0, |acc, num| acc + num, |a, b| a + b), 11);
+}
+
+#[test]
+fn test_reduce() {
+    let v: Vec<u64> = (1..=100).collect();
+    assert_eq!(parallel_reduce(v, 6, |a, b| a + b), Some(5050));
+    assert_eq!(parallel_reduce(vec![4, 19, 2], 2, |a: i32, b: i32| a.max(b)), Some(19));
+    assert_eq!(parallel_reduce(Vec::<u64>::new(), 3, |a, b| a + b), None);
+}
@@ -0,0 +1,26 @@
+use parallel_map::parallel_map_indexed;
+use std::{thread, time};
+
+#[test]
+fn test_indexed_gets_each_index() {
+    let v = vec!["a", "b", "c", "d", "e"];
+    let labels = parallel_map_indexed(v, 3, |index, s| format!("{}{}", s, index));
+    assert_eq!(labels, vec!["a0", "b1", "c2", "d3", "e4"]);
+}
+
+#[test]
+fn test_indexed_keeps_order() {
+    let v: Vec<u64> = (0..20).collect();
+    let output = parallel_map_indexed(v, 6, |index, num| {
+        // Make later elements finish first, so that results arrive out of order
+        thread::sleep(time::Duration::from_millis(100 - num * 5));
+        index as u64 * 1000 + num
+    });
+    assert_eq!(output, (0..20).map(|num| num * 1001).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_indexed_empty() {
+    let output = parallel_map_indexed(Vec::<u64>::new(), 4, |index, num| index as u64 + num);
+    assert!(output.is_empty());
+}
@@ -0,0 +1,107 @@
+use parallel_map::{
+    parallel_filter, parallel_fold, parallel_map, parallel_map_chunked, parallel_map_unordered,
+    parallel_map_work_steal, parallel_scan, parallel_sort_by,
+};
+use std::panic;
+
+/// A panic in `f` reaches the caller with the payload it was raised with
+#[test]
+fn test_panic_propagates() {
+    let v: Vec<u64> = (0..20).collect();
+    let result = panic::catch_unwind(|| {
+        parallel_map(v, 4, |num| {
+            if num == 7 {
+                panic!("Can't handle {}", num);
+            }
+            num * num
+        })
+    });
+    let payload = result.expect_err("parallel_map should have panicked");
+    assert_eq!(payload.downcast_ref::<String>().map(String::as_str), Some("Can't handle 7"));
+}
+
+#[derive(Debug, PartialEq)]
+struct BadElement(u64);
+
+#[test]
+fn test_panic_payload_kept() {
+    let v: Vec<u64> = (0..10).collect();
+    let result = panic::catch_unwind(|| {
+        parallel_map(v, 3, |num| {
+            if num % 4 == 3 {
+                panic::panic_any(BadElement(num));
+            }
+            num
+        })
+    });
+    let payload = result.expect_err("parallel_map should have panicked");
+    let bad = payload.downcast_ref::<BadElement>().expect("The payload should be a BadElement");
+    assert!(bad == &BadElement(3) || bad == &BadElement(7), "Unexpected payload {:?}", bad);
+}
+
+/// Every thread panicking still leaves the caller with a panic, rather than a vec of defaults
+#[test]
+fn test_panic_in_every_thread() {
+    let v: Vec<u64> = (0..8).collect();
+    let result = panic::catch_unwind(|| {
+        parallel_map(v, 2, |_: u64| -> u64 {
+            panic!("Nothing works");
+        })
+    });
+    let payload = result.expect_err("parallel_map should have panicked");
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"Nothing works"));
+}
+
+/// Returns the message of a panic raised with a formatted string
+fn panic_message(result: std::thread::Result<impl Sized>) -> String {
+    let payload = result.err().expect("The function should have panicked");
+    payload.downcast_ref::<String>().expect("The payload should be a String").clone()
+}
+
+fn fail_on_7(num: u64) -> u64 {
+    if num == 7 {
+        panic!("Can't handle {}", num);
+    }
+    num
+}
+
+/// The other variants pass panics on the same way as parallel_map
+#[test]
+fn test_panic_propagates_from_variants() {
+    let v = || (0..20).collect::<Vec<u64>>();
+    let expected = "Can't handle 7";
+    assert_eq!(
+        panic_message(panic::catch_unwind(|| parallel_map_chunked(v(), 3, 4, fail_on_7))),
+        expected
+    );
+    assert_eq!(
+        panic_message(panic::catch_unwind(|| parallel_map_work_steal(v(), 3, fail_on_7))),
+        expected
+    );
+    assert_eq!(
+        panic_message(panic::catch_unwind(|| parallel_map_unordered(v(), 3, fail_on_7).count())),
+        expected
+    );
+    assert_eq!(
+        panic_message(panic::catch_unwind(|| parallel_filter(v(), 3, |num| fail_on_7(*num) > 2))),
+        expected
+    );
+    assert_eq!(
+        panic_message(panic::catch_unwind(|| {
+            parallel_fold(v(), 3, 0, |acc, num| acc + fail_on_7(num), |a, b| a + b)
+        })),
+        expected
+    );
+    assert_eq!(
+        panic_message(panic::catch_unwind(|| {
+            parallel_scan(v(), 3, 0, |acc, num| acc + fail_on_7(num))
+        })),
+        expected
+    );
+    assert_eq!(
+        panic_message(panic::catch_unwind(|| {
+            parallel_sort_by(v(), 3, |a, b| fail_on_7(*a).cmp(&fail_on_7(*b)))
+        })),
+        expected
+    );
+}
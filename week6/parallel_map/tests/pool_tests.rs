@@ -0,0 +1,73 @@
+use parallel_map::ThreadPool;
+use std::sync::Arc;
+use std::{thread, time};
+
+#[test]
+fn test_pool_map_keeps_order() {
+    let pool = ThreadPool::new(8);
+    let v: Vec<u64> = (0..20).collect();
+    let squares = pool.map(v, |num| {
+        // Make later elements finish first, so that results arrive out of order
+        thread::sleep(time::Duration::from_millis(100 - num * 5));
+        num * num
+    });
+    assert_eq!(squares, (0..20).map(|num| num * num).collect::<Vec<_>>());
+}
+
+/// The same workers should serve every call, rather than new threads being spawned each time
+#[test]
+fn test_pool_reuses_threads() {
+    let pool = ThreadPool::new(4);
+    let mut thread_ids = std::collections::HashSet::new();
+    for _ in 0..200 {
+        let ids = pool.map(vec![(); 8], |_| thread::current().id());
+        thread_ids.extend(ids);
+    }
+    assert!(thread_ids.len() <= 4, "Saw {} different threads", thread_ids.len());
+    assert!(!thread_ids.contains(&thread::current().id()));
+}
+
+#[test]
+fn test_pool_shared_between_threads() {
+    let pool = Arc::new(ThreadPool::new(3));
+    let handles: Vec<_> = (0..4_u64)
+        .map(|offset| {
+            let pool = pool.clone();
+            thread::spawn(move || pool.map((0..50).collect(), move |num: u64| num + offset * 1000))
+        })
+        .collect();
+    for (offset, handle) in handles.into_iter().enumerate() {
+        let expected: Vec<u64> = (0..50).map(|num| num + offset as u64 * 1000).collect();
+        assert_eq!(handle.join().unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_pool_edge_cases() {
+    let pool = ThreadPool::new(0);
+    assert!(pool.map(Vec::<u64>::new(), |num| num).is_empty());
+    assert_eq!(pool.map(vec!["a", "bc"], |s: &str| s.len()), vec![1, 2]);
+    // Dropping the pool stops and joins its workers
+    drop(pool);
+}
+
+/// A panic in `f` reaches the caller with its payload, and leaves the workers able to run more jobs
+#[test]
+fn test_pool_survives_panics() {
+    let pool = ThreadPool::new(2);
+    for _ in 0..4 {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            pool.map((0..10).collect(), |num: u64| {
+                if num == 3 {
+                    panic!("bad element {}", num);
+                }
+                num
+            })
+        }));
+        let payload = result.expect_err("The panic should have been passed on");
+        assert_eq!(payload.downcast_ref::<String>().map(String::as_str), Some("bad element 3"));
+    }
+    assert_eq!(pool.map(vec![1, 2, 3], |num: u64| num * 2), vec![2, 4, 6]);
+    // Dropping the pool joins its workers, none of which should have died
+    drop(pool);
+}
@@ -0,0 +1,41 @@
+use parallel_map::parallel_map_with_progress;
+use std::{thread, time};
+
+#[test]
+fn test_progress_counts_up() {
+    let v: Vec<u64> = (0..20).collect();
+    let mut calls = Vec::new();
+    let squares = parallel_map_with_progress(
+        v,
+        6,
+        |num| {
+            // Make later elements finish first, so that results arrive out of order
+            thread::sleep(time::Duration::from_millis(100 - num * 5));
+            num * num
+        },
+        |completed, total| calls.push((completed, total)),
+    );
+    assert_eq!(squares, (0..20).map(|num| num * num).collect::<Vec<_>>());
+    assert_eq!(calls, (1..=20).map(|completed| (completed, 20)).collect::<Vec<_>>());
+}
+
+/// The callback runs on the calling thread, so it can use things that can't be sent elsewhere
+#[test]
+fn test_progress_on_calling_thread() {
+    let caller = thread::current().id();
+    let last = std::rc::Rc::new(std::cell::Cell::new(0));
+    let last_ref = last.clone();
+    parallel_map_with_progress(vec![1, 2, 3, 4], 2, |num| num + 1, move |completed, _| {
+        assert_eq!(thread::current().id(), caller);
+        last_ref.set(completed);
+    });
+    assert_eq!(last.get(), 4);
+}
+
+#[test]
+fn test_progress_empty() {
+    let mut called = false;
+    let output = parallel_map_with_progress(Vec::<u64>::new(), 4, |num| num, |_, _| called = true);
+    assert!(output.is_empty());
+    assert!(!called);
+}
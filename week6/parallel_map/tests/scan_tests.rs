@@ -0,0 +1,51 @@
+use parallel_map::parallel_scan;
+
+/// Scans sequentially, for comparison
+fn sequential_scan<T: Clone>(input_vec: &[T], identity: T, f: impl Fn(T, T) -> T) -> Vec<T> {
+    let mut acc = identity;
+    input_vec
+        .iter()
+        .map(|val| {
+            acc = f(acc.clone(), val.clone());
+            acc.clone()
+        })
+        .collect()
+}
+
+#[test]
+fn test_scan_sum() {
+    let v: Vec<u64> = (1..=100).collect();
+    let sums = parallel_scan(v.clone(), 7, 0, |a, b| a + b);
+    assert_eq!(sums, sequential_scan(&v, 0, |a, b| a + b));
+    assert_eq!(sums[99], 5050);
+}
+
+#[test]
+fn test_scan_max() {
+    let v = vec![3, -8, 41, 7, 0, 52, -100, 12, 9, 60, 1];
+    let maxes = parallel_scan(v, 3, i32::MIN, |a, b| a.max(b));
+    assert_eq!(maxes, vec![3, 3, 41, 41, 41, 52, 52, 52, 52, 60, 60]);
+}
+
+#[test]
+fn test_scan_xor() {
+    let v: Vec<u32> = (0..50).map(|num: u32| num.wrapping_mul(2654435761)).collect();
+    let xors = parallel_scan(v.clone(), 4, 0, |a, b| a ^ b);
+    assert_eq!(xors, sequential_scan(&v, 0, |a, b| a ^ b));
+}
+
+/// Associative but not commutative, so the chunks have to be put back together in order
+#[test]
+fn test_scan_concat() {
+    let v: Vec<String> = "abcdefg".chars().map(String::from).collect();
+    let prefixes = parallel_scan(v, 3, String::new(), |a, b| a + &b);
+    assert_eq!(prefixes, vec!["a", "ab", "abc", "abcd", "abcde", "abcdef", "abcdefg"]);
+}
+
+#[test]
+fn test_scan_edge_cases() {
+    assert!(parallel_scan(Vec::<u64>::new(), 4, 0, |a, b| a + b).is_empty());
+    // More threads than elements
+    assert_eq!(parallel_scan(vec![5, 6], 10, 0, |a, b| a + b), vec![5, 11]);
+    assert_eq!(parallel_scan(vec![5, 6, 7], 1, 0, |a, b| a + b), vec![5, 11, 18]);
+}
@@ -0,0 +1,42 @@
+use parallel_map::parallel_sort_by;
+use rand::Rng;
+
+#[test]
+fn test_sort_matches_std() {
+    let mut rng = rand::thread_rng();
+    let v: Vec<i64> = (0..10000).map(|_| rng.gen_range(-1000..1000)).collect();
+    let mut expected = v.clone();
+    expected.sort();
+    assert_eq!(parallel_sort_by(v, 8, |a, b| a.cmp(b)), expected);
+}
+
+#[test]
+fn test_sort_descending() {
+    let v = vec![5, 1, 4, 2, 3, 9, 0];
+    assert_eq!(parallel_sort_by(v, 3, |a, b| b.cmp(a)), vec![9, 5, 4, 3, 2, 1, 0]);
+}
+
+/// Elements that compare equal keep their original order, even when they end up in different
+/// chunks
+#[test]
+fn test_sort_is_stable() {
+    let v: Vec<(u32, usize)> = (0..1000).map(|index| ((index * 7 % 10) as u32, index)).collect();
+    let sorted = parallel_sort_by(v, 6, |a, b| a.0.cmp(&b.0));
+    for pair in sorted.windows(2) {
+        assert!(pair[0].0 < pair[1].0 || (pair[0].0 == pair[1].0 && pair[0].1 < pair[1].1));
+    }
+}
+
+/// Any number of threads works, including more threads than elements
+#[test]
+fn test_sort_thread_counts() {
+    let v = vec![3, 1, 2];
+    for num_threads in 0..6 {
+        assert_eq!(parallel_sort_by(v.clone(), num_threads, |a, b| a.cmp(b)), vec![1, 2, 3]);
+    }
+}
+
+#[test]
+fn test_sort_empty() {
+    assert!(parallel_sort_by(Vec::<u64>::new(), 4, |a, b| a.cmp(b)).is_empty());
+}
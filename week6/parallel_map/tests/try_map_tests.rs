@@ -0,0 +1,51 @@
+use parallel_map::parallel_try_map;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{thread, time};
+
+#[test]
+fn test_try_map_all_ok() {
+    let v: Vec<u64> = (0..20).collect();
+    let squares = parallel_try_map(v, 6, |num| {
+        // Make later elements finish first, so that results arrive out of order
+        thread::sleep(time::Duration::from_millis(100 - num * 5));
+        Ok::<u64, String>(num * num)
+    });
+    assert_eq!(squares, Ok((0..20).map(|num| num * num).collect()));
+}
+
+/// The error returned should be the one for the earliest element that failed, not the first
+/// failure to finish
+#[test]
+fn test_try_map_first_error_in_order() {
+    let v = vec!["1", "2", "x", "4", "y", "6"];
+    let parsed = parallel_try_map(v, 4, |s| {
+        if s == "x" {
+            thread::sleep(time::Duration::from_millis(100));
+        }
+        s.parse::<i32>().map_err(|_| format!("bad number {}", s))
+    });
+    assert_eq!(parsed, Err("bad number x".to_string()));
+}
+
+#[test]
+fn test_try_map_processes_everything() {
+    static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+    let v: Vec<u64> = (0..30).collect();
+    let result = parallel_try_map(v, 5, |num| {
+        PROCESSED.fetch_add(1, Ordering::SeqCst);
+        if num == 3 {
+            Err(num)
+        } else {
+            Ok(num)
+        }
+    });
+    assert_eq!(result, Err(3));
+    assert_eq!(PROCESSED.load(Ordering::SeqCst), 30);
+}
+
+#[test]
+fn test_try_map_edge_cases() {
+    assert_eq!(parallel_try_map(Vec::<u64>::new(), 4, Ok::<u64, ()>), Ok(Vec::new()));
+    // More threads than elements
+    assert_eq!(parallel_try_map(vec![2, 3], 10, |num: u64| Ok::<u64, ()>(num + 1)), Ok(vec![3, 4]));
+}
@@ -0,0 +1,57 @@
+use parallel_map::parallel_map_unordered;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{thread, time};
+
+#[test]
+fn test_unordered_has_every_result() {
+    let v: Vec<u64> = (0..50).collect();
+    let mut squares: Vec<u64> = parallel_map_unordered(v, 8, |num| num * num).collect();
+    squares.sort_unstable();
+    assert_eq!(squares, (0..50).map(|num| num * num).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_unordered_in_completion_order() {
+    let v: Vec<u64> = vec![300, 100, 200];
+    let order: Vec<u64> = parallel_map_unordered(v, 3, |millis| {
+        thread::sleep(time::Duration::from_millis(millis));
+        millis
+    })
+    .collect();
+    assert_eq!(order, vec![100, 200, 300]);
+}
+
+/// The first result can be used long before the slowest element is done
+#[test]
+fn test_unordered_streams_results() {
+    let start = time::Instant::now();
+    let mut results = parallel_map_unordered(vec![10, 1000], 2, |millis| {
+        thread::sleep(time::Duration::from_millis(millis));
+        millis
+    });
+    assert_eq!(results.next(), Some(10));
+    assert!(start.elapsed() < time::Duration::from_millis(500));
+    assert_eq!(results.next(), Some(1000));
+    assert_eq!(results.next(), None);
+}
+
+/// Dropping the iterator stops the threads from starting on more elements
+#[test]
+fn test_unordered_dropped_early() {
+    static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+    let v: Vec<u64> = (0..100).collect();
+    let first = parallel_map_unordered(v, 2, |num| {
+        thread::sleep(time::Duration::from_millis(10));
+        PROCESSED.fetch_add(1, Ordering::SeqCst);
+        num
+    })
+    .next();
+    assert!(first.is_some());
+    thread::sleep(time::Duration::from_millis(100));
+    assert!(PROCESSED.load(Ordering::SeqCst) < 10);
+}
+
+#[test]
+fn test_unordered_empty() {
+    assert_eq!(parallel_map_unordered(Vec::<u64>::new(), 4, |num| num).count(), 0);
+}
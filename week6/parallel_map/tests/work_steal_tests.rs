@@ -0,0 +1,39 @@
+use parallel_map::parallel_map_work_steal;
+use std::time::{Duration, Instant};
+use std::{thread, time};
+
+#[test]
+fn test_work_steal_keeps_order() {
+    let v: Vec<u64> = (0..20).collect();
+    let squares = parallel_map_work_steal(v, 6, |num| {
+        // Make later elements finish first, so that results arrive out of order
+        thread::sleep(time::Duration::from_millis(100 - num * 5));
+        num * num
+    });
+    assert_eq!(squares, (0..20).map(|num| num * num).collect::<Vec<_>>());
+}
+
+/// All the slow elements start out on the first thread, so the others have to steal them for the
+/// map to finish quickly
+#[test]
+fn test_work_steal_balances_skewed_work() {
+    let v: Vec<u64> = (0..16).collect();
+    let start = Instant::now();
+    let doubled = parallel_map_work_steal(v, 4, |num| {
+        if num < 4 {
+            thread::sleep(Duration::from_millis(200));
+        }
+        num * 2
+    });
+    let elapsed = start.elapsed();
+    assert_eq!(doubled, (0..16).map(|num| num * 2).collect::<Vec<_>>());
+    assert!(elapsed < Duration::from_millis(600), "Took {:?}, so nothing was stolen", elapsed);
+}
+
+#[test]
+fn test_work_steal_edge_cases() {
+    assert!(parallel_map_work_steal(Vec::<u64>::new(), 4, |num| num).is_empty());
+    // More threads than elements
+    assert_eq!(parallel_map_work_steal(vec![1, 2, 3], 10, |num| num + 1), vec![2, 3, 4]);
+    assert_eq!(parallel_map_work_steal(vec!["a", "bc"], 0, |s: &str| s.len()), vec![1, 2]);
+}
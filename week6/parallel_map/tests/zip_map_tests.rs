@@ -0,0 +1,35 @@
+use parallel_map::parallel_zip_map;
+use std::{thread, time};
+
+#[test]
+fn test_zip_map_keeps_order() {
+    let a: Vec<u64> = (0..20).collect();
+    let b: Vec<u64> = (100..120).collect();
+    let sums = parallel_zip_map(a, b, 8, |x, y| {
+        // Make later elements finish first, so that results arrive out of order
+        thread::sleep(time::Duration::from_millis(100 - x * 5));
+        x + y
+    });
+    assert_eq!(sums, (0..20).map(|x| 100 + 2 * x).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_zip_map_different_types() {
+    let names = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+    let counts = vec![3, 0, 2];
+    let repeated = parallel_zip_map(names, counts, 2, |name, count| name.repeat(count));
+    assert_eq!(repeated, vec!["aaa", "", "cc"]);
+}
+
+#[test]
+fn test_zip_map_edge_cases() {
+    assert!(parallel_zip_map(Vec::<u64>::new(), Vec::<u64>::new(), 4, |x, y| x * y).is_empty());
+    // More threads than elements
+    assert_eq!(parallel_zip_map(vec![2, 3], vec![4, 5], 10, |x, y| x * y), vec![8, 15]);
+}
+
+#[test]
+#[should_panic(expected = "same length")]
+fn test_zip_map_length_mismatch() {
+    parallel_zip_map(vec![1, 2, 3], vec![1, 2], 2, |x: i32, y: i32| x + y);
+}